@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// pods of `SPA_PARAM_Format` are sent by consumers, parsing must not panic
+fuzz_target!(|data: &[u8]| {
+    pw_capture_client::fuzzing::parse_format_param(data);
+});
@@ -2,7 +2,9 @@ use crate::*;
 
 use anyhow::Context;
 
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::rc::Rc;
+use std::sync::Arc;
 use std::thread;
 use std::{cell::RefCell, fmt::Debug};
 
@@ -10,7 +12,7 @@ use anyhow::{anyhow, Result};
 use crossbeam_channel::{bounded, Sender};
 use dashmap::DashMap;
 use educe::Educe;
-use log::{debug, trace};
+use log::{debug, trace, warn};
 use pipewire as pw;
 use pw::main_loop::MainLoop as PwMainLoop;
 use pw::properties::properties;
@@ -40,16 +42,16 @@ struct ClientImplInner {
     core: pw::core::Core,
     stream_next_id: usize,
     stream_map: DashMap<usize, (StreamImpl, OwnedReceiver)>,
+    /// shared with `Client` and its streams
+    alive: Arc<AtomicBool>,
 }
 
-type StreamMessageReceiver<'a> = pw::channel::AttachedReceiver<'a, StreamMessage>;
-
 self_cell!(
     struct OwnedReceiver {
         owner: PwMainLoop,
 
         #[covariant]
-        dependent: StreamMessageReceiver,
+        dependent: StreamSources,
     }
 );
 
@@ -79,6 +81,11 @@ impl ClientMethods for ClientImpl {
             })
         })?;
 
+        let active = stream_impl.active();
+        let errored = stream_impl.errored();
+        let dropped = stream_impl.dropped();
+        let stats = stream_impl.stats();
+        let alive = self.inner.borrow().alive.clone();
         let mainloop = self.inner.borrow().mainloop.clone();
         let (pw_sender, pw_receiver) = pw::channel::channel::<StreamMessage>();
         let receiver = OwnedReceiver::new(mainloop, |mainloop| {
@@ -90,7 +97,14 @@ impl ClientMethods for ClientImpl {
             .stream_map
             .insert(id, (stream_impl, receiver));
 
-        Ok(Stream { pw_sender })
+        Ok(Stream {
+            pw_sender,
+            active,
+            errored,
+            dropped,
+            stats,
+            alive,
+        })
     }
 }
 
@@ -99,6 +113,12 @@ impl ClientMethods for ClientImpl {
 pub struct Stream {
     #[educe(Debug(ignore))]
     pub(crate) pw_sender: pipewire::channel::Sender<StreamMessage>,
+    active: Arc<AtomicBool>,
+    errored: Arc<AtomicBool>,
+    dropped: Arc<AtomicU64>,
+    stats: Arc<StreamStats>,
+    /// of the client, cleared once the connection to PipeWire died
+    alive: Arc<AtomicBool>,
 }
 
 impl Stream {
@@ -113,6 +133,27 @@ impl Stream {
                 .map_err(|e| anyhow!("failed to send {e:?}"))
         })
     }
+
+    /// whether the stream isn't paused, without a round trip to the PipeWire thread
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Acquire)
+    }
+
+    /// whether the stream is in error state, e.g. after the consumer failed or PipeWire
+    /// restarted, nothing should be captured for it then
+    pub fn is_errored(&self) -> bool {
+        self.errored.load(Ordering::Acquire) || !self.alive.load(Ordering::Acquire)
+    }
+
+    /// frames dropped to keep latency bounded, see `StreamInfo::max_latency`
+    pub fn dropped_frames(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// counters shared with the stream, to record timings and read without a round trip
+    pub fn stats(&self) -> Arc<StreamStats> {
+        self.stats.clone()
+    }
 }
 
 impl Drop for Stream {
@@ -127,6 +168,7 @@ pub struct Client {
     #[educe(Debug(ignore))]
     pw_sender: pw::channel::Sender<ClientMessage>,
     pw_thread: Option<thread::JoinHandle<Result<()>>>,
+    alive: Arc<AtomicBool>,
 }
 
 impl Client {
@@ -134,7 +176,15 @@ impl Client {
         debug!("creating client");
         let (done_sender, done_receiver) = bounded(1);
         let (pw_sender, pw_receiver) = pw::channel::channel::<ClientMessage>();
-        let pw_thread = thread::spawn(move || pw_thread(done_sender, pw_receiver));
+        let alive = Arc::new(AtomicBool::new(true));
+        let pw_thread = thread::spawn({
+            let alive = alive.clone();
+            move || {
+                let res = pw_thread(done_sender, pw_receiver, alive.clone());
+                alive.store(false, Ordering::Release);
+                res
+            }
+        });
 
         done_receiver
             .recv()
@@ -143,9 +193,16 @@ impl Client {
         Ok(Self {
             pw_sender,
             pw_thread: Some(pw_thread),
+            alive,
         })
     }
 
+    /// whether the connection to PipeWire is up, false once the daemon went away, e.g. on a
+    /// restart, a new client must be created then
+    pub fn is_alive(&self) -> bool {
+        self.alive.load(Ordering::Acquire)
+    }
+
     pub fn proxy(
         &self,
     ) -> ClientMethodsProxy<anyhow::Error, impl Fn(ClientMessage) -> Result<(), anyhow::Error>>
@@ -191,6 +248,7 @@ mod pw_guard {
 fn pw_thread(
     done_sender: Sender<()>,
     pw_receiver: pw::channel::Receiver<ClientMessage>,
+    alive: Arc<AtomicBool>,
 ) -> Result<()> {
     let _ = pw_guard::PipeWireGuard::new();
 
@@ -208,11 +266,28 @@ fn pw_thread(
 
     debug!("{:?}", core);
 
+    // `EPIPE` on the core is the daemon going away, the connection isn't reestablished
+    let _core_listener = core
+        .add_listener_local()
+        .error({
+            let mainloop = mainloop.clone();
+            let alive = alive.clone();
+            move |id, _seq, res, message| {
+                if id == pw::core::PW_ID_CORE && res == -libc::EPIPE {
+                    warn!("disconnected from PipeWire: {}", message);
+                    alive.store(false, Ordering::Release);
+                    mainloop.quit();
+                }
+            }
+        })
+        .register();
+
     let client_impl_inner = ClientImplInner {
         mainloop: mainloop.clone(),
         core,
         stream_next_id: 0,
         stream_map: DashMap::new(),
+        alive,
     };
     let client_impl = RefCell::new(ClientImpl {
         inner: Rc::new(RefCell::new(client_impl_inner)),
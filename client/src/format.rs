@@ -105,6 +105,30 @@ impl Default for Format {
     }
 }
 
+impl Format {
+    /// Whether frames are stored as luma and chroma, which needs a colour matrix and range
+    pub fn is_yuv(self) -> bool {
+        matches!(
+            self,
+            Format::I420
+                | Format::YV12
+                | Format::YUY2
+                | Format::UYVY
+                | Format::YVYU
+                | Format::VYUY
+                | Format::AYUV
+                | Format::Y41B
+                | Format::Y42B
+                | Format::Y444
+                | Format::NV12
+                | Format::NV21
+                | Format::NV16
+                | Format::NV61
+                | Format::NV24
+        )
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Transfer {
     UNKNOWN,
@@ -119,6 +143,23 @@ pub enum Transfer {
     SFLOAT,
 }
 
+impl Transfer {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Transfer::UNKNOWN => "unknown",
+            Transfer::SRGB => "srgb",
+            Transfer::UNORM => "unorm",
+            Transfer::SNORM => "snorm",
+            Transfer::UINT => "uint",
+            Transfer::SINT => "sint",
+            Transfer::USCALED => "uscaled",
+            Transfer::SSCALED => "sscaled",
+            Transfer::UFLOAT => "ufloat",
+            Transfer::SFLOAT => "sfloat",
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::Format;
@@ -215,4 +256,11 @@ mod tests {
         assert_eq!(SPA_VIDEO_FORMAT_RGBA_102LE, Format::RGBA_102LE.into());
         assert_eq!(SPA_VIDEO_FORMAT_BGRA_102LE, Format::BGRA_102LE.into());
     }
+    #[test]
+    fn yuv() {
+        assert!(Format::NV12.is_yuv());
+        assert!(Format::YUY2.is_yuv());
+        assert!(!Format::BGRA.is_yuv());
+        assert!(!Format::RGBA_F16.is_yuv());
+    }
 }
@@ -10,8 +10,25 @@ pub(crate) use spa_utils::*;
 pub use stream::*;
 pub(crate) use utils::*;
 
+/// entry points of fuzz targets in `fuzz/`
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzzing {
+    pub fn parse_format_param(bytes: &[u8]) {
+        let _ = crate::parse_format_param(bytes);
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Point {
     pub x: i32,
     pub y: i32,
 }
+
+/// rectangle in frame coordinates
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub position: Point,
+    pub width: u32,
+    pub height: u32,
+}
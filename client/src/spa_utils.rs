@@ -1,4 +1,4 @@
-use crate::{Format, Point};
+use crate::{ColorPrimaries, Colorimetry, Format, InterlaceMode, Point, Region, TransferFunction};
 
 pub(crate) use libspa as spa;
 pub(crate) use libspa_sys as spa_sys;
@@ -11,12 +11,15 @@ use core::slice;
 use std::io::Cursor;
 
 use anyhow::{anyhow, Result};
+use log::debug;
 
 #[derive(Clone, Debug, Default)]
 pub(crate) struct VideoRawInfo {
     pub format: Format,
     pub dont_fixate_modifier: bool,
     pub modifiers: Vec<u64>,
+    pub interlace_mode: InterlaceMode,
+    pub colorimetry: Colorimetry,
 }
 
 pub(crate) unsafe fn spa_buffer_find_meta_data<T>(
@@ -36,6 +39,57 @@ pub(crate) unsafe fn spa_buffer_find_meta_data<T>(
     ptr::null_mut()
 }
 
+/// Elements of the meta of `type_` that fit its size, empty if the buffer has no such meta.
+pub(crate) unsafe fn spa_buffer_find_meta_slice<'a, T>(
+    buffer: *mut libspa_sys::spa_buffer,
+    type_: u32,
+) -> &'a mut [T] {
+    let buffer = &*buffer;
+    let metas = slice::from_raw_parts_mut(buffer.metas, buffer.n_metas as _);
+    match metas.iter().find(|meta| meta.type_ == type_) {
+        Some(meta) if !meta.data.is_null() => {
+            slice::from_raw_parts_mut(meta.data as _, meta.size as usize / mem::size_of::<T>())
+        }
+        _ => &mut [],
+    }
+}
+
+/// Fills `SPA_META_VideoDamage` regions with `damage`, or its bounding box if it doesn't fit.
+/// Consumers stop at the first empty region.
+pub(crate) fn fill_damage_regions(regions: &mut [spa_sys::spa_meta_region], damage: &[Region]) {
+    let empty = Region {
+        position: Point { x: 0, y: 0 },
+        width: 0,
+        height: 0,
+    };
+    let bounds;
+    let damage = if damage.len() > regions.len() {
+        let left = damage.iter().map(|v| v.position.x).min().unwrap_or(0);
+        let top = damage.iter().map(|v| v.position.y).min().unwrap_or(0);
+        let right = damage
+            .iter()
+            .map(|v| v.position.x + v.width as i32)
+            .max()
+            .unwrap_or(0);
+        let bottom = damage
+            .iter()
+            .map(|v| v.position.y + v.height as i32)
+            .max()
+            .unwrap_or(0);
+        bounds = [Region {
+            position: Point { x: left, y: top },
+            width: (right - left) as u32,
+            height: (bottom - top) as u32,
+        }];
+        &bounds[..]
+    } else {
+        damage
+    };
+    for (i, region) in regions.iter_mut().enumerate() {
+        region.region = damage.get(i).copied().unwrap_or(empty).into();
+    }
+}
+
 pub(crate) fn spa_pod_serialize<P: serialize::PodSerialize + ?Sized>(value: &P) -> Result<Vec<u8>> {
     let res = serialize::PodSerializer::serialize(Cursor::new(Vec::new()), value)?
         .0
@@ -112,6 +166,21 @@ impl TryFrom<Value> for VideoRawInfo {
                         .map(|v| v as _)
                         .collect();
                 }
+                spa_sys::SPA_FORMAT_VIDEO_interlaceMode => {
+                    if let Some(&mode) = value_collect_id(value).first() {
+                        info.interlace_mode = InterlaceMode::from_raw(mode);
+                    }
+                }
+                spa_sys::SPA_FORMAT_VIDEO_transferFunction => {
+                    if let Some(&raw) = value_collect_id(value).first() {
+                        info.colorimetry.transfer_function = TransferFunction::from_raw(raw);
+                    }
+                }
+                spa_sys::SPA_FORMAT_VIDEO_colorPrimaries => {
+                    if let Some(&raw) = value_collect_id(value).first() {
+                        info.colorimetry.primaries = ColorPrimaries::from_raw(raw);
+                    }
+                }
                 _ => continue,
             }
         }
@@ -119,6 +188,15 @@ impl TryFrom<Value> for VideoRawInfo {
     }
 }
 
+/// Parses a `SPA_PARAM_Format` pod sent by the graph, malformed pods are errors rather than panics,
+/// the fuzz target in `fuzz/` feeds it arbitrary bytes.
+pub(crate) fn parse_format_param(bytes: &[u8]) -> Result<VideoRawInfo> {
+    let (_, value) = deserialize::PodDeserializer::deserialize_from::<Value>(bytes)
+        .map_err(|e| anyhow!("error parsing pod {:?}", e))?;
+    debug!("{value:?}");
+    value.try_into()
+}
+
 impl From<Point> for spa_sys::spa_point {
     fn from(value: Point) -> Self {
         Self {
@@ -127,3 +205,86 @@ impl From<Point> for spa_sys::spa_point {
         }
     }
 }
+
+impl From<Region> for spa_sys::spa_region {
+    fn from(value: Region) -> Self {
+        Self {
+            position: value.position.into(),
+            size: spa_sys::spa_rectangle {
+                width: value.width,
+                height: value.height,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build_format;
+
+    #[test]
+    fn format_param() {
+        let unset = Colorimetry::default();
+        let bytes = build_format(64, 64, &[Format::BGRA], &[0, 1], 60, &[], unset, true).unwrap();
+        let info = parse_format_param(&bytes).unwrap();
+        assert_eq!(info.format, Format::BGRA);
+        assert_eq!(info.modifiers, vec![0]);
+        assert_eq!(info.interlace_mode, InterlaceMode::Progressive);
+        assert_eq!(info.colorimetry, unset);
+
+        let modes = [InterlaceMode::Interleaved, InterlaceMode::Progressive];
+        let hdr10 = Colorimetry {
+            transfer_function: TransferFunction::Pq,
+            primaries: ColorPrimaries::Bt2020,
+        };
+        let bytes = build_format(64, 64, &[Format::RGBA], &[0], 60, &modes, hdr10, false).unwrap();
+        let info = parse_format_param(&bytes).unwrap();
+        assert_eq!(info.interlace_mode, InterlaceMode::Interleaved);
+        assert_eq!(info.colorimetry, hdr10);
+
+        for len in 0..bytes.len() {
+            let _ = parse_format_param(&bytes[..len]);
+        }
+        assert!(parse_format_param(&[0xff; 64]).is_err());
+        // sizes, types and counts of a consumer's pod may be anything, each byte of a valid one
+        // replaced by a few values covers some of what the fuzz target finds
+        let mut mutated = bytes.clone();
+        for i in 0..bytes.len() {
+            for v in [0, 1, 0x7f, 0x80, 0xff] {
+                mutated[i] = v;
+                let _ = parse_format_param(&mutated);
+            }
+            mutated[i] = bytes[i];
+        }
+    }
+
+    #[test]
+    fn damage_regions() {
+        let region = |x, y, width, height| Region {
+            position: Point { x, y },
+            width,
+            height,
+        };
+        let size = |v: &spa_sys::spa_meta_region| (v.region.size.width, v.region.size.height);
+        let mut regions: [spa_sys::spa_meta_region; 2] = unsafe { mem::zeroed() };
+
+        fill_damage_regions(&mut regions, &[region(10, 20, 30, 40)]);
+        assert_eq!(regions[0].region.position.x, 10);
+        assert_eq!(size(&regions[0]), (30, 40));
+        assert_eq!(size(&regions[1]), (0, 0));
+
+        let damage = [
+            region(10, 20, 30, 40),
+            region(0, 50, 5, 5),
+            region(100, 0, 1, 1),
+        ];
+        fill_damage_regions(&mut regions, &damage);
+        assert_eq!(regions[0].region.position.x, 0);
+        assert_eq!(regions[0].region.position.y, 0);
+        assert_eq!(size(&regions[0]), (101, 60));
+        assert_eq!(size(&regions[1]), (0, 0));
+
+        fill_damage_regions(&mut [], &damage);
+    }
+}
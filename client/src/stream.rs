@@ -3,8 +3,14 @@ use crate::*;
 use core::mem;
 use core::ptr;
 use core::slice;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::alloc::{alloc, dealloc, handle_alloc_error, Layout};
-use std::sync::Arc;
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::panic;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
 use std::{cell::RefCell, fmt::Debug};
 
 use anyhow::{anyhow, Result};
@@ -23,6 +29,12 @@ const MAX_PROCESS_BUFFERS: usize = 4;
 const MAX_CURSOR_WIDTH: usize = 64;
 const MAX_CURSOR_BPP: usize = 4;
 const MAX_CURSOR_BITMAP_SIZE: usize = MAX_CURSOR_WIDTH * MAX_CURSOR_WIDTH * MAX_CURSOR_BPP;
+// `SPA_DATA_FLAG_MAPPABLE`, missing in headers of older PipeWire
+const SPA_DATA_FLAG_MAPPABLE: u32 = 1 << 3;
+// `SPA_META_VideoTransform`, missing in headers of older PipeWire
+const SPA_META_VIDEO_TRANSFORM: u32 = 8;
+// more damage rectangles are sent as their bounding box
+const MAX_DAMAGE_REGIONS: usize = 16;
 
 #[enumizer(
     name=StreamMessage,
@@ -36,6 +48,24 @@ pub trait StreamMethods {
     fn terminate(&self) -> Result<()>;
     fn dequeue_buffer(&self) -> Option<(BufferHandle, BufferUserHandle)>;
     fn queue_buffer_process(&self, buffer: BufferHandle) -> Result<()>;
+    fn cancel_buffer(&self, buffer: BufferHandle);
+    /// deactivates the stream, no buffers are dequeued until resumed
+    fn pause(&self) -> Result<()>;
+    fn resume(&self) -> Result<()>;
+    /// offers the formats again at `width`x`height`, consumers renegotiate without the node
+    /// being recreated, also at the same size, e.g. for the producer to fixate again
+    fn resize(&self, width: u32, height: u32) -> Result<()>;
+    fn stats(&self) -> StreamStatsSnapshot;
+    /// updates the HDR metadata node properties
+    fn set_hdr_metadata(&self, metadata: HdrMetadata) -> Result<()>;
+    /// updates the node description with a new window title
+    fn set_title(&self, title: Option<String>) -> Result<()>;
+    /// sends the frames held back in replay mode, one with each frame processed afterwards,
+    /// returns how many
+    fn save_replay(&self) -> Result<usize>;
+    /// marks the whole frame as changed in the damage of the next frame sent, e.g. for a
+    /// consumer that lost track of the frame
+    fn request_full_frame(&self) -> Result<()>;
 }
 
 #[derive(Clone, Debug)]
@@ -50,12 +80,215 @@ pub struct FixateFormat {
     pub num_planes: u32,
 }
 
+/// Counters of a stream, updated by the capturing thread and process calls without locking.
+#[derive(Debug, Default)]
+pub struct StreamStats {
+    frames_captured: AtomicU64,
+    frames_dropped_no_buffer: AtomicU64,
+    last_dequeue_nanos: AtomicU64,
+    last_process_nanos: AtomicU64,
+}
+
+impl StreamStats {
+    /// time taken by the last `dequeue_buffer` call, measured by the caller
+    pub fn set_dequeue_time(&self, duration: Duration) {
+        self.last_dequeue_nanos
+            .store(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// time taken by the last `queue_buffer_process` call, measured by the caller
+    pub fn set_process_time(&self, duration: Duration) {
+        self.last_process_nanos
+            .store(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> StreamStatsSnapshot {
+        StreamStatsSnapshot {
+            frames_captured: self.frames_captured.load(Ordering::Relaxed),
+            frames_dropped_no_buffer: self.frames_dropped_no_buffer.load(Ordering::Relaxed),
+            last_dequeue_time: Duration::from_nanos(
+                self.last_dequeue_nanos.load(Ordering::Relaxed),
+            ),
+            last_process_time: Duration::from_nanos(
+                self.last_process_nanos.load(Ordering::Relaxed),
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StreamStatsSnapshot {
+    /// frames handed to consumers
+    pub frames_captured: u64,
+    /// frames not captured as all buffers were held by consumers
+    pub frames_dropped_no_buffer: u64,
+    pub last_dequeue_time: Duration,
+    pub last_process_time: Duration,
+}
+
+/// relation of color components to alpha, advertised as `pw-capture.alpha-mode` node property
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AlphaMode {
+    #[default]
+    Opaque,
+    Premultiplied,
+    Straight,
+}
+
+impl AlphaMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AlphaMode::Opaque => "opaque",
+            AlphaMode::Premultiplied => "premultiplied",
+            AlphaMode::Straight => "straight",
+        }
+    }
+}
+
+/// transformation applied to frames relative to their display orientation, rotations are
+/// counter-clockwise, in order of `spa_meta_videotransform_value`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VideoTransform {
+    #[default]
+    None,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+    Flipped,
+    Flipped90,
+    Flipped180,
+    Flipped270,
+}
+
+/// layout of the fields of frames, in order of `spa_video_interlace_mode`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InterlaceMode {
+    #[default]
+    Progressive,
+    /// fields interleaved line by line in one frame
+    Interleaved,
+}
+
+impl InterlaceMode {
+    pub(crate) fn from_raw(raw: u32) -> Self {
+        match raw {
+            spa_sys::SPA_VIDEO_INTERLACE_MODE_INTERLEAVED => Self::Interleaved,
+            _ => Self::Progressive,
+        }
+    }
+}
+
+/// transfer function of frame values, see `spa_video_transfer_function`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TransferFunction {
+    #[default]
+    Unknown,
+    /// linear values, `GAMMA10`
+    Linear,
+    Srgb,
+    Bt709,
+    /// perceptual quantizer of HDR10, `SMPTE2084`
+    Pq,
+    /// hybrid log-gamma, `ARIB_STD_B67`
+    Hlg,
+}
+
+impl TransferFunction {
+    const RAW: [(Self, u32); 5] = [
+        (Self::Linear, spa_sys::SPA_VIDEO_TRANSFER_GAMMA10),
+        (Self::Srgb, spa_sys::SPA_VIDEO_TRANSFER_SRGB),
+        (Self::Bt709, spa_sys::SPA_VIDEO_TRANSFER_BT709),
+        (Self::Pq, spa_sys::SPA_VIDEO_TRANSFER_SMPTE2084),
+        (Self::Hlg, spa_sys::SPA_VIDEO_TRANSFER_ARIB_STD_B67),
+    ];
+
+    pub(crate) fn to_raw(self) -> u32 {
+        Self::RAW
+            .iter()
+            .find(|&&(v, _)| v == self)
+            .map_or(spa_sys::SPA_VIDEO_TRANSFER_UNKNOWN, |&(_, raw)| raw)
+    }
+
+    pub(crate) fn from_raw(raw: u32) -> Self {
+        Self::RAW
+            .iter()
+            .find(|&&(_, v)| v == raw)
+            .map_or(Self::Unknown, |&(v, _)| v)
+    }
+}
+
+/// color primaries of frame values, see `spa_video_color_primaries`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorPrimaries {
+    #[default]
+    Unknown,
+    Bt709,
+    Bt2020,
+    /// Display P3, `SMPTEEG432`
+    DisplayP3,
+}
+
+impl ColorPrimaries {
+    const RAW: [(Self, u32); 3] = [
+        (Self::Bt709, spa_sys::SPA_VIDEO_COLOR_PRIMARIES_BT709),
+        (Self::Bt2020, spa_sys::SPA_VIDEO_COLOR_PRIMARIES_BT2020),
+        (
+            Self::DisplayP3,
+            spa_sys::SPA_VIDEO_COLOR_PRIMARIES_SMPTEEG432,
+        ),
+    ];
+
+    pub(crate) fn to_raw(self) -> u32 {
+        Self::RAW
+            .iter()
+            .find(|&&(v, _)| v == self)
+            .map_or(spa_sys::SPA_VIDEO_COLOR_PRIMARIES_UNKNOWN, |&(_, raw)| raw)
+    }
+
+    pub(crate) fn from_raw(raw: u32) -> Self {
+        Self::RAW
+            .iter()
+            .find(|&&(_, v)| v == raw)
+            .map_or(Self::Unknown, |&(v, _)| v)
+    }
+}
+
+/// colorimetry of exported frames, unknown parts aren't advertised and consumers assume sRGB
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Colorimetry {
+    pub transfer_function: TransferFunction,
+    pub primaries: ColorPrimaries,
+}
+
+/// mastering display and content light levels in nits, advertised as `pw-capture.max-luminance`,
+/// `pw-capture.min-luminance`, `pw-capture.max-cll` and `pw-capture.max-fall` node properties
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HdrMetadata {
+    pub max_luminance: f32,
+    pub min_luminance: f32,
+    pub max_content_light_level: f32,
+    pub max_frame_average_light_level: f32,
+}
+
+/// modes offered by a stream, none advertises progressive frames only, like before
+fn offered_interlace_modes(interlaced: bool) -> &'static [InterlaceMode] {
+    if interlaced {
+        &[InterlaceMode::Progressive, InterlaceMode::Interleaved]
+    } else {
+        &[]
+    }
+}
+
 pub struct AddBufferMetaCbs<'a> {
     pub add_cursor: Option<Box<dyn FnOnce(BufferCursorInfo) + 'a>>,
 }
 
 type ProcessBufferCb = Box<dyn Fn(BufferUserHandle, AddBufferMetaCbs) + Send>;
 
+type VideoCropCb = Box<dyn Fn(BufferUserHandle) -> Option<Region> + Send>;
+
+type VideoDamageCb = Box<dyn Fn(BufferUserHandle, bool) -> Vec<Region> + Send>;
+
 #[derive(Educe)]
 #[educe(Debug)]
 pub struct StreamInfo {
@@ -63,14 +296,71 @@ pub struct StreamInfo {
     pub height: u32,
     pub enum_formats: Vec<EnumFormatInfo>,
     pub max_buffers: u32,
+    pub alpha_mode: AlphaMode,
+    /// encoding of exported frames, advertised as `pw-capture.transfer` node property
+    pub transfer: Transfer,
+    /// image count of the captured swapchain, 0 if unknown, advertised as
+    /// `pw-capture.source-images` node property as a hint for consumer buffering
+    pub source_images: u32,
+    /// frames per second the producer limits captures to, 0 for variable
+    pub framerate: u32,
+    /// of the captured window if known, added to the node description
+    pub title: Option<String>,
+    /// sent as `SPA_META_VideoTransform` unless `None`, for consumers to display frames upright
+    pub transform: VideoTransform,
+    /// also offer interleaved frames, which are progressive captures with both fields from the
+    /// same instant, i.e. 2:2 pulldown
+    pub interlaced: bool,
+    /// advertised in formats, e.g. PQ and BT.2020 primaries for HDR10 frames
+    pub colorimetry: Colorimetry,
+    /// buffers carry no data blocks, only meta, `add_buffer` returns no planes
+    pub metadata_only: bool,
+    /// consumers may ask for buffers without data blocks with a `SPA_PARAM_Buffers` param of 0
+    /// blocks, `add_buffer` is passed true for those and returns no planes
+    pub metadata_on_request: bool,
+    /// buffers without a modifier are offered as `SPA_DATA_MemPtr` instead of memfd, the memfd
+    /// planes returned by `add_buffer` are mapped for consumers that can't take fds
+    pub mem_ptr: bool,
+    /// don't drive the graph, only produce a frame after the consumer's cycle asked for one. The
+    /// pull only gates which frames are produced, `dequeue_buffer` returns no buffer until a cycle
+    /// asked for one, frames are still taken when the producer has one, not at cycle time
+    pub pull: bool,
+    /// no buffers are dequeued for this long after the stream starts streaming
+    pub warmup: Duration,
+    /// `pw-capture.static` node property turns `true` once no frame was produced for this long,
+    /// and back to `false` with the next frame, zero to disable
+    pub static_timeout: Duration,
+    /// added to the pts of frames, may be negative, e.g. to sync with audio captured elsewhere
+    pub pts_offset_ns: i64,
+    /// frames waiting longer than this to be processed are dropped, and none are dequeued while
+    /// older ones drain, keeping latency bounded under a slow consumer, zero to disable
+    pub max_latency: Duration,
+    /// processed frames are held back for up to this long instead of being sent, until
+    /// `save_replay`, zero to disable. Bounded by the buffers consumers allocate, `max_buffers`
+    /// is asked for.
+    pub replay: Duration,
+    /// downscaled preview of another stream of the same source, named apart from it and
+    /// advertised with the `pw-capture.preview` node property
+    pub preview: bool,
     #[educe(Debug(ignore))]
     pub fixate_format: Box<dyn Fn(EnumFormatInfo) -> Option<FixateFormat> + Send>,
     #[educe(Debug(ignore))]
-    pub add_buffer: Box<dyn Fn() -> Option<BufferInfo> + Send>,
+    pub add_buffer: Box<dyn Fn(bool) -> Option<BufferInfo> + Send>,
     #[educe(Debug(ignore))]
     pub remove_buffer: Box<dyn Fn(BufferUserHandle) + Send>,
     #[educe(Debug(ignore))]
     pub process_buffer: ProcessBufferCb,
+    /// region of a processed buffer holding content, e.g. without letterboxing, sent as
+    /// `SPA_META_VideoCrop` if set, `None` for the whole frame
+    #[educe(Debug(ignore))]
+    pub video_crop: Option<VideoCropCb>,
+    /// regions of a processed buffer changed since the previous frame, or the whole frame if
+    /// asked to by the flag, sent as `SPA_META_VideoDamage` if set
+    #[educe(Debug(ignore))]
+    pub video_damage: Option<VideoDamageCb>,
+    /// called when a consumer starts streaming, e.g. to capture its first frame right away
+    #[educe(Debug(ignore))]
+    pub streaming: Box<dyn Fn() + Send>,
 }
 
 mod buffer_handle {
@@ -103,10 +393,19 @@ pub struct BufferPlaneInfo {
     pub stride: u32,
 }
 
+/// properties of the memory backing a buffer, `false` if unknown
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BufferMemoryInfo {
+    pub device_local: bool,
+    /// advertised to consumers with `SPA_DATA_FLAG_MAPPABLE`
+    pub host_visible: bool,
+}
+
 #[derive(Clone, Debug)]
 pub struct BufferInfo {
     pub is_dma_buf: bool,
     pub planes: Vec<BufferPlaneInfo>,
+    pub memory: BufferMemoryInfo,
     pub user_handle: BufferUserHandle,
 }
 
@@ -146,56 +445,184 @@ struct StreamImplInner {
     stream: pw::stream::Stream,
     #[allow(unused)]
     listener: Option<pw::stream::StreamListener<StreamData>>,
+    width: u32,
+    height: u32,
     enum_formats: Vec<EnumFormatInfo>,
     max_buffers: u32,
-    buffer_sender: Sender<BufferHandle>,
+    framerate: u32,
+    transform: VideoTransform,
+    interlaced: bool,
+    colorimetry: Colorimetry,
+    /// buffers carry `SPA_META_VideoCrop`
+    video_crop: bool,
+    /// buffers carry `SPA_META_VideoDamage`
+    video_damage: bool,
+    metadata_only: bool,
+    metadata_on_request: bool,
+    mem_ptr: bool,
+    preview: bool,
+    /// shared with process calls, set in replay mode
+    replay: Option<Arc<Mutex<ReplayRing>>>,
+    /// shared with process calls
+    last_frame: Arc<Mutex<LastFrame>>,
+    /// set by `request_full_frame` and when a consumer starts streaming, cleared by the process
+    /// call sending the next frame
+    full_frame: Arc<AtomicBool>,
+    /// with the time queued, to measure how long it waits to be processed
+    buffer_sender: Sender<(BufferHandle, Instant)>,
+    pull: bool,
+    /// set by process call of a cycle without a frame ready, in pull mode
+    pulled: Arc<AtomicBool>,
+    /// cleared while paused, shared with `Stream` handles
+    active: Arc<AtomicBool>,
+    /// set while in error state, shared with `Stream` handles
+    errored: Arc<AtomicBool>,
+    /// set by process calls dropping buffers older than `max_latency`
+    lagging: Arc<AtomicBool>,
+    /// frames dropped for latency, shared with `Stream` handles
+    dropped: Arc<AtomicU64>,
+    /// shared with `Stream` handles
+    stats: Arc<StreamStats>,
+    warmup: Duration,
+    streaming_since: Rc<Cell<Option<Instant>>>,
+    static_timeout: Duration,
+    /// when a frame was last queued, compared against `static_timeout`
+    last_queued: Cell<Instant>,
+    is_static: Cell<bool>,
     on_terminate: Option<Box<dyn FnOnce()>>,
 }
 
+impl StreamImplInner {
+    /// `EnumFormat` params offered at the current size
+    fn enum_format_params(&self) -> Result<Vec<Vec<u8>>> {
+        self.enum_formats
+            .iter()
+            .map(|enum_format| {
+                build_format(
+                    self.width,
+                    self.height,
+                    &enum_format.formats,
+                    &enum_format.modifiers,
+                    self.framerate,
+                    offered_interlace_modes(self.interlaced),
+                    self.colorimetry,
+                    false,
+                )
+            })
+            .collect()
+    }
+
+    /// updates `pw-capture.static` node property if changed
+    fn set_static(&self, is_static: bool) {
+        if self.is_static.replace(is_static) == is_static {
+            return;
+        }
+        debug!("source static: {}", is_static);
+        let props = properties! {
+            "pw-capture.static" => if is_static { "true" } else { "false" },
+        };
+        unsafe {
+            pw::sys::pw_stream_update_properties(
+                self.stream.as_raw_ptr(),
+                &(*props.as_raw_ptr()).dict,
+            );
+        }
+    }
+}
+
+/// loop sources of a stream, removed when dropped
+pub(crate) struct StreamSources<'a> {
+    #[allow(unused)]
+    receiver: pw::channel::AttachedReceiver<'a, StreamMessage>,
+    #[allow(unused)]
+    static_timer: Option<pw::loop_::TimerSource<'a>>,
+}
+
 #[derive(Clone)]
 pub(crate) struct StreamImpl {
     inner: Arc<RefCell<StreamImplInner>>,
 }
 
-pub(crate) fn build_stream_params(max_buffers: u32, blocks: u32, is_dma_buf: bool) -> Vec<Vec<u8>> {
+/// serializes a param pod, logging the offending value on failure
+fn serialize_param(value: &Value) -> Result<Vec<u8>> {
+    spa_pod_serialize(value).map_err(|e| {
+        error!("failed to serialize param {:?}: {}", value, e);
+        anyhow!("failed to serialize param: {}", e)
+    })
+}
+
+/// `SPA_PARAM_BUFFERS_blocks` of buffers with `blocks`, consumers may pick 0 instead with
+/// `metadata_on_request`, other counts still fail to negotiate
+fn blocks_value(blocks: u32, metadata_on_request: bool) -> Value {
+    if blocks == 0 || !metadata_on_request {
+        return Value::Int(blocks as _);
+    }
+    Value::Choice(ChoiceValue::Int(Choice(
+        ChoiceFlags::empty(),
+        ChoiceEnum::Enum {
+            default: blocks as _,
+            alternatives: vec![blocks as _, 0],
+        },
+    )))
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn build_stream_params(
+    max_buffers: u32,
+    blocks: u32,
+    metadata_on_request: bool,
+    is_dma_buf: bool,
+    mem_ptr: bool,
+    transform: VideoTransform,
+    video_crop: bool,
+    video_damage: bool,
+    replay: bool,
+) -> Result<Vec<Vec<u8>>> {
     let data_type_flag = if is_dma_buf {
         1 << spa_sys::SPA_DATA_DmaBuf
+    } else if mem_ptr {
+        1 << spa_sys::SPA_DATA_MemPtr
     } else {
         1 << spa_sys::SPA_DATA_MemFd
     };
+    let mut buffer_properties = vec![
+        Property {
+            key: spa_sys::SPA_PARAM_BUFFERS_buffers,
+            flags: PropertyFlags::empty(),
+            value: Value::Choice(ChoiceValue::Int(Choice(
+                ChoiceFlags::empty(),
+                ChoiceEnum::Range {
+                    // held back frames take buffers, as many as allowed
+                    default: if replay { max_buffers as _ } else { 8 },
+                    min: 1,
+                    max: max_buffers as _,
+                },
+            ))),
+        },
+        Property {
+            key: spa_sys::SPA_PARAM_BUFFERS_blocks,
+            flags: PropertyFlags::empty(),
+            value: blocks_value(blocks, metadata_on_request),
+        },
+    ];
+    // buffers without blocks only carry meta
+    if blocks > 0 {
+        buffer_properties.push(Property {
+            key: spa_sys::SPA_PARAM_BUFFERS_dataType,
+            flags: PropertyFlags::empty(),
+            value: Value::Choice(ChoiceValue::Int(Choice(
+                ChoiceFlags::empty(),
+                ChoiceEnum::Flags {
+                    default: data_type_flag,
+                    flags: vec![],
+                },
+            ))),
+        });
+    }
     let buffers = Value::Object(Object {
         type_: spa_sys::SPA_TYPE_OBJECT_ParamBuffers,
         id: spa_sys::SPA_PARAM_Buffers,
-        properties: vec![
-            Property {
-                key: spa_sys::SPA_PARAM_BUFFERS_buffers,
-                flags: PropertyFlags::empty(),
-                value: Value::Choice(ChoiceValue::Int(Choice(
-                    ChoiceFlags::empty(),
-                    ChoiceEnum::Range {
-                        default: 8,
-                        min: 1,
-                        max: max_buffers as _,
-                    },
-                ))),
-            },
-            Property {
-                key: spa_sys::SPA_PARAM_BUFFERS_blocks,
-                flags: PropertyFlags::empty(),
-                value: Value::Int(blocks.max(1) as _),
-            },
-            Property {
-                key: spa_sys::SPA_PARAM_BUFFERS_dataType,
-                flags: PropertyFlags::empty(),
-                value: Value::Choice(ChoiceValue::Int(Choice(
-                    ChoiceFlags::empty(),
-                    ChoiceEnum::Flags {
-                        default: data_type_flag,
-                        flags: vec![],
-                    },
-                ))),
-            },
-        ],
+        properties: buffer_properties,
     });
 
     let meta_header = Value::Object(Object {
@@ -236,19 +663,75 @@ pub(crate) fn build_stream_params(max_buffers: u32, blocks: u32, is_dma_buf: boo
         ],
     });
 
-    let params = &[buffers, meta_header, meta_cursor];
-    params
-        .iter()
-        .map(|value| -> Result<Vec<u8>> { spa_pod_serialize(value) })
-        .collect::<Result<Vec<_>>>()
-        .unwrap_or_default()
+    let mut params = vec![buffers, meta_header, meta_cursor];
+    // consumers unaware of the meta display the same without it
+    if transform != VideoTransform::None {
+        params.push(Value::Object(Object {
+            type_: spa_sys::SPA_TYPE_OBJECT_ParamMeta,
+            id: spa_sys::SPA_PARAM_Meta,
+            properties: vec![
+                Property {
+                    key: spa_sys::SPA_PARAM_META_type,
+                    flags: PropertyFlags::empty(),
+                    value: Value::Id(Id(SPA_META_VIDEO_TRANSFORM)),
+                },
+                Property {
+                    key: spa_sys::SPA_PARAM_META_size,
+                    flags: PropertyFlags::empty(),
+                    value: Value::Int(mem::size_of::<u32>() as _),
+                },
+            ],
+        }));
+    }
+    if video_crop {
+        params.push(Value::Object(Object {
+            type_: spa_sys::SPA_TYPE_OBJECT_ParamMeta,
+            id: spa_sys::SPA_PARAM_Meta,
+            properties: vec![
+                Property {
+                    key: spa_sys::SPA_PARAM_META_type,
+                    flags: PropertyFlags::empty(),
+                    value: Value::Id(Id(spa_sys::SPA_META_VideoCrop)),
+                },
+                Property {
+                    key: spa_sys::SPA_PARAM_META_size,
+                    flags: PropertyFlags::empty(),
+                    value: Value::Int(mem::size_of::<spa_sys::spa_meta_region>() as _),
+                },
+            ],
+        }));
+    }
+    if video_damage {
+        let size = mem::size_of::<spa_sys::spa_meta_region>() * MAX_DAMAGE_REGIONS;
+        params.push(Value::Object(Object {
+            type_: spa_sys::SPA_TYPE_OBJECT_ParamMeta,
+            id: spa_sys::SPA_PARAM_Meta,
+            properties: vec![
+                Property {
+                    key: spa_sys::SPA_PARAM_META_type,
+                    flags: PropertyFlags::empty(),
+                    value: Value::Id(Id(spa_sys::SPA_META_VideoDamage)),
+                },
+                Property {
+                    key: spa_sys::SPA_PARAM_META_size,
+                    flags: PropertyFlags::empty(),
+                    value: Value::Int(size as _),
+                },
+            ],
+        }));
+    }
+    params.iter().map(serialize_param).collect()
 }
 
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn build_format(
     width: u32,
     height: u32,
     formats: &[Format],
     modifiers: &[u64],
+    framerate: u32,
+    interlace_modes: &[InterlaceMode],
+    colorimetry: Colorimetry,
     fixate: bool,
 ) -> Result<Vec<u8>> {
     assert!(!formats.is_empty());
@@ -289,10 +772,60 @@ pub(crate) fn build_format(
         Property {
             key: spa_sys::SPA_FORMAT_VIDEO_framerate,
             flags: PropertyFlags::empty(),
-            value: Value::Fraction(Fraction { num: 0, denom: 1 }),
+            value: Value::Fraction(Fraction {
+                num: framerate,
+                denom: 1,
+            }),
         },
     ];
 
+    if let Some(&default) = interlace_modes.first() {
+        let value = if interlace_modes.len() > 1 {
+            Value::Choice(ChoiceValue::Id(Choice(
+                ChoiceFlags::empty(),
+                ChoiceEnum::Enum {
+                    default: Id(default as _),
+                    alternatives: interlace_modes.iter().map(|&m| Id(m as _)).collect(),
+                },
+            )))
+        } else {
+            Value::Id(Id(default as _))
+        };
+        properties.push(Property {
+            key: spa_sys::SPA_FORMAT_VIDEO_interlaceMode,
+            flags: PropertyFlags::empty(),
+            value,
+        });
+    }
+    // producers convert luma and chroma frames with the BT.709 matrix into limited range
+    if formats.iter().all(|f| f.is_yuv()) {
+        properties.push(Property {
+            key: spa_sys::SPA_FORMAT_VIDEO_colorMatrix,
+            flags: PropertyFlags::empty(),
+            value: Value::Id(Id(spa_sys::SPA_VIDEO_COLOR_MATRIX_BT709)),
+        });
+        properties.push(Property {
+            key: spa_sys::SPA_FORMAT_VIDEO_colorRange,
+            flags: PropertyFlags::empty(),
+            value: Value::Id(Id(spa_sys::SPA_VIDEO_COLOR_RANGE_16_235)),
+        });
+    }
+
+    if colorimetry.transfer_function != TransferFunction::Unknown {
+        properties.push(Property {
+            key: spa_sys::SPA_FORMAT_VIDEO_transferFunction,
+            flags: PropertyFlags::empty(),
+            value: Value::Id(Id(colorimetry.transfer_function.to_raw())),
+        });
+    }
+    if colorimetry.primaries != ColorPrimaries::Unknown {
+        properties.push(Property {
+            key: spa_sys::SPA_FORMAT_VIDEO_colorPrimaries,
+            flags: PropertyFlags::empty(),
+            value: Value::Id(Id(colorimetry.primaries.to_raw())),
+        });
+    }
+
     if modifiers.len() > 0 {
         let prop = if fixate {
             Property {
@@ -321,7 +854,7 @@ pub(crate) fn build_format(
         id: spa_sys::SPA_PARAM_EnumFormat,
         properties,
     });
-    spa_pod_serialize(&param)
+    serialize_param(&param)
 }
 
 impl StreamMethods for StreamImpl {
@@ -335,19 +868,50 @@ impl StreamMethods for StreamImpl {
     fn dequeue_buffer(&self) -> Option<(BufferHandle, BufferUserHandle)> {
         let inner = self.inner.borrow();
         let stream = &inner.stream;
+        if !inner.active.load(Ordering::Acquire) {
+            return None;
+        }
+        // let the late ones drain instead of queueing more behind them
+        if inner.lagging.load(Ordering::Acquire) && !inner.buffer_sender.is_empty() {
+            trace!("lagging, drop frame");
+            inner.dropped.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
         match inner.stream.state() {
             pw::stream::StreamState::Streaming => (),
             _ => return None,
         }
-        if !inner.stream.is_driving() {
+        if let Some(since) = inner.streaming_since.get() {
+            if since.elapsed() < inner.warmup {
+                trace!("warming up");
+                return None;
+            }
+        }
+        if inner.pull {
+            if !inner.pulled.load(Ordering::Acquire) {
+                return None;
+            }
+        } else if !inner.stream.is_driving() {
             return None;
         }
+        let mut last_frame = inner
+            .last_frame
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
         unsafe {
-            let buffer = ptr::NonNull::new(stream.dequeue_raw_buffer());
+            let buffer = last_frame
+                .spare
+                .pop_front()
+                .map(ptr::NonNull::from)
+                .or_else(|| ptr::NonNull::new(stream.dequeue_raw_buffer()));
             let buffer = if let Some(v) = buffer {
                 v
             } else {
                 trace!("out of buffer");
+                inner
+                    .stats
+                    .frames_dropped_no_buffer
+                    .fetch_add(1, Ordering::Relaxed);
                 return None;
             };
             let pw_buffer = buffer.as_ref();
@@ -357,30 +921,333 @@ impl StreamMethods for StreamImpl {
                 stream.queue_raw_buffer(buffer.as_ptr());
                 return None;
             };
+            let spa_buffer = &*pw_buffer.buffer;
+            if spa_buffer.n_datas > 0 && (*spa_buffer.datas).type_ == libspa_sys::SPA_DATA_Invalid {
+                trace!("buffer marked invalid");
+                stream.queue_raw_buffer(buffer.as_ptr());
+                return None;
+            }
+            // overwritten by the frame captured into it
+            last_frame.forget(buffer.as_ptr());
             Some((buffer.into(), *user_data))
         }
     }
 
     fn queue_buffer_process(&self, buffer: BufferHandle) -> Result<()> {
-        if self.inner.borrow().stream.is_driving() {
-            self.inner
-                .borrow()
+        let inner = self.inner.borrow();
+        inner.last_queued.set(Instant::now());
+        inner.set_static(false);
+        if inner.pull {
+            // processed by the next cycle of the graph driver
+            inner.pulled.store(false, Ordering::Release);
+            inner
                 .buffer_sender
-                .send(buffer)
+                .send((buffer, Instant::now()))
                 .map_err(|e| anyhow!("{e:?}"))?;
+        } else if inner.stream.is_driving() {
+            inner
+                .buffer_sender
+                .send((buffer, Instant::now()))
+                .map_err(|e| anyhow!("{e:?}"))?;
+
+            inner.stream.trigger_process()?;
+        }
+        Ok(())
+    }
+
+    fn cancel_buffer(&self, buffer: BufferHandle) {
+        trace!("cancel buffer");
+        let inner = self.inner.borrow();
+        // there is no way to give a dequeued buffer back to the stream, it's handed out first by
+        // the next dequeue instead of being queued without a frame
+        inner
+            .last_frame
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .spare
+            .push_front(buffer);
+    }
+
+    fn pause(&self) -> Result<()> {
+        debug!("pause stream");
+        let inner = self.inner.borrow();
+        inner.active.store(false, Ordering::Release);
+        inner.stream.set_active(false)?;
+        Ok(())
+    }
+
+    fn resume(&self) -> Result<()> {
+        debug!("resume stream");
+        let inner = self.inner.borrow();
+        inner.stream.set_active(true)?;
+        inner.active.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    fn resize(&self, width: u32, height: u32) -> Result<()> {
+        debug!("resize stream to {}x{}", width, height);
+        let mut inner = self.inner.borrow_mut();
+        inner.width = width;
+        inner.height = height;
+        let params = inner.enum_format_params()?;
+        let mut params = params
+            .iter()
+            .map(|p| Pod::from_bytes(p).expect("not a valid Pod"))
+            .collect::<Vec<_>>();
+        inner.stream.update_params(&mut params)?;
+        Ok(())
+    }
+
+    fn stats(&self) -> StreamStatsSnapshot {
+        self.inner.borrow().stats.snapshot()
+    }
 
-            self.inner.borrow().stream.trigger_process()?;
+    fn set_hdr_metadata(&self, metadata: HdrMetadata) -> Result<()> {
+        debug!("HDR metadata {:?}", metadata);
+        let [max_luminance, min_luminance, max_cll, max_fall] = [
+            metadata.max_luminance,
+            metadata.min_luminance,
+            metadata.max_content_light_level,
+            metadata.max_frame_average_light_level,
+        ]
+        .map(|v| v.to_string());
+        let props = properties! {
+            "pw-capture.max-luminance" => max_luminance.as_str(),
+            "pw-capture.min-luminance" => min_luminance.as_str(),
+            "pw-capture.max-cll" => max_cll.as_str(),
+            "pw-capture.max-fall" => max_fall.as_str(),
+        };
+        unsafe {
+            pw::sys::pw_stream_update_properties(
+                self.inner.borrow().stream.as_raw_ptr(),
+                &(*props.as_raw_ptr()).dict,
+            );
         }
         Ok(())
     }
+
+    fn set_title(&self, title: Option<String>) -> Result<()> {
+        debug!("window title {:?}", title);
+        let preview = self.inner.borrow().preview;
+        let name = get_node_description(&get_app_name(), title.as_deref(), preview);
+        let props = properties! {
+            *pw::keys::MEDIA_NAME => name.as_str(),
+            *pw::keys::NODE_DESCRIPTION => name.as_str(),
+        };
+        unsafe {
+            pw::sys::pw_stream_update_properties(
+                self.inner.borrow().stream.as_raw_ptr(),
+                &(*props.as_raw_ptr()).dict,
+            );
+        }
+        Ok(())
+    }
+
+    fn save_replay(&self) -> Result<usize> {
+        let inner = self.inner.borrow();
+        let replay = inner.replay.as_ref().ok_or(anyhow!("replay not enabled"))?;
+        let count = replay.lock().unwrap_or_else(PoisonError::into_inner).save();
+        info!("saving replay of {} frames", count);
+        Ok(count)
+    }
+
+    fn request_full_frame(&self) -> Result<()> {
+        debug!("full frame requested");
+        self.inner
+            .borrow()
+            .full_frame
+            .store(true, Ordering::Release);
+        Ok(())
+    }
+}
+
+/// Processed frames held back in replay mode, oldest first. Every frame processed sends at most
+/// one held buffer, so a cycle never outputs more than one.
+#[derive(Default)]
+struct ReplayRing {
+    length: Duration,
+    pts_offset_ns: i64,
+    /// allocated by the stream
+    buffers: u32,
+    /// with their pts
+    frames: VecDeque<(BufferHandle, i64)>,
+    /// taken from `frames` by `save_replay`, with the shift of their pts
+    saved: VecDeque<(BufferHandle, i64)>,
+}
+
+impl ReplayRing {
+    /// Holds back a processed frame, then sends the oldest saved frame if any, or drops the
+    /// oldest held one once the ring is full or spans more than `length`.
+    unsafe fn push(&mut self, stream: &pw::stream::StreamRef, buffer: BufferHandle, pts: i64) {
+        self.frames.push_back((buffer, pts));
+        if let Some((buffer, shift)) = self.saved.pop_front() {
+            let pw_buffer = ptr::NonNull::from(buffer).as_mut();
+            let header = spa_buffer_find_meta_data::<libspa_sys::spa_meta_header>(
+                pw_buffer.buffer,
+                libspa_sys::SPA_META_Header,
+            );
+            if !header.is_null() {
+                (*header).pts = (*header).pts.saturating_add(shift);
+            }
+            stream.queue_raw_buffer(pw_buffer);
+            return;
+        }
+        // leaves a buffer for the consumer and one to capture into
+        let capacity = (self.buffers.saturating_sub(2) as usize).max(1);
+        let span = self.frames.front().map_or(0, |&(_, oldest)| pts - oldest);
+        if self.frames.len() + self.saved.len() > capacity || span > self.length.as_nanos() as i64 {
+            if let Some((buffer, _)) = self.frames.pop_front() {
+                drop_buffer(stream, buffer);
+            }
+        }
+    }
+
+    /// Moves held frames to the saved ones, shifting their pts so the newest is sent as now.
+    fn save(&mut self) -> usize {
+        let Some(&(_, newest)) = self.frames.back() else {
+            return 0;
+        };
+        let shift = get_pts_nanos().saturating_add(self.pts_offset_ns) - newest;
+        let count = self.frames.len();
+        self.saved
+            .extend(self.frames.drain(..).map(|(buffer, _)| (buffer, shift)));
+        count
+    }
+
+    /// forgets a buffer removed from the stream, e.g. on renegotiation
+    fn remove(&mut self, buffer: *mut pw::sys::pw_buffer) {
+        self.buffers = self.buffers.saturating_sub(1);
+        let held = |&(v, _): &(BufferHandle, i64)| ptr::NonNull::from(v).as_ptr() != buffer;
+        self.frames.retain(held);
+        self.saved.retain(held);
+    }
+}
+
+/// Buffer of the last frame sent, sent again once a consumer starts streaming, so a consumer
+/// linked while the app presents nothing gets a frame right away.
+#[derive(Default)]
+struct LastFrame {
+    buffer: Option<BufferHandle>,
+    /// `buffer` is dequeued, to be sent again by the next process call
+    resend: bool,
+    /// dequeued while looking for `buffer` or canceled, handed out first by `dequeue_buffer`
+    spare: VecDeque<BufferHandle>,
+}
+
+impl LastFrame {
+    /// Dequeues the buffer of the last frame to send it again, returns whether it's pending.
+    /// Buffers dequeued before it are kept as spares.
+    unsafe fn reclaim(&mut self, stream: &pw::stream::StreamRef) -> bool {
+        let Some(last) = self.buffer else {
+            return false;
+        };
+        if self.resend {
+            return true;
+        }
+        let is_last = |v: &BufferHandle| ptr::NonNull::from(*v) == ptr::NonNull::from(last);
+        if let Some(index) = self.spare.iter().position(is_last) {
+            self.spare.remove(index);
+        } else {
+            loop {
+                // e.g. still held by a consumer
+                let Some(buffer) = ptr::NonNull::new(stream.dequeue_raw_buffer()) else {
+                    return false;
+                };
+                let buffer = BufferHandle::from(buffer);
+                if is_last(&buffer) {
+                    break;
+                }
+                self.spare.push_back(buffer);
+            }
+        }
+        self.resend = true;
+        true
+    }
+
+    fn take_resend(&mut self) -> Option<BufferHandle> {
+        if !mem::take(&mut self.resend) {
+            return None;
+        }
+        self.buffer
+    }
+
+    /// forgets `buffer` as the last frame, e.g. once dequeued to capture into
+    fn forget(&mut self, buffer: *mut pw::sys::pw_buffer) {
+        if self
+            .buffer
+            .map_or(false, |v| ptr::NonNull::from(v).as_ptr() == buffer)
+        {
+            self.buffer = None;
+            self.resend = false;
+        }
+    }
+
+    /// forgets a buffer removed from the stream, e.g. on renegotiation
+    fn remove(&mut self, buffer: *mut pw::sys::pw_buffer) {
+        self.forget(buffer);
+        self.spare
+            .retain(|&v| ptr::NonNull::from(v).as_ptr() != buffer);
+    }
+}
+
+/// queues the buffer of an earlier frame again as a new one, all of it damaged
+unsafe fn resend_buffer(
+    data: &mut StreamData,
+    stream: &pw::stream::StreamRef,
+    buffer: BufferHandle,
+    pts_offset_ns: i64,
+    video_damage: Option<&VideoDamageCb>,
+) {
+    let pw_buffer = ptr::NonNull::from(buffer).as_mut();
+    let header = spa_buffer_find_meta_data::<libspa_sys::spa_meta_header>(
+        pw_buffer.buffer,
+        libspa_sys::SPA_META_Header,
+    );
+    if !header.is_null() {
+        (*header).pts = get_pts_nanos().saturating_add(pts_offset_ns);
+        (*header).seq = data.seq;
+    }
+    // the consumer starting to stream didn't see the frame before it
+    let damage_regions = spa_buffer_find_meta_slice::<libspa_sys::spa_meta_region>(
+        pw_buffer.buffer,
+        libspa_sys::SPA_META_VideoDamage,
+    );
+    let user_data = pw_buffer.user_data as *mut BufferUserHandle;
+    if let Some(video_damage) = video_damage.filter(|_| !damage_regions.is_empty()) {
+        if !user_data.is_null() {
+            fill_damage_regions(damage_regions, &video_damage(*user_data, true));
+        }
+    }
+    data.seq += 1;
+    stream.queue_raw_buffer(pw_buffer);
+}
+
+/// queues a processed buffer without its frame, consumers skip corrupted chunks
+unsafe fn drop_buffer(stream: &pw::stream::StreamRef, buffer: BufferHandle) {
+    let pw_buffer = ptr::NonNull::from(buffer).as_mut();
+    mark_chunks_corrupted(&*pw_buffer.buffer, true);
+    stream.queue_raw_buffer(pw_buffer);
+}
+
+/// sets or clears `SPA_CHUNK_FLAG_CORRUPTED` on all chunks of `spa_buffer`
+unsafe fn mark_chunks_corrupted(spa_buffer: &spa_sys::spa_buffer, corrupted: bool) {
+    let datas = slice::from_raw_parts(spa_buffer.datas, spa_buffer.n_datas as _);
+    for data in datas {
+        let Some(chunk) = data.chunk.as_mut() else {
+            continue;
+        };
+        if corrupted {
+            chunk.flags |= spa_sys::SPA_CHUNK_FLAG_CORRUPTED as i32;
+        } else {
+            chunk.flags &= !(spa_sys::SPA_CHUNK_FLAG_CORRUPTED as i32);
+        }
+    }
 }
 
 unsafe fn on_param_changed(
     inner: &StreamImplInner,
     id: u32,
     param: Option<&Pod>,
-    width: u32,
-    height: u32,
     fixate_format: &Box<dyn Fn(EnumFormatInfo) -> Option<FixateFormat> + Send>,
 ) {
     debug!("param changed: id {}", id);
@@ -390,19 +1257,17 @@ unsafe fn on_param_changed(
     if id != spa_sys::SPA_PARAM_Format {
         return;
     }
-    let pod = deserialize::PodDeserializer::deserialize_from::<Value>(parma.as_bytes());
-    let pod = match pod {
-        Ok((_, v)) => v,
-        Err(e) => {
-            debug!("error parsing pod {:?}", e);
+    // the fuzz target keeps the parser from panicking, a panic it missed must still not unwind
+    // through PipeWire into the app
+    let bytes = parma.as_bytes();
+    let raw_info = match panic::catch_unwind(|| parse_format_param(bytes)) {
+        Ok(Ok(v)) => v,
+        Ok(Err(e)) => {
+            error!("error parsing format info {:?}", e);
             return;
         }
-    };
-    debug!("{pod:?}");
-    let raw_info: VideoRawInfo = match pod.clone().try_into() {
-        Ok(v) => v,
-        Err(e) => {
-            error!("error parsing format info  {:?}", e);
+        Err(_) => {
+            error!("format info parser panicked");
             return;
         }
     };
@@ -426,25 +1291,36 @@ unsafe fn on_param_changed(
 
     if raw_info.modifiers.len() > 0 {
         debug!("has modifier");
-        let fixate_modifier = fixate_info.modifier.unwrap();
+        let Some(fixate_modifier) = fixate_info.modifier else {
+            error!("no modifier fixated for {:?}", raw_info.modifiers);
+            return;
+        };
         if raw_info.dont_fixate_modifier {
-            let mut params =
-                vec![
-                    build_format(width, height, &[raw_info.format], &[fixate_modifier], true)
-                        .unwrap(),
-                ];
-            for enum_format in &inner.enum_formats {
-                params.push(
-                    build_format(
-                        width,
-                        height,
-                        &enum_format.formats,
-                        &enum_format.modifiers,
-                        false,
-                    )
-                    .unwrap(),
-                )
-            }
+            let fixated_modes = [raw_info.interlace_mode];
+            let interlace_modes = if inner.interlaced {
+                &fixated_modes[..]
+            } else {
+                &[]
+            };
+            let params = build_format(
+                inner.width,
+                inner.height,
+                &[raw_info.format],
+                &[fixate_modifier],
+                inner.framerate,
+                interlace_modes,
+                inner.colorimetry,
+                true,
+            )
+            .and_then(|fixated| {
+                let mut params = vec![fixated];
+                params.extend(inner.enum_format_params()?);
+                Ok(params)
+            });
+            let Ok(params) = params else {
+                error!("failed to build formats, keep current params");
+                return;
+            };
             let mut params = params
                 .iter()
                 .map(|p| Pod::from_bytes(p).expect("not a valid Pod"))
@@ -457,11 +1333,30 @@ unsafe fn on_param_changed(
         debug!("no modifier");
     }
 
+    if raw_info.interlace_mode != InterlaceMode::Progressive {
+        info!("interlace mode negotiated: {:?}", raw_info.interlace_mode);
+    }
+
+    let blocks = if inner.metadata_only {
+        0
+    } else {
+        fixate_info.num_planes.max(1)
+    };
     let params = build_stream_params(
         inner.max_buffers,
-        fixate_info.num_planes,
+        blocks,
+        inner.metadata_on_request,
         fixate_info.modifier.is_some(),
+        inner.mem_ptr,
+        inner.transform,
+        inner.video_crop,
+        inner.video_damage,
+        inner.replay.is_some(),
     );
+    let Ok(params) = params else {
+        error!("failed to build stream params, keep current params");
+        return;
+    };
     let mut params = params
         .iter()
         .map(|p| Pod::from_bytes(p).expect("not a valid Pod"))
@@ -470,9 +1365,42 @@ unsafe fn on_param_changed(
     let _ = stream.update_params(&mut params);
 }
 
+fn mark_datas_invalid(datas: &mut [spa_sys::spa_data]) {
+    for data in datas {
+        data.fd = -1;
+        data.data = ptr::null_mut();
+        data.type_ = libspa_sys::SPA_DATA_Invalid;
+    }
+}
+
+unsafe fn mark_datas_empty(datas: &mut [spa_sys::spa_data]) {
+    for data in datas {
+        data.fd = -1;
+        data.data = ptr::null_mut();
+        data.maxsize = 0;
+        data.type_ = libspa_sys::SPA_DATA_MemPtr;
+        if let Some(chunk) = data.chunk.as_mut() {
+            chunk.offset = 0;
+            chunk.size = 0;
+            chunk.stride = 0;
+        }
+    }
+}
+
+/// unmaps memfd planes exposed as `SPA_DATA_MemPtr`
+unsafe fn unmap_datas(datas: &mut [spa_sys::spa_data]) {
+    for data in datas {
+        if data.type_ == libspa_sys::SPA_DATA_MemPtr && !data.data.is_null() {
+            libc::munmap(data.data, data.maxsize as _);
+            data.data = ptr::null_mut();
+        }
+    }
+}
+
 unsafe fn on_add_buffer(
     buffer: *mut pw::sys::pw_buffer,
-    add_buffer: &Box<dyn Fn() -> Option<BufferInfo> + Send>,
+    add_buffer: &Box<dyn Fn(bool) -> Option<BufferInfo> + Send>,
+    mem_ptr: bool,
 ) {
     debug!("add buffer");
     let mut buffer = ptr::NonNull::new(buffer).unwrap();
@@ -483,26 +1411,51 @@ unsafe fn on_add_buffer(
     let datas = slice::from_raw_parts_mut(spa_buffer.datas, spa_buffer.n_datas as _);
     // let metas = slice::from_raw_parts_mut(spa_buffer.metas, spa_buffer.n_metas as _);
 
-    let info = add_buffer();
+    // a consumer asked for buffers without blocks, see `StreamInfo::metadata_on_request`
+    let info = add_buffer(datas.is_empty());
     let info = if let Some(info) = info {
         info
     } else {
         error!("failed to add buffer, mark invalid");
-        for data in datas {
-            data.fd = -1;
-            data.data = ptr::null_mut();
-            data.type_ = libspa_sys::SPA_DATA_Invalid;
-        }
+        mark_datas_invalid(datas);
         return;
     };
 
     let data_type = if info.is_dma_buf {
         libspa_sys::SPA_DATA_DmaBuf
+    } else if mem_ptr {
+        libspa_sys::SPA_DATA_MemPtr
     } else {
         libspa_sys::SPA_DATA_MemFd
     };
 
-    assert_eq!(spa_buffer.n_datas, info.planes.len() as _);
+    let layout = Layout::new::<BufferUserHandle>();
+    let user_data = alloc(layout);
+    if user_data.is_null() {
+        handle_alloc_error(layout);
+    }
+    *(user_data as *mut BufferUserHandle) = info.user_handle;
+    // keep the user handle even if the buffer is invalid below,
+    // so the producer could release its resources in `remove_buffer`
+    pw_buffer.user_data = user_data as _;
+
+    if info.planes.is_empty() {
+        // metadata only, blocks may still have been allocated by other params
+        mark_datas_empty(datas);
+        debug!("added metadata-only buffer");
+        return;
+    }
+
+    if spa_buffer.n_datas as usize != info.planes.len() {
+        error!(
+            "producer returned {} planes but {} blocks were negotiated, mark invalid",
+            info.planes.len(),
+            spa_buffer.n_datas
+        );
+        mark_datas_invalid(datas);
+        return;
+    }
+
     for (data, plane) in datas.iter_mut().zip(&info.planes) {
         let chunk = &mut *data.chunk;
         data.fd = plane.fd as _;
@@ -510,21 +1463,49 @@ unsafe fn on_add_buffer(
         data.mapoffset = plane.offset as _;
         data.maxsize = plane.size as _;
         data.type_ = data_type;
+        if info.memory.host_visible {
+            data.flags |= SPA_DATA_FLAG_MAPPABLE;
+        }
         chunk.offset = plane.offset as _;
         chunk.size = plane.size as _;
         chunk.stride = plane.stride as _;
         debug!("{:?}", plane);
-    }
 
-    let layout = Layout::new::<BufferUserHandle>();
-    let user_data = alloc(layout);
-    if user_data.is_null() {
-        handle_alloc_error(layout);
+        if data_type == libspa_sys::SPA_DATA_MemFd {
+            // consumers add the chunk offset to the mapping, and planes sharing
+            // a memfd wouldn't have a page aligned map offset
+            data.mapoffset = 0;
+            data.maxsize = (plane.offset + plane.size) as _;
+        }
+
+        if data_type == libspa_sys::SPA_DATA_MemPtr {
+            // pixels are inline for consumers, the fd stays with the producer
+            let size = (plane.offset + plane.size) as usize;
+            let mapped = libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                plane.fd as _,
+                0,
+            );
+            if mapped == libc::MAP_FAILED {
+                error!(
+                    "failed to map plane: {}, mark invalid",
+                    std::io::Error::last_os_error()
+                );
+                unmap_datas(datas);
+                mark_datas_invalid(datas);
+                return;
+            }
+            data.fd = -1;
+            data.data = mapped;
+            data.mapoffset = 0;
+            data.maxsize = size as _;
+        }
     }
-    *(user_data as *mut BufferUserHandle) = info.user_handle;
-    pw_buffer.user_data = user_data as _;
 
-    debug!("added buffer");
+    debug!("added buffer, {:?}", info.memory);
 }
 
 unsafe fn on_remove_buffer(
@@ -535,6 +1516,11 @@ unsafe fn on_remove_buffer(
     let mut buffer = ptr::NonNull::new(buffer).unwrap();
 
     let pw_buffer = buffer.as_mut();
+    let spa_buffer = &mut *pw_buffer.buffer;
+    unmap_datas(slice::from_raw_parts_mut(
+        spa_buffer.datas,
+        spa_buffer.n_datas as _,
+    ));
     let user_data = pw_buffer.user_data as *mut BufferUserHandle;
     if user_data.is_null() {
         return;
@@ -606,12 +1592,17 @@ unsafe fn fill_cursor_meta(
     }
 }
 
+/// Fills a buffer for queueing, returns its pts, `None` if it's broken.
 unsafe fn on_process_buffer(
-    stream: &pw::stream::StreamRef,
     data: &mut StreamData,
     buffer: BufferHandle,
+    transform: VideoTransform,
+    pts_offset_ns: i64,
     user_process: &ProcessBufferCb,
-) {
+    video_crop: Option<&VideoCropCb>,
+    video_damage: Option<&VideoDamageCb>,
+    full_frame: bool,
+) -> Option<i64> {
     let pw_buffer = ptr::NonNull::from(buffer).as_mut();
 
     let header = spa_buffer_find_meta_data::<libspa_sys::spa_meta_header>(
@@ -624,12 +1615,28 @@ unsafe fn on_process_buffer(
         libspa_sys::SPA_META_Cursor,
     );
 
+    // `spa_meta_videotransform`
+    let video_transform =
+        spa_buffer_find_meta_data::<u32>(pw_buffer.buffer, SPA_META_VIDEO_TRANSFORM);
+
+    let crop_region = spa_buffer_find_meta_data::<libspa_sys::spa_meta_region>(
+        pw_buffer.buffer,
+        libspa_sys::SPA_META_VideoCrop,
+    );
+
+    let damage_regions = spa_buffer_find_meta_slice::<libspa_sys::spa_meta_region>(
+        pw_buffer.buffer,
+        libspa_sys::SPA_META_VideoDamage,
+    );
+
     let user_data = pw_buffer.user_data as *mut BufferUserHandle;
     if user_data.is_null() {
         error!("buffer broken no user data");
-        return;
+        return None;
     };
 
+    mark_chunks_corrupted(&*pw_buffer.buffer, false);
+
     let mut cursor_meta_filled = false;
     user_process(
         *user_data,
@@ -645,10 +1652,11 @@ unsafe fn on_process_buffer(
         },
     );
 
+    let pts = get_pts_nanos().saturating_add(pts_offset_ns);
     if !header.is_null() {
         let header = &mut *header;
         header.flags = 0;
-        header.pts = get_pts_nanos();
+        header.pts = pts;
         // header.pts = -1;
         header.offset = 0;
         header.seq = data.seq;
@@ -656,13 +1664,26 @@ unsafe fn on_process_buffer(
     }
     data.seq += 1;
 
+    if !video_transform.is_null() {
+        *video_transform = transform as u32;
+    }
+
+    if let Some(video_crop) = video_crop.filter(|_| !crop_region.is_null()) {
+        // consumers ignore empty regions, i.e. take the whole frame
+        (*crop_region).region = video_crop(*user_data).map_or_else(mem::zeroed, Into::into);
+    }
+
+    if let Some(video_damage) = video_damage.filter(|_| !damage_regions.is_empty()) {
+        fill_damage_regions(damage_regions, &video_damage(*user_data, full_frame));
+    }
+
     if !cursor.is_null() && !cursor_meta_filled {
         fill_cursor_meta(&mut data.cursor_id, cursor, None);
     }
 
     pw_buffer.size = 1;
 
-    stream.queue_raw_buffer(pw_buffer);
+    Some(pts)
 }
 
 impl StreamImpl {
@@ -671,29 +1692,87 @@ impl StreamImpl {
         info: StreamInfo,
         on_terminate: Box<dyn FnOnce()>,
     ) -> Result<Self> {
-        let name = format!("{} (pw-capture)", get_app_name());
-        let stream = pw::stream::Stream::new(
-            core,
-            name.as_str(),
-            properties! {
+        if info.enum_formats.is_empty() {
+            return Err(anyhow!("no formats to offer"));
+        }
+
+        let app_name = get_app_name();
+        let node_name = get_node_name(&app_name, info.preview);
+        let name = get_node_description(&app_name, info.title.as_deref(), info.preview);
+        let source_images = info.source_images.to_string();
+        let mut props = properties! {
                 *pw::keys::MEDIA_TYPE => "Video",
                 *pw::keys::MEDIA_CATEGORY => "Capture",
                 *pw::keys::MEDIA_ROLE => "Screen",
                 *pw::keys::MEDIA_CLASS => "Video/Source",
                 *pw::keys::MEDIA_SOFTWARE => "pw-capture",
                 *pw::keys::NODE_WANT_DRIVER => "false",
+                *pw::keys::NODE_NAME => node_name.as_str(),
                 *pw::keys::NODE_DESCRIPTION => name.as_str(),
-            },
-        )?;
-
-        let (buffer_sender, buffer_receiver) = bounded::<BufferHandle>(MAX_PROCESS_BUFFERS);
+                "pw-capture.alpha-mode" => info.alpha_mode.as_str(),
+                "pw-capture.transfer" => info.transfer.as_str(),
+                "pw-capture.source-images" => source_images.as_str(),
+        };
+        if !info.static_timeout.is_zero() {
+            props.insert("pw-capture.static", "false");
+        }
+        if info.preview {
+            props.insert("pw-capture.preview", "true");
+        }
+        let stream = pw::stream::Stream::new(core, name.as_str(), props)?;
+
+        let (buffer_sender, buffer_receiver) =
+            bounded::<(BufferHandle, Instant)>(MAX_PROCESS_BUFFERS);
+        let pulled = Arc::new(AtomicBool::new(false));
+        let lagging = Arc::new(AtomicBool::new(false));
+        let errored = Arc::new(AtomicBool::new(false));
+        let dropped = Arc::new(AtomicU64::new(0));
+        let stats = Arc::new(StreamStats::default());
+        let streaming_since = Rc::new(Cell::new(None));
+        let last_frame = Arc::new(Mutex::new(LastFrame::default()));
+        let full_frame = Arc::new(AtomicBool::new(false));
+        let active = Arc::new(AtomicBool::new(true));
+        let replay = (!info.replay.is_zero()).then(|| {
+            Arc::new(Mutex::new(ReplayRing {
+                length: info.replay,
+                pts_offset_ns: info.pts_offset_ns,
+                ..Default::default()
+            }))
+        });
 
         let inner = StreamImplInner {
             stream,
             listener: None,
+            width: info.width,
+            height: info.height,
             enum_formats: info.enum_formats,
             max_buffers: info.max_buffers,
+            framerate: info.framerate,
+            transform: info.transform,
+            interlaced: info.interlaced,
+            colorimetry: info.colorimetry,
+            video_crop: info.video_crop.is_some(),
+            video_damage: info.video_damage.is_some(),
+            metadata_only: info.metadata_only,
+            metadata_on_request: info.metadata_on_request,
+            mem_ptr: info.mem_ptr,
+            preview: info.preview,
+            replay: replay.clone(),
+            last_frame: last_frame.clone(),
+            full_frame: full_frame.clone(),
             buffer_sender,
+            pull: info.pull,
+            pulled: pulled.clone(),
+            active: active.clone(),
+            errored: errored.clone(),
+            lagging: lagging.clone(),
+            dropped: dropped.clone(),
+            stats: stats.clone(),
+            warmup: info.warmup,
+            streaming_since: streaming_since.clone(),
+            static_timeout: info.static_timeout,
+            last_queued: Cell::new(Instant::now()),
+            is_static: Cell::new(false),
             on_terminate: Some(on_terminate),
         };
         let stream_impl = StreamImpl {
@@ -710,11 +1789,44 @@ impl StreamImpl {
             })
             .state_changed({
                 let buffer_receiver = buffer_receiver.clone();
+                let pulled = pulled.clone();
+                let last_frame = last_frame.clone();
+                let full_frame = full_frame.clone();
                 move |stream, _data, old, new| {
                     info!("stream state changed: {:?} -> {:?}", old, new);
+                    errored.store(
+                        matches!(new, pw::stream::StreamState::Error(_)),
+                        Ordering::Release,
+                    );
+                    streaming_since.set(match new {
+                        pw::stream::StreamState::Streaming => Some(Instant::now()),
+                        _ => None,
+                    });
+                    if let pw::stream::StreamState::Streaming = new {
+                        // damage is relative to frames the consumer never saw
+                        full_frame.store(true, Ordering::Release);
+                        // the warmup delays all frames, also the last one
+                        let resend = info.warmup.is_zero()
+                            && active.load(Ordering::Acquire)
+                            && unsafe {
+                                last_frame
+                                    .lock()
+                                    .unwrap_or_else(PoisonError::into_inner)
+                                    .reclaim(stream)
+                            };
+                        if resend {
+                            debug!("sending last frame again");
+                            if !info.pull && stream.is_driving() {
+                                let _ = stream.trigger_process();
+                            }
+                        }
+                        // a fresh frame follows, also if there was none yet
+                        (info.streaming)();
+                    }
                     match new {
                         pw::stream::StreamState::Paused => {
                             let _ = stream.flush(false);
+                            pulled.store(false, Ordering::Release);
                             for _ in buffer_receiver.try_iter() {
                                 // drain buffer channel, in case buffer was not processed
                             }
@@ -727,56 +1839,119 @@ impl StreamImpl {
             .param_changed({
                 let stream_impl = stream_impl.clone();
                 move |_stream, _data, id, param| unsafe {
-                    on_param_changed(
-                        &stream_impl.inner.borrow(),
-                        id,
-                        param,
-                        info.width,
-                        info.height,
-                        &info.fixate_format,
-                    )
+                    on_param_changed(&stream_impl.inner.borrow(), id, param, &info.fixate_format)
                 }
             })
-            .add_buffer(move |_stream, _data, buffer| unsafe {
-                on_add_buffer(buffer, &info.add_buffer)
+            .add_buffer({
+                let replay = replay.clone();
+                move |_stream, _data, buffer| unsafe {
+                    if let Some(replay) = &replay {
+                        replay
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .buffers += 1;
+                    }
+                    on_add_buffer(buffer, &info.add_buffer, info.mem_ptr)
+                }
             })
-            .remove_buffer(move |_stream, _data, buffer| unsafe {
-                on_remove_buffer(buffer, &info.remove_buffer)
+            .remove_buffer({
+                let replay = replay.clone();
+                let last_frame = last_frame.clone();
+                move |_stream, _data, buffer| unsafe {
+                    last_frame
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .remove(buffer);
+                    if let Some(replay) = &replay {
+                        replay
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .remove(buffer);
+                    }
+                    on_remove_buffer(buffer, &info.remove_buffer)
+                }
             })
             .process(move |stream, data| unsafe {
-                if let Ok(buffer) = buffer_receiver.try_recv() {
-                    on_process_buffer(stream, data, buffer, &info.process_buffer);
+                let resend = last_frame
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .take_resend();
+                if let Some(buffer) = resend {
+                    full_frame.store(false, Ordering::Release);
+                    let video_damage = info.video_damage.as_ref();
+                    resend_buffer(data, stream, buffer, info.pts_offset_ns, video_damage);
+                    return;
+                }
+                if let Ok((buffer, queued)) = buffer_receiver.try_recv() {
+                    let latency = queued.elapsed();
+                    if !info.max_latency.is_zero() && latency > info.max_latency {
+                        if !lagging.swap(true, Ordering::AcqRel) {
+                            warn!("consumer {:?} behind, dropping frames", latency);
+                        }
+                        dropped.fetch_add(1, Ordering::Relaxed);
+                        drop_buffer(stream, buffer);
+                        return;
+                    }
+                    if lagging.swap(false, Ordering::AcqRel) {
+                        info!(
+                            "caught up, {} frames dropped so far",
+                            dropped.load(Ordering::Relaxed)
+                        );
+                    }
+                    let pts = on_process_buffer(
+                        data,
+                        buffer,
+                        info.transform,
+                        info.pts_offset_ns,
+                        &info.process_buffer,
+                        info.video_crop.as_ref(),
+                        info.video_damage.as_ref(),
+                        full_frame.swap(false, Ordering::AcqRel),
+                    );
+                    let Some(pts) = pts else {
+                        return;
+                    };
+                    match &replay {
+                        Some(replay) => replay
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .push(stream, buffer, pts),
+                        None => {
+                            stream.queue_raw_buffer(ptr::NonNull::from(buffer).as_mut());
+                            last_frame
+                                .lock()
+                                .unwrap_or_else(PoisonError::into_inner)
+                                .buffer = Some(buffer);
+                        }
+                    }
+                    stats.frames_captured.fetch_add(1, Ordering::Relaxed);
+                } else if info.pull {
+                    // produced from the next present, consumed by a later cycle
+                    pulled.store(true, Ordering::Release);
                 } else {
                     warn!("unscheduled process call");
                 }
             })
             .register()?;
 
-        let mut params = vec![];
-        for enum_format in &stream_impl.inner.borrow().enum_formats {
-            params.push(
-                build_format(
-                    info.width,
-                    info.height,
-                    &enum_format.formats,
-                    &enum_format.modifiers,
-                    false,
-                )
-                .unwrap(),
-            )
-        }
+        let params = stream_impl.inner.borrow().enum_format_params()?;
         let mut params = params
             .iter()
             .map(|p| Pod::from_bytes(p).expect("not a valid Pod"))
             .collect::<Vec<_>>();
 
-        stream_impl.inner.borrow().stream.connect(
-            spa::utils::Direction::Output,
-            None,
+        let flags = if info.pull {
+            pw::stream::StreamFlags::ALLOC_BUFFERS | pw::stream::StreamFlags::RT_PROCESS
+        } else {
             pw::stream::StreamFlags::DRIVER
                 | pw::stream::StreamFlags::ALLOC_BUFFERS
                 | pw::stream::StreamFlags::RT_PROCESS
-                | pw::stream::StreamFlags::TRIGGER,
+                | pw::stream::StreamFlags::TRIGGER
+        };
+        stream_impl.inner.borrow().stream.connect(
+            spa::utils::Direction::Output,
+            None,
+            flags,
             &mut params,
         )?;
 
@@ -785,11 +1960,27 @@ impl StreamImpl {
         Ok(stream_impl)
     }
 
+    pub(crate) fn active(&self) -> Arc<AtomicBool> {
+        self.inner.borrow().active.clone()
+    }
+
+    pub(crate) fn errored(&self) -> Arc<AtomicBool> {
+        self.inner.borrow().errored.clone()
+    }
+
+    pub(crate) fn dropped(&self) -> Arc<AtomicU64> {
+        self.inner.borrow().dropped.clone()
+    }
+
+    pub(crate) fn stats(&self) -> Arc<StreamStats> {
+        self.inner.borrow().stats.clone()
+    }
+
     pub(crate) fn attach<'a>(
         &self,
         loop_: &'a pw::loop_::LoopRef,
         pw_receiver: pw::channel::Receiver<StreamMessage>,
-    ) -> pw::channel::AttachedReceiver<'a, StreamMessage> {
+    ) -> StreamSources<'a> {
         let inner_weak = Arc::downgrade(&self.inner);
         let receiver = pw_receiver.attach(loop_, move |msg| {
             trace!("[msg] receive {:?}", msg);
@@ -800,6 +1991,79 @@ impl StreamImpl {
                 debug!("stream impl dropped");
             }
         });
-        receiver
+
+        let static_timeout = self.inner.borrow().static_timeout;
+        let static_timer = (!static_timeout.is_zero()).then(|| {
+            let inner_weak = Arc::downgrade(&self.inner);
+            let timer = loop_.add_timer(move |_| {
+                let Some(inner) = inner_weak.upgrade() else {
+                    return;
+                };
+                let inner = inner.borrow();
+                if inner.last_queued.get().elapsed() >= inner.static_timeout {
+                    inner.set_static(true);
+                }
+            });
+            // detected within 1.5 times the timeout
+            let interval = static_timeout / 2;
+            let _ = timer.update_timer(Some(interval), Some(interval));
+            timer
+        });
+
+        StreamSources {
+            receiver,
+            static_timer,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// blocks of the `SPA_PARAM_Buffers` param built for `blocks`
+    fn buffers_blocks(blocks: u32, metadata_on_request: bool) -> Value {
+        let params = build_stream_params(
+            8,
+            blocks,
+            metadata_on_request,
+            true,
+            false,
+            VideoTransform::None,
+            false,
+            false,
+            false,
+        )
+        .unwrap();
+        let (_, value) = deserialize::PodDeserializer::deserialize_any_from(&params[0]).unwrap();
+        let Value::Object(object) = value else {
+            panic!("not an object: {:?}", value);
+        };
+        assert_eq!(object.id, spa_sys::SPA_PARAM_Buffers);
+        object
+            .properties
+            .into_iter()
+            .find(|property| property.key == spa_sys::SPA_PARAM_BUFFERS_blocks)
+            .unwrap()
+            .value
+    }
+
+    #[test]
+    fn metadata_on_request() {
+        assert_eq!(buffers_blocks(2, false), Value::Int(2));
+        assert_eq!(buffers_blocks(0, true), Value::Int(0));
+        // 2 by default, 0 for consumers asking for meta only, but not 1
+        let Value::Choice(ChoiceValue::Int(Choice(
+            _,
+            ChoiceEnum::Enum {
+                default,
+                alternatives,
+            },
+        ))) = buffers_blocks(2, true)
+        else {
+            panic!("blocks not an enum");
+        };
+        assert_eq!(default, 2);
+        assert_eq!(alternatives, vec![2, 0]);
     }
 }
@@ -22,6 +22,36 @@ pub fn get_app_name() -> String {
     get_command_name().unwrap_or_else(|| String::from("unknown"))
 }
 
+/// `node.name` of streams of `app`, `pw-capture.<app>` with characters other than ASCII
+/// alphanumerics, `-` and `_` replaced, and a `.preview` suffix for preview streams
+pub fn get_node_name(app: &str, preview: bool) -> String {
+    let app: String = app
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect();
+    if preview {
+        format!("pw-capture.{}.preview", app)
+    } else {
+        format!("pw-capture.{}", app)
+    }
+}
+
+/// `node.description` of streams of `app`, with the window title if known
+pub fn get_node_description(app: &str, title: Option<&str>, preview: bool) -> String {
+    let suffix = if preview {
+        "pw-capture preview"
+    } else {
+        "pw-capture"
+    };
+    match title.map(str::trim).filter(|v| !v.is_empty()) {
+        Some(title) => format!("{}: {} ({})", app, title, suffix),
+        None => format!("{} ({})", app, suffix),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -43,4 +73,27 @@ mod tests {
         let res = get_app_name();
         assert!(!res.is_empty())
     }
+
+    #[test]
+    fn node_name() {
+        assert_eq!(get_node_name("vkcube", false), "pw-capture.vkcube");
+        assert_eq!(get_node_name("Game.exe 2", false), "pw-capture.Game_exe_2");
+        assert_eq!(get_node_name("vkcube", true), "pw-capture.vkcube.preview");
+        assert_eq!(
+            get_node_description("vkcube", Some("Cube "), false),
+            "vkcube: Cube (pw-capture)"
+        );
+        assert_eq!(
+            get_node_description("vkcube", Some(" "), false),
+            "vkcube (pw-capture)"
+        );
+        assert_eq!(
+            get_node_description("vkcube", None, false),
+            "vkcube (pw-capture)"
+        );
+        assert_eq!(
+            get_node_description("vkcube", Some("Cube"), true),
+            "vkcube: Cube (pw-capture preview)"
+        );
+    }
 }
@@ -1,7 +1,9 @@
+mod serial;
 mod utils;
 mod wayland;
 mod xcb;
 
+pub use serial::*;
 pub use wayland::*;
 pub use xcb::*;
 
@@ -15,6 +17,15 @@ use pw_capture_client as client;
 
 pub trait CursorManager: Send + Sync {
     fn snapshot_cursor(&self, serial: u64) -> Result<Box<dyn CursorSnapshot>>;
+    /// returns whether the window currently holds the input focus, `None` if unknown, e.g. for
+    /// Wayland windows, as clients aren't told. Called on every present, so it must not block.
+    fn focused(&self) -> Option<bool> {
+        None
+    }
+    /// returns the title of the window, `None` if unknown
+    fn title(&self) -> Option<String> {
+        None
+    }
 }
 
 pub trait CursorSnapshot {
@@ -22,8 +33,10 @@ pub trait CursorSnapshot {
     fn entered(&self) -> bool;
     /// returns (x, y) relative to window coordinate
     fn position(&self) -> (i32, i32);
-    /// returns (x, y) relative to bitmap coordinate
-    fn hotspot(&self) -> (i32, i32);
+    /// returns (x, y) relative to bitmap coordinate, (0, 0) if the backend doesn't know it
+    fn hotspot(&self) -> (i32, i32) {
+        (0, 0)
+    }
     /// returns (width, height, bytes_per_pixel, pixels_data)
     fn bitmap(&self) -> Option<(u32, u32, u32, &[u8])>;
     #[cfg(feature = "pw-capture-client")]
@@ -0,0 +1,109 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Serial of the last cursor snapshot added to a stream, to tell whether the cursor changed.
+///
+/// Serials of a manager only count up, until it starts over, e.g. on reconnecting to the display
+/// server, or wraps around. A serial going backwards is a change and replaces the stored one,
+/// comparing it against the old count would miss changes until it caught up.
+#[derive(Debug, Default)]
+pub struct CursorSerial(AtomicU64);
+
+impl CursorSerial {
+    /// serial to pass to `CursorManager::snapshot_cursor`
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Acquire)
+    }
+
+    /// Records `serial` of a snapshot taken with `old` from `get`, returns whether the cursor
+    /// changed since.
+    pub fn update(&self, old: u64, serial: u64) -> bool {
+        if serial == old {
+            return false;
+        }
+        if serial < old {
+            self.0.store(serial, Ordering::Release);
+        } else {
+            // a concurrent snapshot may have recorded a later one already
+            self.0.fetch_max(serial, Ordering::AcqRel);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CursorManager, CursorSnapshot};
+
+    use anyhow::Result;
+
+    #[derive(Default)]
+    struct MockCursorManager {
+        serial: AtomicU64,
+    }
+
+    struct MockSnapshot {
+        serial: u64,
+    }
+
+    impl CursorManager for MockCursorManager {
+        fn snapshot_cursor(&self, _serial: u64) -> Result<Box<dyn CursorSnapshot>> {
+            let serial = self.serial.load(Ordering::Relaxed);
+            Ok(Box::new(MockSnapshot { serial }))
+        }
+    }
+
+    impl CursorSnapshot for MockSnapshot {
+        fn serial(&self) -> u64 {
+            self.serial
+        }
+        fn entered(&self) -> bool {
+            true
+        }
+        fn position(&self) -> (i32, i32) {
+            (0, 0)
+        }
+        fn bitmap(&self) -> Option<(u32, u32, u32, &[u8])> {
+            None
+        }
+        #[cfg(feature = "pw-capture-client")]
+        fn format(&self) -> pw_capture_client::Format {
+            pw_capture_client::Format::BGRA
+        }
+    }
+
+    fn changed(serial: &CursorSerial, manager: &MockCursorManager) -> bool {
+        let old = serial.get();
+        let snap = manager.snapshot_cursor(old).unwrap();
+        serial.update(old, snap.serial())
+    }
+
+    #[test]
+    fn reset() {
+        let serial = CursorSerial::default();
+        let manager = MockCursorManager::default();
+        manager.serial.store(5, Ordering::Relaxed);
+        assert!(changed(&serial, &manager));
+        assert!(!changed(&serial, &manager));
+
+        // manager reconnected and counts from 1 again
+        manager.serial.store(1, Ordering::Relaxed);
+        assert!(changed(&serial, &manager));
+        assert_eq!(serial.get(), 1);
+        assert!(!changed(&serial, &manager));
+        manager.serial.store(2, Ordering::Relaxed);
+        assert!(changed(&serial, &manager));
+
+        // 32-bit X serial wrapping around
+        manager.serial.store(u32::MAX as u64, Ordering::Relaxed);
+        assert!(changed(&serial, &manager));
+        manager.serial.store(0, Ordering::Relaxed);
+        assert!(changed(&serial, &manager));
+        assert_eq!(serial.get(), 0);
+
+        // a stale snapshot taken before a later one was recorded doesn't go back
+        assert!(serial.update(0, 3));
+        assert!(serial.update(0, 2));
+        assert_eq!(serial.get(), 3);
+    }
+}
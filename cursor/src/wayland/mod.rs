@@ -1,3 +1,4 @@
+mod pointer;
 mod sys;
 mod wl_lib;
 pub mod wl_sys {
@@ -21,6 +22,7 @@ use anyhow::{anyhow, Result};
 use dashmap::DashMap;
 use log::{debug, trace, warn};
 
+pub use pointer::WlPointerCursor;
 pub use wl_lib::WlHandle;
 
 struct RegistryState {
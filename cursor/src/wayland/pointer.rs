@@ -0,0 +1,360 @@
+use super::*;
+
+use core::ffi::c_char;
+use core::mem;
+use std::sync::Mutex;
+
+use once_cell::sync::OnceCell;
+
+/// libwayland-client entry points the native pointer tracking needs on top of [`WlLib`]
+struct WlClientLib {
+    wl_registry_interface: WlHandle,
+    wl_seat_interface: WlHandle,
+    wl_pointer_interface: WlHandle,
+    wl_display_interface: WlHandle,
+    wl_proxy_marshal_array_flags: PFN_wl_proxy_marshal_array_flags,
+    wl_proxy_add_listener: PFN_wl_proxy_add_listener,
+    wl_proxy_destroy: PFN_wl_proxy_destroy,
+    wl_proxy_create_wrapper: PFN_wl_proxy_create_wrapper,
+    wl_proxy_wrapper_destroy: PFN_wl_proxy_wrapper_destroy,
+    wl_proxy_set_queue: PFN_wl_proxy_set_queue,
+    wl_proxy_get_version: PFN_wl_proxy_get_version,
+    wl_display_create_queue: PFN_wl_display_create_queue,
+    wl_display_roundtrip_queue: PFN_wl_display_roundtrip_queue,
+    wl_display_dispatch_queue_pending: PFN_wl_display_dispatch_queue_pending,
+    wl_event_queue_destroy: PFN_wl_event_queue_destroy,
+}
+
+impl WlClientLib {
+    unsafe fn load() -> Option<Self> {
+        let wl_client = dlopen(&[
+            cstr!(b"libwayland-client.so.0\0"),
+            cstr!(b"libwayland-client.so\0"),
+        ])?;
+        let sym = |name: &[u8]| {
+            let p = libc::dlsym(wl_client, cstr!(name).as_ptr());
+            (!p.is_null()).then_some(p)
+        };
+        let interface = |name: &[u8]| sym(name).map(|p| wlhandle!(p));
+        Some(Self {
+            wl_registry_interface: interface(b"wl_registry_interface\0")?,
+            wl_seat_interface: interface(b"wl_seat_interface\0")?,
+            wl_pointer_interface: interface(b"wl_pointer_interface\0")?,
+            wl_display_interface: interface(b"wl_display_interface\0")?,
+            wl_proxy_marshal_array_flags: mem::transmute(sym(b"wl_proxy_marshal_array_flags\0")?),
+            wl_proxy_add_listener: mem::transmute(sym(b"wl_proxy_add_listener\0")?),
+            wl_proxy_destroy: mem::transmute(sym(b"wl_proxy_destroy\0")?),
+            wl_proxy_create_wrapper: mem::transmute(sym(b"wl_proxy_create_wrapper\0")?),
+            wl_proxy_wrapper_destroy: mem::transmute(sym(b"wl_proxy_wrapper_destroy\0")?),
+            wl_proxy_set_queue: mem::transmute(sym(b"wl_proxy_set_queue\0")?),
+            wl_proxy_get_version: mem::transmute(sym(b"wl_proxy_get_version\0")?),
+            wl_display_create_queue: mem::transmute(sym(b"wl_display_create_queue\0")?),
+            wl_display_roundtrip_queue: mem::transmute(sym(b"wl_display_roundtrip_queue\0")?),
+            wl_display_dispatch_queue_pending: mem::transmute(sym(
+                b"wl_display_dispatch_queue_pending\0",
+            )?),
+            wl_event_queue_destroy: mem::transmute(sym(b"wl_event_queue_destroy\0")?),
+        })
+    }
+}
+
+static WL_CLIENT: OnceCell<Option<WlClientLib>> = OnceCell::new();
+
+/// wl_seat v5 is the first one with `release`, newer seats only add pointer events we ignore
+const SEAT_VERSION: u32 = 5;
+
+struct PointerTrack {
+    lib: &'static WlClientLib,
+    surface: usize,
+    seat: usize,
+    seat_version: u32,
+    pointer: usize,
+    serial: u32,
+    entered: bool,
+    surface_x: wl_fixed_t,
+    surface_y: wl_fixed_t,
+}
+
+/// Cursor manager of a Wayland surface that doesn't need the OpenGL layer.
+///
+/// It binds a wl_seat of its own on the application's connection, on a private event queue, and
+/// gets a wl_pointer from it. The compositor sends pointer focus and motion to every wl_pointer
+/// of a client, so the application reading its connection is enough to fill the queue, which is
+/// only dispatched without blocking when snapshotting.
+///
+/// The cursor image the application sets can't be seen from there, so snapshots carry the
+/// position only, in surface coordinates.
+pub struct WlPointerCursor {
+    lib: &'static WlClientLib,
+    display: WlHandle,
+    queue: WlHandle,
+    track: Box<Mutex<PointerTrack>>,
+}
+
+impl WlPointerCursor {
+    pub unsafe fn new(display: *mut wl_display, surface: *mut wl_proxy) -> Result<Self> {
+        let lib = WL_CLIENT
+            .get_or_init(|| WlClientLib::load())
+            .as_ref()
+            .ok_or(anyhow!("failed to load libwayland"))?;
+        if !pointer_is_dereferencable(display as _) {
+            return Err(anyhow!("invalid wl_display"));
+        }
+        let interface = *(display as *mut *const wl_interface);
+        if lib.wl_display_interface.as_ptr() as *const _ != interface {
+            return Err(anyhow!(
+                "application not using shared libwayland-client library"
+            ));
+        }
+
+        let queue = (lib.wl_display_create_queue)(display);
+        if queue.is_null() {
+            return Err(anyhow!("failed to create wl_event_queue"));
+        }
+        let this = Self {
+            lib,
+            display: wlhandle!(display),
+            queue: wlhandle!(queue),
+            track: Box::new(Mutex::new(PointerTrack {
+                lib,
+                surface: surface as usize,
+                seat: 0,
+                seat_version: 0,
+                pointer: 0,
+                serial: 0,
+                entered: false,
+                surface_x: wl_fixed_t::ZERO,
+                surface_y: wl_fixed_t::ZERO,
+            })),
+        };
+        let data = &*this.track as *const Mutex<PointerTrack> as *mut c_void;
+
+        let wrapper = (lib.wl_proxy_create_wrapper)(display as _);
+        if wrapper.is_null() {
+            return Err(anyhow!("failed to wrap wl_display"));
+        }
+        (lib.wl_proxy_set_queue)(wrapper, queue);
+        let mut args = [wl_argument { o: ptr::null_mut() }];
+        let registry = (lib.wl_proxy_marshal_array_flags)(
+            wrapper,
+            WL_DISPLAY_GET_REGISTRY,
+            lib.wl_registry_interface.as_ptr(),
+            (lib.wl_proxy_get_version)(wrapper),
+            0,
+            args.as_mut_ptr(),
+        );
+        (lib.wl_proxy_wrapper_destroy)(wrapper);
+        if registry.is_null() {
+            return Err(anyhow!("failed to get wl_registry"));
+        }
+        (lib.wl_proxy_add_listener)(registry, &REGISTRY_LISTENER as *const _ as _, data);
+
+        // first roundtrip binds the seat, second one gets its capabilities and the pointer
+        let mut res = 0;
+        for _ in 0..2 {
+            res = res.min((lib.wl_display_roundtrip_queue)(display, queue));
+        }
+        (lib.wl_proxy_destroy)(registry);
+        if res < 0 {
+            return Err(anyhow!("wl_display_roundtrip_queue failed"));
+        }
+        if this.track.lock().unwrap().pointer == 0 {
+            return Err(anyhow!("no wl_seat with a pointer"));
+        }
+        Ok(this)
+    }
+}
+
+impl Drop for WlPointerCursor {
+    fn drop(&mut self) {
+        let lib = self.lib;
+        let track = self.track.lock().unwrap();
+        unsafe {
+            let mut args = [wl_argument { o: ptr::null_mut() }];
+            for (proxy, release) in [
+                (
+                    track.pointer,
+                    (track.seat_version >= 3).then_some(WL_POINTER_RELEASE),
+                ),
+                (
+                    track.seat,
+                    (track.seat_version >= 5).then_some(WL_SEAT_RELEASE),
+                ),
+            ] {
+                let proxy = proxy as *mut wl_proxy;
+                if proxy.is_null() {
+                    continue;
+                }
+                if let Some(opcode) = release {
+                    (lib.wl_proxy_marshal_array_flags)(
+                        proxy,
+                        opcode,
+                        ptr::null(),
+                        (lib.wl_proxy_get_version)(proxy),
+                        WL_MARSHAL_FLAG_DESTROY,
+                        args.as_mut_ptr(),
+                    );
+                } else {
+                    (lib.wl_proxy_destroy)(proxy);
+                }
+            }
+            (lib.wl_event_queue_destroy)(self.queue.as_ptr());
+        }
+    }
+}
+
+impl CursorManager for WlPointerCursor {
+    fn snapshot_cursor(&self, _serial: u64) -> Result<Box<dyn CursorSnapshot>> {
+        let res = unsafe {
+            (self.lib.wl_display_dispatch_queue_pending)(self.display.as_ptr(), self.queue.as_ptr())
+        };
+        if res < 0 {
+            return Err(anyhow!("wl_display_dispatch_queue_pending failed"));
+        }
+        let track = self.track.lock().unwrap();
+        Ok(Box::new(WlCursorSnapshot {
+            serial: track.serial as _,
+            entered: track.entered,
+            position: (
+                track.surface_x.round().to_num(),
+                track.surface_y.round().to_num(),
+            ),
+            hotspot: (0, 0),
+            bitmap: None,
+        }))
+    }
+}
+
+unsafe fn track<'a>(data: *mut c_void) -> std::sync::MutexGuard<'a, PointerTrack> {
+    (*(data as *const Mutex<PointerTrack>)).lock().unwrap()
+}
+
+unsafe extern "C" fn registry_global(
+    data: *mut c_void,
+    wl_registry: *mut wl_proxy,
+    name: u32,
+    interface: *const c_char,
+    version: u32,
+) {
+    let mut track = track(data);
+    if track.seat != 0 || CStr::from_ptr(interface) != cstr!(b"wl_seat\0") {
+        return;
+    }
+    let lib = track.lib;
+    let version = version.min(SEAT_VERSION);
+    let mut args = [
+        wl_argument { u: name },
+        wl_argument { s: interface },
+        wl_argument { u: version },
+        wl_argument { o: ptr::null_mut() },
+    ];
+    let seat = (lib.wl_proxy_marshal_array_flags)(
+        wl_registry,
+        WL_REGISTRY_BIND,
+        lib.wl_seat_interface.as_ptr(),
+        version,
+        0,
+        args.as_mut_ptr(),
+    );
+    if seat.is_null() {
+        return;
+    }
+    track.seat = seat as usize;
+    track.seat_version = version;
+    (lib.wl_proxy_add_listener)(seat, &SEAT_LISTENER as *const _ as _, data);
+}
+
+unsafe extern "C" fn seat_capabilities(data: *mut c_void, wl_seat: *mut wl_proxy, caps: u32) {
+    let mut track = track(data);
+    // getting a pointer from a seat that never had one is a protocol error
+    if track.pointer != 0 || caps & WL_SEAT_CAPABILITY_POINTER == 0 {
+        return;
+    }
+    let lib = track.lib;
+    let mut args = [wl_argument { o: ptr::null_mut() }];
+    let pointer = (lib.wl_proxy_marshal_array_flags)(
+        wl_seat,
+        WL_SEAT_GET_POINTER,
+        lib.wl_pointer_interface.as_ptr(),
+        track.seat_version,
+        0,
+        args.as_mut_ptr(),
+    );
+    if pointer.is_null() {
+        return;
+    }
+    track.pointer = pointer as usize;
+    (lib.wl_proxy_add_listener)(pointer, &POINTER_LISTENER as *const _ as _, data);
+}
+
+unsafe extern "C" fn pointer_enter(
+    data: *mut c_void,
+    _wl_pointer: *mut wl_proxy,
+    serial: u32,
+    surface: *mut wl_proxy,
+    surface_x: wl_fixed_t,
+    surface_y: wl_fixed_t,
+) {
+    let mut track = track(data);
+    if surface as usize != track.surface {
+        return;
+    }
+    track.serial = serial;
+    track.entered = true;
+    track.surface_x = surface_x;
+    track.surface_y = surface_y;
+}
+
+unsafe extern "C" fn pointer_leave(
+    data: *mut c_void,
+    _wl_pointer: *mut wl_proxy,
+    _serial: u32,
+    surface: *mut wl_proxy,
+) {
+    let mut track = track(data);
+    if surface as usize == track.surface {
+        track.entered = false;
+    }
+}
+
+unsafe extern "C" fn pointer_motion(
+    data: *mut c_void,
+    _wl_pointer: *mut wl_proxy,
+    _time: u32,
+    surface_x: wl_fixed_t,
+    surface_y: wl_fixed_t,
+) {
+    let mut track = track(data);
+    if track.entered {
+        track.surface_x = surface_x;
+        track.surface_y = surface_y;
+    }
+}
+
+unsafe extern "C" fn ignore_global_remove(_: *mut c_void, _: *mut wl_proxy, _: u32) {}
+
+unsafe extern "C" fn ignore_event() {}
+
+static REGISTRY_LISTENER: wl_registry_listener = wl_registry_listener {
+    global: Some(registry_global),
+    global_remove: Some(ignore_global_remove),
+};
+
+static SEAT_LISTENER: wl_seat_listener = wl_seat_listener {
+    capabilities: Some(seat_capabilities),
+    name: Some(ignore_event),
+};
+
+// libwayland calls listener entries without checking them, so every event must have one
+static POINTER_LISTENER: wl_pointer_listener = wl_pointer_listener {
+    enter: Some(pointer_enter),
+    leave: Some(pointer_leave),
+    motion: Some(pointer_motion),
+    button: Some(ignore_event),
+    axis: Some(ignore_event),
+    frame: Some(ignore_event),
+    axis_source: Some(ignore_event),
+    axis_stop: Some(ignore_event),
+    axis_discrete: Some(ignore_event),
+    axis_value120: Some(ignore_event),
+};
@@ -16,12 +16,16 @@ pub const WL_DISPLAY_GET_REGISTRY: u32 = 0;
 pub const WL_REGISTRY_BIND: u32 = 0;
 
 pub const WL_SEAT_GET_POINTER: u32 = 0;
+pub const WL_SEAT_RELEASE: u32 = 3;
+
+pub const WL_SEAT_CAPABILITY_POINTER: u32 = 1;
 
 pub const WL_POINTER_SET_CURSOR: u32 = 0;
 pub const WL_POINTER_RELEASE: u32 = 1;
 
 pub enum wl_proxy {}
 pub enum wl_display {}
+pub enum wl_event_queue {}
 
 pub enum wl_object {}
 
@@ -111,6 +115,12 @@ pub struct wl_registry_listener {
     pub global_remove: Option<PFN_wl_registry_event_global_remove>,
 }
 
+#[repr(C)]
+pub struct wl_seat_listener {
+    pub capabilities: Option<PFN_wl_seat_event_capabilities>,
+    pub name: PFN_void,
+}
+
 #[repr(C)]
 pub struct wl_pointer_listener {
     pub enter: Option<PFN_wl_pointer_event_enter>,
@@ -136,6 +146,9 @@ pub type PFN_wl_registry_event_global = unsafe extern "C" fn(
 pub type PFN_wl_registry_event_global_remove =
     unsafe extern "C" fn(data: *mut c_void, wl_registry: *mut wl_proxy, name: u32);
 
+pub type PFN_wl_seat_event_capabilities =
+    unsafe extern "C" fn(data: *mut c_void, wl_seat: *mut wl_proxy, capabilities: u32);
+
 pub type PFN_wl_pointer_event_enter = unsafe extern "C" fn(
     data: *mut c_void,
     wl_pointer: *mut wl_proxy,
@@ -202,4 +215,20 @@ pub type PFN_wl_proxy_create_wrapper = unsafe extern "C" fn(proxy: *mut wl_proxy
 
 pub type PFN_wl_proxy_wrapper_destroy = unsafe extern "C" fn(proxy: *mut wl_proxy);
 
+pub type PFN_wl_proxy_set_queue =
+    unsafe extern "C" fn(proxy: *mut wl_proxy, queue: *mut wl_event_queue);
+
+pub type PFN_wl_proxy_get_version = unsafe extern "C" fn(proxy: *mut wl_proxy) -> u32;
+
+pub type PFN_wl_display_create_queue =
+    unsafe extern "C" fn(display: *mut wl_display) -> *mut wl_event_queue;
+
+pub type PFN_wl_display_roundtrip_queue =
+    unsafe extern "C" fn(display: *mut wl_display, queue: *mut wl_event_queue) -> c_int;
+
+pub type PFN_wl_display_dispatch_queue_pending =
+    unsafe extern "C" fn(display: *mut wl_display, queue: *mut wl_event_queue) -> c_int;
+
+pub type PFN_wl_event_queue_destroy = unsafe extern "C" fn(queue: *mut wl_event_queue);
+
 pub type PFN_void = Option<unsafe extern "C" fn()>;
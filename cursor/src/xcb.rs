@@ -3,19 +3,136 @@ use crate::{CursorManager, CursorSnapshot, OwnedMem};
 use core::ffi::c_void;
 use core::ptr;
 use core::slice;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::Arc;
+use std::thread;
 
 use anyhow::{anyhow, Result};
+use log::{debug, warn};
+use once_cell::sync::OnceCell;
 use xcb_dl::ffi as xcb_t;
 use xcb_dl::Xcb;
 use xcb_dl::XcbXfixes;
 use xcb_t::xcb_connection_t;
 
+const ATOM_WINDOW: u32 = 33;
+/// longer titles are cut
+const MAX_TITLE_LEN: u32 = 1024;
+const PROPERTY_NOTIFY: u8 = 28;
+const CW_EVENT_MASK: u32 = 1 << 11;
+const EVENT_MASK_PROPERTY_CHANGE: u32 = 1 << 22;
+/// how long the focus watch takes to notice its window is gone
+const FOCUS_WATCH_STOP_MS: i32 = 500;
+const FOCUS_UNKNOWN: u8 = 0;
+const FOCUS_OUT: u8 = 1;
+const FOCUS_IN: u8 = 2;
+
 pub struct XcbWindow {
     conn: usize,
     to_close_conn: bool,
     window: u32,
+    net_active_window: u32,
+    net_wm_name: u32,
+    utf8_string: u32,
     xcb: Xcb,
     xfixes: XcbXfixes,
+    /// started by the first `focused` call, `None` inside if that failed
+    focus_watch: OnceCell<Option<FocusWatch>>,
+}
+
+/// Follows `_NET_ACTIVE_WINDOW` of the root window on a connection and thread of its own, so
+/// reading the focus takes no round trip. Stops once dropped. Also used without cursor capture.
+pub struct FocusWatch {
+    state: Arc<AtomicU8>,
+    stop: Arc<AtomicBool>,
+}
+
+impl FocusWatch {
+    pub fn spawn(window: u32) -> Result<Self> {
+        let state = Arc::new(AtomicU8::new(FOCUS_UNKNOWN));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (thread_state, thread_stop) = (state.clone(), stop.clone());
+        thread::Builder::new()
+            .name("pw-capture-focus".to_string())
+            .spawn(move || unsafe {
+                if let Err(e) = watch_focus(window, &thread_state, &thread_stop) {
+                    warn!("focus of window {:#x} not followed: {:?}", window, e);
+                }
+                thread_state.store(FOCUS_UNKNOWN, Ordering::Release);
+            })?;
+        Ok(Self { state, stop })
+    }
+
+    /// whether `window` or one of its parents is active, `None` until known or if the watch
+    /// stopped
+    pub fn focused(&self) -> Option<bool> {
+        match self.state.load(Ordering::Acquire) {
+            FOCUS_IN => Some(true),
+            FOCUS_OUT => Some(false),
+            _ => None,
+        }
+    }
+}
+
+impl Drop for FocusWatch {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+    }
+}
+
+fn focus_state(focused: Option<bool>) -> u8 {
+    match focused {
+        Some(true) => FOCUS_IN,
+        Some(false) => FOCUS_OUT,
+        None => FOCUS_UNKNOWN,
+    }
+}
+
+/// Updates `state` on every `PropertyNotify` of `_NET_ACTIVE_WINDOW` until `stop` is set.
+unsafe fn watch_focus(window: u32, state: &AtomicU8, stop: &AtomicBool) -> Result<()> {
+    let watcher = XcbWindow::new_connection(window)?;
+    let conn = watcher.conn as *mut xcb_connection_t;
+    if watcher.net_active_window == 0 {
+        return Err(anyhow!("_NET_ACTIVE_WINDOW not supported"));
+    }
+    let (root, _) = watcher
+        .query_tree(window)
+        .ok_or(anyhow!("xcb_query_tree failed"))?;
+    let mask = EVENT_MASK_PROPERTY_CHANGE;
+    watcher.xcb.xcb_change_window_attributes(
+        conn,
+        root,
+        CW_EVENT_MASK,
+        &mask as *const u32 as *const c_void,
+    );
+    // the round trip also sends the event selection, changes after it are notified
+    state.store(focus_state(watcher.query_focused()), Ordering::Release);
+    let fd = watcher.xcb.xcb_get_file_descriptor(conn);
+    while !stop.load(Ordering::Acquire) {
+        if watcher.xcb.xcb_connection_has_error(conn) != 0 {
+            return Err(anyhow!("connection broken"));
+        }
+        let mut changed = false;
+        while let Some(event) = OwnedMem::new(watcher.xcb.xcb_poll_for_event(conn)) {
+            if event.as_ref().response_type & 0x7f != PROPERTY_NOTIFY {
+                continue;
+            }
+            let event = &*(event.as_ptr() as *const xcb_t::xcb_property_notify_event_t);
+            changed |= event.atom == watcher.net_active_window;
+        }
+        if changed {
+            let focused = watcher.query_focused();
+            debug!("window {:#x} focused: {:?}", window, focused);
+            state.store(focus_state(focused), Ordering::Release);
+        }
+        let mut pollfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        libc::poll(&mut pollfd, 1, FOCUS_WATCH_STOP_MS);
+    }
+    Ok(())
 }
 
 pub struct XcbCursor {
@@ -47,12 +164,28 @@ impl XcbWindow {
         let reply = xcb.xcb_get_geometry_reply(conn as _, geometry_cookie, ptr::null_mut());
         let _geometry = OwnedMem::new(reply).ok_or(anyhow!("xcb_get_geometry failed"))?;
 
+        let intern_atom = |name: &[u8]| {
+            let atom_cookie =
+                xcb.xcb_intern_atom_unchecked(conn, 1, name.len() as _, name.as_ptr() as _);
+            let reply = xcb.xcb_intern_atom_reply(conn, atom_cookie, ptr::null_mut());
+            OwnedMem::new(reply)
+                .map(|reply| reply.as_ref().atom)
+                .unwrap_or(0)
+        };
+        let net_active_window = intern_atom(b"_NET_ACTIVE_WINDOW");
+        let net_wm_name = intern_atom(b"_NET_WM_NAME");
+        let utf8_string = intern_atom(b"UTF8_STRING");
+
         Ok(Self {
             conn: conn as _,
             to_close_conn,
             window,
+            net_active_window,
+            net_wm_name,
+            utf8_string,
             xcb,
             xfixes,
+            focus_watch: OnceCell::new(),
         })
     }
 
@@ -63,6 +196,87 @@ impl XcbWindow {
     pub unsafe fn new_connection(window: u32) -> Result<Self> {
         Self::new_internal(ptr::null_mut(), window)
     }
+
+    /// (root, parent) of `window`
+    unsafe fn query_tree(&self, window: u32) -> Option<(u32, u32)> {
+        let cookie = self.xcb.xcb_query_tree_unchecked(self.conn as _, window);
+        let reply = self
+            .xcb
+            .xcb_query_tree_reply(self.conn as _, cookie, ptr::null_mut());
+        let tree = OwnedMem::new(reply)?;
+        Some((tree.as_ref().root, tree.as_ref().parent))
+    }
+
+    /// whether `_NET_ACTIVE_WINDOW` is our window or one of its parents, with round trips
+    unsafe fn query_focused(&self) -> Option<bool> {
+        // walk up from our window as it might be a child of the managed top-level window
+        let mut window = self.window;
+        let mut active = None;
+        loop {
+            let (root, parent) = self.query_tree(window)?;
+            if active.is_none() {
+                active = Some(self.active_window(root)?);
+            }
+            if Some(window) == active {
+                return Some(true);
+            }
+            if parent == 0 || parent == root {
+                return Some(false);
+            }
+            window = parent;
+        }
+    }
+
+    unsafe fn active_window(&self, root: u32) -> Option<u32> {
+        if self.net_active_window == 0 {
+            return None;
+        }
+        let cookie = self.xcb.xcb_get_property_unchecked(
+            self.conn as _,
+            0,
+            root,
+            self.net_active_window,
+            ATOM_WINDOW,
+            0,
+            1,
+        );
+        let reply = self
+            .xcb
+            .xcb_get_property_reply(self.conn as _, cookie, ptr::null_mut());
+        let reply = OwnedMem::new(reply)?;
+        if self.xcb.xcb_get_property_value_length(reply.as_ptr()) < 4 {
+            return None;
+        }
+        let value = self.xcb.xcb_get_property_value(reply.as_ptr()) as *const u32;
+        Some(value.read_unaligned())
+    }
+
+    /// `_NET_WM_NAME` of `window`, `None` if not set
+    unsafe fn net_wm_name(&self, window: u32) -> Option<String> {
+        if self.net_wm_name == 0 || self.utf8_string == 0 {
+            return None;
+        }
+        let cookie = self.xcb.xcb_get_property_unchecked(
+            self.conn as _,
+            0,
+            window,
+            self.net_wm_name,
+            self.utf8_string,
+            0,
+            MAX_TITLE_LEN / 4,
+        );
+        let reply = self
+            .xcb
+            .xcb_get_property_reply(self.conn as _, cookie, ptr::null_mut());
+        let reply = OwnedMem::new(reply)?;
+        let len = self.xcb.xcb_get_property_value_length(reply.as_ptr());
+        if len <= 0 {
+            return None;
+        }
+        let value = self.xcb.xcb_get_property_value(reply.as_ptr()) as *const u8;
+        let value = slice::from_raw_parts(value, len as _);
+        Some(String::from_utf8_lossy(value).into_owned())
+    }
 }
 
 impl Drop for XcbWindow {
@@ -74,6 +288,34 @@ impl Drop for XcbWindow {
 }
 
 impl CursorManager for XcbWindow {
+    fn focused(&self) -> Option<bool> {
+        self.focus_watch
+            .get_or_init(|| {
+                FocusWatch::spawn(self.window)
+                    .map_err(|e| warn!("failed to follow focus: {:?}", e))
+                    .ok()
+            })
+            .as_ref()?
+            .focused()
+    }
+
+    fn title(&self) -> Option<String> {
+        unsafe {
+            // set on the managed top-level window, which may be a parent of ours
+            let mut window = self.window;
+            loop {
+                if let Some(title) = self.net_wm_name(window) {
+                    return Some(title);
+                }
+                let (root, parent) = self.query_tree(window)?;
+                if parent == 0 || parent == root {
+                    return None;
+                }
+                window = parent;
+            }
+        }
+    }
+
     fn snapshot_cursor(&self, serial: u64) -> Result<Box<dyn CursorSnapshot>> {
         let serial = (serial & u32::MAX as u64) as u32;
         unsafe {
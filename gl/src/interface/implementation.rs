@@ -6,10 +6,10 @@ use crate::utils::*;
 use core::ffi::CStr;
 use core::ptr;
 use core::slice;
-use core::sync::atomic::{self, AtomicU64};
 use std::collections::VecDeque;
 use std::result::Result::Ok;
 use std::sync::Mutex;
+use std::time::Duration;
 
 use anyhow::{anyhow, Result};
 use client::BufferPlaneInfo;
@@ -18,7 +18,7 @@ use function_name::named;
 use libc::{c_char, c_void};
 use pw_capture_client as client;
 use pw_capture_cursor as local_cursor;
-use pw_capture_cursor::CursorManager;
+use pw_capture_cursor::{CursorManager, CursorSerial};
 use pw_capture_gl_sys::prelude::*;
 use sentinel::SSlice;
 
@@ -452,6 +452,9 @@ unsafe fn capture(
 ) -> Result<()> {
     let gl = gl(native);
 
+    if ly_capture.stream.is_errored() {
+        return Ok(());
+    }
     let stream = ly_capture.stream.proxy();
 
     let (buffer, user_handle) = if let Some(v) = stream.try_dequeue_buffer()?? {
@@ -808,7 +811,7 @@ unsafe fn try_init_capture(
 
     let ly_capture = LayerCapture {
         context,
-        cursor_serial: AtomicU64::new(0),
+        cursor_serial: CursorSerial::default(),
         width,
         height,
         stream,
@@ -1189,6 +1192,8 @@ fn on_add_buffer(surface: GlHandle) -> Result<client::BufferInfo> {
     let res = client::BufferInfo {
         is_dma_buf: true,
         planes: export_texture.planes.clone(),
+        // allocated by the GL driver, not known here
+        memory: client::BufferMemoryInfo::default(),
         user_handle: client::BufferUserHandle::Texture(texture),
     };
 
@@ -1246,17 +1251,11 @@ fn on_process_buffer(
     };
 
     if let Some(add_cursor) = add_meta_cbs.add_cursor {
-        let old_serial = ly_capture.cursor_serial.load(atomic::Ordering::Acquire);
+        let old_serial = ly_capture.cursor_serial.get();
         if let Some(cursor_manager) = ly_surface.cursor_manager.as_ref() {
             if let Ok(snap) = cursor_manager.snapshot_cursor(old_serial) {
-                let _ = ly_capture.cursor_serial.compare_exchange(
-                    old_serial,
-                    snap.serial(),
-                    atomic::Ordering::AcqRel,
-                    atomic::Ordering::Acquire,
-                );
-
-                if let Some(info) = snap.as_cursor_info(old_serial != snap.serial()) {
+                let changed = ly_capture.cursor_serial.update(old_serial, snap.serial());
+                if let Some(info) = snap.as_cursor_info(changed) {
                     add_cursor(info)
                 }
             }
@@ -1283,6 +1282,10 @@ fn create_stream(
     width: u32,
     height: u32,
 ) -> Result<client::Stream> {
+    // read again with the stream recreated on resize
+    let title = SURFACE_MAP
+        .get(&surface)
+        .and_then(|ly_surface| ly_surface.cursor_manager.as_ref()?.title());
     let stream_info = client::StreamInfo {
         width,
         height,
@@ -1291,6 +1294,25 @@ fn create_stream(
             modifiers: vec![modifier],
         }],
         max_buffers,
+        alpha_mode: client::AlphaMode::Opaque,
+        transfer: client::Transfer::UNKNOWN,
+        // buffers of the window system aren't visible to the layer
+        source_images: 0,
+        framerate: 0,
+        title,
+        transform: client::VideoTransform::None,
+        interlaced: false,
+        colorimetry: Default::default(),
+        metadata_only: false,
+        metadata_on_request: false,
+        mem_ptr: false,
+        pull: false,
+        warmup: Duration::ZERO,
+        static_timeout: Duration::ZERO,
+        pts_offset_ns: 0,
+        max_latency: Duration::ZERO,
+        replay: Duration::ZERO,
+        preview: false,
         fixate_format: Box::new(move |enum_format| {
             info!("fixate format: {:?}", enum_format);
             let fixate_format = *enum_format.formats.first()?;
@@ -1303,13 +1325,16 @@ fn create_stream(
                 num_planes,
             })
         }),
-        add_buffer: Box::new(move || on_add_buffer(surface).ok()),
+        add_buffer: Box::new(move |_| on_add_buffer(surface).ok()),
         remove_buffer: Box::new(move |user_handle| {
             let _ = on_remove_buffer(surface, user_handle);
         }),
         process_buffer: Box::new(move |user_handle, add_meta_cbs| {
             let _ = on_process_buffer(surface, user_handle, add_meta_cbs);
         }),
+        video_crop: None,
+        video_damage: None,
+        streaming: Box::new(|| {}),
     };
     CLIENT
         .as_ref()
@@ -34,9 +34,16 @@ const _: DlvsymFunc = dlvsym;
 
 /// Provides actual implementation for underlying Vulkan Layer
 pub use wl_impl::me_eh5_pw_capture_get_wl_cursor_manager;
+pub use wl_impl::me_eh5_pw_capture_register_surface_title_hook;
 pub use wl_impl::me_eh5_pw_capture_release_wl_cursor_manager;
 pub use wl_impl::me_eh5_pw_capture_wl_cursor_snapshot;
 
+/// Tells underlying Vulkan Layer GL frames are captured here, e.g. to skip Zink swapchains
+#[no_mangle]
+pub unsafe fn me_eh5_pw_capture_gl_layer_loaded() -> bool {
+    true
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn wl_proxy_marshal_array_flags(
     proxy: *mut wl_proxy,
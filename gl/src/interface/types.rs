@@ -3,13 +3,12 @@ use crate::utils::*;
 use super::*;
 
 use core::fmt::Debug;
-use core::sync::atomic::AtomicU64;
 use std::collections::VecDeque;
 use std::sync::Mutex;
 
 use dashmap::DashMap;
 use pw_capture_client as client;
-use pw_capture_cursor::CursorManager;
+use pw_capture_cursor::{CursorManager, CursorSerial};
 use pw_capture_gl_sys::prelude::*;
 
 #[macro_export]
@@ -152,7 +151,7 @@ pub struct LayerCapture {
     pub context: GlHandle,
     pub width: u32,
     pub height: u32,
-    pub cursor_serial: AtomicU64,
+    pub cursor_serial: CursorSerial,
     pub stream: client::Stream,
     pub free_textures: Mutex<VecDeque<ExportTexture>>,
     pub mapped_textures: DashMap<u32, ExportTexture>,
@@ -1,16 +1,103 @@
 use super::*;
 
-use core::ffi::c_int;
+use core::ffi::{c_int, CStr};
+use core::ptr;
+use std::ffi::CString;
 
 use pw_capture_cursor::wl_sys::*;
 use pw_capture_cursor::{CursorManager, CursorSnapshot, WlCursorManager};
 
 use dashmap::DashMap;
-use once_cell::sync::Lazy;
+use once_cell::sync::{Lazy, OnceCell};
 
 static CURSOR_MANAGER_MAP: Lazy<DashMap<usize, Box<WlCursorManager>>> =
     Lazy::new(DashMap::new);
 
+type SetSurfaceTitleFunc = unsafe extern "C" fn(*mut c_void, *const c_char) -> bool;
+
+const XDG_WM_BASE_GET_XDG_SURFACE: u32 = 2;
+const XDG_SURFACE_GET_TOPLEVEL: u32 = 1;
+const XDG_TOPLEVEL_SET_TITLE: u32 = 2;
+const WL_MARSHAL_FLAG_DESTROY: u32 = 1 << 0;
+
+/// `wl_surface` of each `xdg_surface`
+static XDG_SURFACE_MAP: Lazy<DashMap<usize, usize>> = Lazy::new(DashMap::new);
+/// `wl_surface` of each `xdg_toplevel`
+static XDG_TOPLEVEL_MAP: Lazy<DashMap<usize, usize>> = Lazy::new(DashMap::new);
+/// last title set on each toplevel `wl_surface`, replayed to the Vulkan layer when it hooks
+static TITLE_MAP: Lazy<DashMap<usize, String>> = Lazy::new(DashMap::new);
+static SET_SURFACE_TITLE: OnceCell<SetSurfaceTitleFunc> = OnceCell::new();
+
+#[no_mangle]
+pub unsafe fn me_eh5_pw_capture_register_surface_title_hook(set_title: SetSurfaceTitleFunc) {
+    if SET_SURFACE_TITLE.set(set_title).is_err() {
+        return;
+    }
+    let titles: Vec<_> = TITLE_MAP
+        .iter()
+        .map(|v| (*v.key(), v.value().clone()))
+        .collect();
+    for (surface, title) in titles {
+        if let Ok(title) = CString::new(title) {
+            set_title(surface as _, title.as_ptr());
+        }
+    }
+}
+
+/// Follows `xdg_toplevel.set_title` of the app, the Vulkan layer has no other way to learn
+/// titles of Wayland windows.
+unsafe fn track_xdg_request(
+    interface_name: &[u8],
+    proxy: *mut wl_proxy,
+    opcode: u32,
+    flags: u32,
+    args: *mut wl_argument,
+    new_proxy: *mut wl_proxy,
+) {
+    match (interface_name, opcode) {
+        (b"xdg_wm_base", XDG_WM_BASE_GET_XDG_SURFACE) if !new_proxy.is_null() => {
+            let surface = (*args.add(1)).o as usize;
+            XDG_SURFACE_MAP.insert(new_proxy as usize, surface);
+        }
+        (b"xdg_surface", XDG_SURFACE_GET_TOPLEVEL) if !new_proxy.is_null() => {
+            if let Some(surface) = XDG_SURFACE_MAP.get(&(proxy as usize)).map(|v| *v) {
+                XDG_TOPLEVEL_MAP.insert(new_proxy as usize, surface);
+            }
+        }
+        (b"xdg_toplevel", XDG_TOPLEVEL_SET_TITLE) => {
+            let Some(surface) = XDG_TOPLEVEL_MAP.get(&(proxy as usize)).map(|v| *v) else {
+                return;
+            };
+            let title = (*args).s;
+            if title.is_null() {
+                return;
+            }
+            let title_str = CStr::from_ptr(title).to_string_lossy().into_owned();
+            TITLE_MAP.insert(surface, title_str);
+            if let Some(set_title) = SET_SURFACE_TITLE.get() {
+                set_title(surface as _, title);
+            }
+        }
+        _ => {}
+    }
+    if flags & WL_MARSHAL_FLAG_DESTROY != 0 {
+        forget_xdg_proxy(proxy);
+    }
+}
+
+/// Drops `proxy` from the maps, a destroyed toplevel clears the title of its surface.
+unsafe fn forget_xdg_proxy(proxy: *mut wl_proxy) {
+    XDG_SURFACE_MAP.remove(&(proxy as usize));
+    let Some((_, surface)) = XDG_TOPLEVEL_MAP.remove(&(proxy as usize)) else {
+        return;
+    };
+    if TITLE_MAP.remove(&surface).is_some() {
+        if let Some(set_title) = SET_SURFACE_TITLE.get() {
+            set_title(surface as _, ptr::null());
+        }
+    }
+}
+
 #[no_mangle]
 pub unsafe fn me_eh5_pw_capture_get_wl_cursor_manager(
     display: *mut c_void,
@@ -64,8 +151,12 @@ pub unsafe extern "C" fn impl_wl_proxy_marshal_array_flags(
     args: *mut wl_argument,
 ) -> *mut wl_proxy {
     let wl_intercept = WL_INTERCEPT.as_ref().unwrap();
-    wl_intercept
-        .intercept_wl_proxy_marshal_array_flags(proxy, opcode, interface, version, flags, args)
+    // the interface outlives the proxy, which is gone after destructors
+    let interface_name = CStr::from_ptr((**(proxy as *mut *const wl_interface)).name).to_bytes();
+    let new_proxy = wl_intercept
+        .intercept_wl_proxy_marshal_array_flags(proxy, opcode, interface, version, flags, args);
+    track_xdg_request(interface_name, proxy, opcode, flags, args, new_proxy);
+    new_proxy
 }
 
 #[inline(never)]
@@ -106,6 +197,7 @@ pub unsafe extern "C" fn impl_wl_proxy_get_listener(proxy: *mut wl_proxy) -> *mu
 
 #[inline(never)]
 pub unsafe extern "C" fn impl_wl_proxy_destroy(proxy: *mut wl_proxy) {
+    forget_xdg_proxy(proxy);
     let wl_intercept = WL_INTERCEPT.as_ref().unwrap();
     wl_intercept.intercept_wl_proxy_destroy(proxy)
 }
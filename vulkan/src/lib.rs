@@ -2,28 +2,38 @@ mod utils;
 use utils::*;
 
 use pw_capture_client as client;
-use pw_capture_cursor::{self as local_cursor, CursorManager, CursorSnapshot};
+use pw_capture_cursor::{self as local_cursor, CursorManager, CursorSerial, CursorSnapshot};
 
 use core::ffi::{c_char, c_void, CStr};
 use core::mem;
+use core::ops::Deref;
 use core::ptr;
 use core::result::Result::{Err, Ok};
 use core::slice;
-use core::sync::atomic::{self, AtomicU64};
+use core::sync::atomic::{self, AtomicBool, AtomicU32, AtomicU64};
 use std::collections::HashSet;
+use std::env;
 use std::ffi::CString;
-use std::time::Instant;
+use std::fs;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Result};
 use ash::extensions::khr;
-use ash::vk;
+use ash::vk::{self, Handle};
 use ash_layer::*;
+use dashmap::mapref::one::{Ref, RefMut};
 use dashmap::DashMap;
 use function_name::named;
 
 use once_cell::sync::{Lazy, OnceCell};
 
 const MAX_BUFFERS: u32 = 128;
+const FRAME_RATE_WINDOW: usize = 120;
+/// delay before creating the stream of a recreated swapchain, coalesces resize bursts
+const STREAM_DEBOUNCE: Duration = Duration::from_millis(200);
+/// frames with more pixels get memfd buffers with `PW_CAPTURE_BUFFER_TYPE=memptr`
+const MAX_MEM_PTR_PIXELS: u32 = 1280 * 720;
 
 struct LayerInstanceValid {
     khr_phy_props2: khr::GetPhysicalDeviceProperties2,
@@ -35,11 +45,19 @@ struct LayerInstance {
     xlib_surface: khr::XlibSurface,
     xcb_surface: khr::XcbSurface,
     wayland_surface: khr::WaylandSurface,
+    /// created by Mesa Zink for OpenGL apps
+    is_zink: bool,
     valid: Option<LayerInstanceValid>,
 }
 
 struct LayerDeviceValid {
     khr_memfd: khr::ExternalMemoryFd,
+    /// if the app enabled timeline semaphores, copies signal them instead of fences
+    khr_timeline: Option<khr::TimelineSemaphore>,
+    /// if supported, copies are attached to DMA-BUFs as sync files instead of waited for
+    khr_semaphore_fd: Option<khr::ExternalSemaphoreFd>,
+    /// `imageCompressionControl` is enabled with `PW_CAPTURE_FIXED_RATE`
+    image_compression: bool,
     // ext_modifier: ext::ImageDrmFormatModifier,
 }
 
@@ -49,15 +67,37 @@ struct LayerDevice {
     ash_device: ash::Device,
     khr_swapchain: khr::Swapchain,
     queues: Vec<vk::Queue>,
+    capabilities: u32,
+    /// of known driver issues, see `DRIVER_QUIRKS`
+    workarounds: Vec<Workaround>,
+    /// physical devices of the device group, 1 if created without one
+    device_group_size: u32,
+    /// next `vkQueueSubmit2` and `vkSignalSemaphore`, core or KHR, if the app enabled them, the
+    /// hooks record timeline values like those of `vkQueueSubmit`
+    queue_submit2: Option<vk::PFN_vkQueueSubmit2>,
+    signal_semaphore: Option<vk::PFN_vkSignalSemaphore>,
     valid: Option<LayerDeviceValid>,
 }
 
+struct LayerSemaphore {
+    device: vk::Device,
+    timeline: bool,
+    /// highest value the app signaled a timeline semaphore with, from the device or the host,
+    /// copies wait for it when presents wait on the semaphore
+    signaled: AtomicU64,
+    /// of the queue that last signaled the semaphore, `QUEUE_FAMILY_IGNORED` if unknown
+    signal_family: AtomicU32,
+    /// raw swapchain handle of the acquire signaling the semaphore, 0 once a submit waited on it
+    acquired_for: AtomicU64,
+}
+
 #[allow(unused)]
 struct LayerQueue {
     device: vk::Device,
     family_index: u32,
     family_props: vk::QueueFamilyProperties,
     index: u32,
+    flags: vk::DeviceQueueCreateFlags,
 }
 
 struct LayerSurface {
@@ -66,12 +106,88 @@ struct LayerSurface {
     #[allow(unused)]
     cursor_manager: Option<Box<dyn CursorManager + Send + Sync>>,
     wl_cursor_manager: usize,
+    /// follows the focus of X11 windows with `PW_CAPTURE_FOCUSED_ONLY` but no cursor manager,
+    /// e.g. with `PW_CAPTURE_CURSOR=0`
+    focus_watch: Option<local_cursor::FocusWatch>,
+    /// last read from the cursor manager or focus watch, to log changes
+    focused: AtomicBool,
+    /// X11 window id or `wl_surface` pointer, as passed to `me_eh5_pw_capture_set_surface_title`
+    native_window: usize,
+    /// set with `me_eh5_pw_capture_set_surface_title`, preferred over the one of the cursor manager
+    title: Mutex<Option<String>>,
 }
 
 struct ImageData {
     semaphores: Vec<vk::Semaphore>,
-    fence: FenceState,
+    sync: CopySync,
     seq: usize,
+    watermark: Option<WatermarkOverlay>,
+    /// signaled for the frame callback, created on first use
+    frame_semaphore: Option<vk::Semaphore>,
+    /// per present and copy queue family, created on first copy between them
+    ownership_transfers: Vec<OwnershipTransfer>,
+    /// signaled by copies, exported as sync file, created on first use
+    sync_file_semaphore: Option<vk::Semaphore>,
+    /// signaled once the timeline semaphores a present waits on are, see `bridge_timeline_waits`,
+    /// created on first use
+    timeline_bridge: Option<vk::Semaphore>,
+    /// signaled once the work rendering the image is done, see `capture_acquired`, created on
+    /// first use
+    acquire_bridge: Option<vk::Semaphore>,
+}
+
+/// Captured frame passed to the callback set with `me_eh5_pw_capture_set_frame_callback`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct PwCaptureFrame {
+    pub image: vk::Image,
+    pub memory: vk::DeviceMemory,
+    pub format: vk::Format,
+    pub width: u32,
+    pub height: u32,
+    /// family owning `image`, the copy was submitted on a queue of it
+    pub queue_family_index: u32,
+    /// signaled once the copy is done, must be waited on exactly once
+    pub semaphore: vk::Semaphore,
+    /// present count of the swapchain
+    pub present_count: u64,
+}
+
+pub type PwCaptureFrameCallback =
+    unsafe extern "C" fn(user_data: *mut c_void, frame: *const PwCaptureFrame);
+
+/// Latest frame read back with `PW_CAPTURE_POLL`, filled by `me_eh5_pw_capture_poll_frame`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct PwCapturePolledFrame {
+    pub format: vk::Format,
+    pub width: u32,
+    pub height: u32,
+    /// bytes between rows
+    pub stride: u32,
+    /// bytes of pixels
+    pub size: usize,
+    /// present count of the swapchain
+    pub present_count: u64,
+}
+
+struct PolledFrame {
+    info: PwCapturePolledFrame,
+    data: Vec<u8>,
+}
+
+/// The latest frame for pollers and a spare one to read the next frame into, a frame a poller
+/// is still copying from is left to it.
+#[derive(Default)]
+struct PolledFrames {
+    latest: Option<Arc<PolledFrame>>,
+    spare: Option<Arc<PolledFrame>>,
+}
+
+#[derive(Clone, Copy)]
+struct FrameCallback {
+    callback: PwCaptureFrameCallback,
+    user_data: usize,
 }
 
 struct ExportImage {
@@ -80,6 +196,48 @@ struct ExportImage {
     memory: vk::DeviceMemory,
     fds: Vec<(i32, vk::SubresourceLayout)>,
     src_image: (vk::Image, usize),
+    /// content region of the frame copied last, in frame coordinates
+    content_region: Option<vk::Rect2D>,
+    /// regions changed by the frame copied last, in frame coordinates, `None` for all of it
+    damage: Option<Vec<vk::Rect2D>>,
+    /// of memfd buffers, the image is read back into the memfd
+    host: Option<HostCopy>,
+    /// the last copy is attached to the DMA-BUF, consumers wait for it instead of the layer
+    sync_file_attached: bool,
+    /// allocated for an earlier fixation, no frame is copied into it until its buffer is removed
+    stale: bool,
+    /// of converted formats, the image holds the frame the kernel converts into this buffer,
+    /// whose memory the fds and the host copy are of
+    conversion: Option<ConvertTarget>,
+}
+
+/// Mapped pixels of a linear export image and the mapped memfd they are copied to.
+struct HostCopy {
+    /// pointers, so `ExportImage` stays `Send`
+    image_ptr: usize,
+    memfd_ptr: usize,
+    size: usize,
+    coherent: bool,
+}
+
+impl HostCopy {
+    /// copies the pixels of a completed copy into the memfd
+    unsafe fn read(&self, ash_device: &ash::Device, memory: vk::DeviceMemory) -> Result<()> {
+        if !self.coherent {
+            let range = vk::MappedMemoryRange::builder()
+                .memory(memory)
+                .offset(0)
+                .size(vk::WHOLE_SIZE)
+                .build();
+            ash_device.invalidate_mapped_memory_ranges(&[range])?;
+        }
+        ptr::copy_nonoverlapping(
+            self.image_ptr as *const u8,
+            self.memfd_ptr as *mut u8,
+            self.size,
+        );
+        Ok(())
+    }
 }
 
 #[derive(Default)]
@@ -89,33 +247,158 @@ struct ExportData {
     queue_family_index: u32,
     command_pool: vk::CommandPool,
     command_buffers: Vec<vk::CommandBuffer>,
+    /// command buffers recorded since the last pool reset, with `CommandReset::Pool`
+    recorded: Mutex<Vec<bool>>,
+    copy_timer: Option<CopyTimer>,
     modifier: Option<u64>,
     num_planes: u32,
+    /// export images are created with fixed-rate compression, see `fixed_rate_supported`
+    fixed_rate: bool,
+    need_blit: bool,
+    /// of converted formats, e.g. NV12, and the parameters of their buffers
+    conversion: Option<(ConvertPipeline, ConvertParams)>,
+}
+
+/// Swapchain captured by a stream, moved to the new swapchain when it's recreated with the same
+/// format, so the node stays.
+struct StreamTarget(Mutex<(vk::SwapchainKHR, u64)>);
+
+impl StreamTarget {
+    fn new(swapchain: vk::SwapchainKHR, generation: u64) -> Self {
+        Self(Mutex::new((swapchain, generation)))
+    }
+
+    /// swapchain and generation to look up with `get_swapchain`
+    fn get(&self) -> (vk::SwapchainKHR, u64) {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn set(&self, swapchain: vk::SwapchainKHR, generation: u64) {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner) = (swapchain, generation);
+    }
+}
+
+/// With `PW_CAPTURE_COMPOSE`, swapchains presented together with the one owning the stream,
+/// copied next to its frame into the same export images, see `compose_layout`.
+struct Composition {
+    /// of stream frames and export images
+    extent: vk::Extent2D,
+    /// swapchains presented after the owning one, their generation and where they're copied to
+    parts: Vec<(vk::SwapchainKHR, u64, vk::Rect2D)>,
+    /// some of the frames are downscaled, so all are blitted
+    scaled: bool,
+}
+
+/// Presented image of a part of a `Composition`.
+struct ComposedImage {
+    /// index in `Composition::parts`
+    part: usize,
+    image: vk::Image,
+    crop: vk::Rect2D,
+    dst: vk::Rect2D,
 }
 
 struct LayerSwapchain {
-    #[allow(unused)]
+    /// unique among swapchains of the process, unlike the handle
+    generation: u64,
     device: vk::Device,
     #[allow(unused)]
     surface: vk::SurfaceKHR,
     format: vk::Format,
     extent: vk::Extent2D,
+    /// region of images captured
+    crop: vk::Rect2D,
+    /// of stream and export images, the crop downscaled with `PW_CAPTURE_RESOLUTION`, unless
+    /// composed, see `swapchain_frame_extent`
+    stream_extent: vk::Extent2D,
+    /// set up on the first present with other swapchains before the stream is created
+    composition: Option<Composition>,
     images: Vec<vk::Image>,
-    stream: Option<client::Stream>,
+    sharing_mode: vk::SharingMode,
+    /// families sharing the images with `CONCURRENT` sharing mode
+    queue_family_indices: Vec<u32>,
+    /// of the last submit waiting on an acquire of the swapchain, `QUEUE_FAMILY_IGNORED` until
+    /// known, see `select_source_family`
+    render_family: AtomicU32,
+    /// raw handle of the queue of that submit, 0 until known
+    render_queue: AtomicU64,
+    /// with `PW_CAPTURE_ON_ACQUIRE`, index of the image acquired last if the swapchain was never
+    /// presented, see `capture_acquired`
+    unpresented: Mutex<Option<u32>>,
+    /// set on the first present, images aren't captured on acquire anymore then
+    presented: AtomicBool,
+    /// created on first present after `stream_deadline`, `None` inside if creation failed
+    stream: OnceCell<Option<client::Stream>>,
+    stream_deadline: Option<Instant>,
+    /// shared with callbacks of the stream
+    stream_target: Arc<StreamTarget>,
+    alpha_mode: client::AlphaMode,
+    transform: client::VideoTransform,
+    colorimetry: client::Colorimetry,
+    /// of HDR frames with `PW_CAPTURE_TONEMAP`, the stream is SDR then
+    tonemap: Option<Tonemapping>,
+    /// `PW_CAPTURE_ENCODING`, unless ignored, see `swapchain_encoding`
+    encoding: Option<client::Transfer>,
+    /// last set with `vkSetHdrMetadataEXT`, sent to the stream once created
+    hdr_metadata: Option<client::HdrMetadata>,
+    /// of the window when the swapchain was created, if the surface knows it
+    title: Option<String>,
     image_datas: DashMap<vk::Image, ImageData>,
     export_images: DashMap<vk::Image, ExportImage>,
     export_data: Option<ExportData>,
-    cursor_serial: AtomicU64,
+    cursor_serial: CursorSerial,
+    capture_requested: AtomicBool,
+    /// toggled over the control socket
+    capture_enabled: AtomicBool,
+    /// set once a present returned `SURFACE_LOST_KHR`, never captured again
+    surface_lost: AtomicBool,
+    /// set once a present or acquire returned `OUT_OF_DATE_KHR`, the app recreates it then
+    out_of_date: AtomicBool,
+    present_count: AtomicU64,
+    frame_rate: Mutex<FrameRate>,
+    /// with `PW_CAPTURE_TARGET_FPS`
+    pacer: Option<Mutex<Pacer>>,
+    negotiation: Mutex<Negotiation>,
+    frame_callback: Mutex<Option<FrameCallback>>,
+    /// set with `me_eh5_pw_capture_set_content_region`, in image coordinates
+    content_region: Mutex<Option<vk::Rect2D>>,
+    /// image rectangles changed since the last capture, `None` for the whole image
+    damage: Mutex<Option<Vec<vk::Rect2D>>>,
+    /// with `PW_CAPTURE_POLL`, created on the first capture, `None` if that failed
+    poll_readback: OnceCell<Option<PollReadback>>,
+    polled_frames: Mutex<PolledFrames>,
+    /// with `PW_CAPTURE_PREVIEW`, created on the first present after `preview_deadline`, `None`
+    /// inside if that failed. Unlike `stream`, it isn't moved to a recreating swapchain.
+    preview: OnceCell<Option<Preview>>,
+    preview_deadline: Option<Instant>,
+    /// with `PW_CAPTURE_V4L2_DEVICE`, created like `preview`, `None` inside if the device is
+    /// missing or creation failed otherwise
+    #[cfg(feature = "v4l2")]
+    v4l2_sink: OnceCell<Option<V4l2Sink>>,
+    #[cfg(feature = "v4l2")]
+    v4l2_deadline: Option<Instant>,
 }
 
 static LOGGING: Lazy<()> = Lazy::new(init_logger);
 
-static CLIENT: Lazy<Option<client::Client>> = Lazy::new(|| {
-    client::Client::new()
-        .map_err(|e| error!(target:"client init", "failed to create client: {e:?}"))
-        .ok()
+static CONFIG: Lazy<LayerConfig> = Lazy::new(LayerConfig::load);
+
+/// of `PW_CAPTURE_COLOR_TRANSFORM`, `None` if unset or invalid
+static COLOR_TRANSFORM: Lazy<Option<ColorTransform>> = Lazy::new(load_color_transform);
+
+static CONTROL: Lazy<()> = Lazy::new(init_control_socket);
+
+static TITLE_HOOK: Lazy<()> = Lazy::new(|| unsafe {
+    me_eh5_pw_capture_register_surface_title_hook(me_eh5_pw_capture_set_surface_title);
 });
 
+/// cleared for processes denied by `PW_CAPTURE_APP_ALLOW` or `PW_CAPTURE_APP_DENY`, their
+/// instances and devices are left out of the maps and every call is passed through
+static APP_ENABLED: Lazy<bool> = Lazy::new(app_enabled);
+
+/// connected on swapchain creation, again once PipeWire restarted
+static CLIENT: Mutex<Option<client::Client>> = Mutex::new(None);
+
 static GIPA: OnceCell<vk::PFN_vkGetInstanceProcAddr> = OnceCell::new();
 static ENTRY: OnceCell<ash::Entry> = OnceCell::new();
 
@@ -128,8 +411,16 @@ static GDPA_MAP: Lazy<DashMap<vk::Device, vk::PFN_vkGetDeviceProcAddr>> =
 static DEVICE_MAP: Lazy<DashMap<vk::Device, LayerDevice>> = Lazy::new(DashMap::new);
 static QUEUE_MAP: Lazy<DashMap<vk::Queue, LayerQueue>> = Lazy::new(DashMap::new);
 static SURFACE_MAP: Lazy<DashMap<vk::SurfaceKHR, LayerSurface>> = Lazy::new(DashMap::new);
+/// titles reported for native windows without a surface, taken by the next surface of the window
+static PENDING_TITLES: Lazy<DashMap<usize, String>> = Lazy::new(DashMap::new);
+static SEMAPHORE_MAP: Lazy<DashMap<vk::Semaphore, LayerSemaphore>> = Lazy::new(DashMap::new);
+/// queues presented on without being enumerated at device creation, never captured
+static UNKNOWN_QUEUE_MAP: Lazy<DashMap<vk::Queue, vk::Device>> = Lazy::new(DashMap::new);
 static SWAPCHAIN_MAP: Lazy<DashMap<vk::SwapchainKHR, LayerSwapchain>> =
     Lazy::new(DashMap::new);
+static SWAPCHAIN_GENERATION: AtomicU64 = AtomicU64::new(0);
+/// cleared with `me_eh5_pw_capture_set_enabled`, no swapchain is captured then
+static CAPTURE_ENABLED: AtomicBool = AtomicBool::new(true);
 
 macro_rules! map_err {
     ($e:expr) => {{
@@ -151,6 +442,10 @@ macro_rules! map_result {
 }
 
 /// would be injected by GL layer
+///
+/// Only asked when `WlPointerCursor` can't track the pointer on the app's connection. The GL
+/// layer, preloaded to interpose libwayland-client, sees the cursor the app sets as well, which
+/// no request lets this layer query.
 #[no_mangle]
 pub unsafe fn me_eh5_pw_capture_get_wl_cursor_manager(
     _display: *mut c_void,
@@ -172,6 +467,330 @@ pub unsafe fn me_eh5_pw_capture_wl_cursor_snapshot(
     None
 }
 
+#[no_mangle]
+pub unsafe fn me_eh5_pw_capture_gl_layer_loaded() -> bool {
+    false
+}
+
+/// would be injected by GL layer, calling `set_title` with the titles of the app's toplevels, those
+/// set before right away and later ones as the app sets them
+#[no_mangle]
+pub unsafe fn me_eh5_pw_capture_register_surface_title_hook(
+    _set_title: unsafe extern "C" fn(*mut c_void, *const c_char) -> bool,
+) {
+}
+
+#[named]
+fn init_control_socket() {
+    if !CONFIG.control_socket {
+        return;
+    }
+    let Some(path) = control_socket_path() else {
+        warn!("XDG_RUNTIME_DIR not set, control socket disabled");
+        return;
+    };
+    let _ = spawn_control_socket(path, handle_control_command)
+        .map_err(|e| error!("failed to create control socket: {e:?}"));
+}
+
+#[named]
+fn handle_control_command(command: ControlCommand) -> Result<Vec<String>> {
+    let (handle, enabled) = match command {
+        ControlCommand::List => {
+            return Ok(SWAPCHAIN_MAP
+                .iter()
+                .map(|ly_swapchain| describe_swapchain(*ly_swapchain.key(), &ly_swapchain))
+                .collect())
+        }
+        ControlCommand::Enable(handle) => (handle, true),
+        ControlCommand::Disable(handle) => (handle, false),
+        ControlCommand::SaveReplay(handle) => return save_replay(handle),
+        ControlCommand::FullFrame(handle) => return request_full_frame(handle),
+    };
+    let swapchain = vk::SwapchainKHR::from_raw(handle);
+    let ly_swapchain = SWAPCHAIN_MAP
+        .get(&swapchain)
+        .ok_or(anyhow!("unknown swapchain {:#x}", handle))?;
+    if enabled && ly_swapchain.surface_lost.load(atomic::Ordering::Acquire) {
+        return Err(anyhow!("surface of {:#x} lost", handle));
+    }
+    ly_swapchain
+        .capture_enabled
+        .store(enabled, atomic::Ordering::Relaxed);
+    let preview = preview_proxy(&ly_swapchain);
+    if let Some(stream) = stream_proxy(ly_swapchain) {
+        if enabled {
+            stream.try_resume()???;
+        } else {
+            stream.try_pause()???;
+        }
+    }
+    if let Some(preview) = preview {
+        if enabled {
+            preview.try_resume()???;
+        } else {
+            preview.try_pause()???;
+        }
+    }
+    info!(
+        "capture of {:?} {}",
+        swapchain,
+        if enabled { "enabled" } else { "disabled" }
+    );
+    Ok(vec![])
+}
+
+/// sends the frames held back by the stream of a swapchain, answers how many
+fn save_replay(handle: u64) -> Result<Vec<String>> {
+    let swapchain = vk::SwapchainKHR::from_raw(handle);
+    let ly_swapchain = SWAPCHAIN_MAP
+        .get(&swapchain)
+        .ok_or(anyhow!("unknown swapchain {:#x}", handle))?;
+    let Some(stream) = stream_proxy(ly_swapchain) else {
+        return Err(anyhow!("no stream for {:#x}", handle));
+    };
+    let count = stream.try_save_replay()???;
+    Ok(vec![format!("{} frames", count)])
+}
+
+/// marks the whole next frame of the stream of a swapchain as damaged
+fn request_full_frame(handle: u64) -> Result<Vec<String>> {
+    let swapchain = vk::SwapchainKHR::from_raw(handle);
+    let ly_swapchain = SWAPCHAIN_MAP
+        .get(&swapchain)
+        .ok_or(anyhow!("unknown swapchain {:#x}", handle))?;
+    let Some(stream) = stream_proxy(ly_swapchain) else {
+        return Err(anyhow!("no stream for {:#x}", handle));
+    };
+    stream.try_request_full_frame()???;
+    Ok(vec![])
+}
+
+/// `<handle> <width>x<height> <format> <enabled|disabled> <stream state> <dropped frames>`
+fn describe_swapchain(swapchain: vk::SwapchainKHR, ly_swapchain: &LayerSwapchain) -> String {
+    let vk::Extent2D { width, height } = ly_swapchain.extent;
+    let enabled = ly_swapchain.capture_enabled.load(atomic::Ordering::Relaxed);
+    let stream = match ly_swapchain.stream.get() {
+        _ if ly_swapchain.surface_lost.load(atomic::Ordering::Acquire) => "lost",
+        None => "pending",
+        Some(None) => "failed",
+        Some(Some(v)) if v.is_errored() => "errored",
+        Some(Some(_)) if ly_swapchain.export_data.is_some() => "fixated",
+        Some(Some(_)) => "created",
+    };
+    let dropped = match ly_swapchain.stream.get() {
+        Some(Some(stream)) => stream.dropped_frames(),
+        _ => 0,
+    };
+    format!(
+        "{:#x} {}x{} {:?} {} {} {}",
+        swapchain.as_raw(),
+        width,
+        height,
+        ly_swapchain.format,
+        if enabled { "enabled" } else { "disabled" },
+        stream,
+        dropped
+    )
+}
+
+/// Logs formats offered and fixated for every swapchain, for diagnosing failed negotiations.
+/// Also logged on every change with `PW_CAPTURE_DUMP_NEGOTIATION=1`.
+#[no_mangle]
+#[named]
+pub unsafe extern "C" fn me_eh5_pw_capture_dump_negotiation() {
+    if SWAPCHAIN_MAP.is_empty() {
+        info!("no swapchain");
+    }
+    for ly_swapchain in SWAPCHAIN_MAP.iter() {
+        info!(
+            "{}",
+            describe_negotiation(*ly_swapchain.key(), &ly_swapchain)
+        );
+    }
+}
+
+/// Sets `callback` to be called with every frame captured from `swapchain`, or clears it if
+/// null. Returns `false` if the swapchain is unknown.
+///
+/// The callback runs on the thread calling `vkQueuePresentKHR`, after the copy was submitted
+/// and before the present itself. The frame is only valid during the callback and until the
+/// next present of `swapchain`, work reading it must be submitted within the callback, wait on
+/// `semaphore` and finish before that next present. The image is in `GENERAL` layout, owned by
+/// `queue_family_index`, and is read concurrently by PipeWire consumers so it must not be
+/// written. The callback must not call back into `me_eh5_pw_capture_set_frame_callback`.
+#[no_mangle]
+pub unsafe extern "C" fn me_eh5_pw_capture_set_frame_callback(
+    swapchain: vk::SwapchainKHR,
+    callback: Option<PwCaptureFrameCallback>,
+    user_data: *mut c_void,
+) -> bool {
+    let Some(ly_swapchain) = SWAPCHAIN_MAP.get(&swapchain) else {
+        return false;
+    };
+    let Ok(mut frame_callback) = ly_swapchain.frame_callback.lock() else {
+        return false;
+    };
+    *frame_callback = callback.map(|callback| FrameCallback {
+        callback,
+        user_data: user_data as usize,
+    });
+    true
+}
+
+/// Sets the region of `swapchain` images holding content, e.g. without letterboxing, or clears
+/// it if null. It's sent to consumers with frames captured from then on. Returns `false` if the
+/// swapchain is unknown.
+#[no_mangle]
+pub unsafe extern "C" fn me_eh5_pw_capture_set_content_region(
+    swapchain: vk::SwapchainKHR,
+    region: *const vk::Rect2D,
+) -> bool {
+    let Some(ly_swapchain) = SWAPCHAIN_MAP.get(&swapchain) else {
+        return false;
+    };
+    let Ok(mut content_region) = ly_swapchain.content_region.lock() else {
+        return false;
+    };
+    *content_region = region.as_ref().copied();
+    true
+}
+
+/// Requests the next present of `swapchain`, or every swapchain if null, to be captured
+/// regardless of options that would skip it. Returns whether any swapchain was found.
+///
+/// Thread safe, it only marks the swapchain so it can be called from any thread, but must not
+/// race with `vkDestroySwapchainKHR` of the same handle. The request is kept until a buffer
+/// is available for capturing.
+#[no_mangle]
+#[named]
+pub unsafe extern "C" fn me_eh5_pw_capture_trigger(swapchain: vk::SwapchainKHR) -> bool {
+    if swapchain == vk::SwapchainKHR::null() {
+        SWAPCHAIN_MAP.iter().for_each(|ly_swapchain| {
+            ly_swapchain
+                .capture_requested
+                .store(true, atomic::Ordering::Relaxed)
+        });
+        return !SWAPCHAIN_MAP.is_empty();
+    }
+    match SWAPCHAIN_MAP.get(&swapchain) {
+        Some(ly_swapchain) => {
+            ly_swapchain
+                .capture_requested
+                .store(true, atomic::Ordering::Relaxed);
+            true
+        }
+        None => {
+            warn!("trigger on unknown swapchain {:?}", swapchain);
+            false
+        }
+    }
+}
+
+/// Sets the title of the window `native_window`, an X11 window id or a `wl_surface` pointer, used
+/// to name the nodes of its swapchains in place of the one read from X11, or clears it if null.
+/// Injected by a GL layer, which sees requests of the app such as `xdg_toplevel.set_title` this
+/// layer can't. Returns whether a surface of the window was found, titles of windows without one
+/// are kept for the next surface created for the window.
+#[no_mangle]
+#[named]
+pub unsafe extern "C" fn me_eh5_pw_capture_set_surface_title(
+    native_window: *mut c_void,
+    title: *const c_char,
+) -> bool {
+    let title = (!title.is_null()).then(|| CStr::from_ptr(title).to_string_lossy().into_owned());
+    let surfaces: Vec<_> = SURFACE_MAP
+        .iter()
+        .filter(|ly_surface| ly_surface.native_window == native_window as usize)
+        .map(|ly_surface| {
+            *ly_surface
+                .title
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner) = title.clone();
+            *ly_surface.key()
+        })
+        .collect();
+    if surfaces.is_empty() {
+        if let Some(title) = title {
+            PENDING_TITLES.insert(native_window as usize, title);
+        } else {
+            PENDING_TITLES.remove(&(native_window as usize));
+        }
+        return false;
+    }
+    debug!("title of {:?}: {:?}", surfaces, title);
+
+    // streams of swapchains already created follow, a cleared title falls back to X11
+    let titles: Vec<_> = surfaces
+        .iter()
+        .map(|&surface| (surface, surface_title(surface)))
+        .collect();
+    let mut streams = vec![];
+    let mut previews = vec![];
+    for mut ly_swapchain in SWAPCHAIN_MAP.iter_mut() {
+        let Some((_, title)) = titles.iter().find(|(v, _)| *v == ly_swapchain.surface) else {
+            continue;
+        };
+        if ly_swapchain.title == *title {
+            continue;
+        }
+        ly_swapchain.title = title.clone();
+        let preview = preview_proxy(&ly_swapchain);
+        if let Some(stream) = stream_proxy(ly_swapchain) {
+            streams.push((stream, title.clone()));
+        }
+        if let Some(preview) = preview {
+            previews.push((preview, title.clone()));
+        }
+    }
+    for (stream, title) in streams {
+        let _ = stream.try_set_title(title).map_err(|e| map_err!(e));
+    }
+    for (preview, title) in previews {
+        let _ = preview.try_set_title(title).map_err(|e| map_err!(e));
+    }
+    true
+}
+
+/// Enables or disables capturing every swapchain of the process, e.g. from a push-to-stream
+/// hotkey. Streams stay connected and only stop receiving frames. Callable from any thread,
+/// frames whose copy was already submitted when disabling are given back unprocessed.
+#[no_mangle]
+#[named]
+pub unsafe extern "C" fn me_eh5_pw_capture_set_enabled(enabled: bool) {
+    if CAPTURE_ENABLED.swap(enabled, atomic::Ordering::Relaxed) != enabled {
+        info!("capture {}", if enabled { "enabled" } else { "disabled" });
+    }
+}
+
+/// Writes the present rate of `swapchain` in frames per second, between the last two presents
+/// and averaged over the last `FRAME_RATE_WINDOW` presents. Returns `false` if the swapchain is
+/// unknown or hasn't been presented twice yet.
+#[no_mangle]
+pub unsafe extern "C" fn me_eh5_pw_capture_frame_rate(
+    swapchain: vk::SwapchainKHR,
+    p_instantaneous: *mut f64,
+    p_average: *mut f64,
+) -> bool {
+    let Some(ly_swapchain) = SWAPCHAIN_MAP.get(&swapchain) else {
+        return false;
+    };
+    let Ok(frame_rate) = ly_swapchain.frame_rate.lock() else {
+        return false;
+    };
+    let (Some(instantaneous), Some(average)) = (frame_rate.instantaneous(), frame_rate.average())
+    else {
+        return false;
+    };
+    if !p_instantaneous.is_null() {
+        *p_instantaneous = instantaneous;
+    }
+    if !p_average.is_null() {
+        *p_average = average;
+    }
+    true
+}
+
 #[no_mangle]
 #[doc = "https://vulkan.lunarg.com/doc/view/1.3.236.0/linux/LoaderLayerInterface.html#user-content-layer-interface-version-2"]
 #[named]
@@ -229,6 +848,10 @@ unsafe extern "system" fn pwcap_vkGetInstanceProcAddr(
     // for extension command, return NULL if next layer does not support given command
     let res = gipa(instance, p_name)?;
 
+    if !*APP_ENABLED {
+        return Some(res);
+    }
+
     'outer: {
         let pfn: *const () = match name.to_bytes() {
             b"vkCreateXlibSurfaceKHR" => pwcap_vkCreateXlibSurfaceKHR as _,
@@ -270,10 +893,24 @@ unsafe extern "system" fn pwcap_vkGetDeviceProcAddr(
         return mem::transmute(pfn);
     }
 
-    let gdpa = GDPA_MAP.get(&device)?;
+    // copy out the pointer so the map isn't locked while calling down
+    let Some(gdpa) = GDPA_MAP.get(&device).map(|v| *v) else {
+        debug!("unknown device {:?} for {}", device, name.to_string_lossy());
+        return None;
+    };
     // for extension command, return NULL if next layer does not support given command
     let res = gdpa(device, p_name)?;
 
+    // device is still being created, our hooks would fail to find it
+    if !DEVICE_MAP.contains_key(&device) {
+        debug!(
+            "device {:?} not initialized, pass through {}",
+            device,
+            name.to_string_lossy()
+        );
+        return Some(res);
+    }
+
     'outer: {
         let pfn: *const () = match name.to_bytes() {
             b"vkCreateSwapchainKHR" => pwcap_vkCreateSwapchainKHR as _,
@@ -281,6 +918,13 @@ unsafe extern "system" fn pwcap_vkGetDeviceProcAddr(
             b"vkAcquireNextImageKHR" => pwcap_vkAcquireNextImageKHR as _,
             b"vkAcquireNextImage2KHR" => pwcap_vkAcquireNextImage2KHR as _,
             b"vkQueuePresentKHR" => pwcap_vkQueuePresentKHR as _,
+            b"vkCreateSemaphore" => pwcap_vkCreateSemaphore as _,
+            b"vkDestroySemaphore" => pwcap_vkDestroySemaphore as _,
+            b"vkQueueSubmit" => pwcap_vkQueueSubmit as _,
+            b"vkQueueSubmit2" | b"vkQueueSubmit2KHR" => pwcap_vkQueueSubmit2 as _,
+            b"vkSignalSemaphore" | b"vkSignalSemaphoreKHR" => pwcap_vkSignalSemaphore as _,
+            b"vkGetDeviceQueue2" => pwcap_vkGetDeviceQueue2 as _,
+            b"vkSetHdrMetadataEXT" => pwcap_vkSetHdrMetadataEXT as _,
             _ => break 'outer,
         };
         debug!(
@@ -295,6 +939,45 @@ unsafe extern "system" fn pwcap_vkGetDeviceProcAddr(
 
 const _: vk::PFN_vkGetDeviceProcAddr = pwcap_vkGetDeviceProcAddr;
 
+/// `PW_CAPTURE_COLOR_TRANSFORM` parsed, errors are logged
+#[named]
+fn load_color_transform() -> Option<ColorTransform> {
+    let path = CONFIG.color_transform.as_ref()?;
+    let res = fs::read_to_string(path)
+        .map_err(|e| format!("{e:?}"))
+        .and_then(|text| ColorTransform::parse(&text));
+    match res {
+        Ok(v) => {
+            info!("applying color transform {:?}", path);
+            Some(v)
+        }
+        Err(e) => {
+            warn!("ignoring color transform {:?}, {}", path, e);
+            None
+        }
+    }
+}
+
+/// Matches `argv[0]` and `/proc/self/comm` of this process against `PW_CAPTURE_APP_ALLOW` and
+/// `PW_CAPTURE_APP_DENY`.
+#[named]
+fn app_enabled() -> bool {
+    let argv0 = env::args_os()
+        .next()
+        .map(|v| v.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let comm = fs::read_to_string("/proc/self/comm").unwrap_or_default();
+    let comm = comm.trim_end();
+    let enabled = CONFIG.app_enabled(&[&argv0, comm]);
+    if !enabled {
+        info!(
+            "{:?} ({}) is denied, passing all calls through",
+            argv0, comm
+        );
+    }
+    enabled
+}
+
 const LAYER_INSTANCE_EXTENSIONS: &[&CStr] = &[
     vk::KhrSurfaceFn::name(),
     vk::KhrExternalMemoryCapabilitiesFn::name(),
@@ -331,6 +1014,11 @@ unsafe extern "system" fn pwcap_vkCreateInstance(
     let create_instance: vk::PFN_vkCreateInstance =
         mem::transmute(gipa(vk::Instance::null(), name.as_ptr()));
 
+    if !*APP_ENABLED {
+        let _ = GIPA.set(gipa);
+        return create_instance(p_create_info, p_allocator, p_instance);
+    }
+
     let mut extensions: HashSet<CString> = slice::from_raw_parts(
         create_info.pp_enabled_extension_names,
         create_info.enabled_extension_count as _,
@@ -388,6 +1076,16 @@ unsafe extern "system" fn pwcap_vkCreateInstance(
         None
     };
 
+    let engine_name = create_info
+        .p_application_info
+        .as_ref()
+        .filter(|app_info| !app_info.p_engine_name.is_null())
+        .map(|app_info| CStr::from_ptr(app_info.p_engine_name));
+    let is_zink = engine_name.map_or(false, |name| name.to_bytes() == b"mesa zink");
+    if is_zink {
+        debug!("instance created by Zink");
+    }
+
     let khr_surface = khr::Surface::new(&entry, &ash_instance);
     let xlib_surface = khr::XlibSurface::new(&entry, &ash_instance);
     let xcb_surface = khr::XcbSurface::new(&entry, &ash_instance);
@@ -401,6 +1099,7 @@ unsafe extern "system" fn pwcap_vkCreateInstance(
             xlib_surface,
             xcb_surface,
             wayland_surface,
+            is_zink,
             valid,
         },
     );
@@ -415,6 +1114,14 @@ unsafe fn destroy_instance(
     p_allocator: *const vk::AllocationCallbacks,
 ) -> Result<()> {
     debug!("destroying instance");
+    if !*APP_ENABLED {
+        let name = CStr::from_bytes_with_nul_unchecked(b"vkDestroyInstance\0");
+        let pfn = dispatch_next_vkGetInstanceProcAddr(instance, name.as_ptr())
+            .ok_or(vk::Result::ERROR_UNKNOWN)?;
+        let destroy_instance: vk::PFN_vkDestroyInstance = mem::transmute(pfn);
+        destroy_instance(instance, p_allocator);
+        return Ok(());
+    }
     let (_, ly_instance) = INSTANCE_MAP
         .remove(&instance)
         .ok_or(vk::Result::ERROR_UNKNOWN)?;
@@ -453,6 +1160,219 @@ const LAYER_DEVICE_EXTENSIONS: &[&CStr] = &[
     vk::KhrSwapchainFn::name(),
 ];
 
+/// enabled if supported, copies are waited for on the CPU without them
+const LAYER_OPTIONAL_DEVICE_EXTENSIONS: &[&CStr] = &[
+    vk::KhrExternalSemaphoreFn::name(),
+    vk::KhrExternalSemaphoreFdFn::name(),
+];
+
+/// cleared once the kernel refused to attach a sync file to a DMA-BUF, e.g. before Linux 6.0
+static DMA_BUF_SYNC_FILE: AtomicBool = AtomicBool::new(true);
+
+/// capture is enabled, i.e. all of `LAYER_DEVICE_EXTENSIONS` are enabled
+pub const PW_CAPTURE_CAP_CAPTURE: u32 = 1 << 0;
+pub const PW_CAPTURE_CAP_DRM_FORMAT_MODIFIER: u32 = 1 << 1;
+pub const PW_CAPTURE_CAP_EXTERNAL_MEMORY_FD: u32 = 1 << 2;
+pub const PW_CAPTURE_CAP_SAMPLER_YCBCR_CONVERSION: u32 = 1 << 3;
+/// copies of DMA-BUFs are waited for by consumers, see `LAYER_OPTIONAL_DEVICE_EXTENSIONS`
+pub const PW_CAPTURE_CAP_EXTERNAL_SEMAPHORE_FD: u32 = 1 << 4;
+
+fn device_capabilities(extensions: &HashSet<CString>, valid: bool) -> u32 {
+    let mut capabilities = if valid { PW_CAPTURE_CAP_CAPTURE } else { 0 };
+    for (name, cap) in [
+        (
+            vk::ExtImageDrmFormatModifierFn::name(),
+            PW_CAPTURE_CAP_DRM_FORMAT_MODIFIER,
+        ),
+        (
+            vk::KhrExternalMemoryFdFn::name(),
+            PW_CAPTURE_CAP_EXTERNAL_MEMORY_FD,
+        ),
+        (
+            vk::KhrSamplerYcbcrConversionFn::name(),
+            PW_CAPTURE_CAP_SAMPLER_YCBCR_CONVERSION,
+        ),
+        (
+            vk::KhrExternalSemaphoreFdFn::name(),
+            PW_CAPTURE_CAP_EXTERNAL_SEMAPHORE_FD,
+        ),
+    ] {
+        if extensions.contains(name) {
+            capabilities |= cap;
+        }
+    }
+    capabilities
+}
+
+/// whether the `timelineSemaphore` feature is enabled in the chain of `create_info`
+unsafe fn timeline_semaphore_enabled(create_info: &vk::DeviceCreateInfo) -> bool {
+    let mut p_next = create_info.p_next as *const vk::BaseInStructure;
+    while let Some(next) = p_next.as_ref() {
+        let enabled = match next.s_type {
+            vk::StructureType::PHYSICAL_DEVICE_TIMELINE_SEMAPHORE_FEATURES => {
+                let features = &*(p_next as *const vk::PhysicalDeviceTimelineSemaphoreFeatures);
+                features.timeline_semaphore
+            }
+            vk::StructureType::PHYSICAL_DEVICE_VULKAN_1_2_FEATURES => {
+                let features = &*(p_next as *const vk::PhysicalDeviceVulkan12Features);
+                features.timeline_semaphore
+            }
+            _ => vk::FALSE,
+        };
+        if enabled == vk::TRUE {
+            return true;
+        }
+        p_next = next.p_next;
+    }
+    false
+}
+
+/// per swapchain of `present_info`, set by apps using `VK_KHR_incremental_present`
+unsafe fn present_regions(present_info: &vk::PresentInfoKHR) -> &[vk::PresentRegionKHR] {
+    let mut p_next = present_info.p_next as *const vk::BaseInStructure;
+    while let Some(next) = p_next.as_ref() {
+        if next.s_type == vk::StructureType::PRESENT_REGIONS_KHR {
+            let regions = &*(p_next as *const vk::PresentRegionsKHR);
+            if regions.p_regions.is_null() {
+                return &[];
+            }
+            return slice::from_raw_parts(regions.p_regions, regions.swapchain_count as _);
+        }
+        p_next = next.p_next;
+    }
+    &[]
+}
+
+/// Physical devices of the group the device is created from, `VK_KHR_device_group_creation`.
+unsafe fn device_group_size(create_info: &vk::DeviceCreateInfo) -> u32 {
+    let mut p_next = create_info.p_next as *const vk::BaseInStructure;
+    while let Some(next) = p_next.as_ref() {
+        if next.s_type == vk::StructureType::DEVICE_GROUP_DEVICE_CREATE_INFO {
+            let group_info = &*(p_next as *const vk::DeviceGroupDeviceCreateInfo);
+            return group_info.physical_device_count.max(1);
+        }
+        p_next = next.p_next;
+    }
+    1
+}
+
+/// Looks up `DRIVER_QUIRKS` for the driver of `phy_device`.
+#[named]
+unsafe fn driver_workarounds(
+    khr_phy_props2: &khr::GetPhysicalDeviceProperties2,
+    phy_device: vk::PhysicalDevice,
+) -> Vec<Workaround> {
+    let mut driver_props = vk::PhysicalDeviceDriverProperties::default();
+    let mut props = vk::PhysicalDeviceProperties2::builder().push_next(&mut driver_props);
+    khr_phy_props2.get_physical_device_properties2(phy_device, &mut props);
+    let driver_version = props.properties.driver_version;
+    let driver_info = CStr::from_ptr(driver_props.driver_info.as_ptr()).to_string_lossy();
+
+    let mut workarounds = Vec::new();
+    for quirk in find_driver_quirks(driver_props.driver_id, driver_version) {
+        if !CONFIG.driver_quirks {
+            info!(
+                "{:?} {}: {}, workaround disabled",
+                driver_props.driver_id, driver_info, quirk.description
+            );
+            continue;
+        }
+        warn!(
+            "{:?} {}: {}, applying {:?}",
+            driver_props.driver_id, driver_info, quirk.description, quirk.workaround
+        );
+        workarounds.push(quirk.workaround);
+    }
+    workarounds
+}
+
+/// Whether swapchains of `phy_device` are captured, i.e. it matches `PW_CAPTURE_DEVICE`.
+#[named]
+unsafe fn device_selected(
+    ash_instance: &ash::Instance,
+    khr_phy_props2: Option<&khr::GetPhysicalDeviceProperties2>,
+    phy_device: vk::PhysicalDevice,
+    supported_extensions: &HashSet<CString>,
+) -> bool {
+    let props = ash_instance.get_physical_device_properties(phy_device);
+    let name = CStr::from_ptr(props.device_name.as_ptr()).to_string_lossy();
+    let pci_bus_id = match khr_phy_props2 {
+        Some(khr_phy_props2) if supported_extensions.contains(vk::ExtPciBusInfoFn::name()) => {
+            let mut pci_props = vk::PhysicalDevicePCIBusInfoPropertiesEXT::default();
+            let mut props = vk::PhysicalDeviceProperties2::builder().push_next(&mut pci_props);
+            khr_phy_props2.get_physical_device_properties2(phy_device, &mut props);
+            Some(format!(
+                "{:04x}:{:02x}:{:02x}.{:x}",
+                pci_props.pci_domain,
+                pci_props.pci_bus,
+                pci_props.pci_device,
+                pci_props.pci_function
+            ))
+        }
+        _ => None,
+    };
+    let Some(filter) = &CONFIG.device_filter else {
+        return true;
+    };
+    let selected = device_filter_matches(filter, &name, pci_bus_id.as_deref());
+    info!(
+        "device {} ({}) {} by {:?}",
+        name,
+        pci_bus_id.as_deref().unwrap_or("unknown PCI bus id"),
+        if selected { "selected" } else { "skipped" },
+        filter
+    );
+    selected
+}
+
+/// Returns `PW_CAPTURE_CAP_*` bits of what the layer enabled for `device`, 0 if unknown.
+#[no_mangle]
+pub unsafe extern "C" fn me_eh5_pw_capture_device_capabilities(device: vk::Device) -> u32 {
+    DEVICE_MAP
+        .get(&device)
+        .map(|ly_device| ly_device.capabilities)
+        .unwrap_or(0)
+}
+
+/// `imageCompressionControl` of the features struct the app chained to `create_info`, if any
+unsafe fn chained_image_compression_control(create_info: &vk::DeviceCreateInfo) -> Option<bool> {
+    let mut p_next = create_info.p_next as *const vk::BaseInStructure;
+    while let Some(next) = p_next.as_ref() {
+        if next.s_type == vk::StructureType::PHYSICAL_DEVICE_IMAGE_COMPRESSION_CONTROL_FEATURES_EXT
+        {
+            let features =
+                &*(p_next as *const vk::PhysicalDeviceImageCompressionControlFeaturesEXT);
+            return Some(features.image_compression_control == vk::TRUE);
+        }
+        p_next = next.p_next;
+    }
+    None
+}
+
+/// whether `phy_device` supports `imageCompressionControl`, if the extension is supported
+unsafe fn image_compression_control_supported(
+    khr_phy_props2: &khr::GetPhysicalDeviceProperties2,
+    phy_device: vk::PhysicalDevice,
+) -> bool {
+    let mut compression_features = vk::PhysicalDeviceImageCompressionControlFeaturesEXT::default();
+    let mut features = vk::PhysicalDeviceFeatures2::builder().push_next(&mut compression_features);
+    khr_phy_props2.get_physical_device_features2(phy_device, &mut features);
+    compression_features.image_compression_control == vk::TRUE
+}
+
+/// First of `names` the next layer provides for `device`, none of them if the app didn't enable
+/// the version or extension.
+unsafe fn next_device_fn(
+    gdpa: vk::PFN_vkGetDeviceProcAddr,
+    device: vk::Device,
+    names: &[&[u8]],
+) -> vk::PFN_vkVoidFunction {
+    names.iter().find_map(|&name| {
+        let name = CStr::from_bytes_with_nul(name).ok()?;
+        gdpa(device, name.as_ptr())
+    })
+}
+
 #[no_mangle]
 #[named]
 unsafe extern "system" fn pwcap_vkCreateDevice(
@@ -463,9 +1383,24 @@ unsafe extern "system" fn pwcap_vkCreateDevice(
 ) -> vk::Result {
     debug!("creating device");
 
-    let instance = *PHY_TO_INSTANCE_MAP.get(&physical_device).unwrap();
-    let layer_instance = INSTANCE_MAP.get(&instance).unwrap();
-    let ash_instance = &layer_instance.ash_instance;
+    if !*APP_ENABLED {
+        return create_device_passthrough(physical_device, p_create_info, p_allocator, p_device);
+    }
+
+    let Some(instance) = PHY_TO_INSTANCE_MAP.get(&physical_device).map(|v| *v) else {
+        error!("unknown physical device {:?}", physical_device);
+        return vk::Result::ERROR_INITIALIZATION_FAILED;
+    };
+    // copied out so the map isn't locked while calling down, the driver may take long and
+    // other threads may create or destroy instances meanwhile
+    let Some((ash_instance, khr_phy_props2)) = INSTANCE_MAP.get(&instance).map(|v| {
+        let khr_phy_props2 = v.valid.as_ref().map(|valid| valid.khr_phy_props2.clone());
+        (v.ash_instance.clone(), khr_phy_props2)
+    }) else {
+        error!("unknown instance {:?}", instance);
+        return vk::Result::ERROR_INITIALIZATION_FAILED;
+    };
+    let ash_instance = &ash_instance;
     let instance_fn = ash_instance.fp_v1_0();
 
     let create_info = *p_create_info;
@@ -473,36 +1408,81 @@ unsafe extern "system" fn pwcap_vkCreateDevice(
     let chain_info = if let Some(mut v) = chain_info {
         v.as_mut()
     } else {
+        error!("no chain info");
         return vk::Result::ERROR_INITIALIZATION_FAILED;
     };
 
     let layer_info = chain_info.u.p_layer_info.read();
     chain_info.u.p_layer_info = layer_info.p_next;
 
-    let gdpa = layer_info
-        .pfn_next_get_device_proc_addr
-        .expect("broken layer info");
+    let Some(gdpa) = layer_info.pfn_next_get_device_proc_addr else {
+        error!("broken layer info");
+        return vk::Result::ERROR_INITIALIZATION_FAILED;
+    };
 
-    let mut extensions: HashSet<CString> = slice::from_raw_parts(
+    let app_extensions: HashSet<CString> = slice::from_raw_parts(
         create_info.pp_enabled_extension_names,
         create_info.enabled_extension_count as _,
     )
     .iter()
     .map(|&ptr| CStr::from_ptr(ptr).to_owned())
     .collect();
+    let supported: HashSet<CString> = ash_instance
+        .enumerate_device_extension_properties(physical_device)
+        .unwrap_or_default()
+        .iter()
+        .map(|props| CStr::from_ptr(props.extension_name.as_ptr()).to_owned())
+        .collect();
+    let mut extensions = app_extensions.clone();
     // extra extensions used by layer
     for &name in LAYER_DEVICE_EXTENSIONS {
         extensions.insert(name.to_owned());
     }
+    for &name in LAYER_OPTIONAL_DEVICE_EXTENSIONS {
+        if supported.contains(name) {
+            extensions.insert(name.to_owned());
+        }
+    }
+    // features the app chained are kept as they are, the layer can't change them
+    let chained_compression = chained_image_compression_control(&create_info);
+    let image_compression = CONFIG.fixed_rate_compression
+        && supported.contains(vk::ExtImageCompressionControlFn::name())
+        && chained_compression.unwrap_or_else(|| {
+            khr_phy_props2.as_ref().map_or(false, |khr_phy_props2| {
+                image_compression_control_supported(khr_phy_props2, physical_device)
+            })
+        });
+    if image_compression {
+        extensions.insert(vk::ExtImageCompressionControlFn::name().to_owned());
+    } else if CONFIG.fixed_rate_compression {
+        warn!("fixed-rate compression not supported by device");
+    }
     debug!("{:?}", extensions);
     let extensions_data: Vec<*const i8> = extensions.iter().map(|ext| ext.as_ptr()).collect();
 
     let mut create_info_ext = create_info;
     create_info_ext.enabled_extension_count = extensions_data.len() as _;
     create_info_ext.pp_enabled_extension_names = extensions_data.as_ptr();
+    let mut compression_features = vk::PhysicalDeviceImageCompressionControlFeaturesEXT::builder()
+        .image_compression_control(true)
+        .build();
+    if image_compression && chained_compression.is_none() {
+        compression_features.p_next = create_info_ext.p_next as *mut c_void;
+        create_info_ext.p_next = &compression_features as *const _ as *const c_void;
+    }
 
-    let res = (instance_fn.create_device)(physical_device, &create_info_ext, p_allocator, p_device);
-    let valid = res == vk::Result::SUCCESS;
+    // other devices are created as the app asked, without the capture path
+    let selected = device_selected(
+        ash_instance,
+        khr_phy_props2.as_ref(),
+        physical_device,
+        &supported,
+    );
+    let valid = selected && {
+        let res =
+            (instance_fn.create_device)(physical_device, &create_info_ext, p_allocator, p_device);
+        res == vk::Result::SUCCESS
+    };
     if !valid {
         *p_device = vk::Device::null();
         let res = (instance_fn.create_device)(physical_device, &create_info, p_allocator, p_device);
@@ -515,19 +1495,63 @@ unsafe extern "system" fn pwcap_vkCreateDevice(
     assert!(device != vk::Device::null());
     debug!("device created {:?}", device);
 
+    let capabilities = if valid {
+        device_capabilities(&extensions, true)
+    } else {
+        device_capabilities(&app_extensions, false)
+    };
+    info!("device {:?} capabilities: {:#06b}", device, capabilities);
+
     // IMPORTANT: this should be put before any code executing dispatch_next_vkGetDeviceProcAddr,
-    //            i.e. `ash::Device::load()` and `khr::Swapchain::new()`
+    //            i.e. `ash::Device::load()` and `khr::Swapchain::new()`.
+    //            Until DEVICE_MAP is populated below, pwcap_vkGetDeviceProcAddr passes through.
     GDPA_MAP.insert(device, gdpa);
 
     let ash_device = ash::Device::load(instance_fn, device);
 
     let khr_swapchain = khr::Swapchain::new(ash_instance, &ash_device);
 
+    let queue_submit2 =
+        next_device_fn(gdpa, device, &[b"vkQueueSubmit2\0", b"vkQueueSubmit2KHR\0"])
+            .map(|pfn| mem::transmute::<_, vk::PFN_vkQueueSubmit2>(pfn));
+    let signal_semaphore = next_device_fn(
+        gdpa,
+        device,
+        &[b"vkSignalSemaphore\0", b"vkSignalSemaphoreKHR\0"],
+    )
+    .map(|pfn| mem::transmute::<_, vk::PFN_vkSignalSemaphore>(pfn));
+
+    let device_group_size = device_group_size(&create_info);
+    if device_group_size > 1 {
+        warn!(
+            "device group of {} physical devices, swapchains aren't captured",
+            device_group_size
+        );
+    }
+
+    let workarounds = match (valid, &khr_phy_props2) {
+        (true, Some(khr_phy_props2)) => driver_workarounds(khr_phy_props2, physical_device),
+        _ => Vec::new(),
+    };
+
     let valid = if valid {
         let khr_memfd = khr::ExternalMemoryFd::new(ash_instance, &ash_device);
         // let ext_modifier = ext::ImageDrmFormatModifier::new(ash_instance, &ash_device);
+        // the extension functions are only loaded if the app enabled it, not core 1.2 ones
+        let khr_timeline = (app_extensions.contains(vk::KhrTimelineSemaphoreFn::name())
+            && timeline_semaphore_enabled(&create_info))
+        .then(|| khr::TimelineSemaphore::new(ash_instance, &ash_device));
+        debug!("timeline semaphores: {}", khr_timeline.is_some());
+        let khr_semaphore_fd = extensions
+            .contains(vk::KhrExternalSemaphoreFdFn::name())
+            .then(|| khr::ExternalSemaphoreFd::new(ash_instance, &ash_device));
+        debug!("semaphore fd export: {}", khr_semaphore_fd.is_some());
+        debug!("fixed-rate compression: {}", image_compression);
         Some(LayerDeviceValid {
             khr_memfd,
+            khr_timeline,
+            khr_semaphore_fd,
+            image_compression,
             // ext_modifier,
         })
     } else {
@@ -545,6 +1569,7 @@ unsafe extern "system" fn pwcap_vkCreateDevice(
     let mut queues = Vec::new();
     for queue_create_info in queue_create_infos {
         let &vk::DeviceQueueCreateInfo {
+            flags,
             queue_count,
             queue_family_index: family_index,
             ..
@@ -552,7 +1577,16 @@ unsafe extern "system" fn pwcap_vkCreateDevice(
         let family_props = queue_family_properties[family_index as usize];
 
         for index in 0..queue_count {
-            let queue = ash_device.get_device_queue(family_index, index);
+            // queues created with flags, e.g. protected, are only retrievable with flags
+            let queue = if flags.is_empty() {
+                ash_device.get_device_queue(family_index, index)
+            } else {
+                let queue_info = vk::DeviceQueueInfo2::builder()
+                    .flags(flags)
+                    .queue_family_index(family_index)
+                    .queue_index(index);
+                ash_device.get_device_queue2(&queue_info)
+            };
             debug!(
                 "device queue, family:{} index:{} handle:{:?}",
                 family_index, index, queue
@@ -565,11 +1599,14 @@ unsafe extern "system" fn pwcap_vkCreateDevice(
                     family_index,
                     family_props,
                     index,
+                    flags,
                 },
             );
         }
     }
 
+    // vkCreateDevice has fully completed here, deferred host operations can only be started on
+    // the device once it's returned. Inserted last, so hooks take over only once queues are known.
     DEVICE_MAP.insert(
         device,
         LayerDevice {
@@ -578,6 +1615,11 @@ unsafe extern "system" fn pwcap_vkCreateDevice(
             ash_device,
             khr_swapchain,
             queues,
+            capabilities,
+            workarounds,
+            device_group_size,
+            queue_submit2,
+            signal_semaphore,
             valid,
         },
     );
@@ -586,20 +1628,68 @@ unsafe extern "system" fn pwcap_vkCreateDevice(
 }
 const _: vk::PFN_vkCreateDevice = pwcap_vkCreateDevice;
 
+/// Creates a device of a denied process as the app asked, only advancing the layer chain.
+#[named]
+unsafe fn create_device_passthrough(
+    physical_device: vk::PhysicalDevice,
+    p_create_info: *const vk::DeviceCreateInfo,
+    p_allocator: *const vk::AllocationCallbacks,
+    p_device: *mut vk::Device,
+) -> vk::Result {
+    let chain_info = get_device_chain_info(&*p_create_info, LayerFunction::LAYER_LINK_INFO);
+    let chain_info = if let Some(mut v) = chain_info {
+        v.as_mut()
+    } else {
+        error!("no chain info");
+        return vk::Result::ERROR_INITIALIZATION_FAILED;
+    };
+
+    let layer_info = chain_info.u.p_layer_info.read();
+    chain_info.u.p_layer_info = layer_info.p_next;
+
+    let (Some(gipa), Some(gdpa)) = (
+        layer_info.pfn_next_get_instance_proc_addr,
+        layer_info.pfn_next_get_device_proc_addr,
+    ) else {
+        error!("broken layer info");
+        return vk::Result::ERROR_INITIALIZATION_FAILED;
+    };
+
+    let name = CStr::from_bytes_with_nul_unchecked(b"vkCreateDevice\0");
+    let create_device: vk::PFN_vkCreateDevice =
+        mem::transmute(gipa(vk::Instance::null(), name.as_ptr()));
+    let res = create_device(physical_device, p_create_info, p_allocator, p_device);
+    if res == vk::Result::SUCCESS {
+        // device functions are looked up through it, see `pwcap_vkGetDeviceProcAddr`
+        GDPA_MAP.insert(*p_device, gdpa);
+    }
+    res
+}
+
 #[named]
 unsafe fn destroy_device(
     device: vk::Device,
     p_allocator: *const vk::AllocationCallbacks,
 ) -> Result<()> {
     debug!("destroying device");
+    if !*APP_ENABLED {
+        let name = CStr::from_bytes_with_nul_unchecked(b"vkDestroyDevice\0");
+        let pfn = dispatch_next_vkGetDeviceProcAddr(device, name.as_ptr())
+            .ok_or(vk::Result::ERROR_DEVICE_LOST)?;
+        let destroy_device: vk::PFN_vkDestroyDevice = mem::transmute(pfn);
+        GDPA_MAP.remove(&device);
+        destroy_device(device, p_allocator);
+        return Ok(());
+    }
+    // remove from DEVICE_MAP first so lookups stop intercepting before dispatch goes away
+    let ly_device = DEVICE_MAP.remove(&device).map(|(_, v)| v);
     GDPA_MAP.remove(&device);
-    let (_, ly_device) = DEVICE_MAP
-        .remove(&device)
-        .ok_or(vk::Result::ERROR_DEVICE_LOST)?;
+    let ly_device = ly_device.ok_or(vk::Result::ERROR_DEVICE_LOST)?;
 
-    for queue in ly_device.queues {
-        QUEUE_MAP.remove(&queue);
-    }
+    // also drops queues only retrieved by the app with vkGetDeviceQueue2
+    QUEUE_MAP.retain(|_, ly_queue| ly_queue.device != device);
+    SEMAPHORE_MAP.retain(|_, ly_semaphore| ly_semaphore.device != device);
+    UNKNOWN_QUEUE_MAP.retain(|_, &mut v| v != device);
 
     (ly_device.ash_device.fp_v1_0().destroy_device)(device, p_allocator);
     Ok(())
@@ -654,7 +1744,7 @@ unsafe extern "system" fn dispatch_next_vkGetDeviceProcAddr(
         };
         return mem::transmute(pfn);
     }
-    let gdpa = GDPA_MAP.get(&device)?;
+    let gdpa = GDPA_MAP.get(&device).map(|v| *v)?;
     gdpa(device, p_name)
 }
 const _: vk::PFN_vkGetDeviceProcAddr = dispatch_next_vkGetDeviceProcAddr;
@@ -666,8 +1756,17 @@ unsafe fn init_surface(
     raw_handle: SurfaceRawHandle,
 ) {
     debug!("create surface: {:?} raw_handle: {:?}", surface, raw_handle);
+    let native_window = match raw_handle {
+        SurfaceRawHandle::Xlib { window, .. } => window as usize,
+        SurfaceRawHandle::Xcb { window, .. } => window as usize,
+        SurfaceRawHandle::Wayland { surface, .. } => surface as usize,
+    };
     let mut wl_cursor_manager = 0;
     let cursor_manager: Option<Box<dyn CursorManager + Send + Sync>> = 'outer: {
+        // no XFixes connection or Wayland pointer tracking at all
+        if !CONFIG.capture_cursor {
+            break 'outer None;
+        }
         match raw_handle {
             SurfaceRawHandle::Xlib { dpy: _, window } => {
                 let m = local_cursor::XcbWindow::new_connection(window as _);
@@ -691,16 +1790,44 @@ unsafe fn init_surface(
                 }
             }
             SurfaceRawHandle::Wayland { display, surface } => {
+                let m = local_cursor::WlPointerCursor::new(display as _, surface as _);
+                match m {
+                    Ok(m) => break 'outer Some(Box::new(m)),
+                    Err(e) => {
+                        warn!("failed to create wayland cursor manager {e:?}");
+                    }
+                }
                 wl_cursor_manager = me_eh5_pw_capture_get_wl_cursor_manager(display, surface);
             }
         };
         break 'outer None;
     };
 
+    // the cursor manager follows the focus of X11 windows already
+    let x11_window = match raw_handle {
+        SurfaceRawHandle::Xlib { window, .. } => Some(window as u32),
+        SurfaceRawHandle::Xcb { window, .. } => Some(window),
+        SurfaceRawHandle::Wayland { .. } => None,
+    };
+    let focus_watch = x11_window
+        .filter(|_| CONFIG.focused_only && cursor_manager.is_none())
+        .and_then(|window| {
+            local_cursor::FocusWatch::spawn(window)
+                .map_err(|e| warn!("failed to follow focus of window {:#x}: {e:?}", window))
+                .ok()
+        });
+
+    // titles set before the surface, the GL layer reports those of earlier toplevels on hooking
+    Lazy::force(&TITLE_HOOK);
+    let title = PENDING_TITLES.remove(&native_window).map(|(_, v)| v);
     let ly_surface = LayerSurface {
         instance,
         cursor_manager,
         wl_cursor_manager,
+        focus_watch,
+        focused: AtomicBool::new(true),
+        native_window,
+        title: Mutex::new(title),
     };
     SURFACE_MAP.insert(surface, ly_surface);
 }
@@ -823,16 +1950,150 @@ unsafe extern "system" fn pwcap_vkDestroySurfaceKHR(
         if ly_surface.wl_cursor_manager > 0 {
             me_eh5_pw_capture_release_wl_cursor_manager(ly_surface.wl_cursor_manager);
         }
+        // a surface recreated for the window keeps its title
+        let title = ly_surface.title.into_inner();
+        if let Some(title) = title.unwrap_or_else(PoisonError::into_inner) {
+            PENDING_TITLES.insert(ly_surface.native_window, title);
+        }
     }
 
     (ly_instance.khr_surface.fp().destroy_surface_khr)(instance, surface, p_allocator);
 }
 const _: vk::PFN_vkDestroySurfaceKHR = pwcap_vkDestroySurfaceKHR;
 
+#[named]
+fn surface_is_focused(surface: vk::SurfaceKHR) -> bool {
+    let Some(ly_surface) = SURFACE_MAP.get(&surface) else {
+        return true;
+    };
+    // followed by the X11 cursor manager or focus watch from events, reading it takes no round
+    // trip; surfaces without a window (headless) or with unknown focus state, e.g. all Wayland
+    // ones, are always considered focused
+    let focused = match (&ly_surface.cursor_manager, &ly_surface.focus_watch) {
+        (Some(m), _) => m.focused(),
+        (None, Some(watch)) => watch.focused(),
+        (None, None) => None,
+    }
+    .unwrap_or(true);
+    let old = ly_surface.focused.swap(focused, atomic::Ordering::AcqRel);
+    if old != focused {
+        debug!("surface {:?} focused: {}", surface, focused);
+    }
+    focused
+}
+
+/// title of the window of `surface`, as reported by a GL layer, or read by the cursor manager of
+/// X11 windows
+fn surface_title(surface: vk::SurfaceKHR) -> Option<String> {
+    let ly_surface = SURFACE_MAP.get(&surface)?;
+    let reported = ly_surface
+        .title
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone();
+    reported.or_else(|| ly_surface.cursor_manager.as_ref()?.title())
+}
+
+/// Frees the command buffers, timer and conversion pipeline of `export_data`.
+unsafe fn destroy_export_data(ash_device: &ash::Device, export_data: ExportData) {
+    // metadata-only streams have no command buffers
+    if !export_data.command_buffers.is_empty() {
+        ash_device.free_command_buffers(export_data.command_pool, &export_data.command_buffers);
+    }
+    ash_device.destroy_command_pool(export_data.command_pool, None);
+    if let Some(timer) = &export_data.copy_timer {
+        timer.destroy(ash_device);
+    }
+    if let Some((pipeline, _)) = &export_data.conversion {
+        pipeline.destroy(ash_device);
+    }
+}
+
+unsafe fn destroy_export_image(ash_device: &ash::Device, export_image: ExportImage) {
+    let ExportImage {
+        image,
+        memory,
+        fds,
+        host,
+        conversion,
+        ..
+    } = export_image;
+    if let Some(host) = host {
+        libc::munmap(host.memfd_ptr as _, host.size);
+    }
+    if let Some(conversion) = conversion {
+        conversion.destroy(ash_device);
+    }
+    // mapped memory is unmapped when freed
+    ash_device.destroy_image(image, None);
+    for (fd, _) in fds {
+        libc::close(fd);
+    }
+    ash_device.free_memory(memory, None);
+}
+
+/// `swapchain` if it wasn't recreated since `generation`, the driver may hand the handle of a
+/// destroyed swapchain to a new one while callbacks of its stream are still in flight
+fn get_swapchain(
+    swapchain: vk::SwapchainKHR,
+    generation: u64,
+) -> Result<Ref<'static, vk::SwapchainKHR, LayerSwapchain>> {
+    SWAPCHAIN_MAP
+        .get(&swapchain)
+        .filter(|ly_swapchain| ly_swapchain.generation == generation)
+        .ok_or(anyhow!("stale stream of {swapchain:?}"))
+}
+
+fn get_swapchain_mut(
+    swapchain: vk::SwapchainKHR,
+    generation: u64,
+) -> Result<RefMut<'static, vk::SwapchainKHR, LayerSwapchain>> {
+    SWAPCHAIN_MAP
+        .get_mut(&swapchain)
+        .filter(|ly_swapchain| ly_swapchain.generation == generation)
+        .ok_or(anyhow!("stale stream of {swapchain:?}"))
+}
+
+/// Proxy of the stream of `ly_swapchain` if created, releasing the swapchain.
+///
+/// Stream callbacks run on the PipeWire thread and lock their swapchain, while calls over the
+/// proxy wait for that thread, so the stream must never be called with a swapchain of
+/// `SWAPCHAIN_MAP` held. Anything else needed from the swapchain is read before.
+fn stream_proxy(
+    ly_swapchain: impl Deref<Target = LayerSwapchain>,
+) -> Option<
+    client::StreamMethodsProxy<
+        anyhow::Error,
+        impl Fn(client::StreamMessage) -> Result<(), anyhow::Error>,
+    >,
+> {
+    match ly_swapchain.stream.get() {
+        Some(Some(stream)) => Some(stream.proxy()),
+        _ => None,
+    }
+}
+
+/// Proxy of the preview stream of `ly_swapchain` if created, which must be released before it's
+/// called, see `stream_proxy`.
+fn preview_proxy(
+    ly_swapchain: &LayerSwapchain,
+) -> Option<
+    client::StreamMethodsProxy<
+        anyhow::Error,
+        impl Fn(client::StreamMessage) -> Result<(), anyhow::Error>,
+    >,
+> {
+    match ly_swapchain.preview.get() {
+        Some(Some(preview)) => Some(preview.stream.proxy()),
+        _ => None,
+    }
+}
+
 #[named]
 unsafe fn on_fixate_format(
     device: vk::Device,
     swapchain: vk::SwapchainKHR,
+    generation: u64,
     info: client::EnumFormatInfo,
 ) -> Result<client::FixateFormat> {
     debug!("on_fixate_format");
@@ -843,12 +2104,23 @@ unsafe fn on_fixate_format(
         .get(&ly_device.instance)
         .ok_or(vk::Result::ERROR_DEVICE_LOST)?;
     let ly_instance_valid = ly_instance.valid.as_ref().unwrap();
-    let mut ly_swapchain = SWAPCHAIN_MAP
-        .get_mut(&swapchain)
-        .ok_or(vk::Result::ERROR_DEVICE_LOST)?;
+    let mut ly_swapchain = get_swapchain_mut(swapchain, generation)?;
 
-    let transfer = vk_format_get_transfer(ly_swapchain.format);
-    let format_info = client_format_get_info(info.formats[0], transfer);
+    let transfer = export_transfer(
+        &vk_format_get_info(ly_swapchain.format),
+        ly_swapchain.encoding,
+    );
+    // converted formats are written by a kernel from a copy of the frame in the swapchain format
+    let processing = swapchain_processing(ly_swapchain.alpha_mode, ly_swapchain.tonemap);
+    let converted = is_converted_format(info.formats[0]) || processing.converts_rgb();
+    let format_info = if converted {
+        VkFormatInfo {
+            format: info.formats[0],
+            ..vk_format_get_info(ly_swapchain.format)
+        }
+    } else {
+        client_format_get_info(info.formats[0], transfer)
+    };
     if format_info.vk_format == vk::Format::UNDEFINED {
         return Err(anyhow!(
             "format not supported: {:?} {:?}",
@@ -857,71 +2129,268 @@ unsafe fn on_fixate_format(
         ));
     }
 
-    let (modifier, num_planes) = if !info.modifiers.is_empty() {
+    if CONFIG.metadata_only {
+        info!("stream format fixated, metadata only: {:?}", format_info);
+        ly_swapchain.export_data = Some(ExportData {
+            format: format_info.vk_format,
+            ..Default::default()
+        });
+        return Ok(client::FixateFormat {
+            modifier: None,
+            num_planes: 0,
+        });
+    }
+
+    let has_graphics_queue = has_graphics_queue(&ly_device);
+    let scaled = ly_swapchain.stream_extent != ly_swapchain.crop.extent
+        || ly_swapchain
+            .composition
+            .as_ref()
+            .map_or(false, |v| v.scaled);
+
+    let mut params = None;
+    let mut fixed_rate = false;
+    let (modifier, num_planes, need_blit) = if converted {
+        let vk::Extent2D { width, height } = swapchain_frame_extent(&ly_swapchain);
+        params = ConvertParams::new(
+            info.formats[0],
+            width,
+            height,
+            format_info.vk_format,
+            processing,
+        );
+        let Some(params) = params else {
+            return Err(anyhow!(
+                "frames can't be converted to {:?}",
+                info.formats[0]
+            ));
+        };
+        let props = ly_instance
+            .ash_instance
+            .get_physical_device_format_properties(ly_device.phy_device, format_info.vk_format);
+        let need_blit = format_convertible(
+            format_info.vk_format,
+            props.optimal_tiling_features,
+            scaled,
+            CONFIG.watermark.is_some(),
+        )
+        .ok_or(anyhow!(
+            "{:?} frames can't be converted",
+            format_info.vk_format
+        ))?;
+        if need_blit && !has_graphics_queue {
+            return Err(anyhow!("blitting frames to convert needs a graphics queue"));
+        }
+        // all planes are in one linear buffer, whether exported or read back
+        if !info.modifiers.is_empty() && !info.modifiers.contains(&DRM_FORMAT_MOD_LINEAR) {
+            return Err(anyhow!(
+                "modifiers {:?} not compatible with converted frames",
+                info.modifiers
+            ));
+        }
+        let modifier = (!info.modifiers.is_empty()).then_some(DRM_FORMAT_MOD_LINEAR);
+        (modifier, params.planes().len() as _, need_blit)
+    } else if !info.modifiers.is_empty() {
         let modifiers = get_supported_modifiers(
             &ly_instance_valid.khr_phy_props2,
             ly_device.phy_device,
             format_info.vk_format,
             vk::ImageUsageFlags::empty(),
-            vk::FormatFeatureFlags::TRANSFER_DST,
+            vk::FormatFeatureFlags::empty(),
         )?;
         let modifiers = modifiers
             .into_iter()
-            .filter(|props| info.modifiers.contains(&props.drm_format_modifier))
+            .filter_map(|props| {
+                if !info.modifiers.contains(&props.drm_format_modifier)
+                    || !modifier_allowed(&ly_device.workarounds, props.drm_format_modifier)
+                {
+                    return None;
+                }
+                let need_blit = copy_needs_blit(
+                    ly_swapchain.format,
+                    format_info.vk_format,
+                    props.drm_format_modifier_tiling_features,
+                    scaled,
+                    CONFIG.watermark.is_some(),
+                )?;
+                if need_blit && !has_graphics_queue {
+                    return None;
+                }
+                Some((props, need_blit))
+            })
             .collect::<Vec<_>>();
 
         debug!("filtered modifiers: {:?}", modifiers);
 
-        let modifier = modifiers.first()
+        // of the modifiers the consumer can import, one allowing fixed-rate compression
+        let image_compression = ly_device
+            .valid
+            .as_ref()
+            .map_or(false, |valid| valid.image_compression);
+        let compressed = modifiers.iter().find(|(props, _)| {
+            image_compression
+                && fixed_rate_supported(
+                    &ly_instance_valid.khr_phy_props2,
+                    ly_device.phy_device,
+                    format_info.vk_format,
+                    props.drm_format_modifier,
+                )
+        });
+        fixed_rate = compressed.is_some();
+        if image_compression && !fixed_rate {
+            info!(
+                "no modifier of {:?} allows fixed-rate compression",
+                format_info.vk_format
+            );
+        }
+        let &(modifier, need_blit) = compressed
+            .or(modifiers.first())
             .ok_or(anyhow!("modifiers {:?} not compatible", info.modifiers))?;
 
+        if need_blit && format_info.vk_format == ly_swapchain.format {
+            info!(
+                "modifier {} of {:?} doesn't allow copying, fall back to blit",
+                modifier.drm_format_modifier, format_info.vk_format
+            );
+        }
+
         (
             Some(modifier.drm_format_modifier),
             modifier.drm_format_modifier_plane_count,
+            need_blit,
         )
     } else {
-        todo!("memfd")
+        // memfd, frames are copied into a linear image and read back
+        let props = ly_instance
+            .ash_instance
+            .get_physical_device_format_properties(ly_device.phy_device, format_info.vk_format);
+        let need_blit = copy_needs_blit(
+            ly_swapchain.format,
+            format_info.vk_format,
+            props.linear_tiling_features,
+            scaled,
+            CONFIG.watermark.is_some(),
+        )
+        .ok_or(anyhow!(
+            "{:?} can't be copied into linear images",
+            format_info.vk_format
+        ))?;
+        if need_blit && !has_graphics_queue {
+            return Err(anyhow!(
+                "blitting into linear images needs a graphics queue"
+            ));
+        }
+        (None, 1, need_blit)
     };
 
-    let need_graphics = format_info.vk_format != ly_swapchain.format;
-    let mut command_queue: Option<(vk::Queue, u32)> = None;
+    // re-fixation on a live stream, buffers of the previous format are removed with the
+    // renegotiation, PipeWire may still hold their fds until then, so their images are only marked
+    // stale here and freed by `on_remove_buffer`
+    let fixated = ly_swapchain.export_data.as_ref().map(|data| {
+        let params = data.conversion.as_ref().map(|(_, params)| *params);
+        (data.format, data.modifier, params)
+    });
+    if fixation_changed(fixated, format_info.vk_format, modifier, params) {
+        debug!(
+            "re-fixating from {:?} to {:?} {:?}",
+            fixated, format_info.vk_format, modifier
+        );
+        // copies into the old images may still be running
+        for mut data in ly_swapchain.image_datas.iter_mut() {
+            data.sync.wait_and_reset(&ly_device.ash_device)?;
+        }
+        for mut export_image in ly_swapchain.export_images.iter_mut() {
+            export_image.stale = true;
+        }
+    }
+
+    // kept for the next fixation converting frames, copies into stale images were waited for
+    let pipeline = ly_swapchain
+        .export_data
+        .as_mut()
+        .and_then(|data| data.conversion.take())
+        .map(|(pipeline, _)| pipeline);
+    let conversion = match (pipeline, params) {
+        (Some(pipeline), Some(params)) => Some((pipeline, params)),
+        (Some(pipeline), None) => {
+            pipeline.destroy(&ly_device.ash_device);
+            None
+        }
+        (None, Some(params)) => {
+            let pipeline = ConvertPipeline::new(
+                &ly_instance.ash_instance,
+                &ly_device.ash_device,
+                ly_device.phy_device,
+                COLOR_TRANSFORM.as_ref(),
+            )?;
+            Some((pipeline, params))
+        }
+        (None, None) => None,
+    };
 
-    for queue in &ly_device.queues {
-        let ly_queue = if let Some(v) = QUEUE_MAP.get(queue) {
-            v
-        } else {
-            continue;
-        };
-        if need_graphics {
-            if ly_queue
-                .family_props
-                .queue_flags
-                .contains(vk::QueueFlags::GRAPHICS)
-            {
-                command_queue = Some((*queue, ly_queue.family_index));
-                break;
-            }
-        } else if ly_queue
-            .family_props
-            .queue_flags
-            .contains(vk::QueueFlags::TRANSFER)
-        {
-            command_queue = Some((*queue, ly_queue.family_index));
-            if !ly_queue
-                .family_props
-                .queue_flags
-                .contains(vk::QueueFlags::GRAPHICS)
-            {
-                break;
-            }
+    let mut need = vk::QueueFlags::empty();
+    if need_blit {
+        need |= vk::QueueFlags::GRAPHICS;
+    }
+    if converted {
+        need |= vk::QueueFlags::COMPUTE;
+    }
+    let queues = ly_device
+        .queues
+        .iter()
+        .filter_map(|queue| {
+            let ly_queue = QUEUE_MAP.get(queue)?;
+            Some((
+                *queue,
+                ly_queue.device,
+                ly_queue.family_index,
+                ly_queue.family_props.queue_flags,
+            ))
+        })
+        .collect::<Vec<_>>();
+    // images rendered by compute work are copied on its queue if it can, once known
+    let render_family = ly_swapchain.render_family.load(atomic::Ordering::Relaxed);
+    let Some((queue, queue_family_index)) =
+        select_capture_queue(device, &queues, need, render_family)
+    else {
+        if let Some((pipeline, _)) = conversion {
+            pipeline.destroy(&ly_device.ash_device);
         }
+        return Err(anyhow!("no compatible queue"));
+    };
+    if ly_swapchain.sharing_mode == vk::SharingMode::CONCURRENT
+        && !ly_swapchain
+            .queue_family_indices
+            .contains(&queue_family_index)
+    {
+        warn!(
+            "capture queue family {} doesn't share images of {:?}",
+            queue_family_index, swapchain
+        );
     }
-    let (queue, queue_family_index) = command_queue.ok_or(anyhow!("no compatible queue"))?;
+    debug!(
+        "capture queue: {:?}, family: {}, needs: {:?}",
+        queue, queue_family_index, need
+    );
 
-    let (command_pool, command_buffers) = 'outer: {
+    // one per image, and for each part of a composition
+    let parts = ly_swapchain
+        .composition
+        .as_ref()
+        .map_or(0, |v| v.parts.len());
+    let command_buffer_count = ly_swapchain.images.len() * (1 + parts);
+    let (command_pool, command_buffers, recorded, copy_timer) = 'outer: {
         if let Some(data) = ly_swapchain.export_data.take() {
-            if data.queue == queue && data.command_buffers.len() >= ly_swapchain.images.len() {
-                break 'outer (data.command_pool, data.command_buffers);
+            if data.queue == queue && data.command_buffers.len() >= command_buffer_count {
+                break 'outer (
+                    data.command_pool,
+                    data.command_buffers,
+                    data.recorded,
+                    data.copy_timer,
+                );
+            }
+            if let Some(timer) = &data.copy_timer {
+                timer.destroy(&ly_device.ash_device);
             }
             ly_device
                 .ash_device
@@ -930,20 +2399,30 @@ unsafe fn on_fixate_format(
                 .ash_device
                 .destroy_command_pool(data.command_pool, None);
         }
+        let cmd_pool_flags = match CONFIG.command_reset {
+            CommandReset::Buffer => vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER,
+            CommandReset::Pool => vk::CommandPoolCreateFlags::empty(),
+        };
         let cmd_pool_info = vk::CommandPoolCreateInfo::builder()
             .queue_family_index(queue_family_index)
-            .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER);
+            .flags(cmd_pool_flags);
         let cmd_pool = ly_device
             .ash_device
             .create_command_pool(&cmd_pool_info, None)?;
         let cmd_buffers_info = vk::CommandBufferAllocateInfo::builder()
             .command_pool(cmd_pool)
             .level(vk::CommandBufferLevel::PRIMARY)
-            .command_buffer_count(ly_swapchain.images.len() as _);
+            .command_buffer_count(command_buffer_count as _);
         let cmd_buffers = ly_device
             .ash_device
             .allocate_command_buffers(&cmd_buffers_info)?;
-        break 'outer (cmd_pool, cmd_buffers);
+        let recorded = Mutex::new(vec![false; cmd_buffers.len()]);
+        let copy_timer = if CONFIG.gpu_timing {
+            create_copy_timer(&ly_instance, &ly_device, queue, cmd_buffers.len())
+        } else {
+            None
+        };
+        break 'outer (cmd_pool, cmd_buffers, recorded, copy_timer);
     };
 
     info!("stream format fixated: {:?}", format_info);
@@ -954,8 +2433,13 @@ unsafe fn on_fixate_format(
         queue_family_index,
         command_pool,
         command_buffers,
+        recorded,
+        copy_timer,
         modifier,
         num_planes,
+        fixed_rate,
+        need_blit,
+        conversion,
     });
 
     Ok(client::FixateFormat {
@@ -964,10 +2448,49 @@ unsafe fn on_fixate_format(
     })
 }
 
+/// Blits need a graphics queue, which apps presenting from compute work may not create.
+fn has_graphics_queue(ly_device: &LayerDevice) -> bool {
+    ly_device.queues.iter().any(|queue| {
+        QUEUE_MAP.get(queue).map_or(false, |ly_queue| {
+            ly_queue
+                .family_props
+                .queue_flags
+                .contains(vk::QueueFlags::GRAPHICS)
+        })
+    })
+}
+
+/// Creates the timer of copies on `queue`, if its family supports timestamps.
+#[named]
+unsafe fn create_copy_timer(
+    ly_instance: &LayerInstance,
+    ly_device: &LayerDevice,
+    queue: vk::Queue,
+    count: usize,
+) -> Option<CopyTimer> {
+    let valid_bits = QUEUE_MAP
+        .get(&queue)
+        .map_or(0, |ly_queue| ly_queue.family_props.timestamp_valid_bits);
+    if valid_bits == 0 {
+        warn!("capture queue doesn't support timestamps, no GPU timing");
+        return None;
+    }
+    let period = ly_instance
+        .ash_instance
+        .get_physical_device_properties(ly_device.phy_device)
+        .limits
+        .timestamp_period;
+    CopyTimer::new(&ly_device.ash_device, count, period, valid_bits)
+        .map_err(|e| error!("failed to create query pool: {e:?}"))
+        .ok()
+}
+
 #[named]
 unsafe fn on_add_buffer(
     device: vk::Device,
     swapchain: vk::SwapchainKHR,
+    generation: u64,
+    metadata_only: bool,
 ) -> Result<client::BufferInfo> {
     debug!("on_add_buffer");
     let ly_device = DEVICE_MAP
@@ -977,9 +2500,7 @@ unsafe fn on_add_buffer(
     let ly_instance = INSTANCE_MAP
         .get(&ly_device.instance)
         .ok_or(vk::Result::ERROR_DEVICE_LOST)?;
-    let ly_swapchain = SWAPCHAIN_MAP
-        .get(&swapchain)
-        .ok_or(vk::Result::ERROR_DEVICE_LOST)?;
+    let ly_swapchain = get_swapchain(swapchain, generation)?;
 
     let export_data = ly_swapchain
         .export_data
@@ -987,19 +2508,55 @@ unsafe fn on_add_buffer(
         .ok_or(anyhow!("no format fixated"))?;
     let export_format = export_data.format;
 
+    // also asked for by consumers of the stream, which fixated a format as usual
+    if CONFIG.metadata_only || metadata_only {
+        return Ok(client::BufferInfo {
+            is_dma_buf: false,
+            planes: vec![],
+            memory: Default::default(),
+            user_handle: client::BufferUserHandle::VkImage(vk::Image::null()),
+        });
+    }
+
+    if let Some((pipeline, params)) = &export_data.conversion {
+        return add_convert_buffer(
+            &ly_instance,
+            &ly_device,
+            &ly_swapchain,
+            export_data,
+            pipeline,
+            *params,
+        );
+    }
+
     if let Some(modifier) = export_data.modifier {
-        let (image, memory, fds) = create_target_image(
+        let (image, memory, memory_props, fds) = create_target_image(
             &ly_instance.ash_instance,
             &ly_device.ash_device,
             &ly_device_valid.khr_memfd,
             ly_device.phy_device,
             export_format,
-            ly_swapchain.extent.width,
-            ly_swapchain.extent.height,
+            swapchain_frame_extent(&ly_swapchain).width,
+            swapchain_frame_extent(&ly_swapchain).height,
             modifier,
             export_data.num_planes,
+            export_data.fixed_rate,
         )?;
 
+        let num_planes = export_data.num_planes.max(1) as usize;
+        if fds.len() != num_planes {
+            ly_device.ash_device.destroy_image(image, None);
+            for (fd, _) in fds.iter() {
+                libc::close(*fd);
+            }
+            ly_device.ash_device.free_memory(memory, None);
+            return Err(anyhow!(
+                "exported {} planes but {} were negotiated",
+                fds.len(),
+                num_planes
+            ));
+        }
+
         let plane_size = fds[0].1.size;
         assert!(plane_size > 0);
 
@@ -1023,23 +2580,229 @@ unsafe fn on_add_buffer(
                 memory,
                 fds,
                 src_image: (vk::Image::null(), 0),
+                content_region: None,
+                damage: None,
+                host: None,
+                sync_file_attached: false,
+                stale: false,
+                conversion: None,
             },
         );
 
         Ok(client::BufferInfo {
             is_dma_buf: true,
             planes,
+            memory: client::BufferMemoryInfo {
+                device_local: memory_props.contains(vk::MemoryPropertyFlags::DEVICE_LOCAL),
+                host_visible: memory_props.contains(vk::MemoryPropertyFlags::HOST_VISIBLE),
+            },
             user_handle: client::BufferUserHandle::VkImage(image),
         })
     } else {
-        todo!()
+        let (image, memory, memory_props, layout, image_ptr) = create_host_image(
+            &ly_instance.ash_instance,
+            &ly_device.ash_device,
+            ly_device.phy_device,
+            export_format,
+            swapchain_frame_extent(&ly_swapchain).width,
+            swapchain_frame_extent(&ly_swapchain).height,
+        )?;
+        let size = layout.size as usize;
+        let (fd, memfd_ptr) = match create_memfd(size) {
+            Ok(v) => v,
+            Err(e) => {
+                ly_device.ash_device.destroy_image(image, None);
+                ly_device.ash_device.free_memory(memory, None);
+                return Err(e);
+            }
+        };
+        // pixels start at the beginning of the memfd, rows keep the pitch of the image
+        let layout = vk::SubresourceLayout {
+            offset: 0,
+            ..layout
+        };
+        debug!("memfd: {}, layout: {:?}", fd, layout);
+
+        ly_swapchain.export_images.insert(
+            image,
+            ExportImage {
+                format: export_format,
+                image,
+                memory,
+                fds: vec![(fd, layout)],
+                src_image: (vk::Image::null(), 0),
+                content_region: None,
+                damage: None,
+                host: Some(HostCopy {
+                    image_ptr: image_ptr as usize,
+                    memfd_ptr: memfd_ptr as usize,
+                    size,
+                    coherent: memory_props.contains(vk::MemoryPropertyFlags::HOST_COHERENT),
+                }),
+                sync_file_attached: false,
+                stale: false,
+                conversion: None,
+            },
+        );
+
+        Ok(client::BufferInfo {
+            is_dma_buf: false,
+            planes: vec![client::BufferPlaneInfo {
+                fd: fd as _,
+                offset: 0,
+                size: layout.size as _,
+                stride: layout.row_pitch as _,
+            }],
+            memory: client::BufferMemoryInfo {
+                device_local: false,
+                host_visible: true,
+            },
+            user_handle: client::BufferUserHandle::VkImage(image),
+        })
     }
 }
 
+/// Adds a buffer of a converted format. Frames are copied into an image of the swapchain format
+/// and converted into a linear buffer holding all planes, exported as DMA-BUF with the fixated
+/// modifier, or read back into a memfd.
+#[named]
+unsafe fn add_convert_buffer(
+    ly_instance: &LayerInstance,
+    ly_device: &LayerDevice,
+    ly_swapchain: &LayerSwapchain,
+    export_data: &ExportData,
+    pipeline: &ConvertPipeline,
+    params: ConvertParams,
+) -> Result<client::BufferInfo> {
+    let ly_device_valid = ly_device.valid.as_ref().unwrap();
+    let ash_device = &ly_device.ash_device;
+    let vk::Extent2D { width, height } = swapchain_frame_extent(ly_swapchain);
+    let (image, image_memory) = create_convert_image(
+        &ly_instance.ash_instance,
+        ash_device,
+        ly_device.phy_device,
+        export_data.format,
+        width,
+        height,
+    )?;
+    let dma_buf = export_data.modifier.is_some();
+    let res = ConvertTarget::new(
+        &ly_instance.ash_instance,
+        ash_device,
+        ly_device.phy_device,
+        pipeline,
+        image,
+        export_data.format,
+        params,
+        dma_buf,
+    );
+    let (target, memory_props) = match res {
+        Ok(v) => v,
+        Err(e) => {
+            ash_device.destroy_image(image, None);
+            ash_device.free_memory(image_memory, None);
+            return Err(e);
+        }
+    };
+
+    let res = if dma_buf {
+        let get_fd_info = vk::MemoryGetFdInfoKHR::builder()
+            .memory(target.memory)
+            .handle_type(vk::ExternalMemoryHandleTypeFlags::DMA_BUF_EXT);
+        ly_device_valid
+            .khr_memfd
+            .get_memory_fd(&get_fd_info)
+            .map(|fd| (fd, None))
+            .map_err(anyhow::Error::from)
+    } else {
+        let size = params.size() as usize;
+        ash_device
+            .map_memory(
+                target.memory,
+                0,
+                vk::WHOLE_SIZE,
+                vk::MemoryMapFlags::empty(),
+            )
+            .map_err(anyhow::Error::from)
+            .and_then(|buffer_ptr| {
+                let (fd, memfd_ptr) = create_memfd(size)?;
+                let host = HostCopy {
+                    image_ptr: buffer_ptr as usize,
+                    memfd_ptr: memfd_ptr as usize,
+                    size,
+                    coherent: memory_props.contains(vk::MemoryPropertyFlags::HOST_COHERENT),
+                };
+                Ok((fd, Some(host)))
+            })
+    };
+    let (fd, host) = match res {
+        Ok(v) => v,
+        Err(e) => {
+            target.destroy(ash_device);
+            ash_device.destroy_image(image, None);
+            ash_device.free_memory(image_memory, None);
+            return Err(e);
+        }
+    };
+    // planes of the same buffer, each with its own fd
+    let fds = params
+        .planes()
+        .into_iter()
+        .enumerate()
+        .map(|(i, layout)| {
+            let fd = if i == 0 {
+                fd
+            } else {
+                libc::fcntl(fd, libc::F_DUPFD_CLOEXEC)
+            };
+            (fd, layout)
+        })
+        .collect::<Vec<_>>();
+    debug!("converted buffer, dma-buf: {}, planes: {:?}", dma_buf, fds);
+
+    let planes = fds
+        .iter()
+        .map(|(fd, layout)| client::BufferPlaneInfo {
+            fd: *fd as _,
+            offset: layout.offset as _,
+            size: layout.size as _,
+            stride: layout.row_pitch as _,
+        })
+        .collect::<Vec<_>>();
+
+    ly_swapchain.export_images.insert(
+        image,
+        ExportImage {
+            format: export_data.format,
+            image,
+            memory: image_memory,
+            fds,
+            src_image: (vk::Image::null(), 0),
+            content_region: None,
+            damage: None,
+            host,
+            sync_file_attached: false,
+            stale: false,
+            conversion: Some(target),
+        },
+    );
+
+    Ok(client::BufferInfo {
+        is_dma_buf: dma_buf,
+        planes,
+        memory: client::BufferMemoryInfo {
+            device_local: dma_buf && memory_props.contains(vk::MemoryPropertyFlags::DEVICE_LOCAL),
+            host_visible: !dma_buf || memory_props.contains(vk::MemoryPropertyFlags::HOST_VISIBLE),
+        },
+        user_handle: client::BufferUserHandle::VkImage(image),
+    })
+}
+
 #[named]
 unsafe fn on_remove_buffer(
     device: vk::Device,
     swapchain: vk::SwapchainKHR,
+    generation: u64,
     user_handle: client::BufferUserHandle,
 ) -> Result<()> {
     debug!("on_remove_buffer");
@@ -1047,27 +2810,21 @@ unsafe fn on_remove_buffer(
         client::BufferUserHandle::VkImage(image) => image,
         _ => unreachable!(),
     };
+    // metadata-only buffer
+    if image == vk::Image::null() {
+        return Ok(());
+    }
 
     let ly_device = DEVICE_MAP
         .get(&device)
         .ok_or(vk::Result::ERROR_DEVICE_LOST)?;
-    let ly_swapchain = SWAPCHAIN_MAP
-        .get(&swapchain)
-        .ok_or(vk::Result::ERROR_DEVICE_LOST)?;
+    let ly_swapchain = get_swapchain(swapchain, generation)?;
 
-    let ExportImage {
-        image, memory, fds, ..
-    } = ly_swapchain
+    let (_, export_image) = ly_swapchain
         .export_images
         .remove(&image)
-        .ok_or(vk::Result::ERROR_UNKNOWN)?
-        .1;
-
-    ly_device.ash_device.destroy_image(image, None);
-    for (fd, _) in fds {
-        libc::close(fd);
-    }
-    ly_device.ash_device.free_memory(memory, None);
+        .ok_or(vk::Result::ERROR_UNKNOWN)?;
+    destroy_export_image(&ly_device.ash_device, export_image);
 
     Ok(())
 }
@@ -1076,6 +2833,7 @@ unsafe fn on_remove_buffer(
 unsafe fn on_process_buffer(
     device: vk::Device,
     swapchain: vk::SwapchainKHR,
+    generation: u64,
     user_handle: client::BufferUserHandle,
     add_meta_cbs: client::AddBufferMetaCbs,
 ) -> Result<()> {
@@ -1089,22 +2847,23 @@ unsafe fn on_process_buffer(
     let ly_device = DEVICE_MAP
         .get(&device)
         .ok_or(vk::Result::ERROR_DEVICE_LOST)?;
-    let ly_swapchain = SWAPCHAIN_MAP
-        .get(&swapchain)
-        .ok_or(vk::Result::ERROR_DEVICE_LOST)?;
+    let ly_swapchain = get_swapchain(swapchain, generation)?;
 
-    let (src_image, seq) = {
+    // metadata-only buffers have no export image to wait for
+    let src_image = if image == vk::Image::null() {
+        None
+    } else {
         let export_image = ly_swapchain.export_images.get(&image);
         if let Some(v) = export_image {
-            v.src_image
+            Some((v.src_image, v.sync_file_attached))
         } else {
             debug!("buffer already removed");
             return Ok(());
         }
     };
 
-    if let Some(add_cursor) = add_meta_cbs.add_cursor {
-        let old_serial = ly_swapchain.cursor_serial.load(atomic::Ordering::Acquire);
+    if let Some(add_cursor) = add_meta_cbs.add_cursor.filter(|_| CONFIG.capture_cursor) {
+        let old_serial = ly_swapchain.cursor_serial.get();
         let mut snap = None;
         if let Some(ly_surface) = SURFACE_MAP.get(&ly_swapchain.surface) {
             if ly_surface.wl_cursor_manager > 0 {
@@ -1115,39 +2874,295 @@ unsafe fn on_process_buffer(
             }
         }
         if let Some(snap) = snap {
-            let _ = ly_swapchain.cursor_serial.compare_exchange(
-                old_serial,
-                snap.serial(),
-                atomic::Ordering::AcqRel,
-                atomic::Ordering::Acquire,
-            );
-            snap.as_cursor_info(old_serial != snap.serial())
+            let changed = ly_swapchain.cursor_serial.update(old_serial, snap.serial());
+            let vk::Rect2D { offset, extent } = ly_swapchain.crop;
+            let stream_extent = ly_swapchain.stream_extent;
+            snap.as_cursor_info(changed)
+                .map(|mut info| {
+                    // relative to the captured region, scaled along with downscaled frames,
+                    // the bitmap isn't
+                    info.position.x = (info.position.x - offset.x) * stream_extent.width as i32
+                        / extent.width as i32;
+                    info.position.y = (info.position.y - offset.y) * stream_extent.height as i32
+                        / extent.height as i32;
+                    info
+                })
                 .map(add_cursor);
         }
     }
 
+    let Some(((src_image, seq), sync_file_attached)) = src_image else {
+        return Ok(());
+    };
     let mut data = ly_swapchain
         .image_datas
         .get_mut(&src_image)
         .ok_or(anyhow!("src image removed"))?;
 
     trace!("src image seq: {}, export image seq: {}", data.seq, seq);
-    if seq == data.seq {
-        data.fence.wait_and_reset(&ly_device.ash_device)?;
+    // consumers wait for attached copies themselves
+    if seq == data.seq && !sync_file_attached {
+        data.sync.wait_and_reset(&ly_device.ash_device)?;
+    }
+    drop(data);
+
+    // consumers read the memfd as soon as the buffer is queued, it's read back here once the
+    // copy is done rather than waited for by the present
+    let Some(export_image) = ly_swapchain.export_images.get(&image) else {
+        return Ok(());
+    };
+    if let Some(host) = &export_image.host {
+        // converted frames are read from the buffer of the kernel
+        let memory = export_image
+            .conversion
+            .as_ref()
+            .map_or(export_image.memory, |target| target.memory);
+        host.read(&ly_device.ash_device, memory)?;
+        trace!("read {} bytes back into memfd", host.size);
     }
 
     Ok(())
 }
 
+/// content region of the buffer of `user_handle`, as of the frame copied into it
+fn on_video_crop(
+    swapchain: vk::SwapchainKHR,
+    generation: u64,
+    user_handle: client::BufferUserHandle,
+) -> Result<Option<client::Region>> {
+    let image = match user_handle {
+        client::BufferUserHandle::VkImage(image) => image,
+        _ => unreachable!(),
+    };
+    let ly_swapchain = get_swapchain(swapchain, generation)?;
+    // metadata-only buffers have no export image keeping the region of its frame
+    let region = if image == vk::Image::null() {
+        swapchain_content_region(&ly_swapchain)
+    } else {
+        ly_swapchain
+            .export_images
+            .get(&image)
+            .ok_or(anyhow!("buffer image not found"))?
+            .content_region
+    };
+    Ok(region.map(rect_to_region))
+}
+
+/// regions of the buffer of `user_handle` changed by the frame copied into it, all of it for
+/// `full_frame`
+fn on_video_damage(
+    swapchain: vk::SwapchainKHR,
+    generation: u64,
+    user_handle: client::BufferUserHandle,
+    full_frame: bool,
+) -> Result<Vec<client::Region>> {
+    let image = match user_handle {
+        client::BufferUserHandle::VkImage(image) => image,
+        _ => unreachable!(),
+    };
+    let ly_swapchain = get_swapchain(swapchain, generation)?;
+    let whole = vk::Rect2D {
+        offset: vk::Offset2D { x: 0, y: 0 },
+        extent: swapchain_frame_extent(&ly_swapchain),
+    };
+    // metadata-only buffers carry no frame to tell changes of
+    let damage = if image == vk::Image::null() || full_frame {
+        None
+    } else {
+        ly_swapchain
+            .export_images
+            .get(&image)
+            .ok_or(anyhow!("buffer image not found"))?
+            .damage
+            .clone()
+    };
+    let damage = damage.unwrap_or_else(|| vec![whole]);
+    Ok(damage.into_iter().map(rect_to_region).collect())
+}
+
+fn rect_to_region(rect: vk::Rect2D) -> client::Region {
+    client::Region {
+        position: client::Point {
+            x: rect.offset.x,
+            y: rect.offset.y,
+        },
+        width: rect.extent.width,
+        height: rect.extent.height,
+    }
+}
+
+/// region set by the app, in stream frame coordinates
+fn swapchain_content_region(ly_swapchain: &LayerSwapchain) -> Option<vk::Rect2D> {
+    let region = (*ly_swapchain.content_region.lock().ok()?)?;
+    region_in_stream(region, ly_swapchain.crop, ly_swapchain.stream_extent)
+}
+
+/// of stream frames, the composition of swapchains presented together or the own frame
+fn swapchain_frame_extent(ly_swapchain: &LayerSwapchain) -> vk::Extent2D {
+    ly_swapchain
+        .composition
+        .as_ref()
+        .map_or(ly_swapchain.stream_extent, |v| v.extent)
+}
+
+fn composite_alpha_to_alpha_mode(composite_alpha: vk::CompositeAlphaFlagsKHR) -> client::AlphaMode {
+    match composite_alpha {
+        vk::CompositeAlphaFlagsKHR::PRE_MULTIPLIED => client::AlphaMode::Premultiplied,
+        vk::CompositeAlphaFlagsKHR::POST_MULTIPLIED => client::AlphaMode::Straight,
+        // INHERIT leaves it to the window system, which can't be queried from here
+        _ => client::AlphaMode::Opaque,
+    }
+}
+
+/// processing of frames of a swapchain with `alpha_mode`, `PW_CAPTURE_ALPHA_MODE` is ignored
+/// for opaque ones, it, tonemapping and the color transform for metadata-only streams
+fn swapchain_processing(alpha_mode: client::AlphaMode, tonemap: Option<Tonemapping>) -> Processing {
+    let target_alpha_mode = match CONFIG.alpha_mode {
+        Some(mode) if alpha_mode != client::AlphaMode::Opaque && !CONFIG.metadata_only => mode,
+        _ => alpha_mode,
+    };
+    Processing {
+        alpha_mode,
+        target_alpha_mode,
+        tonemap: tonemap.filter(|_| !CONFIG.metadata_only),
+        color_transform: COLOR_TRANSFORM.is_some() && !CONFIG.metadata_only,
+    }
+}
+
+/// `PW_CAPTURE_TONEMAP` of a swapchain with `color_space`, `None` for SDR and HDR color spaces
+/// the kernel can't decode, e.g. HLG
+fn color_space_tonemapping(color_space: vk::ColorSpaceKHR) -> Option<Tonemapping> {
+    type C = vk::ColorSpaceKHR;
+    let operator = CONFIG.tonemap?;
+    let (pq, bt2020) = match color_space {
+        C::HDR10_ST2084_EXT => (true, true),
+        C::EXTENDED_SRGB_LINEAR_EXT => (false, false),
+        C::BT2020_LINEAR_EXT => (false, true),
+        _ => return None,
+    };
+    Some(Tonemapping {
+        operator,
+        pq,
+        bt2020,
+    })
+}
+
+/// unknown for sRGB, which consumers assume anyway, and color spaces without SPA counterpart
+fn color_space_to_colorimetry(color_space: vk::ColorSpaceKHR) -> client::Colorimetry {
+    use client::{ColorPrimaries as P, TransferFunction as T};
+    type C = vk::ColorSpaceKHR;
+    let (transfer_function, primaries) = match color_space {
+        C::EXTENDED_SRGB_LINEAR_EXT | C::BT709_LINEAR_EXT => (T::Linear, P::Bt709),
+        C::EXTENDED_SRGB_NONLINEAR_EXT => (T::Srgb, P::Bt709),
+        C::BT709_NONLINEAR_EXT => (T::Bt709, P::Bt709),
+        C::DISPLAY_P3_LINEAR_EXT => (T::Linear, P::DisplayP3),
+        C::DISPLAY_P3_NONLINEAR_EXT => (T::Srgb, P::DisplayP3),
+        C::BT2020_LINEAR_EXT => (T::Linear, P::Bt2020),
+        C::HDR10_ST2084_EXT => (T::Pq, P::Bt2020),
+        C::HDR10_HLG_EXT => (T::Hlg, P::Bt2020),
+        _ => (T::Unknown, P::Unknown),
+    };
+    client::Colorimetry {
+        transfer_function,
+        primaries,
+    }
+}
+
+/// `PW_CAPTURE_CROP` clamped to `extent`, or the whole image
+#[named]
+fn swapchain_crop(extent: vk::Extent2D) -> vk::Rect2D {
+    let whole = vk::Rect2D {
+        offset: vk::Offset2D::default(),
+        extent,
+    };
+    let Some(crop) = CONFIG.crop else {
+        return whole;
+    };
+    let Some(clamped) = crop.clamp(extent.width, extent.height) else {
+        warn!(
+            "crop {:?} outside of {}x{} swapchain, capturing whole image",
+            crop, extent.width, extent.height
+        );
+        return whole;
+    };
+    if clamped != crop {
+        warn!(
+            "crop {:?} exceeds {}x{} swapchain, clamped to {}x{}",
+            crop, extent.width, extent.height, clamped.width, clamped.height
+        );
+    }
+    vk::Rect2D {
+        offset: vk::Offset2D {
+            x: clamped.x as _,
+            y: clamped.y as _,
+        },
+        extent: vk::Extent2D {
+            width: clamped.width,
+            height: clamped.height,
+        },
+    }
+}
+
+/// `crop` downscaled to fit `PW_CAPTURE_RESOLUTION`, as is if the device can't blit
+#[named]
+fn swapchain_stream_extent(ly_device: &LayerDevice, crop: vk::Extent2D) -> vk::Extent2D {
+    let Some(resolution) = CONFIG.resolution else {
+        return crop;
+    };
+    let (width, height) = resolution.fit(crop.width, crop.height);
+    if (width, height) == (crop.width, crop.height) {
+        return crop;
+    }
+    if !has_graphics_queue(ly_device) {
+        warn!(
+            "no graphics queue to downscale {}x{} frames with, capturing them as is",
+            crop.width, crop.height
+        );
+        return crop;
+    }
+    debug!(
+        "downscaling {}x{} frames to {}x{}",
+        crop.width, crop.height, width, height
+    );
+    vk::Extent2D { width, height }
+}
+
+/// Vulkan rotates clockwise, SPA counter-clockwise
+fn pre_transform_to_video_transform(
+    pre_transform: vk::SurfaceTransformFlagsKHR,
+) -> client::VideoTransform {
+    type T = vk::SurfaceTransformFlagsKHR;
+    match pre_transform {
+        T::ROTATE_90 => client::VideoTransform::Rotate270,
+        T::ROTATE_180 => client::VideoTransform::Rotate180,
+        T::ROTATE_270 => client::VideoTransform::Rotate90,
+        T::HORIZONTAL_MIRROR => client::VideoTransform::Flipped,
+        T::HORIZONTAL_MIRROR_ROTATE_90 => client::VideoTransform::Flipped270,
+        T::HORIZONTAL_MIRROR_ROTATE_180 => client::VideoTransform::Flipped180,
+        T::HORIZONTAL_MIRROR_ROTATE_270 => client::VideoTransform::Flipped90,
+        // INHERIT leaves it to the window system as well
+        _ => client::VideoTransform::None,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 #[named]
 unsafe fn create_stream(
     khr_phy_props2: &khr::GetPhysicalDeviceProperties2,
     phy_device: vk::PhysicalDevice,
     device: vk::Device,
-    swapchain: vk::SwapchainKHR,
+    target: Arc<StreamTarget>,
     swapchain_format: vk::Format,
     width: u32,
     height: u32,
+    alpha_mode: client::AlphaMode,
+    transform: client::VideoTransform,
+    colorimetry: client::Colorimetry,
+    tonemap: Option<Tonemapping>,
+    encoding: Option<client::Transfer>,
+    source_images: u32,
+    title: Option<String>,
+    workarounds: &[Workaround],
 ) -> Result<client::Stream> {
     let src_format_info = vk_format_get_info(swapchain_format);
     // TODO: check if swapchain format is valid, e.g. supports TRANSFER_SRC
@@ -1157,12 +3172,47 @@ unsafe fn create_stream(
         width, height, src_format_info
     );
 
-    let formats: Vec<VkFormatInfo> = if src_format_info.format == client::Format::UNKNOWN {
+    // the crop is fixed for the swapchain, only its size is passed
+    let scaled = {
+        let (swapchain, generation) = target.get();
+        get_swapchain(swapchain, generation).map_or(false, |ly_swapchain| {
+            ly_swapchain.stream_extent != ly_swapchain.crop.extent
+        })
+    };
+
+    let processing = swapchain_processing(alpha_mode, tonemap);
+    let transfer = export_transfer(&src_format_info, encoding);
+    let colorimetry = if processing.tonemap.is_some() {
+        // the kernel writes sRGB encoded BT.709
+        client::Colorimetry {
+            transfer_function: client::TransferFunction::Srgb,
+            primaries: client::ColorPrimaries::Bt709,
+        }
+    } else if transfer != src_format_info.transfer {
+        // blitting between sRGB and UNORM formats changes the encoding of values
+        client::Colorimetry {
+            transfer_function: client::TransferFunction::Unknown,
+            ..colorimetry
+        }
+    } else {
+        colorimetry
+    };
+    let mut formats: Vec<VkFormatInfo> = if src_format_info.format == client::Format::UNKNOWN {
         VK_FORMAT_INFO_TABLE
             .iter()
             .filter(|info| info.transfer == src_format_info.transfer)
             .cloned()
             .collect()
+    } else if transfer != src_format_info.transfer {
+        // same layout with the other encoding first, e.g. `B8G8R8A8_UNORM` for `B8G8R8A8_SRGB`
+        let mut formats = VK_FORMAT_INFO_TABLE
+            .iter()
+            .filter(|info| info.transfer == transfer)
+            .cloned()
+            .collect::<Vec<_>>();
+        formats.sort_by_key(|info| info.format != src_format_info.format);
+        info!("converting {:?} frames to {:?}", src_format_info, transfer);
+        formats
     } else {
         let it = VK_FORMAT_INFO_TABLE
             .iter()
@@ -1174,32 +3224,100 @@ unsafe fn create_stream(
         core::iter::once(src_format_info).chain(it).collect()
     };
 
-    // XXX: support for YUV formats with shader conversion?
+    if CONFIG.prefer_10bit && vk_format_component_bits(src_format_info.vk_format) < 10 {
+        // `vkCmdBlitImage` expands components by normalized value, e.g. 0xff to 0x3ff,
+        // upconverting only changes the format, it can't add detail absent in the source
+        formats.sort_by_key(|info| vk_format_component_bits(info.vk_format) != 10);
+        if !formats
+            .iter()
+            .any(|info| vk_format_component_bits(info.vk_format) == 10)
+        {
+            debug!(
+                "no 10-bit format with {:?} transfer",
+                src_format_info.transfer
+            );
+        }
+    }
+
+    if !CONFIG.format_order.is_empty() {
+        // the first offered format is the default, taken by consumers without a preference
+        formats.sort_by_key(|info| {
+            CONFIG
+                .format_order
+                .iter()
+                .position(|&format| format == info.format)
+                .unwrap_or(usize::MAX)
+        });
+        debug!("formats reordered, {:?}", formats);
+    }
 
     let mut enum_formats = Vec::<client::EnumFormatInfo>::new();
 
-    'outer: for format_info in &formats {
-        let (usage, features) = if src_format_info.vk_format == format_info.vk_format {
-            (
-                vk::ImageUsageFlags::TRANSFER_DST,
-                vk::FormatFeatureFlags::TRANSFER_DST,
-            )
-        } else {
-            (
-                vk::ImageUsageFlags::TRANSFER_DST,
-                vk::FormatFeatureFlags::BLIT_DST,
-            )
-        };
-        let mut modifiers = get_supported_modifiers(
+    if CONFIG.metadata_only {
+        // buffers carry no pixels, the format only describes the source
+        if src_format_info.format != client::Format::UNKNOWN {
+            enum_formats.push(client::EnumFormatInfo {
+                formats: vec![src_format_info.format],
+                modifiers: vec![],
+            });
+        }
+        formats.clear();
+    }
+
+    let mut converted_formats = vec![];
+    if processing.converts_rgb() {
+        // copies would keep the alpha mode and values of the swapchain
+        if processing.alpha_mode != processing.target_alpha_mode {
+            info!(
+                "converting alpha of frames from {:?} to {:?}",
+                processing.alpha_mode, processing.target_alpha_mode
+            );
+        }
+        formats.clear();
+        converted_formats.extend(CONVERTED_RGB_FORMATS);
+    }
+    if CONFIG.nv12 && !CONFIG.metadata_only {
+        // offered last, for consumers only taking YUV, e.g. encoders
+        converted_formats.push(client::Format::NV12);
+    }
+
+    let dma_buf_formats = if matches!(CONFIG.buffer_type, BufferType::Memfd | BufferType::MemPtr) {
+        &[][..]
+    } else {
+        &formats[..]
+    };
+    'outer: for format_info in dma_buf_formats {
+        let modifiers = get_supported_modifiers(
             khr_phy_props2,
             phy_device,
             format_info.vk_format,
-            usage,
-            features,
-        )?
-        .into_iter()
-        .map(|props| props.drm_format_modifier)
-        .collect::<Vec<_>>();
+            vk::ImageUsageFlags::TRANSFER_DST,
+            vk::FormatFeatureFlags::empty(),
+        );
+        let mut modifiers = match modifiers {
+            Ok(v) => v
+                .into_iter()
+                .filter(|props| {
+                    // offered formats are single-plane, extra memory planes of a modifier hold
+                    // compression metadata, e.g. Intel CCS or AMD DCC
+                    (CONFIG.compressed_modifiers || props.drm_format_modifier_plane_count <= 1)
+                        && modifier_allowed(workarounds, props.drm_format_modifier)
+                        && copy_needs_blit(
+                            src_format_info.vk_format,
+                            format_info.vk_format,
+                            props.drm_format_modifier_tiling_features,
+                            scaled,
+                            CONFIG.watermark.is_some(),
+                        )
+                        .is_some()
+                })
+                .map(|props| props.drm_format_modifier)
+                .collect::<Vec<_>>(),
+            Err(e) => {
+                debug!("failed to query modifiers, {:?}: {e:?}", format_info);
+                continue;
+            }
+        };
 
         if modifiers.is_empty() {
             debug!("does not support export modifier, {:?}", format_info);
@@ -1229,38 +3347,206 @@ unsafe fn create_stream(
         enum_formats.push(enum_format);
     }
 
-    for _format_info in &formats {
-        // TODO: memfd or linear dma-buf
+    if CONFIG.buffer_type != BufferType::DmaBuf {
+        // offered after DMA-BUFs, for consumers that can't import them, distinguished by the
+        // empty modifiers when fixated
+        let memfd_formats = formats
+            .iter()
+            .filter(|format_info| {
+                let mut props = vk::FormatProperties2::default();
+                khr_phy_props2.get_physical_device_format_properties2(
+                    phy_device,
+                    format_info.vk_format,
+                    &mut props,
+                );
+                copy_needs_blit(
+                    src_format_info.vk_format,
+                    format_info.vk_format,
+                    props.format_properties.linear_tiling_features,
+                    scaled,
+                    CONFIG.watermark.is_some(),
+                )
+                .is_some()
+            })
+            .map(|format_info| format_info.format)
+            .collect::<Vec<_>>();
+        if !memfd_formats.is_empty() {
+            enum_formats.push(client::EnumFormatInfo {
+                formats: memfd_formats,
+                modifiers: vec![],
+            });
+        }
+    }
+
+    if !converted_formats.is_empty() {
+        // frames are copied into an image of the swapchain format as usual and converted into a
+        // linear buffer by a kernel
+        let mut props = vk::FormatProperties2::default();
+        khr_phy_props2.get_physical_device_format_properties2(
+            phy_device,
+            src_format_info.vk_format,
+            &mut props,
+        );
+        let convertible = format_convertible(
+            src_format_info.vk_format,
+            props.format_properties.optimal_tiling_features,
+            scaled,
+            CONFIG.watermark.is_some(),
+        )
+        .is_some();
+        if !convertible {
+            warn!(
+                "{:?} can't be converted to {:?}",
+                src_format_info, converted_formats
+            );
+        } else {
+            // YUV formats are offered apart from RGB ones, with their color matrix
+            let (yuv_formats, rgb_formats): (Vec<_>, Vec<_>) =
+                converted_formats.into_iter().partition(|f| f.is_yuv());
+            for formats in [rgb_formats, yuv_formats] {
+                if formats.is_empty() {
+                    continue;
+                }
+                if !matches!(CONFIG.buffer_type, BufferType::Memfd | BufferType::MemPtr)
+                    && modifier_allowed(workarounds, DRM_FORMAT_MOD_LINEAR)
+                {
+                    enum_formats.push(client::EnumFormatInfo {
+                        formats: formats.clone(),
+                        modifiers: vec![DRM_FORMAT_MOD_LINEAR],
+                    });
+                }
+                if CONFIG.buffer_type != BufferType::DmaBuf {
+                    enum_formats.push(client::EnumFormatInfo {
+                        formats,
+                        modifiers: vec![],
+                    });
+                }
+            }
+        }
+    }
+
+    let (swapchain, generation) = target.get();
+    if let Ok(ly_swapchain) = get_swapchain(swapchain, generation) {
+        if let Ok(mut negotiation) = ly_swapchain.negotiation.lock() {
+            *negotiation = Negotiation {
+                offered: enum_formats.clone(),
+                ..Default::default()
+            };
+        }
+    }
+
+    if enum_formats.is_empty() {
+        return Err(anyhow!(
+            "no exportable format for {:?}, capture disabled",
+            src_format_info
+        ));
     }
 
     debug!("added formats, {:?}", enum_formats);
+    // every frame is read back by the CPU and handed over inline, only small ones are
+    let mut mem_ptr = CONFIG.buffer_type == BufferType::MemPtr;
+    if mem_ptr && width * height > MAX_MEM_PTR_PIXELS {
+        warn!(
+            "{}x{} too large for inline buffers, offering memfd instead",
+            width, height
+        );
+        mem_ptr = false;
+    }
+    if CONFIG.pts_offset_ms != 0 {
+        info!("offsetting frame timestamps by {} ms", CONFIG.pts_offset_ms);
+    }
 
     let stream_info = client::StreamInfo {
         width,
         height,
         enum_formats,
         max_buffers: MAX_BUFFERS,
-        fixate_format: Box::new(move |format| {
-            on_fixate_format(device, swapchain, format)
-                .map_err(|e| map_err!(e))
-                .ok()
+        alpha_mode: processing.target_alpha_mode,
+        transfer,
+        source_images,
+        framerate: CONFIG.target_fps,
+        title,
+        transform,
+        interlaced: CONFIG.interlaced,
+        colorimetry,
+        metadata_only: CONFIG.metadata_only,
+        metadata_on_request: true,
+        mem_ptr,
+        pull: CONFIG.pull,
+        warmup: Duration::from_millis(CONFIG.warmup_ms as _),
+        static_timeout: Duration::from_millis(CONFIG.static_timeout_ms as _),
+        pts_offset_ns: CONFIG.pts_offset_ms as i64 * 1_000_000,
+        max_latency: Duration::from_millis(CONFIG.max_latency_ms as _),
+        replay: Duration::from_secs(CONFIG.replay_seconds as _),
+        fixate_format: Box::new({
+            let target = target.clone();
+            move |format| {
+                let (swapchain, generation) = target.get();
+                let res = on_fixate_format(device, swapchain, generation, format.clone());
+                record_fixation(swapchain, generation, format, &res);
+                res.map_err(|e| map_err!(e)).ok()
+            }
         }),
-        add_buffer: Box::new(move || {
-            on_add_buffer(device, swapchain)
-                .map_err(|e| map_err!(e))
-                .ok()
+        add_buffer: Box::new({
+            let target = target.clone();
+            move |metadata_only| {
+                let (swapchain, generation) = target.get();
+                on_add_buffer(device, swapchain, generation, metadata_only)
+                    .map_err(|e| map_err!(e))
+                    .ok()
+            }
         }),
-        remove_buffer: Box::new(move |user_handle| {
-            let _ = on_remove_buffer(device, swapchain, user_handle).map_err(|e| map_err!(e));
+        remove_buffer: Box::new({
+            let target = target.clone();
+            move |user_handle| {
+                let (swapchain, generation) = target.get();
+                let _ = on_remove_buffer(device, swapchain, generation, user_handle)
+                    .map_err(|e| map_err!(e));
+            }
         }),
-        process_buffer: Box::new(move |user_handle, add_meta_cbs| {
-            let _ = on_process_buffer(device, swapchain, user_handle, add_meta_cbs)
-                .map_err(|e| map_err!(e));
+        process_buffer: Box::new({
+            let target = target.clone();
+            move |user_handle, add_meta_cbs| {
+                let (swapchain, generation) = target.get();
+                let _ = on_process_buffer(device, swapchain, generation, user_handle, add_meta_cbs)
+                    .map_err(|e| map_err!(e));
+            }
+        }),
+        video_crop: Some(Box::new({
+            let target = target.clone();
+            move |user_handle| {
+                let (swapchain, generation) = target.get();
+                on_video_crop(swapchain, generation, user_handle)
+                    .map_err(|e| map_err!(e))
+                    .ok()?
+            }
+        })),
+        video_damage: Some(Box::new({
+            let target = target.clone();
+            move |user_handle, full_frame| {
+                let (swapchain, generation) = target.get();
+                on_video_damage(swapchain, generation, user_handle, full_frame)
+                    .map_err(|e| map_err!(e))
+                    .unwrap_or_default()
+            }
+        })),
+        streaming: Box::new(move || {
+            // consumers linking late shouldn't wait for skipped presents, e.g. with
+            // `PW_CAPTURE_EVERY_N` or unfocused windows, for their first frame
+            let (swapchain, generation) = target.get();
+            if let Ok(ly_swapchain) = get_swapchain(swapchain, generation) {
+                ly_swapchain
+                    .capture_requested
+                    .store(true, atomic::Ordering::Relaxed);
+            }
         }),
     };
 
     let stream = CLIENT
+        .lock()
+        .map_err(|_| anyhow!("client poisoned"))?
         .as_ref()
+        .filter(|v| v.is_alive())
         .ok_or(anyhow!("failed to get client"))?
         .proxy()
         .try_create_stream(stream_info)???;
@@ -1268,6 +3554,21 @@ unsafe fn create_stream(
     Ok(stream)
 }
 
+/// Connects the client if not connected yet or its connection died, e.g. PipeWire restarted.
+#[named]
+fn connect_client() {
+    let mut client = CLIENT.lock().unwrap_or_else(PoisonError::into_inner);
+    if client.as_ref().map_or(false, |v| v.is_alive()) {
+        return;
+    }
+    if client.take().is_some() {
+        info!("reconnecting to PipeWire");
+    }
+    *client = client::Client::new()
+        .map_err(|e| error!(target:"client init", "failed to create client: {e:?}"))
+        .ok();
+}
+
 #[named]
 unsafe fn create_swapchain_khr(
     device: vk::Device,
@@ -1275,6 +3576,9 @@ unsafe fn create_swapchain_khr(
     p_allocator: *const vk::AllocationCallbacks,
     p_swapchain: *mut vk::SwapchainKHR,
 ) -> Result<()> {
+    Lazy::force(&CONTROL);
+    connect_client();
+
     let ly_device = DEVICE_MAP
         .get(&device)
         .ok_or(vk::Result::ERROR_DEVICE_LOST)?;
@@ -1282,22 +3586,91 @@ unsafe fn create_swapchain_khr(
         .get(&ly_device.instance)
         .ok_or(vk::Result::ERROR_DEVICE_LOST)?;
 
+    // there's no query exposing usage of swapchain images, so the added flag isn't visible
+    // to the app, but it has to be supported by the surface
     let mut create_info = p_create_info.read();
-    create_info.image_usage |= vk::ImageUsageFlags::TRANSFER_SRC;
+    let app_usage = create_info.image_usage;
+    let supported_usage = ly_instance
+        .khr_surface
+        .get_physical_device_surface_capabilities(ly_device.phy_device, create_info.surface)
+        .map(|caps| caps.supported_usage_flags)
+        .unwrap_or_default();
+    if supported_usage.contains(vk::ImageUsageFlags::TRANSFER_SRC) {
+        create_info.image_usage |= vk::ImageUsageFlags::TRANSFER_SRC;
+    } else {
+        warn!("surface doesn't support TRANSFER_SRC usage");
+    }
 
     let vk::SwapchainCreateInfoKHR {
         image_format,
         image_extent,
+        image_color_space,
+        image_sharing_mode,
+        composite_alpha,
+        pre_transform,
         ..
     } = create_info;
+    let queue_family_indices = if image_sharing_mode == vk::SharingMode::CONCURRENT {
+        slice::from_raw_parts(
+            create_info.p_queue_family_indices,
+            create_info.queue_family_index_count as _,
+        )
+        .to_vec()
+    } else {
+        vec![]
+    };
+    // copies and blits keep values as is, only the kernel tonemaps
+    let tonemap = color_space_tonemapping(image_color_space);
+    if let Some(tonemap) = tonemap {
+        info!(
+            "HDR swapchain {:?}, frames are tonemapped to SDR, {:?}",
+            image_color_space, tonemap
+        );
+    } else if is_hdr_color_space(image_color_space) {
+        warn!(
+            "HDR swapchain {:?}, frames are exported without tonemapping",
+            image_color_space
+        );
+    }
 
-    (ly_device.khr_swapchain.fp().create_swapchain_khr)(
+    let mut res = (ly_device.khr_swapchain.fp().create_swapchain_khr)(
         device,
         &create_info,
         p_allocator,
         p_swapchain,
-    )
-    .result()?;
+    );
+    if res != vk::Result::SUCCESS && create_info.image_usage != app_usage {
+        warn!("failed to create swapchain with TRANSFER_SRC: {:?}", res);
+        create_info.image_usage = app_usage;
+        res = (ly_device.khr_swapchain.fp().create_swapchain_khr)(
+            device,
+            &create_info,
+            p_allocator,
+            p_swapchain,
+        );
+    }
+    res.result()?;
+    let mut capturable = create_info
+        .image_usage
+        .contains(vk::ImageUsageFlags::TRANSFER_SRC);
+    // GL frames rendered through Zink would otherwise be captured twice
+    if ly_instance.is_zink && me_eh5_pw_capture_gl_layer_loaded() {
+        info!("Zink swapchain, captured by GL layer instead");
+        capturable = false;
+    }
+    let encoding = swapchain_encoding(CONFIG.encoding, ly_instance.is_zink);
+    if encoding != CONFIG.encoding {
+        info!(
+            "Zink swapchain {:?}, frames keep their encoding",
+            image_format
+        );
+    }
+    // images are presented from any device of the mask passed to acquire and present, which may
+    // differ per frame, copies on the device of the first queue could read stale or foreign memory
+    if ly_device.device_group_size > 1 {
+        info!("device group swapchain, capture disabled");
+        capturable = false;
+    }
     let swapchain = *p_swapchain;
     debug!(
         "created: {:?}, old: {:?}",
@@ -1311,33 +3684,46 @@ unsafe fn create_swapchain_khr(
 
     let image_datas = DashMap::new();
 
-    let stream = if let Some(valid) = &ly_instance.valid {
-        if ly_device.valid.is_some() {
+    let stream_deadline = if ly_instance.valid.is_some() {
+        if let Some(valid) = ly_device.valid.as_ref().filter(|_| capturable) {
+            let khr_timeline = valid.khr_timeline.as_ref();
             for &image in images.iter() {
                 let semaphore_info = vk::SemaphoreCreateInfo::builder();
                 let semaphore = ly_device
                     .ash_device
                     .create_semaphore(&semaphore_info, None)?;
+                let watermark = CONFIG.watermark.as_ref().and_then(|_| {
+                    WatermarkOverlay::new(
+                        &ly_instance.ash_instance,
+                        &ly_device.ash_device,
+                        ly_device.phy_device,
+                        CONFIG.watermark_corner,
+                    )
+                    .map_err(|e| error!("failed to create watermark: {e:?}"))
+                    .ok()
+                });
                 let data = ImageData {
                     semaphores: vec![semaphore],
-                    fence: FenceState::new(&ly_device.ash_device)?,
+                    sync: CopySync::new(&ly_device.ash_device, khr_timeline)?,
                     seq: 0,
+                    watermark,
+                    frame_semaphore: None,
+                    ownership_transfers: vec![],
+                    sync_file_semaphore: None,
+                    timeline_bridge: None,
+                    acquire_bridge: None,
                 };
 
                 image_datas.insert(image, data);
             }
 
-            create_stream(
-                &valid.khr_phy_props2,
-                ly_device.phy_device,
-                device,
-                swapchain,
-                image_format,
-                image_extent.width,
-                image_extent.height,
-            )
-            .map_err(|e| error!("failed to create stream: {e:?}"))
-            .ok()
+            // apps recreate swapchains many times per second while resizing, don't create a
+            // node for each of them
+            if create_info.old_swapchain == vk::SwapchainKHR::null() {
+                Some(Instant::now())
+            } else {
+                Some(Instant::now() + STREAM_DEBOUNCE)
+            }
         } else {
             None
         }
@@ -1345,25 +3731,219 @@ unsafe fn create_swapchain_khr(
         None
     };
 
+    // images acquired from a retired swapchain may still be presented and captured, but it's
+    // about to be destroyed, so it doesn't get a node if it had none yet
+    if let Some(mut old) = SWAPCHAIN_MAP.get_mut(&create_info.old_swapchain) {
+        old.stream_deadline = None;
+        old.preview_deadline = None;
+        #[cfg(feature = "v4l2")]
+        {
+            old.v4l2_deadline = None;
+        }
+    }
+    let capturing = stream_deadline.is_some();
+
+    let crop = swapchain_crop(image_extent);
+    let stream_extent = swapchain_stream_extent(&ly_device, crop.extent);
+    let title = surface_title(create_info.surface);
+    let generation = SWAPCHAIN_GENERATION.fetch_add(1, atomic::Ordering::Relaxed);
+
     SWAPCHAIN_MAP.insert(
         swapchain,
         LayerSwapchain {
+            generation,
             device,
             surface: create_info.surface,
             format: image_format,
             extent: image_extent,
+            crop,
+            stream_extent,
+            composition: None,
             images,
+            sharing_mode: image_sharing_mode,
+            queue_family_indices,
+            render_family: AtomicU32::new(vk::QUEUE_FAMILY_IGNORED),
+            render_queue: AtomicU64::new(0),
+            unpresented: Mutex::new(None),
+            presented: AtomicBool::new(false),
             export_data: None,
             image_datas,
-            stream,
+            stream: OnceCell::new(),
+            stream_deadline,
+            stream_target: Arc::new(StreamTarget::new(swapchain, generation)),
+            alpha_mode: composite_alpha_to_alpha_mode(composite_alpha),
+            transform: pre_transform_to_video_transform(pre_transform),
+            colorimetry: color_space_to_colorimetry(image_color_space),
+            tonemap,
+            encoding,
+            hdr_metadata: None,
+            title,
             export_images: DashMap::new(),
-            cursor_serial: AtomicU64::new(0),
+            cursor_serial: CursorSerial::default(),
+            capture_requested: AtomicBool::new(false),
+            capture_enabled: AtomicBool::new(true),
+            surface_lost: AtomicBool::new(false),
+            out_of_date: AtomicBool::new(false),
+            present_count: AtomicU64::new(0),
+            frame_rate: Mutex::new(FrameRate::new(FRAME_RATE_WINDOW)),
+            pacer: (CONFIG.target_fps > 0).then(|| Mutex::new(Pacer::new(CONFIG.target_fps))),
+            negotiation: Mutex::new(Negotiation::default()),
+            frame_callback: Mutex::new(None),
+            content_region: Mutex::new(None),
+            damage: Mutex::new(None),
+            poll_readback: OnceCell::new(),
+            polled_frames: Mutex::new(PolledFrames::default()),
+            preview: OnceCell::new(),
+            preview_deadline: stream_deadline
+                .filter(|_| CONFIG.preview.is_some() && !CONFIG.metadata_only),
+            #[cfg(feature = "v4l2")]
+            v4l2_sink: OnceCell::new(),
+            #[cfg(feature = "v4l2")]
+            v4l2_deadline: stream_deadline.filter(|_| CONFIG.v4l2_device.is_some()),
         },
     );
 
+    // apps recreate swapchains on every resize, consumers would lose the node each time
+    let old_swapchain = create_info.old_swapchain;
+    if capturing && old_swapchain != vk::SwapchainKHR::null() {
+        let _ = transfer_swapchain_stream(&ly_device.ash_device, old_swapchain, swapchain)
+            .map_err(|e| error!("failed to keep stream of {:?}: {e:?}", old_swapchain));
+    }
+
     Ok(())
 }
 
+/// Moves the stream of the retired `old` swapchain to `new` if frames keep their format, only
+/// the size is renegotiated. Returns whether it was moved, `new` gets a node of its own if not.
+#[named]
+unsafe fn transfer_swapchain_stream(
+    ash_device: &ash::Device,
+    old: vk::SwapchainKHR,
+    new: vk::SwapchainKHR,
+) -> Result<bool> {
+    let (generation, format, alpha_mode, transform, colorimetry, tonemap, title) = {
+        let ly_swapchain = SWAPCHAIN_MAP
+            .get(&new)
+            .ok_or(anyhow!("unknown swapchain {:?}", new))?;
+        (
+            ly_swapchain.generation,
+            ly_swapchain.format,
+            ly_swapchain.alpha_mode,
+            ly_swapchain.transform,
+            ly_swapchain.colorimetry,
+            ly_swapchain.tonemap,
+            ly_swapchain.title.clone(),
+        )
+    };
+    let (stream_extent, scaled, image_count) = {
+        let ly_swapchain = get_swapchain(new, generation)?;
+        (
+            ly_swapchain.stream_extent,
+            ly_swapchain.stream_extent != ly_swapchain.crop.extent,
+            ly_swapchain.images.len(),
+        )
+    };
+
+    let Some(mut ly_old) = SWAPCHAIN_MAP.get_mut(&old) else {
+        return Ok(false);
+    };
+    // streams of a lost connection are replaced
+    if !matches!(ly_old.stream.get(), Some(Some(v)) if !v.is_errored()) {
+        return Ok(false);
+    }
+    let old_layout = (
+        ly_old.format,
+        ly_old.alpha_mode,
+        ly_old.transform,
+        ly_old.colorimetry,
+        ly_old.tonemap,
+    );
+    // the swapchains composed with it are recreated too, composed anew once presented together
+    if ly_old.composition.is_some() {
+        info!("{:?} recreated, stream of its composition is replaced", old);
+        return Ok(false);
+    }
+    if old_layout != (format, alpha_mode, transform, colorimetry, tonemap) {
+        info!(
+            "{:?} recreated with {:?} {:?} {:?} {:?}, stream is replaced",
+            old, format, alpha_mode, transform, colorimetry
+        );
+        return Ok(false);
+    }
+    // copies of the last presents may still write export images
+    for mut data in ly_old.image_datas.iter_mut() {
+        data.sync.wait_and_reset(ash_device)?;
+    }
+    let Some(stream) = ly_old.stream.take().flatten() else {
+        return Ok(false);
+    };
+    let export_images = mem::take(&mut ly_old.export_images);
+    let export_data = ly_old.export_data.take();
+    let old_extent = ly_old.stream_extent;
+    let offered = ly_old
+        .negotiation
+        .lock()
+        .map(|negotiation| negotiation.offered.clone())
+        .unwrap_or_default();
+    let enabled = ly_old.capture_enabled.load(atomic::Ordering::Relaxed);
+    let target = ly_old.stream_target.clone();
+    let title_changed = ly_old.title != title;
+    drop(ly_old);
+
+    // frames of the same size keep the fixation and its buffers, otherwise command buffers are
+    // set up again once the consumer fixates the new size, until then presents aren't captured
+    let export_data = export_data.and_then(|data| {
+        let reusable = fixation_reusable(
+            old_extent,
+            stream_extent,
+            data.command_buffers.len(),
+            image_count,
+            data.need_blit,
+            scaled,
+        );
+        if reusable {
+            return Some(data);
+        }
+        destroy_export_data(ash_device, data);
+        None
+    });
+    let renegotiate = export_data.is_none();
+
+    let mut ly_swapchain = get_swapchain_mut(new, generation)?;
+    // buffers of the old size are removed with the renegotiation, freed from the new swapchain
+    ly_swapchain.export_images = export_images;
+    ly_swapchain.export_data = export_data;
+    ly_swapchain.stream_deadline = None;
+    ly_swapchain
+        .capture_enabled
+        .store(enabled, atomic::Ordering::Relaxed);
+    if let Ok(mut negotiation) = ly_swapchain.negotiation.lock() {
+        *negotiation = Negotiation {
+            offered,
+            ..Default::default()
+        };
+    }
+    if ly_swapchain.stream.set(Some(stream)).is_err() {
+        return Err(anyhow!("stream of {:?} already created", new));
+    }
+    target.set(new, generation);
+    ly_swapchain.stream_target = target;
+    let vk::Extent2D { width, height } = ly_swapchain.stream_extent;
+    let proxy = stream_proxy(ly_swapchain).ok_or(anyhow!("stream of {:?} gone", new))?;
+
+    if renegotiate {
+        proxy.try_resize(width, height)???;
+    }
+    if title_changed {
+        proxy.try_set_title(title)???;
+    }
+    info!(
+        "stream of {:?} moved to {:?}, {}x{}",
+        old, new, width, height
+    );
+    Ok(true)
+}
+
 #[no_mangle]
 #[named]
 unsafe extern "system" fn pwcap_vkCreateSwapchainKHR(
@@ -1390,32 +3970,88 @@ unsafe fn destroy_swapchain_khr(
     debug!("destroying: {:?}", swapchain);
 
     if let Some(ly_swapchain) = SWAPCHAIN_MAP.get(&swapchain) {
-        if let Some(stream) = &ly_swapchain.stream {
-            let stream = stream.proxy();
-            drop(ly_swapchain);
+        // copies of the last presents, also of a retired swapchain, may still write export
+        // images, which are freed with the stream
+        if let Some(ly_device) = DEVICE_MAP.get(&device) {
+            for mut data in ly_swapchain.image_datas.iter_mut() {
+                let _ = data
+                    .sync
+                    .wait_and_reset(&ly_device.ash_device)
+                    .map_err(|e| error!("failed to wait for copy: {e:?}"));
+            }
+        }
+        let preview = preview_proxy(&ly_swapchain);
+        if let Some(stream) = stream_proxy(ly_swapchain) {
             let _ = stream.try_terminate().map_err(|e| map_err!(e));
         }
+        if let Some(preview) = preview {
+            let _ = preview.try_terminate().map_err(|e| map_err!(e));
+        }
     }
     let ly_swapchain = SWAPCHAIN_MAP.remove(&swapchain);
+    // copies of its images into the frame of a composition it's part of, too
+    if let Some(ly_device) = DEVICE_MAP.get(&device) {
+        for composing in SWAPCHAIN_MAP.iter().filter(|ly_swapchain| {
+            ly_swapchain
+                .composition
+                .as_ref()
+                .map_or(false, |v| v.parts.iter().any(|part| part.0 == swapchain))
+        }) {
+            for mut data in composing.image_datas.iter_mut() {
+                let _ = data
+                    .sync
+                    .wait_and_reset(&ly_device.ash_device)
+                    .map_err(|e| error!("failed to wait for copy: {e:?}"));
+            }
+        }
+    }
 
-    let ly_device = DEVICE_MAP
-        .get(&device)
-        .ok_or(vk::Result::ERROR_DEVICE_LOST)?;
+    let Some(ly_device) = DEVICE_MAP.get(&device) else {
+        // layer resources of the swapchain are gone with the device, the swapchain isn't
+        destroy_swapchain_khr_next(device, swapchain, p_allocator);
+        return Err(anyhow!("unknown device {:?} of {:?}", device, swapchain));
+    };
 
     if let Some((_, ly_swapchain)) = ly_swapchain {
         for image_data in &ly_swapchain.image_datas {
-            image_data.fence.destroy(&ly_device.ash_device);
+            image_data.sync.destroy(&ly_device.ash_device);
             for &s in &image_data.semaphores {
                 ly_device.ash_device.destroy_semaphore(s, None);
             }
-        }
+            if let Some(s) = image_data.frame_semaphore {
+                ly_device.ash_device.destroy_semaphore(s, None);
+            }
+            for transfer in &image_data.ownership_transfers {
+                transfer.destroy(&ly_device.ash_device);
+            }
+            if let Some(s) = image_data.sync_file_semaphore {
+                ly_device.ash_device.destroy_semaphore(s, None);
+            }
+            if let Some(s) = image_data.timeline_bridge {
+                ly_device.ash_device.destroy_semaphore(s, None);
+            }
+            if let Some(s) = image_data.acquire_bridge {
+                ly_device.ash_device.destroy_semaphore(s, None);
+            }
+            if let Some(watermark) = &image_data.watermark {
+                watermark.destroy(&ly_device.ash_device);
+            }
+        }
+        for (_, export_image) in ly_swapchain.export_images {
+            destroy_export_image(&ly_device.ash_device, export_image);
+        }
         if let Some(export_data) = ly_swapchain.export_data {
-            ly_device
-                .ash_device
-                .free_command_buffers(export_data.command_pool, &export_data.command_buffers);
-            ly_device
-                .ash_device
-                .destroy_command_pool(export_data.command_pool, None);
+            destroy_export_data(&ly_device.ash_device, export_data);
+        }
+        if let Some(Some(readback)) = ly_swapchain.poll_readback.get() {
+            readback.destroy(&ly_device.ash_device);
+        }
+        if let Some(Some(preview)) = ly_swapchain.preview.get() {
+            preview.destroy(&ly_device.ash_device);
+        }
+        #[cfg(feature = "v4l2")]
+        if let Some(Some(sink)) = ly_swapchain.v4l2_sink.get() {
+            sink.destroy(&ly_device.ash_device);
         }
     }
 
@@ -1423,6 +4059,22 @@ unsafe fn destroy_swapchain_khr(
     Ok(())
 }
 
+/// Destroys `swapchain` with the next layer or driver, looked up without layer device state.
+#[named]
+unsafe fn destroy_swapchain_khr_next(
+    device: vk::Device,
+    swapchain: vk::SwapchainKHR,
+    p_allocator: *const vk::AllocationCallbacks,
+) {
+    let name = CStr::from_bytes_with_nul_unchecked(b"vkDestroySwapchainKHR\0");
+    let Some(pfn) = dispatch_next_vkGetDeviceProcAddr(device, name.as_ptr()) else {
+        error!("no vkDestroySwapchainKHR, {:?} leaked", swapchain);
+        return;
+    };
+    let destroy_swapchain: vk::PFN_vkDestroySwapchainKHR = mem::transmute(pfn);
+    destroy_swapchain(device, swapchain, p_allocator);
+}
+
 #[no_mangle]
 #[named]
 unsafe extern "system" fn pwcap_vkDestroySwapchainKHR(
@@ -1434,251 +4086,2378 @@ unsafe extern "system" fn pwcap_vkDestroySwapchainKHR(
 }
 const _: vk::PFN_vkDestroySwapchainKHR = pwcap_vkDestroySwapchainKHR;
 
+unsafe fn set_hdr_metadata_ext(
+    device: vk::Device,
+    swapchain_count: u32,
+    p_swapchains: *const vk::SwapchainKHR,
+    p_metadata: *const vk::HdrMetadataEXT,
+) -> Result<()> {
+    // only intercepted if the app enabled `VK_EXT_hdr_metadata`, not loaded with the device
+    let name = CStr::from_bytes_with_nul_unchecked(b"vkSetHdrMetadataEXT\0");
+    let pfn = dispatch_next_vkGetDeviceProcAddr(device, name.as_ptr())
+        .ok_or(vk::Result::ERROR_EXTENSION_NOT_PRESENT)?;
+    let set_hdr_metadata: vk::PFN_vkSetHdrMetadataEXT = mem::transmute(pfn);
+    set_hdr_metadata(device, swapchain_count, p_swapchains, p_metadata);
+
+    let swapchains = slice::from_raw_parts(p_swapchains, swapchain_count as _);
+    let metadatas = slice::from_raw_parts(p_metadata, swapchain_count as _);
+    for (swapchain, metadata) in swapchains.iter().zip(metadatas) {
+        let metadata = client::HdrMetadata {
+            max_luminance: metadata.max_luminance,
+            min_luminance: metadata.min_luminance,
+            max_content_light_level: metadata.max_content_light_level,
+            max_frame_average_light_level: metadata.max_frame_average_light_level,
+        };
+        let Some(mut ly_swapchain) = SWAPCHAIN_MAP.get_mut(swapchain) else {
+            continue;
+        };
+        ly_swapchain.hdr_metadata = Some(metadata);
+        // it doesn't describe tonemapped frames
+        if ly_swapchain.tonemap.is_some() {
+            continue;
+        }
+        let Some(stream) = stream_proxy(ly_swapchain) else {
+            continue;
+        };
+        stream.try_set_hdr_metadata(metadata)???;
+    }
+    Ok(())
+}
+
+#[no_mangle]
+#[named]
+unsafe extern "system" fn pwcap_vkSetHdrMetadataEXT(
+    device: vk::Device,
+    swapchain_count: u32,
+    p_swapchains: *const vk::SwapchainKHR,
+    p_metadata: *const vk::HdrMetadataEXT,
+) {
+    let _ = map_result!(set_hdr_metadata_ext(
+        device,
+        swapchain_count,
+        p_swapchains,
+        p_metadata
+    ));
+}
+const _: vk::PFN_vkSetHdrMetadataEXT = pwcap_vkSetHdrMetadataEXT;
+
+unsafe fn create_semaphore(
+    device: vk::Device,
+    p_create_info: *const vk::SemaphoreCreateInfo,
+    p_allocator: *const vk::AllocationCallbacks,
+    p_semaphore: *mut vk::Semaphore,
+) -> Result<()> {
+    let ly_device = DEVICE_MAP
+        .get(&device)
+        .ok_or(vk::Result::ERROR_DEVICE_LOST)?;
+    (ly_device.ash_device.fp_v1_0().create_semaphore)(
+        device,
+        p_create_info,
+        p_allocator,
+        p_semaphore,
+    )
+    .result()?;
+
+    let mut timeline = false;
+    let mut initial_value = 0;
+    let mut p_next = (*p_create_info).p_next as *const vk::BaseInStructure;
+    while let Some(next) = p_next.as_ref() {
+        if next.s_type == vk::StructureType::SEMAPHORE_TYPE_CREATE_INFO {
+            let type_info = &*(p_next as *const vk::SemaphoreTypeCreateInfo);
+            timeline = type_info.semaphore_type == vk::SemaphoreType::TIMELINE;
+            initial_value = type_info.initial_value;
+            break;
+        }
+        p_next = next.p_next;
+    }
+    SEMAPHORE_MAP.insert(
+        *p_semaphore,
+        LayerSemaphore {
+            device,
+            timeline,
+            signaled: AtomicU64::new(initial_value),
+            signal_family: AtomicU32::new(vk::QUEUE_FAMILY_IGNORED),
+            acquired_for: AtomicU64::new(0),
+        },
+    );
+    Ok(())
+}
+
+#[no_mangle]
+#[named]
+unsafe extern "system" fn pwcap_vkCreateSemaphore(
+    device: vk::Device,
+    p_create_info: *const vk::SemaphoreCreateInfo,
+    p_allocator: *const vk::AllocationCallbacks,
+    p_semaphore: *mut vk::Semaphore,
+) -> vk::Result {
+    map_result!(create_semaphore(
+        device,
+        p_create_info,
+        p_allocator,
+        p_semaphore
+    ))
+}
+const _: vk::PFN_vkCreateSemaphore = pwcap_vkCreateSemaphore;
+
+unsafe fn destroy_semaphore(
+    device: vk::Device,
+    semaphore: vk::Semaphore,
+    p_allocator: *const vk::AllocationCallbacks,
+) -> Result<()> {
+    SEMAPHORE_MAP.remove(&semaphore);
+    let ly_device = DEVICE_MAP
+        .get(&device)
+        .ok_or(vk::Result::ERROR_DEVICE_LOST)?;
+    (ly_device.ash_device.fp_v1_0().destroy_semaphore)(device, semaphore, p_allocator);
+    Ok(())
+}
+
+#[no_mangle]
+#[named]
+unsafe extern "system" fn pwcap_vkDestroySemaphore(
+    device: vk::Device,
+    semaphore: vk::Semaphore,
+    p_allocator: *const vk::AllocationCallbacks,
+) {
+    let _ = map_result!(destroy_semaphore(device, semaphore, p_allocator));
+}
+const _: vk::PFN_vkDestroySemaphore = pwcap_vkDestroySemaphore;
+
+/// Device of `queue`, also of queues not enumerated at device creation, those share the dispatch
+/// table of their device.
+unsafe fn queue_device(queue: vk::Queue) -> Option<vk::Device> {
+    if let Some(ly_queue) = QUEUE_MAP.get(&queue) {
+        return Some(ly_queue.device);
+    }
+    if let Some(device) = UNKNOWN_QUEUE_MAP.get(&queue) {
+        return Some(*device);
+    }
+    // dispatchable handles point to the loader's dispatch table first
+    let key = *(queue.as_raw() as *const usize);
+    DEVICE_MAP
+        .iter()
+        .map(|v| *v.key())
+        .find(|device| *(device.as_raw() as *const usize) == key)
+}
+
+/// Records what a submit on `queue` means for captures, the queue and family of the swapchains
+/// whose acquires it waits on, the family of the semaphores it signals, and the values timeline
+/// ones are signaled with, see `LayerSemaphore`. The queue is null for host signals.
+fn record_submit(queue: vk::Queue, waits: Vec<vk::Semaphore>, signals: Vec<(vk::Semaphore, u64)>) {
+    let family = queue_family(queue);
+    for semaphore in waits {
+        let Some(ly_semaphore) = SEMAPHORE_MAP.get(&semaphore) else {
+            continue;
+        };
+        let swapchain = ly_semaphore.acquired_for.swap(0, atomic::Ordering::AcqRel);
+        if swapchain == 0 || family == vk::QUEUE_FAMILY_IGNORED {
+            continue;
+        }
+        if let Some(ly_swapchain) = SWAPCHAIN_MAP.get(&vk::SwapchainKHR::from_raw(swapchain)) {
+            ly_swapchain
+                .render_family
+                .store(family, atomic::Ordering::Relaxed);
+            ly_swapchain
+                .render_queue
+                .store(queue.as_raw(), atomic::Ordering::Relaxed);
+        }
+    }
+    for (semaphore, value) in signals {
+        let Some(ly_semaphore) = SEMAPHORE_MAP.get(&semaphore) else {
+            continue;
+        };
+        ly_semaphore
+            .signal_family
+            .store(family, atomic::Ordering::Relaxed);
+        if ly_semaphore.timeline {
+            ly_semaphore
+                .signaled
+                .fetch_max(value, atomic::Ordering::AcqRel);
+        }
+    }
+}
+
+/// family of `queue`, `QUEUE_FAMILY_IGNORED` for queues not enumerated at device creation
+fn queue_family(queue: vk::Queue) -> u32 {
+    QUEUE_MAP
+        .get(&queue)
+        .map_or(vk::QUEUE_FAMILY_IGNORED, |v| v.family_index)
+}
+
+unsafe fn queue_submit(
+    queue: vk::Queue,
+    submit_count: u32,
+    p_submits: *const vk::SubmitInfo,
+    fence: vk::Fence,
+) -> Result<()> {
+    let device = queue_device(queue).ok_or(vk::Result::ERROR_DEVICE_LOST)?;
+    let queue_submit = DEVICE_MAP
+        .get(&device)
+        .map(|v| v.ash_device.fp_v1_0().queue_submit)
+        .ok_or(vk::Result::ERROR_DEVICE_LOST)?;
+    queue_submit(queue, submit_count, p_submits, fence).result()?;
+
+    if !p_submits.is_null() {
+        for submit in slice::from_raw_parts(p_submits, submit_count as _) {
+            record_submit(queue, submit_waits(submit), submit_signals(submit));
+        }
+    }
+    Ok(())
+}
+
+#[no_mangle]
+#[named]
+unsafe extern "system" fn pwcap_vkQueueSubmit(
+    queue: vk::Queue,
+    submit_count: u32,
+    p_submits: *const vk::SubmitInfo,
+    fence: vk::Fence,
+) -> vk::Result {
+    map_result!(queue_submit(queue, submit_count, p_submits, fence))
+}
+const _: vk::PFN_vkQueueSubmit = pwcap_vkQueueSubmit;
+
+unsafe fn queue_submit2(
+    queue: vk::Queue,
+    submit_count: u32,
+    p_submits: *const vk::SubmitInfo2,
+    fence: vk::Fence,
+) -> Result<()> {
+    let device = queue_device(queue).ok_or(vk::Result::ERROR_DEVICE_LOST)?;
+    let queue_submit2 = DEVICE_MAP
+        .get(&device)
+        .and_then(|v| v.queue_submit2)
+        .ok_or(vk::Result::ERROR_EXTENSION_NOT_PRESENT)?;
+    queue_submit2(queue, submit_count, p_submits, fence).result()?;
+
+    if !p_submits.is_null() {
+        for submit in slice::from_raw_parts(p_submits, submit_count as _) {
+            record_submit(queue, submit2_waits(submit), submit2_signals(submit));
+        }
+    }
+    Ok(())
+}
+
+/// also `vkQueueSubmit2KHR`
+#[no_mangle]
+#[named]
+unsafe extern "system" fn pwcap_vkQueueSubmit2(
+    queue: vk::Queue,
+    submit_count: u32,
+    p_submits: *const vk::SubmitInfo2,
+    fence: vk::Fence,
+) -> vk::Result {
+    map_result!(queue_submit2(queue, submit_count, p_submits, fence))
+}
+const _: vk::PFN_vkQueueSubmit2 = pwcap_vkQueueSubmit2;
+
+unsafe fn signal_semaphore(
+    device: vk::Device,
+    p_signal_info: *const vk::SemaphoreSignalInfo,
+) -> Result<()> {
+    let signal_semaphore = DEVICE_MAP
+        .get(&device)
+        .and_then(|v| v.signal_semaphore)
+        .ok_or(vk::Result::ERROR_EXTENSION_NOT_PRESENT)?;
+    signal_semaphore(device, p_signal_info).result()?;
+
+    let signal_info = &*p_signal_info;
+    record_submit(
+        vk::Queue::null(),
+        vec![],
+        vec![(signal_info.semaphore, signal_info.value)],
+    );
+    Ok(())
+}
+
+/// also `vkSignalSemaphoreKHR`
+#[no_mangle]
+#[named]
+unsafe extern "system" fn pwcap_vkSignalSemaphore(
+    device: vk::Device,
+    p_signal_info: *const vk::SemaphoreSignalInfo,
+) -> vk::Result {
+    map_result!(signal_semaphore(device, p_signal_info))
+}
+const _: vk::PFN_vkSignalSemaphore = pwcap_vkSignalSemaphore;
+
+#[named]
+unsafe fn get_device_queue2(
+    device: vk::Device,
+    p_queue_info: *const vk::DeviceQueueInfo2,
+    p_queue: *mut vk::Queue,
+) -> Result<()> {
+    let ly_device = DEVICE_MAP
+        .get(&device)
+        .ok_or(vk::Result::ERROR_DEVICE_LOST)?;
+    (ly_device.ash_device.fp_v1_1().get_device_queue2)(device, p_queue_info, p_queue);
+
+    let queue = *p_queue;
+    if queue == vk::Queue::null() || QUEUE_MAP.contains_key(&queue) {
+        return Ok(());
+    }
+    let ly_instance = INSTANCE_MAP
+        .get(&ly_device.instance)
+        .ok_or(vk::Result::ERROR_DEVICE_LOST)?;
+    let &vk::DeviceQueueInfo2 {
+        flags,
+        queue_family_index: family_index,
+        queue_index: index,
+        ..
+    } = &*p_queue_info;
+    let family_props = ly_instance
+        .ash_instance
+        .get_physical_device_queue_family_properties(ly_device.phy_device)
+        .get(family_index as usize)
+        .copied()
+        .ok_or(anyhow!("invalid queue family {}", family_index))?;
+    debug!(
+        "device queue2, family:{} index:{} flags:{:?} handle:{:?}",
+        family_index, index, flags, queue
+    );
+    // retrieving the same queue again keeps the first entry
+    QUEUE_MAP.entry(queue).or_insert(LayerQueue {
+        device,
+        family_index,
+        family_props,
+        index,
+        flags,
+    });
+    UNKNOWN_QUEUE_MAP.remove(&queue);
+    Ok(())
+}
+
+#[no_mangle]
+#[named]
+unsafe extern "system" fn pwcap_vkGetDeviceQueue2(
+    device: vk::Device,
+    p_queue_info: *const vk::DeviceQueueInfo2,
+    p_queue: *mut vk::Queue,
+) {
+    let _ = map_result!(get_device_queue2(device, p_queue_info, p_queue));
+}
+const _: vk::PFN_vkGetDeviceQueue2 = pwcap_vkGetDeviceQueue2;
+
+#[named]
 unsafe fn queue_present_khr(
     queue: vk::Queue,
     p_present_info: *const vk::PresentInfoKHR,
 ) -> Result<vk::Result> {
-    let ly_queue = QUEUE_MAP.get(&queue).ok_or(vk::Result::ERROR_DEVICE_LOST)?;
+    let Some(ly_queue) = QUEUE_MAP.get(&queue) else {
+        return present_on_unknown_queue(queue, p_present_info);
+    };
     let ly_device = DEVICE_MAP
         .get(&ly_queue.device)
         .ok_or(vk::Result::ERROR_DEVICE_LOST)?;
 
-    let mut present_info = p_present_info.read();
-
-    let _wait_semaphores_new = if ly_device.valid.is_some() {
-        let res = capture(&ly_device.ash_device, ly_queue.family_index, &present_info);
-        if !res.is_empty() {
-            present_info.wait_semaphore_count = res.len() as _;
-            present_info.p_wait_semaphores = res.as_ptr();
+    let present_info = p_present_info.read();
+    if CONFIG.capture_on_acquire {
+        let swapchains =
+            slice::from_raw_parts(present_info.p_swapchains, present_info.swapchain_count as _);
+        for swapchain in swapchains {
+            if let Some(ly_swapchain) = SWAPCHAIN_MAP.get(swapchain) {
+                ly_swapchain
+                    .presented
+                    .store(true, atomic::Ordering::Relaxed);
+            }
         }
-        Some(res)
+    }
+
+    let mut frames = vec![];
+    let wait_semaphores = if let Some(valid) = &ly_device.valid {
+        capture(
+            &ly_device.ash_device,
+            &ly_device.queues,
+            queue,
+            ly_queue.family_index,
+            &present_info,
+            valid.khr_semaphore_fd.as_ref(),
+            &mut frames,
+        )
     } else {
-        None
+        vec![]
     };
+    let mut present_info = present_info_with_waits(&present_info, &wait_semaphores);
+    // results per swapchain tell whose surface was lost, also if the app doesn't ask for them
+    let app_results = present_info.p_results;
+    let mut results = vec![vk::Result::SUCCESS; present_info.swapchain_count as usize];
+    present_info.p_results = results.as_mut_ptr();
 
     let res = (ly_device.khr_swapchain.fp().queue_present_khr)(queue, &present_info);
+    if !app_results.is_null() {
+        ptr::copy_nonoverlapping(results.as_ptr(), app_results, results.len());
+    }
+    let swapchains =
+        slice::from_raw_parts(present_info.p_swapchains, present_info.swapchain_count as _);
+    for (i, &swapchain) in swapchains.iter().enumerate() {
+        match swapchain_present_result(res, &results, i) {
+            vk::Result::ERROR_SURFACE_LOST_KHR => on_surface_lost(swapchain),
+            vk::Result::ERROR_OUT_OF_DATE_KHR => {
+                if let Some(ly_swapchain) = SWAPCHAIN_MAP.get(&swapchain) {
+                    mark_out_of_date(swapchain, &ly_swapchain);
+                }
+            }
+            _ => (),
+        }
+    }
+    // copies already ran as the present still waits on their semaphores when it fails
+    for frame in frames {
+        let Some(i) = swapchains.iter().position(|&v| v == frame.swapchain) else {
+            continue;
+        };
+        let result = swapchain_present_result(res, &results, i);
+        let _ = queue_pending_frame(frame, result).map_err(|e| map_err!(e));
+    }
     match res {
-        vk::Result::SUCCESS | vk::Result::SUBOPTIMAL_KHR => Ok(res),
+        // a resize, handled by the app recreating the swapchain
+        vk::Result::SUCCESS | vk::Result::SUBOPTIMAL_KHR | vk::Result::ERROR_OUT_OF_DATE_KHR => {
+            Ok(res)
+        }
         _ => Err(anyhow!(res)),
     }
 }
 
-unsafe fn ly_swapchain_wait_image(
-    ly_device: &LayerDevice,
-    ly_swapchain: &LayerSwapchain,
-    image_index: usize,
-) -> Result<()> {
-    let image = ly_swapchain.images[image_index];
-    let mut data = ly_swapchain
-        .image_datas
-        .get_mut(&image)
-        .ok_or(anyhow!("image removed"))?;
-    data.fence.wait_and_reset(&ly_device.ash_device)?;
-    Ok(())
+/// Stops capturing `swapchain` until the app replaced it, logged once.
+#[named]
+fn mark_out_of_date(swapchain: vk::SwapchainKHR, ly_swapchain: &LayerSwapchain) {
+    if !ly_swapchain
+        .out_of_date
+        .swap(true, atomic::Ordering::AcqRel)
+    {
+        debug!("{:?} out of date, capture stopped", swapchain);
+    }
 }
 
-unsafe fn acquire_next_image_khr(
-    device: vk::Device,
+/// Frame copied for a present, handed to the stream once the present went through.
+struct PendingFrame {
     swapchain: vk::SwapchainKHR,
-    timeout: u64,
-    semaphore: vk::Semaphore,
-    fence: vk::Fence,
-    p_image_index: *mut u32,
-) -> Result<vk::Result> {
-    let ly_device = DEVICE_MAP
-        .get(&device)
-        .ok_or(vk::Result::ERROR_DEVICE_LOST)?;
-    let ly_swapchain = SWAPCHAIN_MAP
-        .get(&swapchain)
-        .ok_or(vk::Result::ERROR_DEVICE_LOST)?;
+    generation: u64,
+    buffer: client::BufferHandle,
+    /// of the preview stream, read back from the same image
+    preview: bool,
+}
 
-    let res = (ly_device.khr_swapchain.fp().acquire_next_image_khr)(
-        device,
-        swapchain,
-        timeout,
-        semaphore,
-        fence,
-        p_image_index,
-    );
-    match res {
-        vk::Result::SUCCESS | vk::Result::SUBOPTIMAL_KHR => (),
-        _ => return Err(anyhow!(res)),
+/// Queues the buffer of `frame` to be processed if its present succeeded, otherwise gives it
+/// back unprocessed, as the frame was never shown, e.g. out of date on a resize.
+#[named]
+unsafe fn queue_pending_frame(frame: PendingFrame, result: vk::Result) -> Result<()> {
+    let ly_swapchain = get_swapchain(frame.swapchain, frame.generation)?;
+    let stream = if frame.preview {
+        ly_swapchain
+            .preview
+            .get()
+            .and_then(Option::as_ref)
+            .map(|v| &v.stream)
+    } else {
+        ly_swapchain.stream.get().and_then(Option::as_ref)
+    };
+    let Some(stream) = stream else {
+        return Err(anyhow!("stream of {:?} gone", frame.swapchain));
+    };
+    let (stats, stream) = (stream.stats(), stream.proxy());
+    drop(ly_swapchain);
+    if result.as_raw() < 0 {
+        debug!(
+            "present of {:?} failed with {:?}, frame dropped",
+            frame.swapchain, result
+        );
+        stream.try_cancel_buffer(frame.buffer)??;
+        return Ok(());
+    }
+    // disabled while the present was captured
+    if !CAPTURE_ENABLED.load(atomic::Ordering::Relaxed) {
+        stream.try_cancel_buffer(frame.buffer)??;
+        return Ok(());
+    }
+
+    let start = Instant::now();
+    stream.try_queue_buffer_process(frame.buffer)???;
+    let duration = start.elapsed();
+    stats.set_process_time(duration);
+    trace!("process time: {:?}", duration);
+    Ok(())
+}
+
+/// Stops capturing `swapchain` and drops the cursor managers of its surface, whose window is
+/// gone. The stream stays paused until the app destroys the swapchain.
+#[named]
+fn on_surface_lost(swapchain: vk::SwapchainKHR) {
+    let Some(ly_swapchain) = SWAPCHAIN_MAP.get(&swapchain) else {
+        return;
+    };
+    if ly_swapchain
+        .surface_lost
+        .swap(true, atomic::Ordering::AcqRel)
+    {
+        return;
+    }
+    let surface = ly_swapchain.surface;
+    let preview = preview_proxy(&ly_swapchain);
+    let stream = stream_proxy(ly_swapchain);
+    warn!(
+        "surface {:?} of {:?} lost, capture stopped",
+        surface, swapchain
+    );
+
+    if let Some(stream) = stream {
+        let _ = stream.try_pause().map_err(|e| map_err!(e));
+    }
+    if let Some(preview) = preview {
+        let _ = preview.try_pause().map_err(|e| map_err!(e));
+    }
+    if let Some(mut ly_surface) = SURFACE_MAP.get_mut(&surface) {
+        ly_surface.cursor_manager = None;
+        if ly_surface.wl_cursor_manager > 0 {
+            unsafe { me_eh5_pw_capture_release_wl_cursor_manager(ly_surface.wl_cursor_manager) };
+            ly_surface.wl_cursor_manager = 0;
+        }
+    }
+}
+
+/// Presents without capturing, the device is looked up through the presented swapchains
+#[named]
+unsafe fn present_on_unknown_queue(
+    queue: vk::Queue,
+    p_present_info: *const vk::PresentInfoKHR,
+) -> Result<vk::Result> {
+    let device = match UNKNOWN_QUEUE_MAP.get(&queue) {
+        Some(v) => *v,
+        None => {
+            let present_info = &*p_present_info;
+            let swapchains =
+                slice::from_raw_parts(present_info.p_swapchains, present_info.swapchain_count as _);
+            let device = swapchains
+                .iter()
+                .find_map(|swapchain| SWAPCHAIN_MAP.get(swapchain).map(|v| v.device))
+                .ok_or(vk::Result::ERROR_DEVICE_LOST)?;
+            warn!("present on unknown queue {:?}, capture skipped", queue);
+            UNKNOWN_QUEUE_MAP.insert(queue, device);
+            device
+        }
+    };
+    let ly_device = DEVICE_MAP
+        .get(&device)
+        .ok_or(vk::Result::ERROR_DEVICE_LOST)?;
+
+    let res = (ly_device.khr_swapchain.fp().queue_present_khr)(queue, p_present_info);
+    match res {
+        vk::Result::SUCCESS | vk::Result::SUBOPTIMAL_KHR | vk::Result::ERROR_OUT_OF_DATE_KHR => {
+            Ok(res)
+        }
+        _ => Err(anyhow!(res)),
+    }
+}
+
+/// Waits for the last capture copy of the acquired image. A non-blocking acquire with a
+/// `timeout` of 0 must not block on it, a pending copy is left to the next copy of the image,
+/// which waits for it anyway.
+unsafe fn ly_swapchain_wait_image(
+    ly_device: &LayerDevice,
+    ly_swapchain: &LayerSwapchain,
+    image_index: usize,
+    timeout: u64,
+) -> Result<()> {
+    let image = ly_swapchain.images[image_index];
+    let mut data = ly_swapchain
+        .image_datas
+        .get_mut(&image)
+        .ok_or(anyhow!("image removed"))?;
+    if timeout == 0 && data.sync.is_pending(&ly_device.ash_device)? {
+        return Ok(());
+    }
+    data.sync.wait_and_reset(&ly_device.ash_device)?;
+    Ok(())
+}
+
+/// Marks `semaphore` as signaled by an acquire of `swapchain`, the submit waiting on it renders
+/// the image, see `record_submit`.
+fn record_acquire(swapchain: vk::SwapchainKHR, semaphore: vk::Semaphore) {
+    if let Some(ly_semaphore) = SEMAPHORE_MAP.get(&semaphore) {
+        ly_semaphore
+            .acquired_for
+            .store(swapchain.as_raw(), atomic::Ordering::Release);
+    }
+}
+
+/// With `PW_CAPTURE_ON_ACQUIRE`, captures image `image_index` of `swapchain`, acquired earlier
+/// and never presented, as if it was presented now. Nothing tells when the app finished with it,
+/// so the copy is submitted to the queue of the last submit rendering the swapchain, after a
+/// signal ordering it after all work submitted there so far, and the semaphores a present would
+/// wait on are waited on right away.
+#[named]
+unsafe fn capture_acquired(
+    ly_device: &LayerDevice,
+    swapchain: vk::SwapchainKHR,
+    image_index: u32,
+) -> Result<()> {
+    let Some(valid) = &ly_device.valid else {
+        return Ok(());
+    };
+    let ash_device = &ly_device.ash_device;
+    let (queue, bridge) = {
+        let ly_swapchain = SWAPCHAIN_MAP
+            .get(&swapchain)
+            .ok_or(vk::Result::ERROR_UNKNOWN)?;
+        let queue = vk::Queue::from_raw(ly_swapchain.render_queue.load(atomic::Ordering::Relaxed));
+        let image = *ly_swapchain
+            .images
+            .get(image_index as usize)
+            .ok_or(anyhow!("invalid image index {}", image_index))?;
+        let mut data = ly_swapchain
+            .image_datas
+            .get_mut(&image)
+            .ok_or(anyhow!("{:?} isn't captured", swapchain))?;
+        let bridge = match data.acquire_bridge {
+            Some(v) => v,
+            None => {
+                let semaphore_info = vk::SemaphoreCreateInfo::builder();
+                let semaphore = ash_device.create_semaphore(&semaphore_info, None)?;
+                *data.acquire_bridge.insert(semaphore)
+            }
+        };
+        (queue, bridge)
+    };
+    // acquires the app's submits don't wait on leave the queue unknown
+    if queue == vk::Queue::null() {
+        trace!("no submit rendering {:?} seen, capture skipped", swapchain);
+        return Ok(());
+    }
+
+    let signals = [bridge];
+    let signal_info = vk::SubmitInfo::builder().signal_semaphores(&signals);
+    ash_device.queue_submit(queue, &[signal_info.build()], vk::Fence::null())?;
+
+    let swapchains = [swapchain];
+    let image_indices = [image_index];
+    let present_info = vk::PresentInfoKHR::builder()
+        .wait_semaphores(&signals)
+        .swapchains(&swapchains)
+        .image_indices(&image_indices);
+    let mut frames = vec![];
+    let mut waits = capture(
+        ash_device,
+        &ly_device.queues,
+        queue,
+        queue_family(queue),
+        &present_info,
+        valid.khr_semaphore_fd.as_ref(),
+        &mut frames,
+    );
+    // no present consumes the signals, a skipped capture leaves the bridge signaled
+    if waits.is_empty() {
+        waits.push(bridge);
+    }
+    let wait_stages = vec![vk::PipelineStageFlags::ALL_COMMANDS; waits.len()];
+    let wait_info = vk::SubmitInfo::builder()
+        .wait_semaphores(&waits)
+        .wait_dst_stage_mask(&wait_stages);
+    ash_device.queue_submit(queue, &[wait_info.build()], vk::Fence::null())?;
+
+    for frame in frames {
+        let _ = queue_pending_frame(frame, vk::Result::SUCCESS).map_err(|e| map_err!(e));
+    }
+    Ok(())
+}
+
+/// Image to capture with `PW_CAPTURE_ON_ACQUIRE` once `image_index` of `ly_swapchain` was
+/// acquired, the one acquired before if the swapchain was never presented.
+fn acquired_unpresented(ly_swapchain: &LayerSwapchain, image_index: u32) -> Option<u32> {
+    if !CONFIG.capture_on_acquire || ly_swapchain.presented.load(atomic::Ordering::Relaxed) {
+        return None;
+    }
+    ly_swapchain
+        .unpresented
+        .lock()
+        .ok()
+        .and_then(|mut v| v.replace(image_index))
+}
+
+#[named]
+unsafe fn acquire_next_image_khr(
+    device: vk::Device,
+    swapchain: vk::SwapchainKHR,
+    timeout: u64,
+    semaphore: vk::Semaphore,
+    fence: vk::Fence,
+    p_image_index: *mut u32,
+) -> Result<vk::Result> {
+    let ly_device = DEVICE_MAP
+        .get(&device)
+        .ok_or(vk::Result::ERROR_DEVICE_LOST)?;
+    let ly_swapchain = SWAPCHAIN_MAP
+        .get(&swapchain)
+        .ok_or(vk::Result::ERROR_DEVICE_LOST)?;
+
+    let res = (ly_device.khr_swapchain.fp().acquire_next_image_khr)(
+        device,
+        swapchain,
+        timeout,
+        semaphore,
+        fence,
+        p_image_index,
+    );
+    match res {
+        vk::Result::SUCCESS | vk::Result::SUBOPTIMAL_KHR => (),
+        vk::Result::NOT_READY | vk::Result::TIMEOUT => return Ok(res),
+        // no image was acquired, the app recreates the swapchain
+        vk::Result::ERROR_OUT_OF_DATE_KHR => {
+            mark_out_of_date(swapchain, &ly_swapchain);
+            return Ok(res);
+        }
+        _ => return Err(anyhow!(res)),
+    };
+    record_acquire(swapchain, semaphore);
+
+    if ly_device.valid.is_some() {
+        ly_swapchain_wait_image(&ly_device, &ly_swapchain, *p_image_index as _, timeout)?;
+    }
+    let unpresented = acquired_unpresented(&ly_swapchain, *p_image_index);
+    drop(ly_swapchain);
+    if let Some(image_index) = unpresented {
+        let _ = capture_acquired(&ly_device, swapchain, image_index)
+            .map_err(|e| error!("failed to capture acquired image: {e:?}"));
+    }
+    Ok(res)
+}
+
+#[named]
+unsafe fn acquire_next_image2_khr(
+    device: vk::Device,
+    p_acquire_info: *const vk::AcquireNextImageInfoKHR,
+    p_image_index: *mut u32,
+) -> Result<vk::Result> {
+    let acquire_info = p_acquire_info.read();
+    let ly_device = DEVICE_MAP
+        .get(&device)
+        .ok_or(vk::Result::ERROR_DEVICE_LOST)?;
+    let ly_swapchain = SWAPCHAIN_MAP
+        .get(&acquire_info.swapchain)
+        .ok_or(vk::Result::ERROR_DEVICE_LOST)?;
+
+    // `device_mask` only selects devices of a group, swapchains of device groups aren't captured
+    let res = (ly_device.khr_swapchain.fp().acquire_next_image2_khr)(
+        device,
+        p_acquire_info,
+        p_image_index,
+    );
+    match res {
+        vk::Result::SUCCESS | vk::Result::SUBOPTIMAL_KHR => (),
+        vk::Result::NOT_READY | vk::Result::TIMEOUT => return Ok(res),
+        // no image was acquired, the app recreates the swapchain
+        vk::Result::ERROR_OUT_OF_DATE_KHR => {
+            mark_out_of_date(acquire_info.swapchain, &ly_swapchain);
+            return Ok(res);
+        }
+        _ => return Err(anyhow!(res)),
+    };
+    record_acquire(acquire_info.swapchain, acquire_info.semaphore);
+
+    if ly_device.valid.is_some() {
+        ly_swapchain_wait_image(
+            &ly_device,
+            &ly_swapchain,
+            *p_image_index as _,
+            acquire_info.timeout,
+        )?;
+    }
+    let unpresented = acquired_unpresented(&ly_swapchain, *p_image_index);
+    drop(ly_swapchain);
+    if let Some(image_index) = unpresented {
+        let _ = capture_acquired(&ly_device, acquire_info.swapchain, image_index)
+            .map_err(|e| error!("failed to capture acquired image: {e:?}"));
+    }
+    Ok(res)
+}
+
+#[no_mangle]
+#[named]
+unsafe extern "system" fn pwcap_vkAcquireNextImageKHR(
+    device: vk::Device,
+    swapchain: vk::SwapchainKHR,
+    timeout: u64,
+    semaphore: vk::Semaphore,
+    fence: vk::Fence,
+    p_image_index: *mut u32,
+) -> vk::Result {
+    acquire_next_image_khr(device, swapchain, timeout, semaphore, fence, p_image_index)
+        .unwrap_or_else(|e| map_err!(e))
+}
+const _: vk::PFN_vkAcquireNextImageKHR = pwcap_vkAcquireNextImageKHR;
+
+#[no_mangle]
+#[named]
+unsafe extern "system" fn pwcap_vkAcquireNextImage2KHR(
+    device: vk::Device,
+    p_acquire_info: *const vk::AcquireNextImageInfoKHR,
+    p_image_index: *mut u32,
+) -> vk::Result {
+    acquire_next_image2_khr(device, p_acquire_info, p_image_index).unwrap_or_else(|e| map_err!(e))
+}
+const _: vk::PFN_vkAcquireNextImage2KHR = pwcap_vkAcquireNextImage2KHR;
+
+#[no_mangle]
+#[named]
+unsafe extern "system" fn pwcap_vkQueuePresentKHR(
+    queue: vk::Queue,
+    p_present_info: *const vk::PresentInfoKHR,
+) -> vk::Result {
+    queue_present_khr(queue, p_present_info).unwrap_or_else(|e| map_err!(e))
+}
+const _: vk::PFN_vkQueuePresentKHR = pwcap_vkQueuePresentKHR;
+
+/// Queue of `queues` and its family owning the presented image of `swapchain`, see
+/// `select_source_family`. The present queue if that's its family or no queue of the family is
+/// known.
+#[named]
+fn source_queue(
+    queues: &[vk::Queue],
+    swapchain: vk::SwapchainKHR,
+    present_queue: vk::Queue,
+    present_family: u32,
+    wait_families: &[u32],
+) -> (vk::Queue, u32) {
+    let render_family = SWAPCHAIN_MAP
+        .get(&swapchain)
+        .map_or(vk::QUEUE_FAMILY_IGNORED, |v| {
+            v.render_family.load(atomic::Ordering::Relaxed)
+        });
+    let family = select_source_family(present_family, wait_families, render_family);
+    if family == present_family {
+        return (present_queue, present_family);
+    }
+    let queue = queues
+        .iter()
+        .copied()
+        .find(|queue| queue_family(*queue) == family);
+    match queue {
+        Some(queue) => {
+            frame_trace(|| format!("{:?} presented as owned by family {}", swapchain, family));
+            (queue, family)
+        }
+        None => {
+            debug!("no queue of family {} owning {:?}", family, swapchain);
+            (present_queue, present_family)
+        }
+    }
+}
+
+/// Submits a wait on `waits` to `queue`, with the last values timeline semaphores among them were
+/// signaled with, and returns a binary semaphore of the presented image signaled after them.
+unsafe fn bridge_timeline_waits(
+    ash_device: &ash::Device,
+    queue: vk::Queue,
+    swapchain: vk::SwapchainKHR,
+    image_index: usize,
+    waits: &[vk::Semaphore],
+) -> Result<vk::Semaphore> {
+    let values = waits
+        .iter()
+        .map(|semaphore| {
+            SEMAPHORE_MAP
+                .get(semaphore)
+                .filter(|v| v.timeline)
+                .map_or(0, |v| v.signaled.load(atomic::Ordering::Acquire))
+        })
+        .collect::<Vec<_>>();
+
+    let ly_swapchain = SWAPCHAIN_MAP
+        .get(&swapchain)
+        .ok_or(vk::Result::ERROR_UNKNOWN)?;
+    let image = *ly_swapchain
+        .images
+        .get(image_index)
+        .ok_or(anyhow!("invalid image index {}", image_index))?;
+    let mut data = ly_swapchain
+        .image_datas
+        .get_mut(&image)
+        .ok_or(anyhow!("{:?} isn't captured", swapchain))?;
+    let semaphore = match data.timeline_bridge {
+        Some(v) => v,
+        None => {
+            let semaphore_info = vk::SemaphoreCreateInfo::builder();
+            let semaphore = ash_device.create_semaphore(&semaphore_info, None)?;
+            *data.timeline_bridge.insert(semaphore)
+        }
+    };
+
+    let wait_stages = vec![vk::PipelineStageFlags::ALL_COMMANDS; waits.len()];
+    let mut timeline_info =
+        vk::TimelineSemaphoreSubmitInfo::builder().wait_semaphore_values(&values);
+    let submit_info = vk::SubmitInfo::builder()
+        .wait_semaphores(waits)
+        .wait_dst_stage_mask(&wait_stages)
+        .signal_semaphores(slice::from_ref(&semaphore))
+        .push_next(&mut timeline_info);
+    ash_device.queue_submit(queue, &[submit_info.build()], vk::Fence::null())?;
+    Ok(semaphore)
+}
+
+#[allow(clippy::too_many_arguments)]
+unsafe fn create_swapchain_stream(
+    device: vk::Device,
+    target: Arc<StreamTarget>,
+    format: vk::Format,
+    extent: vk::Extent2D,
+    alpha_mode: client::AlphaMode,
+    transform: client::VideoTransform,
+    colorimetry: client::Colorimetry,
+    tonemap: Option<Tonemapping>,
+    encoding: Option<client::Transfer>,
+    source_images: u32,
+    title: Option<String>,
+) -> Result<client::Stream> {
+    let ly_device = DEVICE_MAP
+        .get(&device)
+        .ok_or(vk::Result::ERROR_DEVICE_LOST)?;
+    let ly_instance = INSTANCE_MAP
+        .get(&ly_device.instance)
+        .ok_or(vk::Result::ERROR_DEVICE_LOST)?;
+    let valid = ly_instance
+        .valid
+        .as_ref()
+        .ok_or(anyhow!("instance not initialized"))?;
+    create_stream(
+        &valid.khr_phy_props2,
+        ly_device.phy_device,
+        device,
+        target,
+        format,
+        extent.width,
+        extent.height,
+        alpha_mode,
+        transform,
+        colorimetry,
+        tonemap,
+        encoding,
+        source_images,
+        title,
+        &ly_device.workarounds,
+    )
+}
+
+fn describe_negotiation(swapchain: vk::SwapchainKHR, ly_swapchain: &LayerSwapchain) -> String {
+    let vk::Extent2D { width, height } = ly_swapchain.extent;
+    let negotiation = match ly_swapchain.negotiation.lock() {
+        Ok(v) => v.to_string(),
+        Err(_) => "  poisoned".to_string(),
+    };
+    let stream = match ly_swapchain.stream.get() {
+        Some(Some(_)) => "created",
+        Some(None) => "failed",
+        None if ly_swapchain.stream_deadline.is_some() => "pending",
+        None => "not capturable",
+    };
+    format!(
+        "negotiation of {:?}, {:?} {}x{}, stream {}:\n{}",
+        swapchain, ly_swapchain.format, width, height, stream, negotiation
+    )
+}
+
+#[named]
+fn record_fixation(
+    swapchain: vk::SwapchainKHR,
+    generation: u64,
+    requested: client::EnumFormatInfo,
+    res: &Result<client::FixateFormat>,
+) {
+    let Ok(ly_swapchain) = get_swapchain(swapchain, generation) else {
+        return;
+    };
+    let fixated = match res {
+        Ok(_) => ly_swapchain
+            .export_data
+            .as_ref()
+            .map(|data| Fixated {
+                format: data.format,
+                modifier: data.modifier,
+                need_blit: data.need_blit,
+            })
+            .ok_or("no export data".to_string()),
+        Err(e) => Err(format!("{e:#}")),
+    };
+    if let Ok(mut negotiation) = ly_swapchain.negotiation.lock() {
+        negotiation.requested = Some(requested);
+        negotiation.fixated = Some(fixated);
+    }
+    if CONFIG.dump_negotiation {
+        info!("{}", describe_negotiation(swapchain, &ly_swapchain));
+    }
+}
+
+/// Creates stream of `swapchain` once its deadline passed.
+#[named]
+unsafe fn init_swapchain_stream(swapchain: vk::SwapchainKHR) {
+    let (
+        generation,
+        target,
+        device,
+        format,
+        extent,
+        alpha_mode,
+        transform,
+        colorimetry,
+        tonemap,
+        encoding,
+        source_images,
+        title,
+    ) = {
+        let Some(ly_swapchain) = SWAPCHAIN_MAP.get(&swapchain) else {
+            return;
+        };
+        if ly_swapchain.stream.get().is_some()
+            || !stream_due(ly_swapchain.stream_deadline, Instant::now())
+        {
+            return;
+        }
+        (
+            ly_swapchain.generation,
+            ly_swapchain.stream_target.clone(),
+            ly_swapchain.device,
+            ly_swapchain.format,
+            swapchain_frame_extent(&ly_swapchain),
+            ly_swapchain.alpha_mode,
+            ly_swapchain.transform,
+            ly_swapchain.colorimetry,
+            ly_swapchain.tonemap,
+            ly_swapchain.encoding,
+            ly_swapchain.images.len() as u32,
+            ly_swapchain.title.clone(),
+        )
+    };
+
+    let stream = create_swapchain_stream(
+        device,
+        target,
+        format,
+        extent,
+        alpha_mode,
+        transform,
+        colorimetry,
+        tonemap,
+        encoding,
+        source_images,
+        title,
+    )
+    .map_err(|e| error!("failed to create stream: {e:?}"))
+    .ok();
+
+    let failed = stream.is_none();
+    // not held while creating, see `stream_proxy`, in the meantime it may have been destroyed and
+    // its handle reused, the stream is dropped then
+    if let Ok(ly_swapchain) = get_swapchain(swapchain, generation) {
+        if let Err(stream) = ly_swapchain.stream.set(stream) {
+            drop(ly_swapchain);
+            drop(stream);
+        } else if failed && CONFIG.dump_negotiation {
+            info!("{}", describe_negotiation(swapchain, &ly_swapchain));
+        } else if let (Some(metadata), None) = (ly_swapchain.hdr_metadata, ly_swapchain.tonemap) {
+            let Some(stream) = stream_proxy(ly_swapchain) else {
+                return;
+            };
+            let _ = stream
+                .try_set_hdr_metadata(metadata)
+                .map_err(|e| map_err!(e));
+        }
+    }
+}
+
+/// Creates the preview of `swapchain`, see `Preview`, with readback commands for the queue
+/// family of `queue_family_index`.
+#[named]
+unsafe fn init_swapchain_preview(
+    ash_device: &ash::Device,
+    swapchain: vk::SwapchainKHR,
+    generation: u64,
+    queue_family_index: u32,
+) {
+    let preview = create_swapchain_preview(ash_device, swapchain, generation, queue_family_index)
+        .map_err(|e| error!("failed to create preview of {:?}: {e:?}", swapchain))
+        .ok();
+    // not held while creating, see `stream_proxy`, in the meantime it may have been destroyed
+    let preview = match get_swapchain(swapchain, generation) {
+        Ok(ly_swapchain) => match ly_swapchain.preview.set(preview) {
+            Ok(()) => return,
+            Err(v) => v,
+        },
+        Err(_) => preview,
+    };
+    if let Some(preview) = preview {
+        let _ = preview
+            .stream
+            .proxy()
+            .try_terminate()
+            .map_err(|e| map_err!(e));
+        preview.destroy(ash_device);
+    }
+}
+
+/// Sets up readback of `swapchain` downscaled to fit `PW_CAPTURE_PREVIEW` and creates a stream
+/// offering memfd buffers of the format it reads back in.
+#[named]
+unsafe fn create_swapchain_preview(
+    ash_device: &ash::Device,
+    swapchain: vk::SwapchainKHR,
+    generation: u64,
+    queue_family_index: u32,
+) -> Result<Preview> {
+    let resolution = CONFIG.preview.ok_or(anyhow!("no preview resolution"))?;
+    let (device, format, crop, image_count, alpha_mode, transform, colorimetry, title) = {
+        let ly_swapchain = get_swapchain(swapchain, generation)?;
+        (
+            ly_swapchain.device,
+            ly_swapchain.format,
+            ly_swapchain.crop,
+            ly_swapchain.images.len(),
+            ly_swapchain.alpha_mode,
+            ly_swapchain.transform,
+            ly_swapchain.colorimetry,
+            ly_swapchain.title.clone(),
+        )
+    };
+    let ly_device = DEVICE_MAP
+        .get(&device)
+        .ok_or(vk::Result::ERROR_DEVICE_LOST)?;
+    let ly_instance = INSTANCE_MAP
+        .get(&ly_device.instance)
+        .ok_or(vk::Result::ERROR_DEVICE_LOST)?;
+    if !has_graphics_queue(&ly_device) {
+        return Err(anyhow!("no graphics queue to downscale frames with"));
+    }
+
+    let (width, height) = resolution.fit(crop.extent.width, crop.extent.height);
+    let extent = vk::Extent2D { width, height };
+    let readback = PollReadback::new(
+        &ly_instance.ash_instance,
+        ash_device,
+        ly_device.phy_device,
+        queue_family_index,
+        format,
+        extent,
+        extent != crop.extent,
+        image_count,
+    )?;
+    let src_format_info = vk_format_get_info(format);
+    let format_info = vk_format_get_info(readback.format);
+    if format_info.format == client::Format::UNKNOWN {
+        readback.destroy(ash_device);
+        return Err(anyhow!("{:?} frames can't be streamed", readback.format));
+    }
+    // blitting between sRGB and UNORM formats changes the encoding of values
+    let colorimetry = if format_info.transfer != src_format_info.transfer {
+        client::Colorimetry {
+            transfer_function: client::TransferFunction::Unknown,
+            ..colorimetry
+        }
+    } else {
+        colorimetry
+    };
+
+    info!(
+        "creating preview stream, extent: {}x{} format: {:?}",
+        width, height, format_info
+    );
+    let preview_format = format_info.format;
+    let stream_info = client::StreamInfo {
+        width,
+        height,
+        enum_formats: vec![client::EnumFormatInfo {
+            formats: vec![preview_format],
+            modifiers: vec![],
+        }],
+        max_buffers: MAX_BUFFERS,
+        alpha_mode,
+        transfer: format_info.transfer,
+        source_images: image_count as _,
+        framerate: CONFIG.target_fps,
+        title,
+        transform,
+        interlaced: false,
+        colorimetry,
+        metadata_only: false,
+        metadata_on_request: false,
+        mem_ptr: false,
+        pull: CONFIG.pull,
+        warmup: Duration::ZERO,
+        static_timeout: Duration::ZERO,
+        pts_offset_ns: CONFIG.pts_offset_ms as i64 * 1_000_000,
+        max_latency: Duration::from_millis(CONFIG.max_latency_ms as _),
+        replay: Duration::ZERO,
+        preview: true,
+        fixate_format: Box::new(move |format| {
+            (format.formats.first() == Some(&preview_format)).then_some(client::FixateFormat {
+                modifier: None,
+                num_planes: 1,
+            })
+        }),
+        add_buffer: Box::new(move |_| {
+            let ly_swapchain = get_swapchain(swapchain, generation)
+                .map_err(|e| map_err!(e))
+                .ok()?;
+            let preview = ly_swapchain.preview.get()?.as_ref()?;
+            preview.add_buffer().map_err(|e| map_err!(e)).ok()
+        }),
+        remove_buffer: Box::new(move |user_handle| {
+            let handle = match user_handle {
+                client::BufferUserHandle::VkImage(v) => v,
+                _ => unreachable!(),
+            };
+            let Ok(ly_swapchain) = get_swapchain(swapchain, generation) else {
+                return;
+            };
+            if let Some(Some(preview)) = ly_swapchain.preview.get() {
+                let _ = preview.remove_buffer(handle).map_err(|e| map_err!(e));
+            }
+        }),
+        // frames are read back before they're queued, nothing to wait for
+        process_buffer: Box::new(|_, _| {}),
+        video_crop: None,
+        video_damage: None,
+        streaming: Box::new(|| {}),
+    };
+
+    let stream = CLIENT
+        .lock()
+        .map_err(|_| anyhow!("client poisoned"))
+        .and_then(|client| {
+            let client = client
+                .as_ref()
+                .filter(|v| v.is_alive())
+                .ok_or(anyhow!("failed to get client"))?;
+            Ok(client.proxy().try_create_stream(stream_info)???)
+        });
+    match stream {
+        Ok(stream) => Ok(Preview::new(stream, readback)),
+        Err(e) => {
+            readback.destroy(ash_device);
+            Err(e)
+        }
+    }
+}
+
+/// Creates the v4l2 sink of `swapchain`, see `V4l2Sink`, with conversion commands for `queue`.
+/// Frames are still captured without it.
+#[cfg(feature = "v4l2")]
+#[named]
+unsafe fn init_swapchain_v4l2_sink(
+    ash_device: &ash::Device,
+    swapchain: vk::SwapchainKHR,
+    generation: u64,
+    queue: vk::Queue,
+) {
+    let sink = create_swapchain_v4l2_sink(ash_device, swapchain, generation, queue)
+        .map_err(|e| warn!("not writing frames of {:?} to v4l2: {e:?}", swapchain))
+        .ok();
+    // in the meantime it may have been destroyed
+    let sink = match get_swapchain(swapchain, generation) {
+        Ok(ly_swapchain) => match ly_swapchain.v4l2_sink.set(sink) {
+            Ok(()) => return,
+            Err(v) => v,
+        },
+        Err(_) => sink,
+    };
+    if let Some(sink) = sink {
+        sink.destroy(ash_device);
+    }
+}
+
+/// Opens `PW_CAPTURE_V4L2_DEVICE` for NV12 frames of the stream extent of `swapchain` and sets
+/// up their conversion and readback on the family of `queue`.
+#[cfg(feature = "v4l2")]
+#[named]
+unsafe fn create_swapchain_v4l2_sink(
+    ash_device: &ash::Device,
+    swapchain: vk::SwapchainKHR,
+    generation: u64,
+    queue: vk::Queue,
+) -> Result<V4l2Sink> {
+    let path = CONFIG
+        .v4l2_device
+        .as_ref()
+        .ok_or(anyhow!("no v4l2 device"))?;
+    let (device, format, crop, stream_extent, image_count, alpha_mode, tonemap) = {
+        let ly_swapchain = get_swapchain(swapchain, generation)?;
+        (
+            ly_swapchain.device,
+            ly_swapchain.format,
+            ly_swapchain.crop,
+            ly_swapchain.stream_extent,
+            ly_swapchain.images.len(),
+            ly_swapchain.alpha_mode,
+            ly_swapchain.tonemap,
+        )
+    };
+    let ly_device = DEVICE_MAP
+        .get(&device)
+        .ok_or(vk::Result::ERROR_DEVICE_LOST)?;
+    let ly_instance = INSTANCE_MAP
+        .get(&ly_device.instance)
+        .ok_or(vk::Result::ERROR_DEVICE_LOST)?;
+    let (queue_family_index, queue_flags) = QUEUE_MAP
+        .get(&queue)
+        .map(|ly_queue| (ly_queue.family_index, ly_queue.family_props.queue_flags))
+        .ok_or(anyhow!("unknown queue {:?}", queue))?;
+    let scaled = stream_extent != crop.extent;
+    let mut need = vk::QueueFlags::COMPUTE;
+    if scaled {
+        need |= vk::QueueFlags::GRAPHICS;
+    }
+    if !queue_flags.contains(need) {
+        return Err(anyhow!(
+            "present queue of {:?} can't convert frames",
+            queue_flags
+        ));
+    }
+
+    let vk::Extent2D { width, height } = stream_extent;
+    let params = ConvertParams::new(
+        client::Format::NV12,
+        width,
+        height,
+        format,
+        swapchain_processing(alpha_mode, tonemap),
+    )
+    .ok_or(anyhow!("frames can't be converted to NV12"))?;
+    let v4l2_device = V4l2Device::open(path, width, height)
+        .map_err(|e| e.context(format!("failed to open {:?}", path)))?;
+    info!(
+        "writing frames to {:?}, extent: {}x{} format: {:?}",
+        path, width, height, v4l2_device.format
+    );
+    let readback = PollReadback::converted(
+        &ly_instance.ash_instance,
+        ash_device,
+        ly_device.phy_device,
+        queue_family_index,
+        format,
+        params,
+        scaled,
+        image_count,
+        COLOR_TRANSFORM.as_ref(),
+    )?;
+    Ok(V4l2Sink::new(v4l2_device, readback, params))
+}
+
+/// Resets the command pool of `export_data` once the buffer of `image_index` is recorded again,
+/// waiting for copies of all images as none of the buffers may be pending.
+#[named]
+unsafe fn reset_command_pool_if_recorded(
+    ash_device: &ash::Device,
+    ly_swapchain: &LayerSwapchain,
+    export_data: &ExportData,
+    image_index: usize,
+) -> Result<()> {
+    let mut recorded = export_data
+        .recorded
+        .lock()
+        .map_err(|_| anyhow!("recorded command buffers poisoned"))?;
+    if recorded[image_index] {
+        let start = Instant::now();
+        for mut data in ly_swapchain.image_datas.iter_mut() {
+            data.sync.wait_and_reset(ash_device)?;
+        }
+        ash_device
+            .reset_command_pool(export_data.command_pool, vk::CommandPoolResetFlags::empty())?;
+        recorded.fill(false);
+        trace!("pool reset time: {:?}", start.elapsed());
+    }
+    // also set if recording fails below, a buffer in recording state must be reset too
+    recorded[image_index] = true;
+    Ok(())
+}
+
+/// Records and submits the copy of a presented image into the export image of a dequeued buffer.
+#[named]
+unsafe fn copy_to_export_image(
+    ash_device: &ash::Device,
+    swapchain: vk::SwapchainKHR,
+    image_index: usize,
+    src_queue: vk::Queue,
+    src_queue_family_index: u32,
+    wait_semaphores: &[vk::Semaphore],
+    khr_semaphore_fd: Option<&khr::ExternalSemaphoreFd>,
+    export_image: vk::Image,
+    composed: &[ComposedImage],
+    present_count: u64,
+) -> Result<(Vec<vk::Semaphore>, Option<(FrameCallback, PwCaptureFrame)>)> {
+    let ly_swapchain = SWAPCHAIN_MAP
+        .get(&swapchain)
+        .ok_or(vk::Result::ERROR_UNKNOWN)?;
+    // only consumed once a buffer is dequeued so the request survives a full queue
+    if ly_swapchain
+        .capture_requested
+        .swap(false, atomic::Ordering::Relaxed)
+    {
+        debug!("triggered capture of {:?}", swapchain);
+    }
+
+    let export_data = ly_swapchain
+        .export_data
+        .as_ref()
+        .ok_or(anyhow!("no format fixated"))?;
+
+    let crop = ly_swapchain.crop;
+    let vk::Extent2D { width, height } = swapchain_frame_extent(&ly_swapchain);
+    let src_image = ly_swapchain.images[image_index];
+
+    let mut export_image_data = ly_swapchain
+        .export_images
+        .get_mut(&export_image)
+        .ok_or(anyhow!("buffer image not found"))?;
+    // `need_blit` is decided for the fixated format and modifier
+    if export_image_data.stale || export_image_data.format != export_data.format {
+        return Err(anyhow!(
+            "buffer image of stale format {:?}",
+            export_image_data.format
+        ));
+    }
+
+    let command_buffer = export_data.command_buffers[image_index];
+    frame_trace(|| {
+        format!(
+            "copy {:?} -> {:?} as {:?}, crop {:?}, extent {}x{}, blit {}, {:?}, command buffer {:?}",
+            src_image,
+            export_image,
+            export_image_data.format,
+            crop,
+            width,
+            height,
+            export_data.need_blit,
+            ly_swapchain.sharing_mode,
+            command_buffer
+        )
+    });
+    frame_trace(|| {
+        // fd, offset and stride of each plane
+        let planes: Vec<_> = export_image_data
+            .fds
+            .iter()
+            .map(|(fd, layout)| (fd, layout.offset, layout.row_pitch))
+            .collect();
+        format!(
+            "export memory {:?}, planes {:?}",
+            export_image_data.memory, planes
+        )
+    });
+    if CONFIG.command_reset == CommandReset::Pool {
+        reset_command_pool_if_recorded(ash_device, &ly_swapchain, export_data, image_index)?;
+    }
+
+    let mut data = ly_swapchain
+        .image_datas
+        .get_mut(&src_image)
+        .ok_or(anyhow!("src image data removed"))?;
+    data.sync.wait_and_reset(ash_device)?;
+    let timer = export_data.copy_timer.as_ref();
+    if let Some(duration) = timer.and_then(|timer| timer.last_duration(ash_device, image_index)) {
+        trace!("gpu copy time: {:?}", duration);
+    }
+
+    if let (Some(template), Some(watermark)) = (&CONFIG.watermark, data.watermark.as_mut()) {
+        watermark.upload(&expand_watermark(template, present_count));
+    }
+
+    if CONFIG.command_reset == CommandReset::Buffer {
+        ash_device.reset_command_buffer(command_buffer, vk::CommandBufferResetFlags::empty())?;
+    }
+
+    // the whole image is always copied, damage only tells consumers what changed, so a full frame
+    // requested with `request_full_frame` needs no other copy
+    // concurrent images are accessible from their families as is, ownership can't be transferred
+    let (src_family, dst_family) = if ly_swapchain.sharing_mode == vk::SharingMode::CONCURRENT {
+        (vk::QUEUE_FAMILY_IGNORED, vk::QUEUE_FAMILY_IGNORED)
+    } else {
+        (src_queue_family_index, export_data.queue_family_index)
+    };
+    // exclusive images are released by `src_queue`, of the family owning them, before the copy on
+    // another family and acquired back after it, `record_copy_image` records the acquire and
+    // release in between
+    let transfer = if src_family != dst_family {
+        let found = data
+            .ownership_transfers
+            .iter()
+            .find(|v| (v.owner_family, v.copy_family) == (src_family, dst_family))
+            .copied();
+        match found {
+            Some(v) => Some(v),
+            None => {
+                debug!(
+                    "ownership transfer of {:?} between families {} and {}",
+                    src_image, src_family, dst_family
+                );
+                let transfer =
+                    OwnershipTransfer::new(ash_device, src_image, src_family, dst_family)?;
+                data.ownership_transfers.push(transfer);
+                Some(transfer)
+            }
+        }
+    } else {
+        None
+    };
+    frame_trace(|| format!("queue families {} -> {}", src_family, dst_family));
+    // swapchains composed with this one are copied first, into their part of the export image,
+    // with their own command buffers following those of this swapchain's images
+    let image_count = ly_swapchain.images.len();
+    let mut clear = ly_swapchain.composition.is_some();
+    let mut command_buffers = vec![];
+    for image in composed {
+        let command_buffer = *export_data
+            .command_buffers
+            .get((image.part + 1) * image_count + image_index)
+            .ok_or(anyhow!("no command buffers for composed swapchains"))?;
+        if CONFIG.command_reset == CommandReset::Buffer {
+            ash_device
+                .reset_command_buffer(command_buffer, vk::CommandBufferResetFlags::empty())?;
+        }
+        record_copy_image(
+            ash_device,
+            command_buffer,
+            image.image,
+            export_image,
+            vk::QUEUE_FAMILY_IGNORED,
+            vk::QUEUE_FAMILY_IGNORED,
+            image.crop,
+            image.dst,
+            clear,
+            export_data.need_blit,
+            false,
+            None,
+            None,
+            None,
+        )?;
+        clear = false;
+        command_buffers.push(command_buffer);
+    }
+    record_copy_image(
+        ash_device,
+        command_buffer,
+        src_image,
+        export_image,
+        src_family,
+        dst_family,
+        crop,
+        vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent: ly_swapchain.stream_extent,
+        },
+        clear,
+        export_data.need_blit,
+        export_image_data.host.is_some(),
+        data.watermark.as_ref(),
+        export_data
+            .conversion
+            .as_ref()
+            .map(|(pipeline, _)| pipeline)
+            .zip(export_image_data.conversion.as_ref()),
+        timer.map(|timer| (timer, image_index)),
+    )?;
+
+    let frame_callback = ly_swapchain.frame_callback.lock().ok().and_then(|v| *v);
+    let mut signal_semaphores = data.semaphores.clone();
+    if frame_callback.is_some() {
+        let frame_semaphore = match data.frame_semaphore {
+            Some(v) => v,
+            None => {
+                let semaphore_info = vk::SemaphoreCreateInfo::builder();
+                let semaphore = ash_device.create_semaphore(&semaphore_info, None)?;
+                *data.frame_semaphore.insert(semaphore)
+            }
+        };
+        signal_semaphores.push(frame_semaphore);
+    }
+    let sync_file_semaphore = match khr_semaphore_fd {
+        Some(_)
+            if export_image_data.host.is_none()
+                && DMA_BUF_SYNC_FILE.load(atomic::Ordering::Relaxed) =>
+        {
+            let semaphore = match data.sync_file_semaphore {
+                Some(v) => v,
+                None => {
+                    let mut export_info = vk::ExportSemaphoreCreateInfo::builder()
+                        .handle_types(vk::ExternalSemaphoreHandleTypeFlags::SYNC_FD);
+                    let semaphore_info =
+                        vk::SemaphoreCreateInfo::builder().push_next(&mut export_info);
+                    let semaphore = ash_device.create_semaphore(&semaphore_info, None)?;
+                    *data.sync_file_semaphore.insert(semaphore)
+                }
+            };
+            signal_semaphores.push(semaphore);
+            Some(semaphore)
+        }
+        _ => None,
+    };
+
+    // binary semaphores ignore their values
+    let mut signal_values = vec![0; signal_semaphores.len()];
+    let (fence, timeline_signal) = data.sync.use_sync();
+    if let Some((semaphore, value)) = timeline_signal {
+        signal_semaphores.push(semaphore);
+        signal_values.push(value);
+    }
+
+    let copy_waits = match transfer {
+        Some(transfer) => {
+            let release_buffers = &[transfer.release];
+            let release_signals = &[transfer.released];
+            let release_stages = vec![vk::PipelineStageFlags::ALL_COMMANDS; wait_semaphores.len()];
+            let release_info = vk::SubmitInfo::builder()
+                .command_buffers(release_buffers)
+                .wait_semaphores(wait_semaphores)
+                .signal_semaphores(release_signals)
+                .wait_dst_stage_mask(&release_stages);
+            ash_device.queue_submit(src_queue, &[release_info.build()], vk::Fence::null())?;
+            frame_trace_submit(
+                src_queue,
+                release_buffers,
+                wait_semaphores,
+                &release_stages,
+                release_signals,
+                &[],
+                vk::Fence::null(),
+            );
+            vec![transfer.released]
+        }
+        None => wait_semaphores.to_vec(),
+    };
+
+    command_buffers.push(command_buffer);
+    // one per wait, the builder sets the wait count from either
+    let wait_stages = vec![vk::PipelineStageFlags::TRANSFER; copy_waits.len()];
+    let mut timeline_info =
+        vk::TimelineSemaphoreSubmitInfo::builder().signal_semaphore_values(&signal_values);
+    let mut submit_info = vk::SubmitInfo::builder()
+        .command_buffers(&command_buffers)
+        .wait_semaphores(&copy_waits)
+        .signal_semaphores(&signal_semaphores)
+        .wait_dst_stage_mask(&wait_stages);
+    if timeline_signal.is_some() {
+        submit_info = submit_info.push_next(&mut timeline_info);
+    }
+
+    ash_device.queue_submit(export_data.queue, &[submit_info.build()], fence)?;
+    frame_trace_submit(
+        export_data.queue,
+        &command_buffers,
+        &copy_waits,
+        &wait_stages,
+        &signal_semaphores,
+        &signal_values,
+        fence,
+    );
+    data.sync.submitted();
+    if let Some(timer) = timer {
+        timer.submitted(image_index);
+    }
+    export_image_data.sync_file_attached = match (khr_semaphore_fd, sync_file_semaphore) {
+        (Some(khr_semaphore_fd), Some(semaphore)) => {
+            let dma_buf_fd = export_image_data.fds.first().map_or(-1, |v| v.0);
+            attach_sync_file(khr_semaphore_fd, semaphore, dma_buf_fd)
+        }
+        _ => false,
+    };
+    let attached = export_image_data.sync_file_attached;
+    frame_trace(|| format!("sync file attached: {}", attached));
+    data.seq += 1;
+    export_image_data.src_image = (src_image, data.seq);
+    export_image_data.content_region = swapchain_content_region(&ly_swapchain);
+    // taken once the copy is submitted, a failed one leaves it to the next capture
+    let damage = ly_swapchain
+        .damage
+        .lock()
+        .ok()
+        .and_then(|mut damage| damage.replace(vec![]));
+    // changes of composed swapchains aren't tracked, the whole frame changed
+    export_image_data.damage = damage
+        .filter(|_| ly_swapchain.composition.is_none())
+        .map(|rects| {
+            rects
+                .into_iter()
+                .filter_map(|rect| region_in_stream(rect, crop, ly_swapchain.stream_extent))
+                .collect()
+        });
+
+    let frame = frame_callback.map(|frame_callback| {
+        let frame = PwCaptureFrame {
+            image: export_image_data.image,
+            memory: export_image_data.memory,
+            format: export_image_data.format,
+            width,
+            height,
+            queue_family_index: export_data.queue_family_index,
+            semaphore: data.frame_semaphore.unwrap_or_default(),
+            present_count,
+        };
+        (frame_callback, frame)
+    });
+
+    let present_waits = match transfer {
+        Some(transfer) => {
+            let acquire_buffers = &[transfer.acquire];
+            let acquire_signals = &[transfer.acquired];
+            let acquire_stages = vec![vk::PipelineStageFlags::ALL_COMMANDS; data.semaphores.len()];
+            let acquire_info = vk::SubmitInfo::builder()
+                .command_buffers(acquire_buffers)
+                .wait_semaphores(&data.semaphores)
+                .signal_semaphores(acquire_signals)
+                .wait_dst_stage_mask(&acquire_stages);
+            ash_device.queue_submit(src_queue, &[acquire_info.build()], vk::Fence::null())?;
+            frame_trace_submit(
+                src_queue,
+                acquire_buffers,
+                &data.semaphores,
+                &acquire_stages,
+                acquire_signals,
+                &[],
+                vk::Fence::null(),
+            );
+            vec![transfer.acquired]
+        }
+        None => data.semaphores.clone(),
+    };
+    frame_trace(|| format!("present waits {:?}", present_waits));
+
+    Ok((present_waits, frame))
+}
+
+/// Copies the presented image and reads the one of the previous present back into the spare
+/// frame of `swapchain`, making it the latest. Returns the semaphore the present waits on.
+#[named]
+unsafe fn poll_capture(
+    ash_device: &ash::Device,
+    swapchain: vk::SwapchainKHR,
+    image_index: usize,
+    src_queue: vk::Queue,
+    src_queue_family_index: u32,
+    wait_semaphores: &[vk::Semaphore],
+    present_count: u64,
+) -> Result<vk::Semaphore> {
+    let ly_swapchain = SWAPCHAIN_MAP
+        .get(&swapchain)
+        .ok_or(vk::Result::ERROR_UNKNOWN)?;
+    let readback = ly_swapchain.poll_readback.get_or_init(|| {
+        let ly_device = DEVICE_MAP.get(&ly_swapchain.device)?;
+        let ly_instance = INSTANCE_MAP.get(&ly_device.instance)?;
+        let scaled = ly_swapchain.stream_extent != ly_swapchain.crop.extent;
+        PollReadback::new(
+            &ly_instance.ash_instance,
+            ash_device,
+            ly_device.phy_device,
+            src_queue_family_index,
+            ly_swapchain.format,
+            ly_swapchain.stream_extent,
+            scaled,
+            ly_swapchain.images.len(),
+        )
+        .map_err(|e| error!("failed to set up readback of {:?}: {e:?}", swapchain))
+        .ok()
+    });
+    let Some(readback) = readback else {
+        return Err(anyhow!("no readback"));
     };
 
-    if ly_device.valid.is_some() {
-        ly_swapchain_wait_image(&ly_device, &ly_swapchain, *p_image_index as _)?;
-    }
-    Ok(res)
-}
+    // the frame of the previous present is read back, that of this one at the next
+    let mut frame = None;
+    let semaphore = readback.copy(
+        ash_device,
+        src_queue,
+        ly_swapchain.images[image_index],
+        image_index,
+        ly_swapchain.crop,
+        wait_semaphores,
+        present_count,
+        |present_count, pixels| {
+            // reused unless a poller is still copying from it
+            let spare = ly_swapchain
+                .polled_frames
+                .lock()
+                .ok()
+                .and_then(|mut v| v.spare.take());
+            let mut data = spare
+                .and_then(|v| Arc::try_unwrap(v).ok())
+                .map(|v| v.data)
+                .unwrap_or_default();
+            data.clear();
+            data.extend_from_slice(pixels);
+            frame = Some(PolledFrame {
+                info: PwCapturePolledFrame {
+                    format: readback.format,
+                    width: readback.extent.width,
+                    height: readback.extent.height,
+                    stride: readback.row_pitch as _,
+                    size: data.len(),
+                    present_count,
+                },
+                data,
+            });
+        },
+    )?;
+    let Some(frame) = frame else {
+        return Ok(semaphore);
+    };
 
-unsafe fn acquire_next_image2_khr(
-    device: vk::Device,
-    p_acquire_info: *const vk::AcquireNextImageInfoKHR,
-    p_image_index: *mut u32,
-) -> Result<vk::Result> {
-    let acquire_info = p_acquire_info.read();
-    let ly_device = DEVICE_MAP
-        .get(&device)
-        .ok_or(vk::Result::ERROR_DEVICE_LOST)?;
-    let ly_swapchain = SWAPCHAIN_MAP
-        .get(&acquire_info.swapchain)
-        .ok_or(vk::Result::ERROR_DEVICE_LOST)?;
+    let present_count = frame.info.present_count;
+    let mut frames = ly_swapchain
+        .polled_frames
+        .lock()
+        .map_err(|_| anyhow!("poisoned"))?;
+    frames.spare = frames.latest.replace(Arc::new(frame));
+    trace!("frame {} ready for polling", present_count);
+    Ok(semaphore)
+}
 
-    let res = (ly_device.khr_swapchain.fp().acquire_next_image2_khr)(
-        device,
-        p_acquire_info,
-        p_image_index,
-    );
-    match res {
-        vk::Result::SUCCESS | vk::Result::SUBOPTIMAL_KHR => (),
-        _ => return Err(anyhow!(res)),
+/// Fills `frame` with the latest frame of `swapchain` read back with `PW_CAPTURE_POLL`, and
+/// copies its pixels to `data` if they fit in `capacity` bytes. Returns false without a frame
+/// or if `data` is too small, `frame.size` tells the size needed then. Callable from any
+/// thread, also while the next frame is read back.
+#[no_mangle]
+pub unsafe extern "C" fn me_eh5_pw_capture_poll_frame(
+    swapchain: vk::SwapchainKHR,
+    frame: *mut PwCapturePolledFrame,
+    data: *mut c_void,
+    capacity: usize,
+) -> bool {
+    let Some(frame) = frame.as_mut() else {
+        return false;
     };
-
-    if ly_device.valid.is_some() {
-        ly_swapchain_wait_image(&ly_device, &ly_swapchain, *p_image_index as _)?;
+    // cloned so the copy doesn't block the next readback
+    let latest = SWAPCHAIN_MAP.get(&swapchain).and_then(|ly_swapchain| {
+        let frames = ly_swapchain.polled_frames.lock().ok()?;
+        frames.latest.clone()
+    });
+    let Some(latest) = latest else {
+        return false;
+    };
+    *frame = latest.info;
+    if data.is_null() || capacity < latest.data.len() {
+        return false;
     }
-    Ok(res)
+    ptr::copy_nonoverlapping(latest.data.as_ptr(), data as *mut u8, latest.data.len());
+    true
 }
 
-#[no_mangle]
+/// Exports the signaled `semaphore` as sync file and attaches it to `dma_buf_fd`, false if the
+/// copy must still be waited for on the CPU.
 #[named]
-unsafe extern "system" fn pwcap_vkAcquireNextImageKHR(
-    device: vk::Device,
-    swapchain: vk::SwapchainKHR,
-    timeout: u64,
+unsafe fn attach_sync_file(
+    khr_semaphore_fd: &khr::ExternalSemaphoreFd,
     semaphore: vk::Semaphore,
-    fence: vk::Fence,
-    p_image_index: *mut u32,
-) -> vk::Result {
-    acquire_next_image_khr(device, swapchain, timeout, semaphore, fence, p_image_index)
-        .unwrap_or_else(|e| map_err!(e))
+    dma_buf_fd: i32,
+) -> bool {
+    let fd_info = vk::SemaphoreGetFdInfoKHR::builder()
+        .semaphore(semaphore)
+        .handle_type(vk::ExternalSemaphoreHandleTypeFlags::SYNC_FD);
+    let sync_fd = match khr_semaphore_fd.get_semaphore_fd(&fd_info) {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("failed to export sync file: {}", e);
+            return false;
+        }
+    };
+    // an already signaled semaphore exports as -1, nothing to wait for
+    if sync_fd < 0 {
+        return true;
+    }
+    let res = import_sync_file(dma_buf_fd, sync_fd);
+    libc::close(sync_fd);
+    match res {
+        Ok(()) => true,
+        Err(e) => {
+            warn!(
+                "failed to attach sync file, waiting for copies instead: {}",
+                e
+            );
+            DMA_BUF_SYNC_FILE.store(false, atomic::Ordering::Relaxed);
+            false
+        }
+    }
 }
-const _: vk::PFN_vkAcquireNextImageKHR = pwcap_vkAcquireNextImageKHR;
 
-#[no_mangle]
-#[named]
-unsafe extern "system" fn pwcap_vkAcquireNextImage2KHR(
-    device: vk::Device,
-    p_acquire_info: *const vk::AcquireNextImageInfoKHR,
-    p_image_index: *mut u32,
-) -> vk::Result {
-    acquire_next_image2_khr(device, p_acquire_info, p_image_index).unwrap_or_else(|e| map_err!(e))
+/// Converts the presented image and writes it to the v4l2 sink of `swapchain`. Returns the
+/// semaphore signaled by the conversion, or `None` if there's no sink. The sink is created once
+/// due and the device isn't open for another swapchain.
+#[cfg(feature = "v4l2")]
+#[allow(clippy::too_many_arguments)]
+unsafe fn capture_v4l2(
+    ash_device: &ash::Device,
+    swapchain: vk::SwapchainKHR,
+    generation: u64,
+    image_index: usize,
+    src_queue: vk::Queue,
+    wait_semaphores: &[vk::Semaphore],
+) -> Result<Option<vk::Semaphore>> {
+    let ly_swapchain = get_swapchain(swapchain, generation)?;
+    match ly_swapchain.v4l2_sink.get() {
+        Some(Some(sink)) => sink
+            .write(
+                ash_device,
+                src_queue,
+                ly_swapchain.images[image_index],
+                image_index,
+                ly_swapchain.crop,
+                wait_semaphores,
+            )
+            .map(Some),
+        Some(None) => Ok(None),
+        None => {
+            // e.g. the swapchain this one replaces still has it, retried once it's destroyed
+            let due =
+                stream_due(ly_swapchain.v4l2_deadline, Instant::now()) && !V4l2Device::in_use();
+            drop(ly_swapchain);
+            if due {
+                init_swapchain_v4l2_sink(ash_device, swapchain, generation, src_queue);
+            }
+            Ok(None)
+        }
+    }
 }
-const _: vk::PFN_vkAcquireNextImage2KHR = pwcap_vkAcquireNextImage2KHR;
 
-#[no_mangle]
+/// Copies the presented image for the preview stream of `swapchain` and reads the one of the
+/// previous present back into a dequeued buffer, added to `frames` like full resolution ones.
+/// Returns the semaphore signaled by the copy, or `None` if there was nothing to read into. The
+/// preview is created once due.
+#[allow(clippy::too_many_arguments)]
 #[named]
-unsafe extern "system" fn pwcap_vkQueuePresentKHR(
-    queue: vk::Queue,
-    p_present_info: *const vk::PresentInfoKHR,
-) -> vk::Result {
-    queue_present_khr(queue, p_present_info).unwrap_or_else(|e| map_err!(e))
+unsafe fn capture_preview(
+    ash_device: &ash::Device,
+    swapchain: vk::SwapchainKHR,
+    generation: u64,
+    image_index: usize,
+    src_queue: vk::Queue,
+    src_queue_family_index: u32,
+    wait_semaphores: &[vk::Semaphore],
+    frames: &mut Vec<PendingFrame>,
+) -> Result<Option<vk::Semaphore>> {
+    let ly_swapchain = get_swapchain(swapchain, generation)?;
+    let stream = match ly_swapchain.preview.get() {
+        Some(Some(v)) if !v.stream.is_active() || v.stream.is_errored() => return Ok(None),
+        Some(Some(v)) => v.stream.proxy(),
+        Some(None) => return Ok(None),
+        None => {
+            let due = stream_due(ly_swapchain.preview_deadline, Instant::now());
+            drop(ly_swapchain);
+            if due {
+                init_swapchain_preview(ash_device, swapchain, generation, src_queue_family_index);
+            }
+            return Ok(None);
+        }
+    };
+    drop(ly_swapchain);
+
+    let (buffer, user_handle) = match stream.try_dequeue_buffer()?? {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+    let handle = match user_handle {
+        client::BufferUserHandle::VkImage(v) => v,
+        _ => unreachable!(),
+    };
+    let res = get_swapchain(swapchain, generation).and_then(|ly_swapchain| {
+        let preview = ly_swapchain
+            .preview
+            .get()
+            .and_then(Option::as_ref)
+            .ok_or(anyhow!("preview of {:?} gone", swapchain))?;
+        preview.read(
+            ash_device,
+            src_queue,
+            ly_swapchain.images[image_index],
+            image_index,
+            ly_swapchain.crop,
+            wait_semaphores,
+            handle,
+        )
+    });
+    match res {
+        Ok((semaphore, true)) => {
+            frames.push(PendingFrame {
+                swapchain,
+                generation,
+                buffer,
+                preview: true,
+            });
+            Ok(Some(semaphore))
+        }
+        Ok((semaphore, false)) => {
+            // nothing read back yet, the buffer is written by the CPU only
+            let _ = stream.try_cancel_buffer(buffer);
+            Ok(Some(semaphore))
+        }
+        Err(e) => {
+            // the buffer would be lost otherwise
+            let _ = stream.try_cancel_buffer(buffer);
+            Err(e)
+        }
+    }
 }
-const _: vk::PFN_vkQueuePresentKHR = pwcap_vkQueuePresentKHR;
 
+/// Copies the presented image into a dequeued buffer, added to `frames` to be queued once the
+/// present went through. The v4l2 sink and the preview are read back first, `readback` is set
+/// to the semaphore the last of them signaled, which the copy waits on instead of
+/// `wait_semaphores`. Readbacks are submitted on the present queue, the copy on `source`, see
+/// `source_queue`.
+#[allow(clippy::too_many_arguments)]
 #[named]
 unsafe fn capture_swapchain(
     ash_device: &ash::Device,
     swapchain: vk::SwapchainKHR,
     image_index: usize,
+    src_queue: vk::Queue,
     src_queue_family_index: u32,
+    source: (vk::Queue, u32),
     wait_semaphores: &[vk::Semaphore],
+    khr_semaphore_fd: Option<&khr::ExternalSemaphoreFd>,
+    composed: &[ComposedImage],
+    frames: &mut Vec<PendingFrame>,
+    readback: &mut Option<vk::Semaphore>,
 ) -> Result<Option<Vec<vk::Semaphore>>> {
-    let stream = {
+    let (stream, present_count, generation) = {
         let ly_swapchain = SWAPCHAIN_MAP
             .get(&swapchain)
             .ok_or(vk::Result::ERROR_UNKNOWN)?;
-        match ly_swapchain.stream.as_ref() {
-            Some(v) => v.proxy(),
-            None => return Ok(None),
+        // export queue and command buffers belong to the swapchain device, while `ash_device`
+        // comes from the present queue, they must match with multiple devices
+        if ly_swapchain.device != ash_device.handle() {
+            return Err(anyhow!(
+                "{:?} of {:?} presented on queue of {:?}",
+                swapchain,
+                ly_swapchain.device,
+                ash_device.handle()
+            ));
+        }
+        let now = Instant::now();
+        if let Ok(mut frame_rate) = ly_swapchain.frame_rate.lock() {
+            frame_rate.push(now);
+        }
+        let triggered = ly_swapchain
+            .capture_requested
+            .load(atomic::Ordering::Relaxed);
+        let present_count = ly_swapchain
+            .present_count
+            .fetch_add(1, atomic::Ordering::Relaxed);
+        if !triggered && present_count % CONFIG.capture_every_n as u64 != 0 {
+            return Ok(None);
+        }
+        if !triggered && CONFIG.focused_only && !surface_is_focused(ly_swapchain.surface) {
+            return Ok(None);
+        }
+        let paced = match &ly_swapchain.pacer {
+            Some(pacer) => pacer
+                .lock()
+                .map_or(true, |mut pacer| pacer.should_capture(now)),
+            None => true,
+        };
+        if !triggered && !paced {
+            return Ok(None);
         }
+        if !CAPTURE_ENABLED.load(atomic::Ordering::Relaxed)
+            || !ly_swapchain.capture_enabled.load(atomic::Ordering::Relaxed)
+            || ly_swapchain.surface_lost.load(atomic::Ordering::Acquire)
+            || ly_swapchain.out_of_date.load(atomic::Ordering::Acquire)
+        {
+            return Ok(None);
+        }
+        // PipeWire isn't used at all, frames are kept for pollers, swapchains that can't be
+        // captured have no image data
+        if CONFIG.poll {
+            if ly_swapchain.image_datas.is_empty() {
+                return Ok(None);
+            }
+            drop(ly_swapchain);
+            return poll_capture(
+                ash_device,
+                swapchain,
+                image_index,
+                src_queue,
+                src_queue_family_index,
+                wait_semaphores,
+                present_count,
+            )
+            .map(|semaphore| Some(vec![semaphore]));
+        }
+        let stream = match ly_swapchain.stream.get() {
+            // buffers are only added once fixation set up the export data
+            Some(Some(_)) if ly_swapchain.export_data.is_none() => {
+                trace!("format not fixated yet, skipped");
+                None
+            }
+            // paused, e.g. over the control socket
+            Some(Some(v)) if !v.is_active() => None,
+            // logged once by the stream, retrying every present would only fail again
+            Some(Some(v)) if v.is_errored() => None,
+            Some(Some(v)) => Some((v.proxy(), v.stats())),
+            Some(None) => None,
+            None => {
+                drop(ly_swapchain);
+                init_swapchain_stream(swapchain);
+                return Ok(None);
+            }
+        };
+        (stream, present_count, ly_swapchain.generation)
+    };
+
+    // also without a consumer of the full resolution stream, each waits on the one before
+    #[cfg(feature = "v4l2")]
+    {
+        *readback = capture_v4l2(
+            ash_device,
+            swapchain,
+            generation,
+            image_index,
+            src_queue,
+            wait_semaphores,
+        )
+        .map_err(|e| error!("failed to write frame to v4l2: {e:?}"))
+        .ok()
+        .flatten();
+    }
+    let wait_semaphores = readback.as_ref().map_or(wait_semaphores, slice::from_ref);
+    let preview = capture_preview(
+        ash_device,
+        swapchain,
+        generation,
+        image_index,
+        src_queue,
+        src_queue_family_index,
+        wait_semaphores,
+        frames,
+    )
+    .map_err(|e| error!("failed to capture preview: {e:?}"))
+    .ok()
+    .flatten();
+    if preview.is_some() {
+        *readback = preview;
+    }
+    let wait_semaphores = readback.as_ref().map_or(wait_semaphores, slice::from_ref);
+    let Some((stream, stats)) = stream else {
+        return Ok(None);
     };
 
+    // logged once the frame is done, also if it failed
+    let _trace = FrameTrace::begin(CONFIG.trace_frames, || {
+        format!(
+            "{:?} image {} present {}, waits {:?}",
+            swapchain, image_index, present_count, wait_semaphores
+        )
+    });
+
     let start = Instant::now();
 
     let (buffer, user_handle) = match stream.try_dequeue_buffer()?? {
         Some(v) => v,
-        None => return Ok(None),
+        None => {
+            frame_trace(|| "no buffer to dequeue".to_string());
+            return Ok(None);
+        }
     };
     let export_image = match user_handle {
         client::BufferUserHandle::VkImage(image) => image,
         _ => unreachable!(),
     };
     let duration = start.elapsed();
+    stats.set_dequeue_time(duration);
     trace!("dequeue time: {:?}", duration);
+    frame_trace(|| format!("dequeued buffer of {:?} in {:?}", export_image, duration));
+
+    if export_image == vk::Image::null() {
+        // metadata only, meta is filled when the buffer is processed
+        if let Some(ly_swapchain) = SWAPCHAIN_MAP.get(&swapchain) {
+            ly_swapchain
+                .capture_requested
+                .store(false, atomic::Ordering::Relaxed);
+        }
+        stream.try_queue_buffer_process(buffer)???;
+        return Ok(None);
+    }
 
-    let ly_swapchain = SWAPCHAIN_MAP
-        .get(&swapchain)
-        .ok_or(vk::Result::ERROR_UNKNOWN)?;
-
-    let export_data = ly_swapchain
-        .export_data
-        .as_ref()
-        .ok_or(anyhow!("no format fixated"))?;
-
-    let vk::Extent2D { width, height } = ly_swapchain.extent;
-    let src_image = ly_swapchain.images[image_index];
-
-    let mut export_image_data = ly_swapchain
-        .export_images
-        .get_mut(&export_image)
-        .ok_or(anyhow!("buffer image not found"))?;
-    let export_format = export_image_data.format;
-
-    let need_blit = export_format != ly_swapchain.format;
-
-    let mut data = ly_swapchain
-        .image_datas
-        .get_mut(&src_image)
-        .ok_or(anyhow!("src image data removed"))?;
-    data.fence.wait_and_reset(ash_device)?;
-
-    let command_buffer = export_data.command_buffers[image_index];
-    ash_device.reset_command_buffer(command_buffer, vk::CommandBufferResetFlags::empty())?;
-
-    record_copy_image(
+    let (res, frame) = match copy_to_export_image(
         ash_device,
-        command_buffer,
-        src_image,
+        swapchain,
+        image_index,
+        source.0,
+        source.1,
+        wait_semaphores,
+        khr_semaphore_fd,
         export_image,
-        src_queue_family_index,
-        export_data.queue_family_index,
-        width,
-        height,
-        need_blit,
-    )?;
+        composed,
+        present_count,
+    ) {
+        Ok(v) => v,
+        Err(e) => {
+            frame_trace(|| format!("failed: {:?}", e));
+            // the buffer would be lost otherwise
+            let _ = stream.try_cancel_buffer(buffer);
+            return Err(e);
+        }
+    };
 
-    let command_buffers = &[command_buffer];
-    let wait_stages = &[vk::PipelineStageFlags::TRANSFER];
-    let submit_info = vk::SubmitInfo::builder()
-        .command_buffers(command_buffers)
-        .wait_semaphores(wait_semaphores)
-        .signal_semaphores(&data.semaphores)
-        .wait_dst_stage_mask(wait_stages)
-        .build();
+    // app code must not run while the swapchain is locked
+    if let Some((frame_callback, frame)) = frame {
+        (frame_callback.callback)(frame_callback.user_data as *mut c_void, &frame);
+    }
 
-    ash_device.queue_submit(export_data.queue, &[submit_info], data.fence.use_fence())?;
-    data.seq += 1;
-    export_image_data.src_image = (src_image, data.seq);
+    frames.push(PendingFrame {
+        swapchain,
+        generation,
+        buffer,
+        preview: false,
+    });
+    frame_trace(|| "buffer queued after the present".to_string());
 
-    let res = data.semaphores.clone();
-    drop(data);
-    drop(export_image_data);
-    drop(ly_swapchain);
+    Ok(Some(res))
+}
 
-    let start = Instant::now();
-    stream.try_queue_buffer_process(buffer)???;
-    let duration = start.elapsed();
-    trace!("process time: {:?}", duration);
+/// With `PW_CAPTURE_COMPOSE`, composes the other swapchains of a present into the frame of the
+/// first one, once, before its stream is created. Returns the indices of the swapchains composed
+/// into it, which aren't captured on their own, and their presented images.
+#[named]
+fn compose_swapchains(
+    swapchains: &[vk::SwapchainKHR],
+    image_indices: &[u32],
+    unique: &[usize],
+    src_queue_family_index: u32,
+) -> (Vec<usize>, Vec<ComposedImage>) {
+    let Some((&first, others)) = unique.split_first() else {
+        return (vec![], vec![]);
+    };
+    if !CONFIG.compose || others.is_empty() {
+        return (vec![], vec![]);
+    }
+    let leader = swapchains[first];
+    let Some((device, format, extent, scaled, set_up)) =
+        SWAPCHAIN_MAP.get(&leader).map(|ly_swapchain| {
+            (
+                ly_swapchain.device,
+                ly_swapchain.format,
+                ly_swapchain.stream_extent,
+                ly_swapchain.stream_extent != ly_swapchain.crop.extent,
+                ly_swapchain.composition.is_none() && ly_swapchain.stream.get().is_none(),
+            )
+        })
+    else {
+        return (vec![], vec![]);
+    };
 
-    Ok(Some(res))
+    if set_up {
+        // frames are copied into export images of the first one's format
+        let parts = others
+            .iter()
+            .filter_map(|&i| {
+                let ly_swapchain = SWAPCHAIN_MAP.get(&swapchains[i])?;
+                let composable = ly_swapchain.device == device
+                    && ly_swapchain.format == format
+                    && ly_swapchain.composition.is_none()
+                    && ly_swapchain.stream.get().is_none();
+                if !composable {
+                    warn!(
+                        "{:?} can't be composed with {:?}, captured on its own",
+                        swapchains[i], leader
+                    );
+                    return None;
+                }
+                Some((
+                    swapchains[i],
+                    ly_swapchain.generation,
+                    ly_swapchain.stream_extent,
+                    ly_swapchain.stream_extent != ly_swapchain.crop.extent,
+                ))
+            })
+            .collect::<Vec<_>>();
+        if !parts.is_empty() {
+            let extents = [extent]
+                .into_iter()
+                .chain(parts.iter().map(|part| part.2))
+                .collect::<Vec<_>>();
+            let (frame, offsets) = compose_layout(&extents);
+            let mut created = false;
+            if let Some(mut ly_swapchain) = SWAPCHAIN_MAP.get_mut(&leader) {
+                // the stream may have been created by another present in the meantime
+                if ly_swapchain.composition.is_none() && ly_swapchain.stream.get().is_none() {
+                    info!(
+                        "composing {:?} into a {}x{} frame with {:?}",
+                        parts.iter().map(|part| part.0).collect::<Vec<_>>(),
+                        frame.width,
+                        frame.height,
+                        leader
+                    );
+                    ly_swapchain.composition = Some(Composition {
+                        extent: frame,
+                        parts: parts
+                            .iter()
+                            .zip(&offsets[1..])
+                            .map(|(&(swapchain, generation, extent, _), &offset)| {
+                                (swapchain, generation, vk::Rect2D { offset, extent })
+                            })
+                            .collect(),
+                        scaled: scaled || parts.iter().any(|part| part.3),
+                    });
+                    created = true;
+                }
+            }
+            // the composition has the only node, like retired swapchains they get none
+            for part in parts.iter().filter(|_| created) {
+                if let Some(mut ly_swapchain) = SWAPCHAIN_MAP.get_mut(&part.0) {
+                    ly_swapchain.stream_deadline = None;
+                    ly_swapchain.preview_deadline = None;
+                    #[cfg(feature = "v4l2")]
+                    {
+                        ly_swapchain.v4l2_deadline = None;
+                    }
+                }
+            }
+        }
+    }
+
+    let Some((parts, export_family)) = SWAPCHAIN_MAP.get(&leader).and_then(|ly_swapchain| {
+        let composition = ly_swapchain.composition.as_ref()?;
+        let export_family = ly_swapchain
+            .export_data
+            .as_ref()
+            .map(|v| v.queue_family_index);
+        Some((composition.parts.clone(), export_family))
+    }) else {
+        return (vec![], vec![]);
+    };
+
+    let mut composed = vec![];
+    let mut images = vec![];
+    for (part, (swapchain, generation, dst)) in parts.into_iter().enumerate() {
+        let Some(&i) = others.iter().find(|&&i| swapchains[i] == swapchain) else {
+            continue;
+        };
+        composed.push(i);
+        let Ok(ly_swapchain) = get_swapchain(swapchain, generation) else {
+            continue;
+        };
+        // images of exclusive sharing mode are copied without ownership transfers, from the
+        // family presenting them, the part is left blank otherwise
+        let accessible = ly_swapchain.sharing_mode == vk::SharingMode::CONCURRENT
+            || export_family == Some(src_queue_family_index);
+        if !accessible {
+            trace!(
+                "{:?} presented on another family than copies, skipped",
+                swapchain
+            );
+            continue;
+        }
+        images.push(ComposedImage {
+            part,
+            image: ly_swapchain.images[image_indices[i] as usize],
+            crop: ly_swapchain.crop,
+            dst,
+        });
+    }
+    (composed, images)
 }
 
 #[named]
 unsafe fn capture(
     ash_device: &ash::Device,
+    queues: &[vk::Queue],
+    src_queue: vk::Queue,
     src_queue_family_index: u32,
     present_info: &vk::PresentInfoKHR,
+    khr_semaphore_fd: Option<&khr::ExternalSemaphoreFd>,
+    frames: &mut Vec<PendingFrame>,
 ) -> Vec<vk::Semaphore> {
     let &vk::PresentInfoKHR {
         p_swapchains,
@@ -1693,25 +6472,120 @@ unsafe fn capture(
     let image_indices = slice::from_raw_parts(p_image_indices, swapchain_count as _);
     let wait_semaphores_old = slice::from_raw_parts(p_wait_semaphores, wait_semaphore_count as _);
 
-    let mut wait_semaphores_new = vec![];
+    // semaphores of other devices can't be waited on by the copy, which goes without them at the
+    // risk of a torn frame, the present still waits on them as the app asked
+    let (wait_semaphores_old, wait_semaphores_foreign): (Vec<_>, Vec<_>) =
+        wait_semaphores_old.iter().copied().partition(|semaphore| {
+            SEMAPHORE_MAP
+                .get(semaphore)
+                .map_or(true, |v| v.device == ash_device.handle())
+        });
+    if !wait_semaphores_foreign.is_empty() {
+        warn!(
+            "present waits on semaphores of other devices: {:?}",
+            wait_semaphores_foreign
+        );
+    }
+
+    let wait_families = wait_semaphores_old
+        .iter()
+        .map(|semaphore| {
+            SEMAPHORE_MAP
+                .get(semaphore)
+                .map_or(vk::QUEUE_FAMILY_IGNORED, |v| {
+                    v.signal_family.load(atomic::Ordering::Relaxed)
+                })
+        })
+        .collect::<Vec<_>>();
+
+    // binary semaphores can only be waited on once, copies of several swapchains chain on each
+    // other instead of all waiting on the app's semaphores
+    let mut chain = PresentWaitChain::new(wait_semaphores_old);
+
+    // capturing a swapchain twice in one present would race on its image and export data
+    let unique = unique_swapchain_indices(swapchains);
+    if unique.len() != swapchains.len() {
+        warn!("present lists swapchains more than once: {:?}", swapchains);
+    }
+
+    // copies only wait on binary semaphores, timeline ones are waited on for the last values the
+    // app signaled them with first, the copies and the present go on from there
+    let timeline = chain
+        .waits()
+        .iter()
+        .any(|semaphore| SEMAPHORE_MAP.get(semaphore).map_or(false, |v| v.timeline));
+    if timeline {
+        let Some(&first) = unique.first() else {
+            return vec![];
+        };
+        match bridge_timeline_waits(
+            ash_device,
+            src_queue,
+            swapchains[first],
+            image_indices[first] as _,
+            chain.waits(),
+        ) {
+            Ok(semaphore) => chain.captured(vec![semaphore]),
+            Err(e) => {
+                warn!("failed to wait on timeline semaphores, capture skipped: {e:?}");
+                return vec![];
+            }
+        }
+    }
 
-    for i in 0..swapchains.len() {
+    let (composed, composed_images) =
+        compose_swapchains(swapchains, image_indices, &unique, src_queue_family_index);
+
+    let regions = present_regions(present_info);
+    for (n, i) in unique.into_iter().enumerate() {
+        // skipped presents count too, damage is relative to the last captured frame
+        if let Some(ly_swapchain) = SWAPCHAIN_MAP.get(&swapchains[i]) {
+            // an empty list means the whole image changed
+            let rects = regions
+                .get(i)
+                .filter(|v| v.rectangle_count > 0 && !v.p_rectangles.is_null())
+                .map(|v| slice::from_raw_parts(v.p_rectangles, v.rectangle_count as _));
+            if let Ok(mut damage) = ly_swapchain.damage.lock() {
+                accumulate_damage(&mut damage, rects);
+            }
+        }
+        // copied with the first swapchain of the present
+        if composed.contains(&i) {
+            continue;
+        }
+        // exclusive images are released by the family that wrote them last, some apps present
+        // what another family rendered without transferring it to the present family
+        let source = source_queue(
+            queues,
+            swapchains[i],
+            src_queue,
+            src_queue_family_index,
+            &wait_families,
+        );
+        let mut readback = None;
         let res = capture_swapchain(
             ash_device,
             swapchains[i],
             image_indices[i] as _,
+            src_queue,
             src_queue_family_index,
-            wait_semaphores_old,
+            source,
+            chain.waits(),
+            khr_semaphore_fd,
+            if n == 0 { &composed_images[..] } else { &[] },
+            frames,
+            &mut readback,
         );
-        match res {
-            Ok(Some(v)) => wait_semaphores_new.extend(&v),
-            Err(e) => {
-                error!("failed to capture swapchain: {e:?}");
-                continue;
-            }
-            _ => continue,
+        if let Err(e) = &res {
+            error!("failed to capture swapchain: {e:?}");
+        }
+        // a copy after the readbacks waited on them in turn, the present waits on either
+        match (res, readback) {
+            (Ok(Some(v)), _) => chain.captured(v),
+            (_, Some(semaphore)) => chain.captured(vec![semaphore]),
+            _ => {}
         }
     }
 
-    wait_semaphores_new
+    chain.present_waits(&wait_semaphores_foreign)
 }
@@ -0,0 +1,708 @@
+use crate::utils::*;
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::{env, fs, io};
+
+use function_name::named;
+
+use pw_capture_client::{AlphaMode, Format, Transfer};
+
+/// how command buffers recording copies are reset
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CommandReset {
+    /// reset each buffer before recording
+    #[default]
+    Buffer,
+    /// reset the whole pool once a buffer is about to be recorded again
+    Pool,
+}
+
+impl CommandReset {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "buffer" => Some(Self::Buffer),
+            "pool" => Some(Self::Pool),
+            _ => None,
+        }
+    }
+}
+
+/// transport of stream buffers
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BufferType {
+    /// DMA-BUF offered first, memfd after it
+    #[default]
+    Auto,
+    DmaBuf,
+    Memfd,
+    /// memfd mapped into `SPA_DATA_MemPtr` buffers with inline pixels, for small frames
+    MemPtr,
+}
+
+impl BufferType {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "auto" => Some(Self::Auto),
+            "dmabuf" | "dma-buf" => Some(Self::DmaBuf),
+            "memfd" => Some(Self::Memfd),
+            "memptr" | "mem-ptr" => Some(Self::MemPtr),
+            _ => None,
+        }
+    }
+}
+
+/// `straight` or `premultiplied`, the alpha mode consumers expect of RGB frames
+pub fn parse_alpha_mode(value: &str) -> Option<AlphaMode> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "straight" => Some(AlphaMode::Straight),
+        "premultiplied" => Some(AlphaMode::Premultiplied),
+        _ => None,
+    }
+}
+
+/// operator mapping HDR frames to SDR
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tonemap {
+    /// extended Reinhard, mapping 1000 nits to white
+    Reinhard,
+    /// the ACES filmic curve fit of Krzysztof Narkowicz
+    Aces,
+}
+
+impl Tonemap {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "reinhard" => Some(Self::Reinhard),
+            "aces" => Some(Self::Aces),
+            _ => None,
+        }
+    }
+}
+
+/// region of swapchain images to capture, `X,Y,WIDTHxHEIGHT`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CropRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl CropRect {
+    pub fn parse(value: &str) -> Option<Self> {
+        let (x, rest) = value.trim().split_once(',')?;
+        let (y, size) = rest.split_once(',')?;
+        let (width, height) = size.split_once('x')?;
+        let crop = Self {
+            x: x.trim().parse().ok()?,
+            y: y.trim().parse().ok()?,
+            width: width.trim().parse().ok()?,
+            height: height.trim().parse().ok()?,
+        };
+        (crop.width > 0 && crop.height > 0).then_some(crop)
+    }
+
+    /// the part inside an image of `width`x`height`, `None` if there's none
+    pub fn clamp(&self, width: u32, height: u32) -> Option<Self> {
+        if self.x >= width || self.y >= height {
+            return None;
+        }
+        Some(Self {
+            width: self.width.min(width - self.x),
+            height: self.height.min(height - self.y),
+            ..*self
+        })
+    }
+}
+
+/// largest size of streams, `WIDTHxHEIGHT`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Resolution {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Resolution {
+    pub fn parse(value: &str) -> Option<Self> {
+        let (width, height) = value.trim().split_once('x')?;
+        let resolution = Self {
+            width: width.trim().parse().ok()?,
+            height: height.trim().parse().ok()?,
+        };
+        (resolution.width > 0 && resolution.height > 0).then_some(resolution)
+    }
+
+    /// `width`x`height` downscaled to fit, keeping the aspect ratio, never upscaled
+    pub fn fit(&self, width: u32, height: u32) -> (u32, u32) {
+        if width <= self.width && height <= self.height {
+            return (width, height);
+        }
+        let (max_width, max_height) = (self.width as u64, self.height as u64);
+        let (width, height) = (width as u64, height as u64);
+        let (width, height) = if max_width * height <= max_height * width {
+            (max_width, height * max_width / width)
+        } else {
+            (width * max_height / height, max_height)
+        };
+        (width.max(1) as u32, height.max(1) as u32)
+    }
+}
+
+/// limit of `PW_CAPTURE_PTS_OFFSET_MS` either way
+pub const MAX_PTS_OFFSET_MS: i32 = 10_000;
+
+#[derive(Clone, Debug, Default)]
+pub struct LayerConfig {
+    /// only capture surfaces holding the input focus, `PW_CAPTURE_FOCUSED_ONLY`
+    pub focused_only: bool,
+    /// prefer offering 10-bit formats for 8-bit swapchains, `PW_CAPTURE_PREFER_10BIT`
+    pub prefer_10bit: bool,
+    /// capture every Nth present of a swapchain, `PW_CAPTURE_EVERY_N`
+    pub capture_every_n: u32,
+    /// text burned into captured frames, `PW_CAPTURE_WATERMARK`
+    pub watermark: Option<String>,
+    /// `PW_CAPTURE_WATERMARK_CORNER`
+    pub watermark_corner: WatermarkCorner,
+    /// let consumers pull frames instead of driving the graph, only presents after a pull are
+    /// captured, `PW_CAPTURE_PULL`
+    pub pull: bool,
+    /// `SRGB` or `UNORM` to convert frames of sRGB/UNORM swapchains, `PW_CAPTURE_ENCODING`
+    pub encoding: Option<Transfer>,
+    /// log negotiation state of a swapchain on every change, `PW_CAPTURE_DUMP_NEGOTIATION`
+    pub dump_negotiation: bool,
+    /// delay after the stream starts streaming before capturing, `PW_CAPTURE_WARMUP_MS`
+    pub warmup_ms: u32,
+    /// offer modifiers with compression metadata planes, `PW_CAPTURE_COMPRESSED_MODIFIERS`
+    pub compressed_modifiers: bool,
+    /// fixed-rate compression of export images where supported, `PW_CAPTURE_FIXED_RATE`
+    pub fixed_rate_compression: bool,
+    /// apply workarounds of known driver issues, `PW_CAPTURE_DRIVER_QUIRKS`
+    pub driver_quirks: bool,
+    /// stream buffers carry only meta, no pixels are copied, `PW_CAPTURE_METADATA_ONLY`
+    pub metadata_only: bool,
+    /// `PW_CAPTURE_COMMAND_RESET`
+    pub command_reset: CommandReset,
+    /// list and toggle swapchain captures over a unix socket, `PW_CAPTURE_CONTROL_SOCKET`
+    pub control_socket: bool,
+    /// capture presents evenly spaced at this rate, 0 to disable, `PW_CAPTURE_TARGET_FPS`
+    pub target_fps: u32,
+    /// log GPU time of each copy from timestamp queries, `PW_CAPTURE_GPU_TIMING`
+    pub gpu_timing: bool,
+    /// hint consumers that no frame was captured for this long, 0 to disable,
+    /// `PW_CAPTURE_STATIC_TIMEOUT_MS`
+    pub static_timeout_ms: u32,
+    /// `PW_CAPTURE_CROP`
+    pub crop: Option<CropRect>,
+    /// downscale frames larger than this, `PW_CAPTURE_RESOLUTION`
+    pub resolution: Option<Resolution>,
+    /// added to frame timestamps, may be negative, `PW_CAPTURE_PTS_OFFSET_MS`
+    pub pts_offset_ms: i32,
+    /// also offer interleaved frames, 2:2 pulldown of captures, `PW_CAPTURE_INTERLACED`
+    pub interlaced: bool,
+    /// drop frames waiting longer than this for the consumer, 0 to disable,
+    /// `PW_CAPTURE_MAX_LATENCY_MS`
+    pub max_latency_ms: u32,
+    /// `PW_CAPTURE_BUFFER_TYPE`
+    pub buffer_type: BufferType,
+    /// add cursor position and bitmap to frames, `PW_CAPTURE_CURSOR`
+    pub capture_cursor: bool,
+    /// formats offered first, in this order, `PW_CAPTURE_FORMAT_ORDER`
+    pub format_order: Vec<Format>,
+    /// log the calls made for the first N captured frames, `PW_CAPTURE_TRACE_FRAMES`
+    pub trace_frames: u32,
+    /// only capture on devices of this name or PCI bus id, `PW_CAPTURE_DEVICE`
+    pub device_filter: Option<String>,
+    /// keep frames for `me_eh5_pw_capture_poll_frame` instead of streaming, `PW_CAPTURE_POLL`
+    pub poll: bool,
+    /// only hook processes whose name contains one of these, `PW_CAPTURE_APP_ALLOW`
+    pub app_allow: Vec<String>,
+    /// never hook processes whose name contains one of these, `PW_CAPTURE_APP_DENY`
+    pub app_deny: Vec<String>,
+    /// hold back this many seconds of frames until `save-replay`, `PW_CAPTURE_REPLAY_SECONDS`
+    pub replay_seconds: u32,
+    /// also offer NV12, converted from frames by a compute kernel, `PW_CAPTURE_NV12`
+    pub nv12: bool,
+    /// convert alpha of RGB frames to this mode, `PW_CAPTURE_ALPHA_MODE`
+    pub alpha_mode: Option<AlphaMode>,
+    /// tonemap HDR frames to SDR BT.709 with this operator, `PW_CAPTURE_TONEMAP`
+    pub tonemap: Option<Tonemap>,
+    /// file of a 3x3 matrix or 1D LUT applied to frames, `PW_CAPTURE_COLOR_TRANSFORM`
+    pub color_transform: Option<PathBuf>,
+    /// also stream frames downscaled to fit this on a node of its own, `PW_CAPTURE_PREVIEW`
+    pub preview: Option<Resolution>,
+    /// also write frames to this v4l2loopback device, `PW_CAPTURE_V4L2_DEVICE`
+    pub v4l2_device: Option<PathBuf>,
+    /// copy swapchains presented together side by side into the frame of the first one,
+    /// `PW_CAPTURE_COMPOSE`
+    pub compose: bool,
+    /// capture images of swapchains never presented when the next one is acquired,
+    /// `PW_CAPTURE_ON_ACQUIRE`
+    pub capture_on_acquire: bool,
+}
+
+impl LayerConfig {
+    /// options of `config.toml`, overridden by environment variables
+    #[named]
+    pub fn load() -> Self {
+        let mut config = Self {
+            capture_every_n: 1,
+            compressed_modifiers: true,
+            driver_quirks: true,
+            capture_cursor: true,
+            ..Default::default()
+        };
+        if let Some(path) = config_file_path() {
+            match fs::read_to_string(&path) {
+                Ok(text) => match parse_config_file(&text) {
+                    Ok(options) => {
+                        let used = RefCell::new(HashSet::new());
+                        config.apply(&|name| {
+                            used.borrow_mut().insert(name.to_string());
+                            options.get(name).cloned()
+                        });
+                        let used = used.into_inner();
+                        for name in options.keys().filter(|name| !used.contains(*name)) {
+                            let key = name["PW_CAPTURE_".len()..].to_ascii_lowercase();
+                            warn!("unknown option {} in {:?}", key, path);
+                        }
+                    }
+                    Err(e) => warn!("ignoring malformed {:?}, {}", path, e),
+                },
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => warn!("failed to read {:?}: {e:?}", path),
+            }
+        }
+        config.apply(&|name| env::var(name).ok());
+        if cfg!(not(feature = "v4l2")) && config.v4l2_device.is_some() {
+            warn!("PW_CAPTURE_V4L2_DEVICE ignored, built without the v4l2 feature");
+        }
+        config
+    }
+
+    /// sets options `var` has a value for, named by their environment variable
+    fn apply(&mut self, var: Var) {
+        if let Some(v) = var_bool(var, "PW_CAPTURE_FOCUSED_ONLY") {
+            self.focused_only = v;
+        }
+        if let Some(v) = var_bool(var, "PW_CAPTURE_PREFER_10BIT") {
+            self.prefer_10bit = v;
+        }
+        if let Some(v) = var_u32(var, "PW_CAPTURE_EVERY_N") {
+            self.capture_every_n = v.max(1);
+        }
+        if let Some(v) = var("PW_CAPTURE_WATERMARK") {
+            self.watermark = Some(v).filter(|v| !v.is_empty());
+        }
+        if let Some(v) =
+            var_parsed(var, "PW_CAPTURE_WATERMARK_CORNER", WatermarkCorner::parse).flatten()
+        {
+            self.watermark_corner = v;
+        }
+        if let Some(v) = var_bool(var, "PW_CAPTURE_PULL") {
+            self.pull = v;
+        }
+        if let Some(v) = var_parsed(var, "PW_CAPTURE_ENCODING", parse_encoding) {
+            self.encoding = v;
+        }
+        if let Some(v) = var_bool(var, "PW_CAPTURE_DUMP_NEGOTIATION") {
+            self.dump_negotiation = v;
+        }
+        if let Some(v) = var_u32(var, "PW_CAPTURE_WARMUP_MS") {
+            self.warmup_ms = v;
+        }
+        if let Some(v) = var_bool(var, "PW_CAPTURE_COMPRESSED_MODIFIERS") {
+            self.compressed_modifiers = v;
+        }
+        if let Some(v) = var_bool(var, "PW_CAPTURE_FIXED_RATE") {
+            self.fixed_rate_compression = v;
+        }
+        if let Some(v) = var_bool(var, "PW_CAPTURE_DRIVER_QUIRKS") {
+            self.driver_quirks = v;
+        }
+        if let Some(v) = var_bool(var, "PW_CAPTURE_METADATA_ONLY") {
+            self.metadata_only = v;
+        }
+        if let Some(v) = var_parsed(var, "PW_CAPTURE_COMMAND_RESET", CommandReset::parse).flatten()
+        {
+            self.command_reset = v;
+        }
+        if let Some(v) = var_bool(var, "PW_CAPTURE_CONTROL_SOCKET") {
+            self.control_socket = v;
+        }
+        if let Some(v) = var_u32(var, "PW_CAPTURE_TARGET_FPS") {
+            self.target_fps = v;
+        }
+        if let Some(v) = var_bool(var, "PW_CAPTURE_GPU_TIMING") {
+            self.gpu_timing = v;
+        }
+        if let Some(v) = var_u32(var, "PW_CAPTURE_STATIC_TIMEOUT_MS") {
+            self.static_timeout_ms = v;
+        }
+        if let Some(v) = var_parsed(var, "PW_CAPTURE_CROP", CropRect::parse) {
+            self.crop = v;
+        }
+        if let Some(v) = var_parsed(var, "PW_CAPTURE_RESOLUTION", Resolution::parse) {
+            self.resolution = v;
+        }
+        if let Some(v) = var_i32(var, "PW_CAPTURE_PTS_OFFSET_MS") {
+            self.pts_offset_ms = v.clamp(-MAX_PTS_OFFSET_MS, MAX_PTS_OFFSET_MS);
+        }
+        if let Some(v) = var_bool(var, "PW_CAPTURE_INTERLACED") {
+            self.interlaced = v;
+        }
+        if let Some(v) = var_u32(var, "PW_CAPTURE_MAX_LATENCY_MS") {
+            self.max_latency_ms = v;
+        }
+        if let Some(v) = var_parsed(var, "PW_CAPTURE_BUFFER_TYPE", BufferType::parse).flatten() {
+            self.buffer_type = v;
+        }
+        if let Some(v) = var_bool(var, "PW_CAPTURE_CURSOR") {
+            self.capture_cursor = v;
+        }
+        if let Some(v) = var_parsed(var, "PW_CAPTURE_FORMAT_ORDER", parse_format_list) {
+            self.format_order = v.unwrap_or_default();
+        }
+        if let Some(v) = var_bool(var, "PW_CAPTURE_NV12") {
+            self.nv12 = v;
+        }
+        if let Some(v) = var_parsed(var, "PW_CAPTURE_ALPHA_MODE", parse_alpha_mode) {
+            self.alpha_mode = v;
+        }
+        if let Some(v) = var_parsed(var, "PW_CAPTURE_TONEMAP", Tonemap::parse) {
+            self.tonemap = v;
+        }
+        if let Some(v) = var("PW_CAPTURE_COLOR_TRANSFORM") {
+            self.color_transform = Some(v).filter(|v| !v.is_empty()).map(PathBuf::from);
+        }
+        if let Some(v) = var_parsed(var, "PW_CAPTURE_PREVIEW", Resolution::parse) {
+            self.preview = v;
+        }
+        if let Some(v) = var("PW_CAPTURE_V4L2_DEVICE") {
+            self.v4l2_device = Some(v).filter(|v| !v.is_empty()).map(PathBuf::from);
+        }
+        if let Some(v) = var_bool(var, "PW_CAPTURE_COMPOSE") {
+            self.compose = v;
+        }
+        if let Some(v) = var_bool(var, "PW_CAPTURE_ON_ACQUIRE") {
+            self.capture_on_acquire = v;
+        }
+        if let Some(v) = var_u32(var, "PW_CAPTURE_TRACE_FRAMES") {
+            self.trace_frames = v;
+        }
+        if let Some(v) = var("PW_CAPTURE_DEVICE") {
+            self.device_filter = Some(v).filter(|v| !v.trim().is_empty());
+        }
+        if let Some(v) = var_bool(var, "PW_CAPTURE_POLL") {
+            self.poll = v;
+        }
+        if let Some(v) = var("PW_CAPTURE_APP_ALLOW") {
+            self.app_allow = parse_name_list(&v);
+        }
+        if let Some(v) = var("PW_CAPTURE_APP_DENY") {
+            self.app_deny = parse_name_list(&v);
+        }
+        if let Some(v) = var_u32(var, "PW_CAPTURE_REPLAY_SECONDS") {
+            self.replay_seconds = v;
+        }
+    }
+
+    /// Whether a process known by `names`, e.g. its `argv[0]` and `comm`, is hooked. Deny
+    /// entries win over allow ones.
+    pub fn app_enabled(&self, names: &[&str]) -> bool {
+        let matches = |list: &[String]| {
+            list.iter()
+                .any(|v| names.iter().any(|name| name.contains(v.as_str())))
+        };
+        (self.app_allow.is_empty() || matches(&self.app_allow)) && !matches(&self.app_deny)
+    }
+}
+
+pub fn parse_bool(value: &str) -> Option<bool> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" | "" => Some(false),
+        _ => None,
+    }
+}
+
+type Var<'a> = &'a dyn Fn(&str) -> Option<String>;
+
+fn var_bool(var: Var, name: &str) -> Option<bool> {
+    parse_bool(&var(name)?)
+}
+
+fn var_u32(var: Var, name: &str) -> Option<u32> {
+    var(name)?.trim().parse().ok()
+}
+
+fn var_i32(var: Var, name: &str) -> Option<i32> {
+    var(name)?.trim().parse().ok()
+}
+
+fn parse_name_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Whether `filter` is part of the device name, ignoring case, or of the PCI bus id in
+/// `domain:bus:device.function` form, e.g. `01:00.0`.
+pub fn device_filter_matches(filter: &str, name: &str, pci_bus_id: Option<&str>) -> bool {
+    let filter = filter.trim().to_ascii_lowercase();
+    name.to_ascii_lowercase().contains(&filter)
+        || pci_bus_id.map_or(false, |id| id.to_ascii_lowercase().contains(&filter))
+}
+
+/// Value of `name` parsed with `parse`, `Some(None)` if it's empty, which unsets the option. An
+/// invalid value is logged and leaves the option as it was, like an unset variable.
+#[named]
+fn var_parsed<T>(var: Var, name: &str, parse: impl Fn(&str) -> Option<T>) -> Option<Option<T>> {
+    let value = var(name)?;
+    if value.trim().is_empty() {
+        return Some(None);
+    }
+    let parsed = parse(&value);
+    if parsed.is_none() {
+        warn!("invalid {} {:?}, keeping the previous value", name, value);
+    }
+    parsed.map(Some)
+}
+
+/// `$XDG_CONFIG_HOME/pw-capture/config.toml`, `~/.config` if unset
+fn config_file_path() -> Option<PathBuf> {
+    let dir = match env::var_os("XDG_CONFIG_HOME").filter(|v| !v.is_empty()) {
+        Some(v) => PathBuf::from(v),
+        None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join("pw-capture").join("config.toml"))
+}
+
+/// Parses `key = value` lines of a flat TOML file into options named by their environment
+/// variable, e.g. `target_fps = 60` into `PW_CAPTURE_TARGET_FPS`. Values are strings, numbers or
+/// booleans, tables and arrays aren't supported.
+pub fn parse_config_file(text: &str) -> Result<HashMap<String, String>, String> {
+    let mut options = HashMap::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(format!("line {}: expected `key = value`", i + 1));
+        };
+        let key = key.trim();
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("line {}: invalid key `{}`", i + 1, key));
+        }
+        let value = value.trim();
+        let value = if let Some(quoted) = value.strip_prefix('"') {
+            let Some((value, rest)) = quoted.split_once('"') else {
+                return Err(format!("line {}: unterminated string", i + 1));
+            };
+            let rest = rest.trim_start();
+            if !rest.is_empty() && !rest.starts_with('#') {
+                return Err(format!("line {}: unexpected `{}`", i + 1, rest));
+            }
+            value
+        } else {
+            let value = value.split_once('#').map_or(value, |(v, _)| v).trim();
+            if value.is_empty() || value.starts_with(['[', '{', '\'']) {
+                return Err(format!("line {}: unsupported value `{}`", i + 1, value));
+            }
+            value
+        };
+        let name = format!("PW_CAPTURE_{}", key.to_ascii_uppercase());
+        options.insert(name, value.to_string());
+    }
+    Ok(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::Path;
+
+    #[test]
+    fn bool_value() {
+        assert_eq!(parse_bool("1"), Some(true));
+        assert_eq!(parse_bool(" True "), Some(true));
+        assert_eq!(parse_bool("off"), Some(false));
+        assert_eq!(parse_bool(""), Some(false));
+        assert_eq!(parse_bool("maybe"), None);
+    }
+
+    #[test]
+    fn buffer_type() {
+        assert_eq!(BufferType::parse(" DMA-BUF"), Some(BufferType::DmaBuf));
+        assert_eq!(BufferType::parse("memptr"), Some(BufferType::MemPtr));
+        assert_eq!(BufferType::parse("shm"), None);
+    }
+
+    #[test]
+    fn alpha_mode() {
+        assert_eq!(parse_alpha_mode(" Straight"), Some(AlphaMode::Straight));
+        assert_eq!(
+            parse_alpha_mode("premultiplied"),
+            Some(AlphaMode::Premultiplied)
+        );
+        assert_eq!(parse_alpha_mode("opaque"), None);
+    }
+
+    #[test]
+    fn tonemap() {
+        assert_eq!(Tonemap::parse(" ACES"), Some(Tonemap::Aces));
+        assert_eq!(Tonemap::parse("reinhard"), Some(Tonemap::Reinhard));
+        assert_eq!(Tonemap::parse("hable"), None);
+    }
+
+    #[test]
+    fn crop() {
+        let crop = CropRect::parse("100, 50, 640x360").unwrap();
+        assert_eq!(
+            crop,
+            CropRect {
+                x: 100,
+                y: 50,
+                width: 640,
+                height: 360
+            }
+        );
+        assert_eq!(CropRect::parse("0,0,0x360"), None);
+        assert_eq!(CropRect::parse("0,0,640"), None);
+
+        assert_eq!(crop.clamp(1920, 1080), Some(crop));
+        let clamped = crop.clamp(400, 300).unwrap();
+        assert_eq!((clamped.width, clamped.height), (300, 250));
+        assert_eq!(crop.clamp(100, 1080), None);
+    }
+
+    #[test]
+    fn resolution() {
+        let resolution = Resolution::parse("1280x720").unwrap();
+        assert_eq!(Resolution::parse("1280"), None);
+        assert_eq!(Resolution::parse("0x720"), None);
+
+        assert_eq!(resolution.fit(3840, 2160), (1280, 720));
+        // ultrawide is limited by width, 16:10 by height
+        assert_eq!(resolution.fit(3440, 1440), (1280, 535));
+        assert_eq!(resolution.fit(1920, 1200), (1152, 720));
+        // smaller ones aren't upscaled
+        assert_eq!(resolution.fit(1280, 720), (1280, 720));
+        assert_eq!(resolution.fit(800, 600), (800, 600));
+    }
+
+    #[test]
+    fn config_file() {
+        let options = parse_config_file(
+            r#"
+            # capture at 30fps
+            target_fps = 30
+            crop = "0, 0, 640x360" # top left
+            cursor = false
+            pts_offset_ms = -40
+            preview = "320x180"
+            v4l2_device = "/dev/video10"
+            "#,
+        )
+        .unwrap();
+        let get = |name: &str| options.get(name).map(String::as_str);
+        assert_eq!(get("PW_CAPTURE_TARGET_FPS"), Some("30"));
+        assert_eq!(get("PW_CAPTURE_CROP"), Some("0, 0, 640x360"));
+        assert_eq!(get("PW_CAPTURE_CURSOR"), Some("false"));
+        assert_eq!(get("PW_CAPTURE_PTS_OFFSET_MS"), Some("-40"));
+
+        let mut config = LayerConfig::default();
+        config.apply(&|name| options.get(name).cloned());
+        assert_eq!(config.target_fps, 30);
+        assert_eq!(config.crop.map(|crop| crop.width), Some(640));
+        assert_eq!(config.pts_offset_ms, -40);
+        assert_eq!(
+            config.preview,
+            Some(Resolution {
+                width: 320,
+                height: 180
+            })
+        );
+        assert_eq!(
+            config.v4l2_device.as_deref(),
+            Some(Path::new("/dev/video10"))
+        );
+
+        assert!(parse_config_file("[section]").is_err());
+        assert!(parse_config_file("target_fps").is_err());
+        assert!(parse_config_file("crop = \"0,0,1x1").is_err());
+        assert!(parse_config_file("modifiers = [1, 2]").is_err());
+    }
+
+    #[test]
+    fn device_filter() {
+        let name = "AMD Radeon RX 7600 (RADV NAVI33)";
+        assert!(device_filter_matches("radeon", name, None));
+        assert!(device_filter_matches(" NAVI33 ", name, None));
+        assert!(!device_filter_matches("intel", name, None));
+        assert!(device_filter_matches("03:00.0", name, Some("0000:03:00.0")));
+        assert!(device_filter_matches(
+            "0000:03:00.0",
+            name,
+            Some("0000:03:00.0")
+        ));
+        assert!(!device_filter_matches(
+            "00:02.0",
+            name,
+            Some("0000:03:00.0")
+        ));
+    }
+
+    #[test]
+    fn app_filter() {
+        let names = ["/usr/bin/vkcube", "vkcube"];
+        let config = |allow: &str, deny: &str| LayerConfig {
+            app_allow: parse_name_list(allow),
+            app_deny: parse_name_list(deny),
+            ..Default::default()
+        };
+        assert!(config("", "").app_enabled(&names));
+        assert!(config("game, vkcube", "").app_enabled(&names));
+        assert!(!config("game", "").app_enabled(&names));
+        assert!(!config("", "steamwebhelper,cube").app_enabled(&names));
+        assert!(!config("vkcube", "/usr/bin").app_enabled(&names));
+    }
+
+    #[test]
+    fn invalid_values() {
+        let mut config = LayerConfig::default();
+        let valid = HashMap::from([
+            ("PW_CAPTURE_CROP", "0,0,640x360"),
+            ("PW_CAPTURE_TONEMAP", "aces"),
+            ("PW_CAPTURE_FORMAT_ORDER", "BGRx"),
+            ("PW_CAPTURE_BUFFER_TYPE", "memfd"),
+        ]);
+        config.apply(&|name| valid.get(name).map(|v| v.to_string()));
+
+        // e.g. typos in the environment keep the values of the config file
+        let invalid = HashMap::from([
+            ("PW_CAPTURE_CROP", "0,0,640"),
+            ("PW_CAPTURE_TONEMAP", "filmic"),
+            ("PW_CAPTURE_FORMAT_ORDER", "BGRx,BGRy"),
+            ("PW_CAPTURE_BUFFER_TYPE", "shm"),
+            ("PW_CAPTURE_RESOLUTION", "720p"),
+        ]);
+        config.apply(&|name| invalid.get(name).map(|v| v.to_string()));
+        assert_eq!(config.crop.map(|crop| crop.width), Some(640));
+        assert_eq!(config.tonemap, Some(Tonemap::Aces));
+        assert_eq!(config.format_order, [Format::BGRx]);
+        assert_eq!(config.buffer_type, BufferType::Memfd);
+        assert_eq!(config.resolution, None);
+
+        // empty ones unset them
+        config.apply(&|name| name.ends_with("_CROP").then(String::new));
+        assert_eq!(config.crop, None);
+        assert_eq!(config.tonemap, Some(Tonemap::Aces));
+    }
+}
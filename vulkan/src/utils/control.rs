@@ -0,0 +1,193 @@
+use crate::utils::*;
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::{env, fs, process, thread};
+
+use anyhow::{anyhow, Result};
+use function_name::named;
+use once_cell::sync::OnceCell;
+
+/// of the socket served, removed at exit
+static SOCKET_PATH: OnceCell<PathBuf> = OnceCell::new();
+
+/// command of a line received on the control socket
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControlCommand {
+    /// list swapchains, one per line
+    List,
+    /// resume capturing a swapchain, by its handle
+    Enable(u64),
+    /// stop capturing a swapchain, by its handle
+    Disable(u64),
+    /// send the frames held back in replay mode, by swapchain handle
+    SaveReplay(u64),
+    /// mark the whole next frame of a swapchain as damaged
+    FullFrame(u64),
+}
+
+impl ControlCommand {
+    pub fn parse(line: &str) -> Result<Self> {
+        let mut words = line.split_whitespace();
+        let command = words.next().ok_or(anyhow!("empty command"))?;
+        let handle = words.next().map(parse_handle).transpose()?;
+        if words.next().is_some() {
+            return Err(anyhow!("too many arguments"));
+        }
+        match (command, handle) {
+            ("list", None) => Ok(Self::List),
+            ("enable", Some(handle)) => Ok(Self::Enable(handle)),
+            ("disable", Some(handle)) => Ok(Self::Disable(handle)),
+            ("save-replay", Some(handle)) => Ok(Self::SaveReplay(handle)),
+            ("full-frame", Some(handle)) => Ok(Self::FullFrame(handle)),
+            ("enable" | "disable" | "save-replay" | "full-frame", None) => {
+                Err(anyhow!("{command} needs a swapchain"))
+            }
+            _ => Err(anyhow!("unknown command {line:?}")),
+        }
+    }
+}
+
+/// handle as listed, hex with `0x` prefix or decimal
+fn parse_handle(value: &str) -> Result<u64> {
+    let res = match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    res.map_err(|_| anyhow!("invalid swapchain {value:?}"))
+}
+
+/// `$XDG_RUNTIME_DIR/pw-capture-<pid>.sock`
+pub fn control_socket_path() -> Option<PathBuf> {
+    let dir = env::var_os("XDG_RUNTIME_DIR")?;
+    Some(Path::new(&dir).join(format!("pw-capture-{}.sock", process::id())))
+}
+
+extern "C" fn remove_control_socket() {
+    if let Some(path) = SOCKET_PATH.get() {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// Serves the control socket at `path` from a new thread, each client from a thread of its own
+/// so an idle one doesn't hold up others. Each line received is answered with the lines returned
+/// by `handle` followed by `ok`, or with `error: <reason>`. The socket file is removed at exit.
+#[named]
+pub fn spawn_control_socket<F>(path: PathBuf, handle: F) -> Result<()>
+where
+    F: Fn(ControlCommand) -> Result<Vec<String>> + Send + Sync + 'static,
+{
+    // left over by an earlier process of the same pid
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+    info!("control socket listening on {}", path.display());
+    if SOCKET_PATH.set(path).is_ok() {
+        unsafe { libc::atexit(remove_control_socket) };
+    }
+    let handle = Arc::new(handle);
+    thread::Builder::new()
+        .name("pw-capture-control".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(v) => v,
+                    Err(e) => {
+                        warn!("failed to accept control client: {e:?}");
+                        continue;
+                    }
+                };
+                let handle = handle.clone();
+                let res = thread::Builder::new()
+                    .name("pw-capture-control-client".to_string())
+                    .spawn(move || {
+                        if let Err(e) = serve_control_client(stream, &*handle) {
+                            debug!("control client error: {e:?}");
+                        }
+                    });
+                if let Err(e) = res {
+                    warn!("failed to spawn control client thread: {e:?}");
+                }
+            }
+        })?;
+    Ok(())
+}
+
+fn serve_control_client<F>(stream: UnixStream, handle: &F) -> Result<()>
+where
+    F: Fn(ControlCommand) -> Result<Vec<String>>,
+{
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match ControlCommand::parse(&line).and_then(handle) {
+            Ok(lines) => {
+                for line in lines {
+                    writeln!(writer, "{line}")?;
+                }
+                writeln!(writer, "ok")?;
+            }
+            Err(e) => writeln!(writer, "error: {e}")?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command() {
+        assert_eq!(
+            ControlCommand::parse(" list ").unwrap(),
+            ControlCommand::List
+        );
+        assert_eq!(
+            ControlCommand::parse("disable 0x5c0ffee").unwrap(),
+            ControlCommand::Disable(0x5c0ffee)
+        );
+        assert_eq!(
+            ControlCommand::parse("enable 42").unwrap(),
+            ControlCommand::Enable(42)
+        );
+        assert_eq!(
+            ControlCommand::parse("save-replay 0x10").unwrap(),
+            ControlCommand::SaveReplay(0x10)
+        );
+        assert_eq!(
+            ControlCommand::parse("full-frame 7").unwrap(),
+            ControlCommand::FullFrame(7)
+        );
+        assert!(ControlCommand::parse("enable").is_err());
+        assert!(ControlCommand::parse("save-replay").is_err());
+        assert!(ControlCommand::parse("full-frame").is_err());
+        assert!(ControlCommand::parse("list 1").is_err());
+        assert!(ControlCommand::parse("disable 0xzz").is_err());
+        assert!(ControlCommand::parse("stop 1").is_err());
+    }
+
+    #[test]
+    fn idle_client() {
+        let path = env::temp_dir().join(format!("pw-capture-test-{}.sock", process::id()));
+        spawn_control_socket(path.clone(), |_| Ok(vec!["listed".to_string()])).unwrap();
+
+        // connected without sending anything
+        let _idle = UnixStream::connect(&path).unwrap();
+        let mut client = UnixStream::connect(&path).unwrap();
+        writeln!(client, "list").unwrap();
+        let lines: Vec<_> = BufReader::new(client)
+            .lines()
+            .take(2)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(lines, ["listed", "ok"]);
+
+        remove_control_socket();
+        assert!(!path.exists());
+    }
+}
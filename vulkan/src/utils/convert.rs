@@ -0,0 +1,1020 @@
+use crate::utils::*;
+
+use core::ffi::CStr;
+use core::mem;
+use core::ptr;
+use std::io::Cursor;
+
+use anyhow::{anyhow, Result};
+use ash::prelude::VkResult;
+use ash::vk;
+use pw_capture_client::{AlphaMode, Format, Transfer};
+
+/// kernel converting frames to NV12 or RGBA, assembled from `convert.spvasm`
+static CONVERT_SPV: &[u8] = include_bytes!("../shaders/convert.spv");
+
+/// `LocalSize` of the kernel in both dimensions
+const CONVERT_GROUP_SIZE: u32 = 8;
+/// rows of all planes start at multiples of this, as importers of linear DMA-BUFs expect
+const STRIDE_ALIGN: u32 = 256;
+/// the frame is fetched from an `*_SRGB` image, which returns linear values
+const CONVERT_FLAG_SRGB: u32 = 1;
+const CONVERT_FLAG_UNPREMULTIPLY: u32 = 2;
+const CONVERT_FLAG_PREMULTIPLY: u32 = 4;
+/// RGBA is written in BGRA order
+const CONVERT_FLAG_BGRA: u32 = 8;
+/// HDR values are tonemapped to SDR, linear scRGB ones unless `CONVERT_FLAG_PQ` is set
+const CONVERT_FLAG_TONEMAP: u32 = 16;
+const CONVERT_FLAG_PQ: u32 = 32;
+/// primaries of HDR values are BT.2020 and converted to BT.709
+const CONVERT_FLAG_BT2020: u32 = 64;
+/// ACES is the operator instead of extended Reinhard
+const CONVERT_FLAG_ACES: u32 = 128;
+/// the `ColorTransform` of the pipeline is applied
+const CONVERT_FLAG_TRANSFORM: u32 = 256;
+/// `output` of the kernel
+const CONVERT_OUTPUT_NV12: u32 = 0;
+const CONVERT_OUTPUT_RGBA: u32 = 1;
+
+/// RGB formats offered instead of copied ones if frames need processing
+pub const CONVERTED_RGB_FORMATS: [Format; 2] = [Format::BGRA, Format::RGBA];
+
+/// formats frames are always converted to in a compute pass instead of copied into
+pub fn is_converted_format(format: Format) -> bool {
+    format == Format::NV12
+}
+
+/// entries of a LUT at most, as in `.cube` files
+const MAX_LUT_SIZE: usize = 65536;
+
+/// Color transform of `PW_CAPTURE_COLOR_TRANSFORM`, applied by the kernel to non-linear SDR
+/// values after any tonemapping. Results of both steps are clamped to [0, 1].
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColorTransform {
+    /// rows multiplied with RGB
+    pub matrix: [[f32; 3]; 3],
+    /// RGB of inputs spread evenly over [0, 1], interpolated linearly, at least 2 entries
+    pub lut: Vec<[f32; 3]>,
+}
+
+impl Default for ColorTransform {
+    fn default() -> Self {
+        Self {
+            matrix: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+            lut: vec![[0.0; 3], [1.0; 3]],
+        }
+    }
+}
+
+impl ColorTransform {
+    /// Parses a 1D LUT in the `.cube` format, `LUT_1D_SIZE` followed by its entries, or else a
+    /// matrix of 3 rows with 3 values each, separated by whitespace or commas. Lines starting
+    /// with `#` are comments.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let values = |line: &str| {
+            line.split(|c: char| c.is_whitespace() || c == ',')
+                .filter(|v| !v.is_empty())
+                .map(|v| v.parse::<f32>().ok().filter(|v| v.is_finite()))
+                .collect::<Option<Vec<_>>>()
+        };
+        let mut size = None;
+        let mut rows = vec![];
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line.starts_with(|c: char| c.is_ascii_alphabetic()) {
+                let (key, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+                match key {
+                    "LUT_1D_SIZE" => {
+                        size = Some(value.trim().parse::<usize>().map_err(|_| {
+                            format!("line {}: invalid LUT_1D_SIZE {:?}", i + 1, value.trim())
+                        })?);
+                    }
+                    "DOMAIN_MIN" | "DOMAIN_MAX" => {
+                        let bound = if key == "DOMAIN_MIN" { 0.0 } else { 1.0 };
+                        if !values(value).map_or(false, |v| v == [bound; 3]) {
+                            return Err(format!(
+                                "line {}: only a domain of 0 to 1 is supported",
+                                i + 1
+                            ));
+                        }
+                    }
+                    "TITLE" => (),
+                    "LUT_3D_SIZE" => return Err("3D LUTs aren't supported".to_string()),
+                    _ => return Err(format!("line {}: unknown keyword {}", i + 1, key)),
+                }
+                continue;
+            }
+            let row = values(line).ok_or(format!("line {}: invalid value", i + 1))?;
+            let row: [f32; 3] = row.try_into().map_err(|row: Vec<_>| {
+                format!("line {}: {} values, expected 3", i + 1, row.len())
+            })?;
+            rows.push(row);
+        }
+
+        let Some(size) = size else {
+            let matrix: [[f32; 3]; 3] = rows
+                .try_into()
+                .map_err(|rows: Vec<_>| format!("{} matrix rows, expected 3", rows.len()))?;
+            return Ok(Self {
+                matrix,
+                ..Default::default()
+            });
+        };
+        if !(2..=MAX_LUT_SIZE).contains(&size) {
+            return Err(format!("LUT_1D_SIZE {} out of 2 to {}", size, MAX_LUT_SIZE));
+        }
+        if rows.len() != size {
+            return Err(format!(
+                "{} LUT entries, LUT_1D_SIZE is {}",
+                rows.len(),
+                size
+            ));
+        }
+        Ok(Self {
+            lut: rows,
+            ..Default::default()
+        })
+    }
+
+    /// `transform` of `convert.spvasm`, the size of the LUT, the matrix and the LUT
+    fn to_words(&self) -> Vec<u32> {
+        let matrix = self.matrix.iter().flatten();
+        let lut = self.lut.iter().flatten();
+        core::iter::once(self.lut.len() as u32)
+            .chain(matrix.chain(lut).map(|v| v.to_bits()))
+            .collect()
+    }
+}
+
+/// HDR frames of a swapchain mapped to SDR BT.709 by the kernel
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tonemapping {
+    pub operator: Tonemap,
+    /// values are PQ encoded, linear scRGB otherwise
+    pub pq: bool,
+    /// primaries are BT.2020, BT.709 otherwise
+    pub bt2020: bool,
+}
+
+impl Tonemapping {
+    fn flags(&self) -> u32 {
+        let mut flags = CONVERT_FLAG_TONEMAP;
+        if self.pq {
+            flags |= CONVERT_FLAG_PQ;
+        }
+        if self.bt2020 {
+            flags |= CONVERT_FLAG_BT2020;
+        }
+        if self.operator == Tonemap::Aces {
+            flags |= CONVERT_FLAG_ACES;
+        }
+        flags
+    }
+}
+
+/// Processing of frames besides converting their format, of a swapchain. RGB frames are
+/// converted by the kernel instead of copied if any is needed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Processing {
+    /// of the swapchain
+    pub alpha_mode: AlphaMode,
+    /// of RGB frames, advertised for the stream
+    pub target_alpha_mode: AlphaMode,
+    /// of HDR frames, which are advertised as SDR then
+    pub tonemap: Option<Tonemapping>,
+    /// whether the `ColorTransform` of the pipeline is applied
+    pub color_transform: bool,
+}
+
+impl Processing {
+    /// `CONVERT_FLAG_*` of the processing of frames converted to `format`
+    fn flags(&self, format: Format) -> u32 {
+        // YUV frames have no alpha, colors are blended over black
+        let target_alpha_mode = if format.is_yuv() {
+            AlphaMode::Opaque
+        } else {
+            self.target_alpha_mode
+        };
+        let alpha = match (self.alpha_mode, target_alpha_mode) {
+            (AlphaMode::Premultiplied, AlphaMode::Straight) => CONVERT_FLAG_UNPREMULTIPLY,
+            (AlphaMode::Straight, AlphaMode::Premultiplied | AlphaMode::Opaque) => {
+                CONVERT_FLAG_PREMULTIPLY
+            }
+            _ => 0,
+        };
+        let mut flags = alpha | self.tonemap.map_or(0, |tonemap| tonemap.flags());
+        if self.color_transform {
+            flags |= CONVERT_FLAG_TRANSFORM;
+        }
+        flags
+    }
+
+    /// whether RGB frames have to be converted, only `CONVERTED_RGB_FORMATS` are offered then
+    pub fn converts_rgb(&self) -> bool {
+        self.flags(Format::BGRA) != 0
+    }
+}
+
+/// Push constants of the kernel, `Params` of `convert.spvasm`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConvertParams {
+    pub width: u32,
+    pub height: u32,
+    /// bytes between rows of both planes
+    pub stride: u32,
+    /// of the chroma plane in the buffer, 0 for RGBA
+    pub uv_offset: u32,
+    pub flags: u32,
+    pub output: u32,
+}
+
+impl ConvertParams {
+    /// Frames of `width`x`height` converted to `format` with `processing` from an image of
+    /// `src_format`, `None` if the kernel can't write `format`.
+    pub fn new(
+        format: Format,
+        width: u32,
+        height: u32,
+        src_format: vk::Format,
+        processing: Processing,
+    ) -> Option<Self> {
+        // the kernel writes blocks of 4x2 pixels, luma of NV12 in words of 4 pixels
+        let (output, pixel_size, flags) = match format {
+            Format::NV12 => (CONVERT_OUTPUT_NV12, 1, 0),
+            Format::RGBA => (CONVERT_OUTPUT_RGBA, 4, 0),
+            Format::BGRA => (CONVERT_OUTPUT_RGBA, 4, CONVERT_FLAG_BGRA),
+            _ => return None,
+        };
+        let stride = (width + 3) / 4 * 4 * pixel_size;
+        let stride = (stride + STRIDE_ALIGN - 1) / STRIDE_ALIGN * STRIDE_ALIGN;
+        let rows = (height + 1) / 2 * 2;
+        let mut flags = flags | processing.flags(format);
+        // tonemapped values are encoded with the sRGB curve anyway
+        if vk_format_get_transfer(src_format) == Transfer::SRGB && processing.tonemap.is_none() {
+            flags |= CONVERT_FLAG_SRGB;
+        }
+        Some(Self {
+            width,
+            height,
+            stride,
+            uv_offset: if output == CONVERT_OUTPUT_NV12 {
+                stride * rows
+            } else {
+                0
+            },
+            flags,
+            output,
+        })
+    }
+
+    fn rows(&self) -> u32 {
+        (self.height + 1) / 2 * 2
+    }
+
+    /// layouts of the planes in the buffer, luma and chroma for NV12
+    pub fn planes(&self) -> Vec<vk::SubresourceLayout> {
+        let plane = |offset: u32, size: u32| vk::SubresourceLayout {
+            offset: offset as _,
+            size: size as _,
+            row_pitch: self.stride as _,
+            ..Default::default()
+        };
+        if self.output == CONVERT_OUTPUT_NV12 {
+            vec![
+                plane(0, self.uv_offset),
+                plane(self.uv_offset, self.uv_offset / 2),
+            ]
+        } else {
+            vec![plane(0, self.stride * self.rows())]
+        }
+    }
+
+    /// bytes of all planes
+    pub fn size(&self) -> u64 {
+        self.planes()
+            .iter()
+            .map(|plane| plane.offset + plane.size)
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// workgroups dispatched, each invocation converts a block of 4x2 pixels
+    pub fn group_count(&self) -> (u32, u32) {
+        let blocks = ((self.width + 3) / 4, (self.height + 1) / 2);
+        (
+            (blocks.0 + CONVERT_GROUP_SIZE - 1) / CONVERT_GROUP_SIZE,
+            (blocks.1 + CONVERT_GROUP_SIZE - 1) / CONVERT_GROUP_SIZE,
+        )
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        [
+            self.width,
+            self.height,
+            self.stride,
+            self.uv_offset,
+            self.flags,
+            self.output,
+        ]
+        .iter()
+        .flat_map(|v| v.to_ne_bytes())
+        .collect()
+    }
+}
+
+/// Compute pipeline of the kernel, kept with the fixation.
+pub struct ConvertPipeline {
+    shader: vk::ShaderModule,
+    set_layout: vk::DescriptorSetLayout,
+    layout: vk::PipelineLayout,
+    pipeline: vk::Pipeline,
+    /// words of the `ColorTransform`, in host visible memory
+    transform_buffer: vk::Buffer,
+    transform_memory: vk::DeviceMemory,
+}
+
+impl ConvertPipeline {
+    /// Creates the pipeline applying `transform` to frames of `Processing::color_transform`,
+    /// an identity transform if `None`.
+    pub unsafe fn new(
+        ash_instance: &ash::Instance,
+        ash_device: &ash::Device,
+        phy_device: vk::PhysicalDevice,
+        transform: Option<&ColorTransform>,
+    ) -> Result<Self> {
+        let code = ash::util::read_spv(&mut Cursor::new(CONVERT_SPV))?;
+        let shader_info = vk::ShaderModuleCreateInfo::builder().code(&code);
+        let shader = ash_device.create_shader_module(&shader_info, None)?;
+
+        let bindings = [
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(0)
+                .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE)
+                .build(),
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(1)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE)
+                .build(),
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(2)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE)
+                .build(),
+        ];
+        let set_layout_info = vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings);
+        let set_layout = match ash_device.create_descriptor_set_layout(&set_layout_info, None) {
+            Ok(v) => v,
+            Err(e) => {
+                ash_device.destroy_shader_module(shader, None);
+                return Err(e.into());
+            }
+        };
+
+        let push_constant = vk::PushConstantRange::builder()
+            .stage_flags(vk::ShaderStageFlags::COMPUTE)
+            .offset(0)
+            .size(mem::size_of::<ConvertParams>() as _)
+            .build();
+        let set_layouts = [set_layout];
+        let push_constants = [push_constant];
+        let layout_info = vk::PipelineLayoutCreateInfo::builder()
+            .set_layouts(&set_layouts)
+            .push_constant_ranges(&push_constants);
+        let layout = match ash_device.create_pipeline_layout(&layout_info, None) {
+            Ok(v) => v,
+            Err(e) => {
+                ash_device.destroy_descriptor_set_layout(set_layout, None);
+                ash_device.destroy_shader_module(shader, None);
+                return Err(e.into());
+            }
+        };
+
+        let stage = vk::PipelineShaderStageCreateInfo::builder()
+            .stage(vk::ShaderStageFlags::COMPUTE)
+            .module(shader)
+            .name(CStr::from_bytes_with_nul(b"main\0").unwrap())
+            .build();
+        let pipeline_info = vk::ComputePipelineCreateInfo::builder()
+            .stage(stage)
+            .layout(layout)
+            .build();
+        let pipeline = match ash_device.create_compute_pipelines(
+            vk::PipelineCache::null(),
+            &[pipeline_info],
+            None,
+        ) {
+            Ok(v) => v[0],
+            Err((_, e)) => {
+                ash_device.destroy_pipeline_layout(layout, None);
+                ash_device.destroy_descriptor_set_layout(set_layout, None);
+                ash_device.destroy_shader_module(shader, None);
+                return Err(e.into());
+            }
+        };
+
+        let mut pipeline = Self {
+            shader,
+            set_layout,
+            layout,
+            pipeline,
+            transform_buffer: vk::Buffer::null(),
+            transform_memory: vk::DeviceMemory::null(),
+        };
+        let transform = transform.cloned().unwrap_or_default();
+        if let Err(e) = pipeline.upload_transform(ash_instance, ash_device, phy_device, &transform)
+        {
+            pipeline.destroy(ash_device);
+            return Err(e);
+        }
+        Ok(pipeline)
+    }
+
+    /// creates the buffer of `transform` the kernel reads
+    unsafe fn upload_transform(
+        &mut self,
+        ash_instance: &ash::Instance,
+        ash_device: &ash::Device,
+        phy_device: vk::PhysicalDevice,
+        transform: &ColorTransform,
+    ) -> Result<()> {
+        let words = transform.to_words();
+        let buffer_info = vk::BufferCreateInfo::builder()
+            .size((words.len() * mem::size_of::<u32>()) as _)
+            .usage(vk::BufferUsageFlags::STORAGE_BUFFER)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+        self.transform_buffer = ash_device.create_buffer(&buffer_info, None)?;
+        self.transform_memory = allocate_memory(
+            ash_instance,
+            ash_device,
+            phy_device,
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+            ash_device.get_buffer_memory_requirements(self.transform_buffer),
+        )?;
+        ash_device.bind_buffer_memory(self.transform_buffer, self.transform_memory, 0)?;
+        // written once, submissions make host writes before them visible
+        let mapped = ash_device.map_memory(
+            self.transform_memory,
+            0,
+            vk::WHOLE_SIZE,
+            vk::MemoryMapFlags::empty(),
+        )?;
+        ptr::copy_nonoverlapping(words.as_ptr(), mapped as *mut u32, words.len());
+        ash_device.unmap_memory(self.transform_memory);
+        Ok(())
+    }
+
+    pub unsafe fn destroy(&self, ash_device: &ash::Device) {
+        ash_device.destroy_buffer(self.transform_buffer, None);
+        ash_device.free_memory(self.transform_memory, None);
+        ash_device.destroy_pipeline(self.pipeline, None);
+        ash_device.destroy_pipeline_layout(self.layout, None);
+        ash_device.destroy_descriptor_set_layout(self.set_layout, None);
+        ash_device.destroy_shader_module(self.shader, None);
+    }
+
+    /// Records the conversion of the frame copied into the image of `target`, in `GENERAL`
+    /// layout and visible to compute shaders. The buffer is made visible to the host if
+    /// `host_read`.
+    pub unsafe fn record(
+        &self,
+        ash_device: &ash::Device,
+        command_buffer: vk::CommandBuffer,
+        target: &ConvertTarget,
+        host_read: bool,
+    ) {
+        ash_device.cmd_bind_pipeline(
+            command_buffer,
+            vk::PipelineBindPoint::COMPUTE,
+            self.pipeline,
+        );
+        ash_device.cmd_bind_descriptor_sets(
+            command_buffer,
+            vk::PipelineBindPoint::COMPUTE,
+            self.layout,
+            0,
+            &[target.descriptor_set],
+            &[],
+        );
+        ash_device.cmd_push_constants(
+            command_buffer,
+            self.layout,
+            vk::ShaderStageFlags::COMPUTE,
+            0,
+            &target.params.to_bytes(),
+        );
+        let (x, y) = target.params.group_count();
+        ash_device.cmd_dispatch(command_buffer, x, y, 1);
+        frame_trace(|| {
+            format!(
+                "vkCmdDispatch -> {:?}, {}x{} groups, {:?}",
+                target.buffer, x, y, target.params
+            )
+        });
+
+        let barrier = vk::BufferMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(if host_read {
+                vk::AccessFlags::HOST_READ
+            } else {
+                vk::AccessFlags::empty()
+            })
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .buffer(target.buffer)
+            .offset(0)
+            .size(vk::WHOLE_SIZE)
+            .build();
+        let dst_stage = if host_read {
+            vk::PipelineStageFlags::BOTTOM_OF_PIPE | vk::PipelineStageFlags::HOST
+        } else {
+            vk::PipelineStageFlags::BOTTOM_OF_PIPE
+        };
+        ash_device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            dst_stage,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[barrier],
+            &[],
+        );
+    }
+}
+
+/// Buffer the kernel writes the planes of an export image into, exported or read back in place
+/// of the image, which only holds the frame to convert.
+pub struct ConvertTarget {
+    pub params: ConvertParams,
+    pub buffer: vk::Buffer,
+    /// of the buffer, exported as DMA-BUF or mapped for memfd buffers
+    pub memory: vk::DeviceMemory,
+    view: vk::ImageView,
+    descriptor_pool: vk::DescriptorPool,
+    descriptor_set: vk::DescriptorSet,
+}
+
+impl ConvertTarget {
+    /// Creates the buffer converting the frames copied into `image` of `format`, in device local
+    /// memory exportable as DMA-BUF if `dma_buf`, in host visible memory otherwise.
+    #[allow(clippy::too_many_arguments)]
+    pub unsafe fn new(
+        ash_instance: &ash::Instance,
+        ash_device: &ash::Device,
+        phy_device: vk::PhysicalDevice,
+        pipeline: &ConvertPipeline,
+        image: vk::Image,
+        format: vk::Format,
+        params: ConvertParams,
+        dma_buf: bool,
+    ) -> Result<(Self, vk::MemoryPropertyFlags)> {
+        let mut external_info = vk::ExternalMemoryBufferCreateInfo::builder()
+            .handle_types(vk::ExternalMemoryHandleTypeFlags::DMA_BUF_EXT);
+        let mut buffer_info = vk::BufferCreateInfo::builder()
+            .size(params.size())
+            .usage(vk::BufferUsageFlags::STORAGE_BUFFER)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+        if dma_buf {
+            buffer_info = buffer_info.push_next(&mut external_info);
+        }
+        let buffer = ash_device.create_buffer(&buffer_info, None)?;
+
+        let requirements = ash_device.get_buffer_memory_requirements(buffer);
+        let indices = if dma_buf {
+            get_memory_type_indices(
+                ash_instance,
+                phy_device,
+                vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                requirements,
+            )
+        } else {
+            // cached memory is much faster to read from
+            let mut indices = get_memory_type_indices(
+                ash_instance,
+                phy_device,
+                vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_CACHED,
+                requirements,
+            );
+            indices.extend(get_memory_type_indices(
+                ash_instance,
+                phy_device,
+                vk::MemoryPropertyFlags::HOST_VISIBLE,
+                requirements,
+            ));
+            indices
+        };
+        let mut memory: VkResult<vk::DeviceMemory> = Err(vk::Result::ERROR_OUT_OF_DEVICE_MEMORY);
+        let mut memory_type_index = 0;
+        for i in indices {
+            let mut export_info = vk::ExportMemoryAllocateInfo::builder()
+                .handle_types(vk::ExternalMemoryHandleTypeFlags::DMA_BUF_EXT);
+            let mut memory_info = vk::MemoryAllocateInfo::builder()
+                .allocation_size(requirements.size)
+                .memory_type_index(i);
+            if dma_buf {
+                memory_info = memory_info.push_next(&mut export_info);
+            }
+            memory = ash_device.allocate_memory(&memory_info, None);
+            if memory.is_ok() {
+                memory_type_index = i;
+                break;
+            }
+        }
+        let memory = match memory.and_then(|memory| {
+            ash_device
+                .bind_buffer_memory(buffer, memory, 0)
+                .map(|_| memory)
+                .map_err(|e| {
+                    ash_device.free_memory(memory, None);
+                    e
+                })
+        }) {
+            Ok(v) => v,
+            Err(e) => {
+                ash_device.destroy_buffer(buffer, None);
+                return Err(e.into());
+            }
+        };
+        let memory_props = ash_instance
+            .get_physical_device_memory_properties(phy_device)
+            .memory_types[memory_type_index as usize]
+            .property_flags;
+
+        let mut target = Self {
+            params,
+            buffer,
+            memory,
+            view: vk::ImageView::null(),
+            descriptor_pool: vk::DescriptorPool::null(),
+            descriptor_set: vk::DescriptorSet::null(),
+        };
+        if let Err(e) = target.bind(ash_device, pipeline, image, format) {
+            target.destroy(ash_device);
+            return Err(e.into());
+        }
+        Ok((target, memory_props))
+    }
+
+    /// creates the view of `image` and the descriptor set binding it and the buffer
+    unsafe fn bind(
+        &mut self,
+        ash_device: &ash::Device,
+        pipeline: &ConvertPipeline,
+        image: vk::Image,
+        format: vk::Format,
+    ) -> VkResult<()> {
+        let view_info = vk::ImageViewCreateInfo::builder()
+            .image(image)
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(format)
+            .subresource_range(
+                vk::ImageSubresourceRange::builder()
+                    .aspect_mask(vk::ImageAspectFlags::COLOR)
+                    .level_count(1)
+                    .layer_count(1)
+                    .build(),
+            );
+        self.view = ash_device.create_image_view(&view_info, None)?;
+
+        let pool_sizes = [
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::SAMPLED_IMAGE,
+                descriptor_count: 1,
+            },
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::STORAGE_BUFFER,
+                descriptor_count: 2,
+            },
+        ];
+        let pool_info = vk::DescriptorPoolCreateInfo::builder()
+            .max_sets(1)
+            .pool_sizes(&pool_sizes);
+        self.descriptor_pool = ash_device.create_descriptor_pool(&pool_info, None)?;
+        let set_layouts = [pipeline.set_layout];
+        let set_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(self.descriptor_pool)
+            .set_layouts(&set_layouts);
+        self.descriptor_set = ash_device.allocate_descriptor_sets(&set_info)?[0];
+
+        let image_info = [vk::DescriptorImageInfo::builder()
+            .image_view(self.view)
+            .image_layout(vk::ImageLayout::GENERAL)
+            .build()];
+        let buffer_info = [vk::DescriptorBufferInfo::builder()
+            .buffer(self.buffer)
+            .offset(0)
+            .range(vk::WHOLE_SIZE)
+            .build()];
+        let transform_info = [vk::DescriptorBufferInfo::builder()
+            .buffer(pipeline.transform_buffer)
+            .offset(0)
+            .range(vk::WHOLE_SIZE)
+            .build()];
+        let writes = [
+            vk::WriteDescriptorSet::builder()
+                .dst_set(self.descriptor_set)
+                .dst_binding(0)
+                .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                .image_info(&image_info)
+                .build(),
+            vk::WriteDescriptorSet::builder()
+                .dst_set(self.descriptor_set)
+                .dst_binding(1)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(&buffer_info)
+                .build(),
+            vk::WriteDescriptorSet::builder()
+                .dst_set(self.descriptor_set)
+                .dst_binding(2)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(&transform_info)
+                .build(),
+        ];
+        ash_device.update_descriptor_sets(&writes, &[]);
+        Ok(())
+    }
+
+    /// frees the buffer, its memory and the descriptor set, mapped memory is unmapped when freed
+    pub unsafe fn destroy(&self, ash_device: &ash::Device) {
+        // destroying the pool frees its set
+        ash_device.destroy_descriptor_pool(self.descriptor_pool, None);
+        ash_device.destroy_image_view(self.view, None);
+        ash_device.destroy_buffer(self.buffer, None);
+        ash_device.free_memory(self.memory, None);
+    }
+}
+
+/// Creates the image frames are copied into before conversion, in the swapchain `format` and
+/// device local memory.
+pub unsafe fn create_convert_image(
+    ash_instance: &ash::Instance,
+    ash_device: &ash::Device,
+    phy_device: vk::PhysicalDevice,
+    format: vk::Format,
+    width: u32,
+    height: u32,
+) -> Result<(vk::Image, vk::DeviceMemory)> {
+    let image_info = vk::ImageCreateInfo::builder()
+        .image_type(vk::ImageType::TYPE_2D)
+        .format(format)
+        .extent(vk::Extent3D {
+            width,
+            height,
+            depth: 1,
+        })
+        .mip_levels(1)
+        .array_layers(1)
+        .samples(vk::SampleCountFlags::TYPE_1)
+        .tiling(vk::ImageTiling::OPTIMAL)
+        .usage(vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE);
+    let image = ash_device.create_image(&image_info, None)?;
+
+    let requirements = ash_device.get_image_memory_requirements(image);
+    let indices = get_memory_type_indices(
+        ash_instance,
+        phy_device,
+        vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        requirements,
+    );
+    let memory = indices.into_iter().find_map(|i| {
+        let memory_info = vk::MemoryAllocateInfo::builder()
+            .allocation_size(requirements.size)
+            .memory_type_index(i);
+        ash_device.allocate_memory(&memory_info, None).ok()
+    });
+    let Some(memory) = memory else {
+        ash_device.destroy_image(image, None);
+        return Err(anyhow!("no memory for the image to convert"));
+    };
+    if let Err(e) = ash_device.bind_image_memory(image, memory, 0) {
+        ash_device.destroy_image(image, None);
+        ash_device.free_memory(memory, None);
+        return Err(e.into());
+    }
+    Ok((image, memory))
+}
+
+/// whether frames of `format` can be copied into the image to convert, with `features` of its
+/// optimal tiling, and sampled from it
+pub fn format_convertible(
+    format: vk::Format,
+    features: vk::FormatFeatureFlags,
+    scaled: bool,
+    watermark: bool,
+) -> Option<bool> {
+    if !features.contains(vk::FormatFeatureFlags::SAMPLED_IMAGE) {
+        return None;
+    }
+    copy_needs_blit(format, format, features, scaled, watermark)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nv12_layout() {
+        let new = |width, height, src_format| {
+            ConvertParams::new(
+                Format::NV12,
+                width,
+                height,
+                src_format,
+                Processing::default(),
+            )
+            .unwrap()
+        };
+        let params = new(1920, 1080, vk::Format::B8G8R8A8_SRGB);
+        assert_eq!(params.stride, 2048);
+        assert_eq!(params.uv_offset, 2048 * 1080);
+        assert_eq!(params.flags, CONVERT_FLAG_SRGB);
+        let planes = params.planes();
+        let [luma, chroma] = planes[..] else {
+            panic!("{:?}", planes);
+        };
+        assert_eq!((luma.offset, luma.size), (0, 2048 * 1080));
+        assert_eq!((chroma.offset, chroma.size), (2048 * 1080, 2048 * 540));
+        assert_eq!(params.size(), chroma.offset + chroma.size);
+        // 480x270 blocks
+        assert_eq!(params.group_count(), (60, 34));
+
+        // odd sizes get a full row and word of padding
+        let params = new(257, 3, vk::Format::R8G8B8A8_UNORM);
+        assert_eq!(params.stride, 512);
+        assert_eq!(params.uv_offset, 512 * 4);
+        assert_eq!(params.flags, 0);
+        assert_eq!(params.group_count(), (9, 1));
+        assert_eq!(params.to_bytes().len(), mem::size_of::<ConvertParams>());
+    }
+
+    #[test]
+    fn rgba_layout() {
+        let processing = Processing {
+            alpha_mode: AlphaMode::Premultiplied,
+            target_alpha_mode: AlphaMode::Straight,
+            tonemap: None,
+            color_transform: false,
+        };
+        let params = ConvertParams::new(
+            Format::BGRA,
+            1366,
+            767,
+            vk::Format::B8G8R8A8_UNORM,
+            processing,
+        )
+        .unwrap();
+        assert_eq!(params.stride, 5632);
+        assert_eq!(params.output, CONVERT_OUTPUT_RGBA);
+        assert_eq!(params.flags, CONVERT_FLAG_BGRA | CONVERT_FLAG_UNPREMULTIPLY);
+        assert_eq!(params.planes().len(), 1);
+        assert_eq!(params.size(), 5632 * 768);
+        assert_eq!(
+            ConvertParams::new(Format::RGBx, 16, 16, vk::Format::B8G8R8A8_UNORM, processing),
+            None
+        );
+    }
+
+    #[test]
+    fn alpha() {
+        let processing = |alpha_mode, target_alpha_mode| Processing {
+            alpha_mode,
+            target_alpha_mode,
+            tonemap: None,
+            color_transform: false,
+        };
+        let premultiplied = processing(AlphaMode::Premultiplied, AlphaMode::Straight);
+        assert!(premultiplied.converts_rgb());
+        assert_eq!(
+            premultiplied.flags(Format::RGBA),
+            CONVERT_FLAG_UNPREMULTIPLY
+        );
+        // already blended over black
+        assert_eq!(premultiplied.flags(Format::NV12), 0);
+
+        let straight = processing(AlphaMode::Straight, AlphaMode::Straight);
+        assert!(!straight.converts_rgb());
+        assert_eq!(straight.flags(Format::NV12), CONVERT_FLAG_PREMULTIPLY);
+        let straight = processing(AlphaMode::Straight, AlphaMode::Premultiplied);
+        assert_eq!(straight.flags(Format::BGRA), CONVERT_FLAG_PREMULTIPLY);
+
+        assert!(!Processing::default().converts_rgb());
+        assert_eq!(Processing::default().flags(Format::NV12), 0);
+    }
+
+    #[test]
+    fn tonemap() {
+        let processing = Processing {
+            alpha_mode: AlphaMode::Opaque,
+            target_alpha_mode: AlphaMode::Opaque,
+            tonemap: Some(Tonemapping {
+                operator: Tonemap::Aces,
+                pq: true,
+                bt2020: true,
+            }),
+            color_transform: false,
+        };
+        assert!(processing.converts_rgb());
+        let params = ConvertParams::new(
+            Format::NV12,
+            3840,
+            2160,
+            vk::Format::A2B10G10R10_UNORM_PACK32,
+            processing,
+        )
+        .unwrap();
+        assert_eq!(
+            params.flags,
+            CONVERT_FLAG_TONEMAP | CONVERT_FLAG_PQ | CONVERT_FLAG_BT2020 | CONVERT_FLAG_ACES
+        );
+
+        // scRGB
+        let processing = Processing {
+            tonemap: Some(Tonemapping {
+                operator: Tonemap::Reinhard,
+                pq: false,
+                bt2020: false,
+            }),
+            ..processing
+        };
+        let params = ConvertParams::new(
+            Format::BGRA,
+            1920,
+            1080,
+            vk::Format::R16G16B16A16_SFLOAT,
+            processing,
+        )
+        .unwrap();
+        assert_eq!(params.flags, CONVERT_FLAG_BGRA | CONVERT_FLAG_TONEMAP);
+    }
+
+    #[test]
+    fn color_transform() {
+        let transform = ColorTransform::parse(
+            "# sepia\n0.393 0.769 0.189\n0.349, 0.686, 0.168\n\n0.272 0.534 0.131\n",
+        )
+        .unwrap();
+        assert_eq!(transform.matrix[1], [0.349, 0.686, 0.168]);
+        assert_eq!(transform.lut, ColorTransform::default().lut);
+        let words = transform.to_words();
+        assert_eq!(words.len(), 1 + 9 + 6);
+        assert_eq!((words[0], words[1]), (2, 0.393f32.to_bits()));
+
+        let transform = ColorTransform::parse(
+            "TITLE \"gamma\"\nLUT_1D_SIZE 3\nDOMAIN_MIN 0 0 0\n0 0 0\n0.25 0.5 0.5\n1 1 1\n",
+        )
+        .unwrap();
+        assert_eq!(transform.lut.len(), 3);
+        assert_eq!(transform.matrix, ColorTransform::default().matrix);
+
+        assert!(ColorTransform::parse("1 0 0\n0 1 0\n").is_err());
+        assert!(ColorTransform::parse("1 0 0 0\n0 1 0\n0 0 1\n").is_err());
+        assert!(ColorTransform::parse("1 0 0\n0 nan 0\n0 0 1\n").is_err());
+        assert!(ColorTransform::parse("LUT_1D_SIZE 3\n0 0 0\n1 1 1\n").is_err());
+        assert!(ColorTransform::parse("LUT_1D_SIZE 1\n0 0 0\n").is_err());
+        assert!(ColorTransform::parse("LUT_3D_SIZE 2\n").is_err());
+        assert!(ColorTransform::parse("DOMAIN_MAX 2 2 2\nLUT_1D_SIZE 2\n0 0 0\n1 1 1").is_err());
+
+        let processing = Processing {
+            color_transform: true,
+            ..Default::default()
+        };
+        assert!(processing.converts_rgb());
+        assert_eq!(processing.flags(Format::NV12), CONVERT_FLAG_TRANSFORM);
+    }
+
+    #[test]
+    fn kernel() {
+        assert_eq!(CONVERT_SPV.len() % 4, 0);
+        let code = ash::util::read_spv(&mut Cursor::new(CONVERT_SPV)).unwrap();
+        assert_eq!(code[0], 0x0723_0203);
+    }
+
+    #[test]
+    fn convertible() {
+        let format = vk::Format::B8G8R8A8_UNORM;
+        let sampled = vk::FormatFeatureFlags::SAMPLED_IMAGE;
+        let copy = vk::FormatFeatureFlags::TRANSFER_DST;
+        let blit = vk::FormatFeatureFlags::BLIT_DST;
+        assert_eq!(
+            format_convertible(format, sampled | copy, false, false),
+            Some(false)
+        );
+        assert_eq!(
+            format_convertible(format, sampled | copy | blit, true, false),
+            Some(true)
+        );
+        assert_eq!(format_convertible(format, copy | blit, false, false), None);
+        assert!(is_converted_format(Format::NV12));
+        assert!(!is_converted_format(Format::BGRA));
+    }
+}
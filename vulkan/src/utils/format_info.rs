@@ -70,23 +70,21 @@ pub const VK_FORMAT_INFO_TABLE: &[VkFormatInfo] = &[
     finfo!(BGRA_102LE, A2R10G10B10, UINT, _PACK32),
     finfo!(BGRA_102LE, A2R10G10B10, UNORM, _PACK32),
     finfo!(BGRA_102LE, A2R10G10B10, USCALED, _PACK32),
+    // RGB16/BGR16, 5:6:5 packed in 16-bit words, `DRM_FORMAT_RGB565`/`DRM_FORMAT_BGR565`
+    finfo!(RGB16, R5G6B5, UNORM, _PACK16),
+    finfo!(BGR16, B5G6R5, UNORM, _PACK16),
     // RGBA_F16
     finfo!(RGBA_F16, R16G16B16A16, SFLOAT),
     // RGBA_F32
     finfo!(RGBA_F32, R32G32B32A32, SFLOAT),
-    // GRAY16
+    // GRAY16, components of `R16` are in host order, there is no big-endian counterpart and
+    // neither copies nor blits swap bytes
     finfo!(GRAY16_LE, R16, SINT),
     finfo!(GRAY16_LE, R16, SNORM),
     finfo!(GRAY16_LE, R16, SSCALED),
     finfo!(GRAY16_LE, R16, UINT),
     finfo!(GRAY16_LE, R16, UNORM),
     finfo!(GRAY16_LE, R16, USCALED),
-    finfo!(GRAY16_BE, R16, SINT),
-    finfo!(GRAY16_BE, R16, SNORM),
-    finfo!(GRAY16_BE, R16, SSCALED),
-    finfo!(GRAY16_BE, R16, UINT),
-    finfo!(GRAY16_BE, R16, UNORM),
-    finfo!(GRAY16_BE, R16, USCALED),
     // GRAY8
     finfo!(GRAY8, R8, SINT),
     finfo!(GRAY8, R8, SNORM),
@@ -123,6 +121,61 @@ pub fn vk_format_get_transfer(vk_format: vk::Format) -> Transfer {
     Transfer::UNKNOWN
 }
 
+/// returns the widest color component bits, e.g. 10 for `A2B10G10R10_UNORM_PACK32`
+pub fn vk_format_component_bits(vk_format: vk::Format) -> u32 {
+    let format_name = format!("{:?}", vk_format);
+    let components = format_name.split('_').next().unwrap_or_default();
+    components
+        .split(|c: char| c.is_ascii_alphabetic())
+        .filter_map(|bits| bits.parse::<u32>().ok())
+        .max()
+        .unwrap_or(0)
+}
+
+/// transfer of exported frames, `encoding` overrides sRGB or linear UNORM swapchains having
+/// the same layout in the other encoding, frames are converted by blitting between the two
+pub fn export_transfer(src: &VkFormatInfo, encoding: Option<Transfer>) -> Transfer {
+    match (src.transfer, encoding) {
+        (Transfer::SRGB | Transfer::UNORM, Some(v @ (Transfer::SRGB | Transfer::UNORM)))
+            if client_format_get_info(src.format, v).vk_format != vk::Format::UNDEFINED =>
+        {
+            v
+        }
+        _ => src.transfer,
+    }
+}
+
+/// `encoding` of frames of a swapchain, see `export_transfer`. Zink swapchains are UNORM or sRGB
+/// views of the same sRGB encoded window contents, depending on the GL config, so blitting
+/// between the two would encode values twice or decode them, their frames are exported as is.
+pub fn swapchain_encoding(encoding: Option<Transfer>, is_zink: bool) -> Option<Transfer> {
+    encoding.filter(|_| !is_zink)
+}
+
+pub fn parse_encoding(value: &str) -> Option<Transfer> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "srgb" => Some(Transfer::SRGB),
+        "linear" | "unorm" => Some(Transfer::UNORM),
+        _ => None,
+    }
+}
+
+/// parses a comma separated list of formats named like `enum spa_video_format`, e.g. `BGRx,RGBA`
+pub fn parse_format_list(value: &str) -> Option<Vec<Format>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            // values past the last format map to `UNKNOWN`
+            (1..)
+                .map(Format::from)
+                .take_while(|format| *format != Format::UNKNOWN)
+                .find(|format| format!("{:?}", format).eq_ignore_ascii_case(name))
+        })
+        .collect()
+}
+
 pub fn vk_format_get_info(vk_format: vk::Format) -> VkFormatInfo {
     for info in VK_FORMAT_INFO_TABLE {
         if info.vk_format == vk_format {
@@ -153,7 +206,7 @@ pub fn client_format_get_info(format: Format, transfer: Transfer) -> VkFormatInf
 mod tests {
     use crate::utils::*;
     use ash::vk;
-    use pw_capture_client::Transfer;
+    use pw_capture_client::{Format, Transfer};
 
     #[test]
     fn get_transfer() {
@@ -175,4 +228,112 @@ mod tests {
             vk_format_get_transfer(vk::Format::G12X4_B12X4_R12X4_3PLANE_420_UNORM_3PACK16);
         assert_eq!(Transfer::UNORM, transfer);
     }
+
+    #[test]
+    fn encoding() {
+        assert_eq!(parse_encoding("sRGB"), Some(Transfer::SRGB));
+        assert_eq!(parse_encoding("linear"), Some(Transfer::UNORM));
+        assert_eq!(parse_encoding("auto"), None);
+        let linear = Some(Transfer::UNORM);
+        let srgb = vk_format_get_info(vk::Format::B8G8R8A8_SRGB);
+        assert_eq!(export_transfer(&srgb, linear), Transfer::UNORM);
+        assert_eq!(export_transfer(&srgb, None), Transfer::SRGB);
+        let uint = vk_format_get_info(vk::Format::B8G8R8A8_UINT);
+        assert_eq!(export_transfer(&uint, linear), Transfer::UINT);
+        // no 10-bit sRGB format
+        let unorm10 = vk_format_get_info(vk::Format::A2B10G10R10_UNORM_PACK32);
+        assert_eq!(
+            export_transfer(&unorm10, Some(Transfer::SRGB)),
+            Transfer::UNORM
+        );
+    }
+
+    #[test]
+    fn format_list() {
+        assert_eq!(
+            parse_format_list("nv12, BGRx,RGBA_102LE,"),
+            Some(vec![Format::NV12, Format::BGRx, Format::RGBA_102LE])
+        );
+        assert_eq!(parse_format_list(""), Some(vec![]));
+        assert_eq!(parse_format_list("BGRx,RGB24"), None);
+    }
+
+    #[test]
+    fn zink_encoding() {
+        let srgb = Some(Transfer::SRGB);
+        assert_eq!(swapchain_encoding(srgb, false), srgb);
+        // UNORM swapchains of Zink already hold sRGB values, exported with their format
+        assert_eq!(swapchain_encoding(srgb, true), None);
+        let unorm = vk_format_get_info(vk::Format::B8G8R8A8_UNORM);
+        assert_eq!(
+            export_transfer(&unorm, swapchain_encoding(srgb, true)),
+            Transfer::UNORM
+        );
+        assert_eq!(swapchain_encoding(None, false), None);
+    }
+
+    #[test]
+    fn rgb565() {
+        let info = vk_format_get_info(vk::Format::R5G6B5_UNORM_PACK16);
+        assert_eq!(Format::RGB16, info.format);
+        assert_eq!(Transfer::UNORM, info.transfer);
+        let info = client_format_get_info(Format::BGR16, Transfer::UNORM);
+        assert_eq!(vk::Format::B5G6R5_UNORM_PACK16, info.vk_format);
+        assert_eq!(6, vk_format_component_bits(info.vk_format));
+    }
+
+    /// channels of byte-aligned formats in memory order, e.g. `BGRA` for `B8G8R8A8_UNORM`
+    fn vk_byte_order(vk_format: vk::Format) -> Option<String> {
+        let name = format!("{:?}", vk_format);
+        if name.contains("_PACK") {
+            return None;
+        }
+        let components = name.split('_').next()?;
+        Some(
+            components
+                .chars()
+                .filter(char::is_ascii_alphabetic)
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn byte_order() {
+        for info in VK_FORMAT_INFO_TABLE {
+            let back = vk_format_get_info(info.vk_format);
+            assert_eq!(back.format, info.format, "{:?}", info);
+            let back = client_format_get_info(info.format, info.transfer);
+            assert_eq!(back.vk_format, info.vk_format, "{:?}", info);
+            match vk_byte_order(info.vk_format).as_deref() {
+                None => (),
+                Some("R") => assert!(
+                    matches!(info.format, Format::GRAY8 | Format::GRAY16_LE),
+                    "{:?}",
+                    info
+                ),
+                Some(order) => assert_eq!(order, format!("{:?}", info.format)),
+            }
+        }
+        // packed formats name components from the most significant bit
+        let info = vk_format_get_info(vk::Format::A2B10G10R10_UNORM_PACK32);
+        assert_eq!(Format::RGBA_102LE, info.format);
+        let info = vk_format_get_info(vk::Format::A2R10G10B10_UNORM_PACK32);
+        assert_eq!(Format::BGRA_102LE, info.format);
+        let info = client_format_get_info(Format::GRAY16_BE, Transfer::UNORM);
+        assert_eq!(vk::Format::UNDEFINED, info.vk_format);
+    }
+
+    #[test]
+    fn component_bits() {
+        assert_eq!(8, vk_format_component_bits(vk::Format::B8G8R8A8_SRGB));
+        assert_eq!(
+            10,
+            vk_format_component_bits(vk::Format::A2B10G10R10_UNORM_PACK32)
+        );
+        assert_eq!(
+            16,
+            vk_format_component_bits(vk::Format::R16G16B16A16_SFLOAT)
+        );
+        assert_eq!(0, vk_format_component_bits(vk::Format::UNDEFINED));
+    }
 }
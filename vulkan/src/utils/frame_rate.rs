@@ -0,0 +1,72 @@
+use std::collections::VecDeque;
+use std::time::Instant;
+
+/// ring of recent present timestamps
+#[derive(Debug)]
+pub struct FrameRate {
+    times: VecDeque<Instant>,
+    capacity: usize,
+}
+
+impl FrameRate {
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(2);
+        Self {
+            times: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, time: Instant) {
+        if self.times.len() == self.capacity {
+            self.times.pop_front();
+        }
+        self.times.push_back(time);
+    }
+
+    /// frames per second between the last two timestamps
+    pub fn instantaneous(&self) -> Option<f64> {
+        let mut it = self.times.iter().rev();
+        let last = it.next()?;
+        let prev = it.next()?;
+        fps(1, last.duration_since(*prev).as_secs_f64())
+    }
+
+    /// frames per second over the whole ring
+    pub fn average(&self) -> Option<f64> {
+        let first = self.times.front()?;
+        let last = self.times.back()?;
+        fps(
+            self.times.len() - 1,
+            last.duration_since(*first).as_secs_f64(),
+        )
+    }
+}
+
+fn fps(frames: usize, secs: f64) -> Option<f64> {
+    if frames == 0 || secs <= 0.0 {
+        return None;
+    }
+    Some(frames as f64 / secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn ring() {
+        let start = Instant::now();
+        let mut rate = FrameRate::new(4);
+        assert_eq!(rate.instantaneous(), None);
+        rate.push(start);
+        assert_eq!(rate.average(), None);
+        for ms in [10, 20, 30, 50] {
+            rate.push(start + Duration::from_millis(ms));
+        }
+        // oldest timestamp dropped, 3 frames over 40ms
+        assert!((rate.average().unwrap() - 75.0).abs() < 1e-6);
+        assert!((rate.instantaneous().unwrap() - 50.0).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,135 @@
+use crate::utils::*;
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use std::cell::RefCell;
+
+use ash::vk;
+
+/// frames traced so far, of all swapchains
+static TRACED: AtomicU32 = AtomicU32::new(0);
+
+thread_local! {
+    /// calls recorded for the frame captured on this thread
+    static CURRENT: RefCell<Option<Vec<String>>> = RefCell::new(None);
+}
+
+/// Calls of the layer for one captured frame, logged as one record once dropped.
+pub struct FrameTrace {
+    title: String,
+    index: u32,
+    limit: u32,
+}
+
+impl FrameTrace {
+    /// Starts recording calls made on this thread, `None` once `limit` frames were traced.
+    pub fn begin(limit: u32, title: impl FnOnce() -> String) -> Option<Self> {
+        if limit == 0 {
+            return None;
+        }
+        let index = TRACED
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| {
+                (v < limit).then_some(v + 1)
+            })
+            .ok()?;
+        CURRENT.with(|v| *v.borrow_mut() = Some(vec![]));
+        Some(Self {
+            title: title(),
+            index: index + 1,
+            limit,
+        })
+    }
+}
+
+impl Drop for FrameTrace {
+    fn drop(&mut self) {
+        let lines = CURRENT.with(|v| v.borrow_mut().take()).unwrap_or_default();
+        let mut text = format!("frame trace {}/{}, {}", self.index, self.limit, self.title);
+        for (i, line) in lines.iter().enumerate() {
+            text += &format!("\n  {:>3} {}", i, line);
+        }
+        info!(target: "frame_trace", "{}", text);
+    }
+}
+
+/// Adds a line to the trace of the frame captured on this thread, if any.
+pub fn frame_trace(line: impl FnOnce() -> String) {
+    CURRENT.with(|v| {
+        if let Some(lines) = v.borrow_mut().as_mut() {
+            lines.push(line());
+        }
+    });
+}
+
+/// Adds a `vkCmdPipelineBarrier` with its image barriers to the trace.
+pub fn frame_trace_barrier(
+    src_stage: vk::PipelineStageFlags,
+    dst_stage: vk::PipelineStageFlags,
+    barriers: &[vk::ImageMemoryBarrier],
+) {
+    frame_trace(|| {
+        let mut line = format!("vkCmdPipelineBarrier {:?} -> {:?}", src_stage, dst_stage);
+        for barrier in barriers {
+            line += &format!(
+                "\n        {:?}: {:?} -> {:?}, access {:?} -> {:?}, family {} -> {}",
+                barrier.image,
+                barrier.old_layout,
+                barrier.new_layout,
+                barrier.src_access_mask,
+                barrier.dst_access_mask,
+                queue_family_name(barrier.src_queue_family_index),
+                queue_family_name(barrier.dst_queue_family_index),
+            );
+        }
+        line
+    });
+}
+
+/// Adds a `vkQueueSubmit` of one batch to the trace.
+pub fn frame_trace_submit(
+    queue: vk::Queue,
+    command_buffers: &[vk::CommandBuffer],
+    waits: &[vk::Semaphore],
+    wait_stages: &[vk::PipelineStageFlags],
+    signals: &[vk::Semaphore],
+    signal_values: &[u64],
+    fence: vk::Fence,
+) {
+    frame_trace(|| {
+        format!(
+            "vkQueueSubmit {:?}, command buffers {:?}, wait {:?} at {:?}, signal {:?} values {:?}, \
+             fence {:?}",
+            queue, command_buffers, waits, wait_stages, signals, signal_values, fence
+        )
+    });
+}
+
+fn queue_family_name(index: u32) -> String {
+    if index == vk::QUEUE_FAMILY_IGNORED {
+        "ignored".to_string()
+    } else {
+        index.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines() -> Option<Vec<String>> {
+        CURRENT.with(|v| v.borrow().clone())
+    }
+
+    #[test]
+    fn limit() {
+        frame_trace(|| unreachable!("not tracing"));
+        assert!(FrameTrace::begin(0, String::new).is_none());
+
+        let trace = FrameTrace::begin(1, || "first".to_string()).unwrap();
+        frame_trace(|| "call".to_string());
+        assert_eq!(lines(), Some(vec!["call".to_string()]));
+        drop(trace);
+        assert_eq!(lines(), None);
+
+        assert!(FrameTrace::begin(1, String::new).is_none());
+    }
+}
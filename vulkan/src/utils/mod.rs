@@ -1,10 +1,34 @@
+mod config;
+mod control;
+mod convert;
 mod format_info;
+mod frame_rate;
+mod frame_trace;
 mod logger;
+mod negotiation;
+mod pacing;
+mod preview;
+mod quirks;
+#[cfg(feature = "v4l2")]
+mod v4l2;
 mod vk_helper;
+mod watermark;
 
+pub use config::*;
+pub use control::*;
+pub use convert::*;
 pub use format_info::*;
+pub use frame_rate::*;
+pub use frame_trace::*;
 pub use logger::*;
+pub use negotiation::*;
+pub use pacing::*;
+pub use preview::*;
+pub use quirks::*;
+#[cfg(feature = "v4l2")]
+pub use v4l2::*;
 pub use vk_helper::*;
+pub use watermark::*;
 
 use core::ffi::{c_ulong, c_void};
 
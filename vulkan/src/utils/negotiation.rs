@@ -0,0 +1,113 @@
+use core::fmt;
+
+use ash::vk;
+use pw_capture_client::EnumFormatInfo;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Fixated {
+    pub format: vk::Format,
+    pub modifier: Option<u64>,
+    pub need_blit: bool,
+}
+
+/// formats offered to and requested by the consumer of a swapchain, kept for diagnostics
+#[derive(Clone, Debug, Default)]
+pub struct Negotiation {
+    pub offered: Vec<EnumFormatInfo>,
+    /// formats of the last fixation
+    pub requested: Option<EnumFormatInfo>,
+    pub fixated: Option<Result<Fixated, String>>,
+}
+
+struct Modifiers<'a>(&'a [u64]);
+
+impl fmt::Display for Modifiers<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "none");
+        }
+        for (i, modifier) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{modifier:#x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Negotiation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "  offered:")?;
+        if self.offered.is_empty() {
+            writeln!(f, "    nothing, no exportable format")?;
+        }
+        for info in &self.offered {
+            writeln!(
+                f,
+                "    {:?} modifiers: {}",
+                info.formats,
+                Modifiers(&info.modifiers)
+            )?;
+        }
+        match &self.requested {
+            Some(info) => writeln!(
+                f,
+                "  requested: {:?} modifiers: {}",
+                info.formats,
+                Modifiers(&info.modifiers)
+            )?,
+            None => writeln!(f, "  requested: nothing yet")?,
+        }
+        match &self.fixated {
+            Some(Ok(fixated)) => write!(
+                f,
+                "  fixated: {:?} modifier: {}, {}",
+                fixated.format,
+                fixated
+                    .modifier
+                    .map_or("none".to_string(), |m| format!("{m:#x}")),
+                if fixated.need_blit { "blit" } else { "copy" }
+            ),
+            Some(Err(e)) => write!(f, "  fixation failed: {e}"),
+            None => write!(f, "  fixated: nothing yet"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pw_capture_client::Format;
+
+    #[test]
+    fn describe() {
+        let mut negotiation = Negotiation::default();
+        assert!(negotiation
+            .to_string()
+            .contains("nothing, no exportable format"));
+
+        negotiation.offered.push(EnumFormatInfo {
+            formats: vec![Format::BGRA, Format::RGBA],
+            modifiers: vec![0, 0x100000000000001],
+        });
+        negotiation.requested = Some(EnumFormatInfo {
+            formats: vec![Format::BGRA],
+            modifiers: vec![],
+        });
+        negotiation.fixated = Some(Ok(Fixated {
+            format: vk::Format::B8G8R8A8_UNORM,
+            modifier: Some(0),
+            need_blit: false,
+        }));
+        let text = negotiation.to_string();
+        assert!(text.contains("[BGRA, RGBA] modifiers: 0x0, 0x100000000000001"));
+        assert!(text.contains("requested: [BGRA] modifiers: none"));
+        assert!(text.contains("fixated: B8G8R8A8_UNORM modifier: 0x0, copy"));
+
+        negotiation.fixated = Some(Err("modifiers [] not compatible".to_string()));
+        assert!(negotiation
+            .to_string()
+            .contains("fixation failed: modifiers [] not compatible"));
+    }
+}
@@ -0,0 +1,67 @@
+use std::time::{Duration, Instant};
+
+/// Picks presents to capture at a fixed rate.
+///
+/// Capture slots are laid on a grid of `1 / fps` anchored at the first capture. A present is
+/// captured if it's the first one at most half an interval before the next slot, so the
+/// captured present is the closest to the slot among presents faster than the target rate.
+/// Slots missed by slower presents are skipped rather than caught up, the grid never drifts.
+#[derive(Debug)]
+pub struct Pacer {
+    interval: Duration,
+    next: Option<Instant>,
+}
+
+impl Pacer {
+    pub fn new(fps: u32) -> Self {
+        Self {
+            interval: Duration::from_secs(1) / fps.max(1),
+            next: None,
+        }
+    }
+
+    /// whether a present at `time` should be captured, advances to the next slot if so
+    pub fn should_capture(&mut self, time: Instant) -> bool {
+        let Some(slot) = self.next else {
+            self.next = Some(time + self.interval);
+            return true;
+        };
+        if time + self.interval / 2 <= slot {
+            return false;
+        }
+        let missed = time.saturating_duration_since(slot).as_nanos() / self.interval.as_nanos();
+        let slot = slot + self.interval * missed as u32;
+        self.next = Some(slot + self.interval);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn captured(fps: u32, present_interval: Duration, presents: u32) -> Vec<u32> {
+        let start = Instant::now();
+        let mut pacer = Pacer::new(fps);
+        (0..presents)
+            .filter(|&i| pacer.should_capture(start + present_interval * i))
+            .collect()
+    }
+
+    #[test]
+    fn pace() {
+        // 120Hz presents, every other one is on the 60Hz grid
+        let frames = captured(60, Duration::from_secs(1) / 120, 120);
+        assert_eq!(frames.len(), 60);
+        assert!(frames.iter().all(|i| i % 2 == 0));
+
+        // 144Hz presents, 2 or 3 apart, the last one is the closest to the slot at 1s
+        let frames = captured(60, Duration::from_secs(1) / 144, 144);
+        assert_eq!(frames.len(), 61);
+        assert!(frames.windows(2).all(|w| matches!(w[1] - w[0], 2 | 3)));
+
+        // slower presents are all captured, missed slots aren't caught up
+        let frames = captured(60, Duration::from_secs(1) / 40, 40);
+        assert_eq!(frames.len(), 40);
+    }
+}
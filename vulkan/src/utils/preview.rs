@@ -0,0 +1,130 @@
+use crate::utils::*;
+
+use core::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{anyhow, Result};
+use ash::vk;
+use ash::vk::Handle;
+use dashmap::DashMap;
+use pw_capture_client::{BufferInfo, BufferMemoryInfo, BufferPlaneInfo, BufferUserHandle, Stream};
+
+/// memfd a preview frame is read into
+struct PreviewBuffer {
+    fd: i32,
+    /// pointer, so `Preview` stays `Send`
+    ptr: usize,
+    size: usize,
+}
+
+impl PreviewBuffer {
+    unsafe fn free(&self) {
+        libc::munmap(self.ptr as _, self.size);
+        libc::close(self.fd);
+    }
+}
+
+/// Downscaled preview of a swapchain with `PW_CAPTURE_PREVIEW`, streamed on a node of its own.
+/// Presented images are read back into its memfd buffers like with `PW_CAPTURE_POLL`, one present
+/// behind, the copy is submitted before the full resolution copy of the same present.
+pub struct Preview {
+    pub stream: Stream,
+    pub readback: PollReadback,
+    /// buffers have no image, their handles are made up, counted from 1
+    buffers: DashMap<vk::Image, PreviewBuffer>,
+    next_handle: AtomicU64,
+}
+
+impl Preview {
+    pub fn new(stream: Stream, readback: PollReadback) -> Self {
+        Self {
+            stream,
+            readback,
+            buffers: DashMap::new(),
+            next_handle: AtomicU64::new(1),
+        }
+    }
+
+    /// Adds a memfd buffer holding a frame of the readback.
+    pub unsafe fn add_buffer(&self) -> Result<BufferInfo> {
+        let size = self.readback.size;
+        let (fd, ptr) = create_memfd(size)?;
+        let handle = vk::Image::from_raw(self.next_handle.fetch_add(1, Ordering::Relaxed));
+        self.buffers.insert(
+            handle,
+            PreviewBuffer {
+                fd,
+                ptr: ptr as usize,
+                size,
+            },
+        );
+        Ok(BufferInfo {
+            is_dma_buf: false,
+            planes: vec![BufferPlaneInfo {
+                fd: fd as _,
+                offset: 0,
+                size: size as _,
+                stride: self.readback.row_pitch as _,
+            }],
+            memory: BufferMemoryInfo {
+                device_local: false,
+                host_visible: true,
+            },
+            user_handle: BufferUserHandle::VkImage(handle),
+        })
+    }
+
+    pub unsafe fn remove_buffer(&self, handle: vk::Image) -> Result<()> {
+        let (_, buffer) = self
+            .buffers
+            .remove(&handle)
+            .ok_or(anyhow!("unknown preview buffer {:?}", handle))?;
+        buffer.free();
+        Ok(())
+    }
+
+    /// Copies `crop` of `src_image` and reads the frame of the previous present into the buffer
+    /// of `handle`, see `PollReadback::copy`. Returns whether the buffer was filled, it's not on
+    /// the first present.
+    #[allow(clippy::too_many_arguments)]
+    pub unsafe fn read(
+        &self,
+        device: &ash::Device,
+        queue: vk::Queue,
+        src_image: vk::Image,
+        image_index: usize,
+        crop: vk::Rect2D,
+        waits: &[vk::Semaphore],
+        handle: vk::Image,
+    ) -> Result<(vk::Semaphore, bool)> {
+        let buffer = self
+            .buffers
+            .get(&handle)
+            .ok_or(anyhow!("unknown preview buffer {:?}", handle))?;
+        let mut filled = false;
+        let semaphore = self.readback.copy(
+            device,
+            queue,
+            src_image,
+            image_index,
+            crop,
+            waits,
+            0,
+            |_, pixels| {
+                let size = pixels.len().min(buffer.size);
+                ptr::copy_nonoverlapping(pixels.as_ptr(), buffer.ptr as *mut u8, size);
+                filled = true;
+            },
+        )?;
+        Ok((semaphore, filled))
+    }
+
+    /// Frees buffers the stream didn't remove and the readback, once the stream is gone.
+    pub unsafe fn destroy(&self, device: &ash::Device) {
+        for buffer in self.buffers.iter() {
+            buffer.free();
+        }
+        self.buffers.clear();
+        self.readback.destroy(device);
+    }
+}
@@ -0,0 +1,74 @@
+use ash::vk;
+
+pub const DRM_FORMAT_MOD_LINEAR: u64 = 0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Workaround {
+    /// only offer `DRM_FORMAT_MOD_LINEAR`, tiled exports aren't importable by consumers
+    LinearOnly,
+}
+
+/// known issue of a driver before a version, `driverVersion` encoded
+///
+/// keyed by driver id rather than vendor id, Mesa drivers for the same hardware share the latter
+#[derive(Debug)]
+pub struct DriverQuirk {
+    pub driver_id: vk::DriverId,
+    pub below_version: u32,
+    pub description: &'static str,
+    pub workaround: Workaround,
+}
+
+/// `driverVersion` encoding of NVIDIA proprietary driver, 10.8.8.6 bits
+pub const fn nvidia_version(major: u32, minor: u32) -> u32 {
+    (major << 22) | (minor << 14)
+}
+
+pub static DRIVER_QUIRKS: &[DriverQuirk] = &[DriverQuirk {
+    driver_id: vk::DriverId::NVIDIA_PROPRIETARY,
+    below_version: nvidia_version(545, 0),
+    description: "block-linear DMA-BUF exports fail to import in EGL consumers",
+    workaround: Workaround::LinearOnly,
+}];
+
+/// quirks of the table applying to a driver
+pub fn find_driver_quirks(
+    driver_id: vk::DriverId,
+    driver_version: u32,
+) -> impl Iterator<Item = &'static DriverQuirk> {
+    DRIVER_QUIRKS
+        .iter()
+        .filter(move |quirk| quirk.driver_id == driver_id && driver_version < quirk.below_version)
+}
+
+/// whether exporting with `modifier` is allowed under `workarounds`
+pub fn modifier_allowed(workarounds: &[Workaround], modifier: u64) -> bool {
+    !workarounds.contains(&Workaround::LinearOnly) || modifier == DRM_FORMAT_MOD_LINEAR
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nvidia() {
+        let old = nvidia_version(535, 154) | (5 << 6);
+        let quirks = find_driver_quirks(vk::DriverId::NVIDIA_PROPRIETARY, old).collect::<Vec<_>>();
+        assert_eq!(quirks.len(), 1);
+        assert_eq!(quirks[0].workaround, Workaround::LinearOnly);
+
+        let new = nvidia_version(550, 67);
+        assert_eq!(
+            find_driver_quirks(vk::DriverId::NVIDIA_PROPRIETARY, new).count(),
+            0
+        );
+        assert_eq!(find_driver_quirks(vk::DriverId::MESA_RADV, old).count(), 0);
+
+        assert!(modifier_allowed(&[Workaround::LinearOnly], 0));
+        assert!(!modifier_allowed(
+            &[Workaround::LinearOnly],
+            0x300000000606014
+        ));
+        assert!(modifier_allowed(&[], 0x300000000606014));
+    }
+}
@@ -0,0 +1,360 @@
+use crate::utils::*;
+
+use core::mem;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Result};
+use ash::vk;
+use function_name::named;
+
+const V4L2_BUF_TYPE_VIDEO_OUTPUT: u32 = 2;
+const V4L2_CAP_VIDEO_OUTPUT: u32 = 0x2;
+const V4L2_CAP_DEVICE_CAPS: u32 = 0x8000_0000;
+const V4L2_FIELD_NONE: u32 = 1;
+const V4L2_COLORSPACE_REC709: u32 = 3;
+const V4L2_YCBCR_ENC_709: u32 = 2;
+const V4L2_QUANTIZATION_LIM_RANGE: u32 = 2;
+const V4L2_XFER_FUNC_709: u32 = 2;
+const V4L2_PIX_FMT_NV12: u32 = u32::from_le_bytes(*b"NV12");
+
+/// `_IOC` of `linux/ioctl.h` for the `'V'` type
+const fn v4l2_ioc(dir: u32, nr: u32, size: usize) -> u32 {
+    (dir << 30) | ((size as u32) << 16) | ((b'V' as u32) << 8) | nr
+}
+
+const VIDIOC_QUERYCAP: u32 = v4l2_ioc(2, 0, mem::size_of::<V4l2Capability>());
+const VIDIOC_S_FMT: u32 = v4l2_ioc(3, 5, mem::size_of::<V4l2Format>());
+
+/// `struct v4l2_capability`
+#[repr(C)]
+#[derive(Default)]
+struct V4l2Capability {
+    driver: [u8; 16],
+    card: [u8; 32],
+    bus_info: [u8; 32],
+    version: u32,
+    capabilities: u32,
+    device_caps: u32,
+    reserved: [u32; 3],
+}
+
+/// `struct v4l2_pix_format`
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct V4l2PixFormat {
+    pub width: u32,
+    pub height: u32,
+    pub pixelformat: u32,
+    pub field: u32,
+    pub bytesperline: u32,
+    pub sizeimage: u32,
+    pub colorspace: u32,
+    pub priv_: u32,
+    pub flags: u32,
+    pub ycbcr_enc: u32,
+    pub quantization: u32,
+    pub xfer_func: u32,
+}
+
+/// union of `struct v4l2_format`, 200 bytes aligned like pointers
+#[repr(C)]
+struct V4l2FormatData {
+    pix: V4l2PixFormat,
+    raw_data: [u8; 200 - mem::size_of::<V4l2PixFormat>()],
+    _align: [usize; 0],
+}
+
+/// `struct v4l2_format`
+#[repr(C)]
+struct V4l2Format {
+    type_: u32,
+    fmt: V4l2FormatData,
+}
+
+/// set while a `V4l2Device` is open, frames of several swapchains would be interleaved
+static IN_USE: AtomicBool = AtomicBool::new(false);
+
+/// Output device of v4l2loopback, NV12 frames of a fixed size are written to it. Only one is
+/// open at a time.
+pub struct V4l2Device {
+    file: File,
+    pub format: V4l2PixFormat,
+}
+
+impl V4l2Device {
+    /// whether a device is open, another can't be until it's dropped
+    pub fn in_use() -> bool {
+        IN_USE.load(Ordering::Acquire)
+    }
+
+    /// Opens the device at `path` and sets its format to NV12 of `width`x`height`, the driver
+    /// may adjust it, see `format`.
+    pub fn open(path: &Path, width: u32, height: u32) -> Result<Self> {
+        if IN_USE.swap(true, Ordering::AcqRel) {
+            return Err(anyhow!("a device is open already"));
+        }
+        let res = Self::open_unused(path, width, height);
+        if res.is_err() {
+            IN_USE.store(false, Ordering::Release);
+        }
+        res
+    }
+
+    fn open_unused(path: &Path, width: u32, height: u32) -> Result<Self> {
+        let file = OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK | libc::O_CLOEXEC)
+            .open(path)?;
+        let fd = file.as_raw_fd();
+
+        let mut caps = V4l2Capability::default();
+        if unsafe { libc::ioctl(fd, VIDIOC_QUERYCAP as _, &mut caps) } < 0 {
+            return Err(anyhow!(
+                "not a video device: {}",
+                io::Error::last_os_error()
+            ));
+        }
+        let caps = if caps.capabilities & V4L2_CAP_DEVICE_CAPS != 0 {
+            caps.device_caps
+        } else {
+            caps.capabilities
+        };
+        if caps & V4L2_CAP_VIDEO_OUTPUT == 0 {
+            return Err(anyhow!("not a video output device, caps: {:#x}", caps));
+        }
+
+        let mut format = V4l2Format {
+            type_: V4L2_BUF_TYPE_VIDEO_OUTPUT,
+            fmt: V4l2FormatData {
+                pix: V4l2PixFormat {
+                    width,
+                    height,
+                    pixelformat: V4L2_PIX_FMT_NV12,
+                    field: V4L2_FIELD_NONE,
+                    bytesperline: width,
+                    sizeimage: width * height * 3 / 2,
+                    colorspace: V4L2_COLORSPACE_REC709,
+                    ycbcr_enc: V4L2_YCBCR_ENC_709,
+                    quantization: V4L2_QUANTIZATION_LIM_RANGE,
+                    xfer_func: V4L2_XFER_FUNC_709,
+                    ..Default::default()
+                },
+                raw_data: [0; 200 - mem::size_of::<V4l2PixFormat>()],
+                _align: [],
+            },
+        };
+        if unsafe { libc::ioctl(fd, VIDIOC_S_FMT as _, &mut format) } < 0 {
+            return Err(anyhow!(
+                "failed to set format: {}",
+                io::Error::last_os_error()
+            ));
+        }
+        if format.fmt.pix.pixelformat != V4L2_PIX_FMT_NV12 {
+            return Err(anyhow!(
+                "NV12 not accepted, got {:?}",
+                String::from_utf8_lossy(&format.fmt.pix.pixelformat.to_le_bytes())
+            ));
+        }
+        Ok(Self {
+            file,
+            format: format.fmt.pix,
+        })
+    }
+
+    /// Writes a frame of `format`, dropped if the device isn't ready for it.
+    pub fn write(&self, frame: &[u8]) -> Result<()> {
+        match (&self.file).write(frame) {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl Drop for V4l2Device {
+    fn drop(&mut self) {
+        IN_USE.store(false, Ordering::Release);
+    }
+}
+
+/// Copies NV12 planes laid out by `params` from `pixels` into `frame`, rows `bytesperline` apart
+/// for `width`x`height` frames of a v4l2 device. Pixels outside either size are left out or
+/// black.
+pub fn pack_nv12(
+    params: &ConvertParams,
+    pixels: &[u8],
+    format: &V4l2PixFormat,
+    frame: &mut Vec<u8>,
+) {
+    let pitch = format.bytesperline as usize;
+    let height = format.height as usize;
+    let size = (format.sizeimage as usize).max(pitch * (height + (height + 1) / 2));
+    frame.clear();
+    // limited range black
+    frame.resize(pitch * height, 16);
+    frame.resize(size, 128);
+
+    let stride = params.stride as usize;
+    let row = (format.width as usize)
+        .min(params.width as usize)
+        .min(pitch);
+    let planes = [
+        (0, 0, height.min(params.height as usize)),
+        (
+            params.uv_offset as usize,
+            pitch * height,
+            ((height + 1) / 2).min((params.height as usize + 1) / 2),
+        ),
+    ];
+    for (src_offset, dst_offset, rows) in planes {
+        for y in 0..rows {
+            let src = src_offset + y * stride;
+            let dst = dst_offset + y * pitch;
+            let (Some(src), Some(dst)) =
+                (pixels.get(src..src + row), frame.get_mut(dst..dst + row))
+            else {
+                return;
+            };
+            dst.copy_from_slice(src);
+        }
+    }
+}
+
+/// Virtual camera of a swapchain with `PW_CAPTURE_V4L2_DEVICE`. Presented images are converted
+/// to NV12 by the kernel and read back like with `PW_CAPTURE_POLL`, one present behind, then
+/// written to the device. The conversion is submitted before the copy of the same present.
+pub struct V4l2Sink {
+    pub device: V4l2Device,
+    pub readback: PollReadback,
+    /// of the buffer the kernel converts into
+    params: ConvertParams,
+    /// packed frame written to the device
+    frame: Mutex<Vec<u8>>,
+    /// a write failed, logged once
+    failed: AtomicBool,
+}
+
+impl V4l2Sink {
+    pub fn new(device: V4l2Device, readback: PollReadback, params: ConvertParams) -> Self {
+        Self {
+            device,
+            readback,
+            params,
+            frame: Mutex::new(vec![]),
+            failed: AtomicBool::new(false),
+        }
+    }
+
+    /// Converts `crop` of `src_image` and writes the frame of the previous present to the
+    /// device, see `PollReadback::copy`. Frames the device didn't take are dropped, the present
+    /// must still wait on the semaphore.
+    #[allow(clippy::too_many_arguments)]
+    #[named]
+    pub unsafe fn write(
+        &self,
+        device: &ash::Device,
+        queue: vk::Queue,
+        src_image: vk::Image,
+        image_index: usize,
+        crop: vk::Rect2D,
+        waits: &[vk::Semaphore],
+    ) -> Result<vk::Semaphore> {
+        self.readback.copy(
+            device,
+            queue,
+            src_image,
+            image_index,
+            crop,
+            waits,
+            0,
+            |_, pixels| {
+                let Ok(mut frame) = self.frame.lock() else {
+                    return;
+                };
+                pack_nv12(&self.params, pixels, &self.device.format, &mut frame);
+                match self.device.write(&frame) {
+                    Ok(()) => self.failed.store(false, Ordering::Relaxed),
+                    Err(e) if !self.failed.swap(true, Ordering::Relaxed) => {
+                        error!("failed to write frame to v4l2 device: {e:?}");
+                    }
+                    Err(_) => {}
+                }
+            },
+        )
+    }
+
+    /// Frees the readback, the device is closed once dropped.
+    pub unsafe fn destroy(&self, device: &ash::Device) {
+        self.readback.destroy(device);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pw_capture_client::Format;
+
+    #[test]
+    fn ioctl_layout() {
+        assert_eq!(mem::size_of::<V4l2Capability>(), 104);
+        assert_eq!(mem::size_of::<V4l2PixFormat>(), 48);
+        #[cfg(target_pointer_width = "64")]
+        {
+            assert_eq!(mem::size_of::<V4l2Format>(), 208);
+            assert_eq!(VIDIOC_S_FMT, 0xc0d0_5605);
+        }
+        assert_eq!(VIDIOC_QUERYCAP, 0x8068_5600);
+        assert_eq!(V4L2_PIX_FMT_NV12, 0x3231_564e);
+    }
+
+    #[test]
+    fn pack() {
+        let params = ConvertParams::new(
+            Format::NV12,
+            6,
+            4,
+            vk::Format::B8G8R8A8_UNORM,
+            Processing::default(),
+        )
+        .unwrap();
+        let pixels = (0..params.size()).map(|v| v as u8).collect::<Vec<_>>();
+        let format = V4l2PixFormat {
+            width: 6,
+            height: 4,
+            bytesperline: 6,
+            sizeimage: 36,
+            ..Default::default()
+        };
+        let mut frame = vec![];
+        pack_nv12(&params, &pixels, &format, &mut frame);
+        assert_eq!(frame.len(), 36);
+        let stride = params.stride as usize;
+        let uv = params.uv_offset as usize;
+        assert_eq!(frame[..6], pixels[..6]);
+        assert_eq!(frame[18..24], pixels[3 * stride..3 * stride + 6]);
+        assert_eq!(frame[24..30], pixels[uv..uv + 6]);
+        assert_eq!(frame[30..36], pixels[uv + stride..uv + stride + 6]);
+
+        // a larger device frame is padded with black
+        let format = V4l2PixFormat {
+            width: 8,
+            height: 6,
+            bytesperline: 8,
+            sizeimage: 72,
+            ..Default::default()
+        };
+        pack_nv12(&params, &pixels, &format, &mut frame);
+        assert_eq!(frame.len(), 72);
+        assert_eq!(frame[6..8], [16, 16]);
+        assert_eq!(frame[40..48], [16; 8]);
+        assert_eq!(frame[48..54], pixels[uv..uv + 6]);
+        assert_eq!(frame[70..72], [128, 128]);
+    }
+}
@@ -1,6 +1,9 @@
 use crate::utils::*;
 
-use anyhow::Result;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
 use ash::extensions::khr;
 use ash::prelude::VkResult;
 use ash::vk;
@@ -33,11 +36,132 @@ impl FenceState {
         Ok(())
     }
 
+    /// returns whether the fence is in use and not yet signaled, without blocking
+    pub unsafe fn is_pending(&self, device: &ash::Device) -> VkResult<bool> {
+        if !self.busy {
+            return Ok(false);
+        }
+        Ok(!device.get_fence_status(self.fence)?)
+    }
+
     pub unsafe fn destroy(&self, device: &ash::Device) {
         device.destroy_fence(self.fence, None);
     }
 }
 
+/// Timeline semaphore signaled with an increasing value per submit, nothing to reset in between.
+pub struct TimelineState {
+    khr_timeline: khr::TimelineSemaphore,
+    semaphore: vk::Semaphore,
+    /// signaled by the last submit
+    value: u64,
+}
+
+impl TimelineState {
+    pub unsafe fn new(
+        device: &ash::Device,
+        khr_timeline: &khr::TimelineSemaphore,
+    ) -> VkResult<Self> {
+        let mut type_info = vk::SemaphoreTypeCreateInfo::builder()
+            .semaphore_type(vk::SemaphoreType::TIMELINE)
+            .initial_value(0);
+        let semaphore_info = vk::SemaphoreCreateInfo::builder().push_next(&mut type_info);
+        let semaphore = device.create_semaphore(&semaphore_info, None)?;
+        Ok(Self {
+            khr_timeline: khr_timeline.clone(),
+            semaphore,
+            value: 0,
+        })
+    }
+
+    /// semaphore and value for the next submit to signal
+    pub fn next_signal(&self) -> (vk::Semaphore, u64) {
+        (self.semaphore, self.value + 1)
+    }
+
+    /// the submit signaling `next_signal` succeeded
+    pub fn submitted(&mut self) {
+        self.value += 1;
+    }
+
+    pub unsafe fn wait(&self) -> VkResult<()> {
+        let semaphores = [self.semaphore];
+        let values = [self.value];
+        let wait_info = vk::SemaphoreWaitInfo::builder()
+            .semaphores(&semaphores)
+            .values(&values);
+        self.khr_timeline.wait_semaphores(&wait_info, u64::MAX)
+    }
+
+    /// returns whether the last submit is not yet done, without blocking
+    pub unsafe fn is_pending(&self) -> VkResult<bool> {
+        let value = self
+            .khr_timeline
+            .get_semaphore_counter_value(self.semaphore)?;
+        Ok(value < self.value)
+    }
+
+    pub unsafe fn destroy(&self, device: &ash::Device) {
+        device.destroy_semaphore(self.semaphore, None);
+    }
+}
+
+/// Completion of the last copy of an image, tracked with a timeline semaphore if the device has
+/// `VK_KHR_timeline_semaphore` enabled, otherwise with a fence.
+pub enum CopySync {
+    Fence(FenceState),
+    Timeline(TimelineState),
+}
+
+impl CopySync {
+    pub unsafe fn new(
+        device: &ash::Device,
+        khr_timeline: Option<&khr::TimelineSemaphore>,
+    ) -> VkResult<Self> {
+        match khr_timeline {
+            Some(khr_timeline) => Ok(Self::Timeline(TimelineState::new(device, khr_timeline)?)),
+            None => Ok(Self::Fence(FenceState::new(device)?)),
+        }
+    }
+
+    /// fence to pass to the next submit, and timeline semaphore and value for it to signal
+    pub unsafe fn use_sync(&mut self) -> (vk::Fence, Option<(vk::Semaphore, u64)>) {
+        match self {
+            Self::Fence(fence) => (fence.use_fence(), None),
+            Self::Timeline(timeline) => (vk::Fence::null(), Some(timeline.next_signal())),
+        }
+    }
+
+    /// the submit using `use_sync` succeeded
+    pub fn submitted(&mut self) {
+        if let Self::Timeline(timeline) = self {
+            timeline.submitted();
+        }
+    }
+
+    pub unsafe fn wait_and_reset(&mut self, device: &ash::Device) -> VkResult<()> {
+        match self {
+            Self::Fence(fence) => fence.wait_and_reset(device),
+            Self::Timeline(timeline) => timeline.wait(),
+        }
+    }
+
+    /// returns whether the last submit is not yet done, without blocking
+    pub unsafe fn is_pending(&self, device: &ash::Device) -> VkResult<bool> {
+        match self {
+            Self::Fence(fence) => fence.is_pending(device),
+            Self::Timeline(timeline) => timeline.is_pending(),
+        }
+    }
+
+    pub unsafe fn destroy(&self, device: &ash::Device) {
+        match self {
+            Self::Fence(fence) => fence.destroy(device),
+            Self::Timeline(timeline) => timeline.destroy(device),
+        }
+    }
+}
+
 #[named]
 pub unsafe fn get_supported_modifiers(
     khr_phy_props2: &khr::GetPhysicalDeviceProperties2,
@@ -104,6 +228,45 @@ pub unsafe fn get_supported_modifiers(
     Ok(modifier_props)
 }
 
+/// Whether export images of `format` with `modifier` can be created with fixed-rate compression,
+/// needs `VK_EXT_image_compression_control`.
+pub unsafe fn fixed_rate_supported(
+    khr_phy_props2: &khr::GetPhysicalDeviceProperties2,
+    phy_device: vk::PhysicalDevice,
+    format: vk::Format,
+    modifier: u64,
+) -> bool {
+    let mut external_info = vk::PhysicalDeviceExternalImageFormatInfo::builder()
+        .handle_type(vk::ExternalMemoryHandleTypeFlags::DMA_BUF_EXT)
+        .build();
+    let mut modifier_info = vk::PhysicalDeviceImageDrmFormatModifierInfoEXT::builder()
+        .drm_format_modifier(modifier)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE)
+        .build();
+    let mut compression_control = vk::ImageCompressionControlEXT::builder()
+        .flags(vk::ImageCompressionFlagsEXT::FIXED_RATE_DEFAULT)
+        .build();
+    let image_format_info = vk::PhysicalDeviceImageFormatInfo2KHR::builder()
+        .push_next(&mut external_info)
+        .push_next(&mut modifier_info)
+        .push_next(&mut compression_control)
+        .format(format)
+        .ty(vk::ImageType::TYPE_2D)
+        .tiling(vk::ImageTiling::DRM_FORMAT_MODIFIER_EXT)
+        .usage(vk::ImageUsageFlags::TRANSFER_DST)
+        .flags(vk::ImageCreateFlags::empty());
+    let mut compression_props = vk::ImageCompressionPropertiesEXT::default();
+    let mut image_format_props =
+        vk::ImageFormatProperties2KHR::builder().push_next(&mut compression_props);
+    let res = khr_phy_props2.get_physical_device_image_format_properties2(
+        phy_device,
+        &image_format_info,
+        &mut image_format_props,
+    );
+    let fixed_rate_flags = compression_props.image_compression_fixed_rate_flags;
+    res.is_ok() && !fixed_rate_flags.is_empty()
+}
+
 pub unsafe fn get_memory_type_indices(
     instance: &ash::Instance,
     phy_device: vk::PhysicalDevice,
@@ -120,6 +283,26 @@ pub unsafe fn get_memory_type_indices(
         .collect()
 }
 
+/// memory of the first type with `properties` it can be allocated from
+pub unsafe fn allocate_memory(
+    ash_instance: &ash::Instance,
+    ash_device: &ash::Device,
+    phy_device: vk::PhysicalDevice,
+    properties: vk::MemoryPropertyFlags,
+    requirements: vk::MemoryRequirements,
+) -> Result<vk::DeviceMemory> {
+    let indices = get_memory_type_indices(ash_instance, phy_device, properties, requirements);
+    for i in indices {
+        let memory_info = vk::MemoryAllocateInfo::builder()
+            .allocation_size(requirements.size)
+            .memory_type_index(i);
+        if let Ok(memory) = ash_device.allocate_memory(&memory_info, None) {
+            return Ok(memory);
+        }
+    }
+    Err(anyhow!("no memory type for {:?}", properties))
+}
+
 pub unsafe fn create_target_image(
     ash_instance: &ash::Instance,
     ash_device: &ash::Device,
@@ -131,9 +314,12 @@ pub unsafe fn create_target_image(
     height: u32,
     modifier: u64,
     num_planes: u32,
+    // see `fixed_rate_supported`
+    fixed_rate: bool,
 ) -> Result<(
     vk::Image,
     vk::DeviceMemory,
+    vk::MemoryPropertyFlags,
     Vec<(i32, vk::SubresourceLayout)>,
 )> {
     let mut modidier_list = vk::ImageDrmFormatModifierListCreateInfoEXT::builder()
@@ -142,7 +328,10 @@ pub unsafe fn create_target_image(
     let mut external_info = vk::ExternalMemoryImageCreateInfo::builder()
         .handle_types(vk::ExternalMemoryHandleTypeFlags::DMA_BUF_EXT)
         .build();
-    let image_info = vk::ImageCreateInfo::builder()
+    let mut compression_control = vk::ImageCompressionControlEXT::builder()
+        .flags(vk::ImageCompressionFlagsEXT::FIXED_RATE_DEFAULT)
+        .build();
+    let mut image_info = vk::ImageCreateInfo::builder()
         .push_next(&mut external_info)
         .push_next(&mut modidier_list)
         .image_type(vk::ImageType::TYPE_2D)
@@ -159,6 +348,9 @@ pub unsafe fn create_target_image(
         .usage(vk::ImageUsageFlags::TRANSFER_DST)
         .initial_layout(vk::ImageLayout::UNDEFINED)
         .sharing_mode(vk::SharingMode::EXCLUSIVE);
+    if fixed_rate {
+        image_info = image_info.push_next(&mut compression_control);
+    }
 
     let image = ash_device.create_image(&image_info, None)?;
 
@@ -173,16 +365,22 @@ pub unsafe fn create_target_image(
     );
 
     let mut memory: VkResult<vk::DeviceMemory> = Err(vk::Result::ERROR_UNKNOWN);
+    let mut memory_type_index = 0;
     for i in indices {
         let memory_info = vk::MemoryAllocateInfo::builder()
             .allocation_size(requirements.size)
             .memory_type_index(i);
         memory = ash_device.allocate_memory(&memory_info, None);
         if memory.is_ok() {
+            memory_type_index = i;
             break;
         }
     }
     let memory = memory?;
+    let memory_props = ash_instance
+        .get_physical_device_memory_properties(phy_device)
+        .memory_types[memory_type_index as usize]
+        .property_flags;
 
     ash_device.bind_image_memory(image, memory, 0)?;
 
@@ -216,9 +414,1078 @@ pub unsafe fn create_target_image(
         })
         .collect::<Vec<_>>();
 
-    Ok((image, memory, fds))
+    Ok((image, memory, memory_props, fds))
+}
+
+/// Creates a linear image in host visible memory, mapped, for frames read back into memfd
+/// buffers. Returns the layout of its pixels and their mapped address.
+pub unsafe fn create_host_image(
+    ash_instance: &ash::Instance,
+    ash_device: &ash::Device,
+    phy_device: vk::PhysicalDevice,
+    format: vk::Format,
+    width: u32,
+    height: u32,
+) -> Result<(
+    vk::Image,
+    vk::DeviceMemory,
+    vk::MemoryPropertyFlags,
+    vk::SubresourceLayout,
+    *mut u8,
+)> {
+    let image_info = vk::ImageCreateInfo::builder()
+        .image_type(vk::ImageType::TYPE_2D)
+        .format(format)
+        .extent(vk::Extent3D {
+            width,
+            height,
+            depth: 1,
+        })
+        .mip_levels(1)
+        .array_layers(1)
+        .samples(vk::SampleCountFlags::TYPE_1)
+        .tiling(vk::ImageTiling::LINEAR)
+        .usage(vk::ImageUsageFlags::TRANSFER_DST)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE);
+
+    let image = ash_device.create_image(&image_info, None)?;
+
+    let requirements = ash_device.get_image_memory_requirements(image);
+    // cached memory is much faster to read from
+    let mut indices = get_memory_type_indices(
+        ash_instance,
+        phy_device,
+        vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_CACHED,
+        requirements,
+    );
+    indices.extend(get_memory_type_indices(
+        ash_instance,
+        phy_device,
+        vk::MemoryPropertyFlags::HOST_VISIBLE,
+        requirements,
+    ));
+
+    let mut memory: VkResult<vk::DeviceMemory> = Err(vk::Result::ERROR_OUT_OF_DEVICE_MEMORY);
+    let mut memory_type_index = 0;
+    for i in indices {
+        let memory_info = vk::MemoryAllocateInfo::builder()
+            .allocation_size(requirements.size)
+            .memory_type_index(i);
+        memory = ash_device.allocate_memory(&memory_info, None);
+        if memory.is_ok() {
+            memory_type_index = i;
+            break;
+        }
+    }
+    let memory = match memory {
+        Ok(v) => v,
+        Err(e) => {
+            ash_device.destroy_image(image, None);
+            return Err(e.into());
+        }
+    };
+    let memory_props = ash_instance
+        .get_physical_device_memory_properties(phy_device)
+        .memory_types[memory_type_index as usize]
+        .property_flags;
+
+    let mapped = ash_device
+        .bind_image_memory(image, memory, 0)
+        .and_then(|_| {
+            ash_device.map_memory(memory, 0, vk::WHOLE_SIZE, vk::MemoryMapFlags::empty())
+        });
+    let mapped = match mapped {
+        Ok(v) => v as *mut u8,
+        Err(e) => {
+            ash_device.destroy_image(image, None);
+            ash_device.free_memory(memory, None);
+            return Err(e.into());
+        }
+    };
+
+    let subresource = vk::ImageSubresource::builder()
+        .array_layer(0)
+        .mip_level(0)
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .build();
+    let layout = ash_device.get_image_subresource_layout(image, subresource);
+
+    Ok((
+        image,
+        memory,
+        memory_props,
+        layout,
+        mapped.add(layout.offset as usize),
+    ))
+}
+
+/// `struct dma_buf_import_sync_file`
+#[repr(C)]
+struct DmaBufImportSyncFile {
+    flags: u32,
+    fd: i32,
+}
+
+/// `DMA_BUF_IOCTL_IMPORT_SYNC_FILE`, `_IOW('b', 3, struct dma_buf_import_sync_file)`
+const DMA_BUF_IOCTL_IMPORT_SYNC_FILE: libc::c_ulong = 0x4008_6203;
+const DMA_BUF_SYNC_WRITE: u32 = 2;
+
+/// Adds the fence of `sync_fd` to the writes of `dma_buf_fd`, readers with implicit sync wait
+/// for it. Needs Linux 6.0.
+pub unsafe fn import_sync_file(dma_buf_fd: i32, sync_fd: i32) -> std::io::Result<()> {
+    let arg = DmaBufImportSyncFile {
+        flags: DMA_BUF_SYNC_WRITE,
+        fd: sync_fd,
+    };
+    if libc::ioctl(dma_buf_fd, DMA_BUF_IOCTL_IMPORT_SYNC_FILE as _, &arg) < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Creates a memfd of `size` bytes, mapped shared for writing.
+pub unsafe fn create_memfd(size: usize) -> Result<(i32, *mut u8)> {
+    let fd = libc::memfd_create(
+        b"pw-capture\0".as_ptr() as _,
+        libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING,
+    );
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    if libc::ftruncate(fd, size as _) < 0 {
+        let e = std::io::Error::last_os_error();
+        libc::close(fd);
+        return Err(e.into());
+    }
+    // consumers can't shrink or grow the buffer under the mapping
+    libc::fcntl(
+        fd,
+        libc::F_ADD_SEALS,
+        libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_SEAL,
+    );
+    let ptr = libc::mmap(
+        std::ptr::null_mut(),
+        size,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_SHARED,
+        fd,
+        0,
+    );
+    if ptr == libc::MAP_FAILED {
+        let e = std::io::Error::last_os_error();
+        libc::close(fd);
+        return Err(e.into());
+    }
+    Ok((fd, ptr as *mut u8))
+}
+
+/// returns whether frames of `src_format` have to be blitted into `dst_format` images with
+/// `dst_features`, e.g. modifiers that only allow blitting or `scaled` frames, `None` if neither
+/// is supported
+pub fn copy_needs_blit(
+    src_format: vk::Format,
+    dst_format: vk::Format,
+    dst_features: vk::FormatFeatureFlags,
+    scaled: bool,
+    watermark: bool,
+) -> Option<bool> {
+    let can_blit = dst_features.contains(vk::FormatFeatureFlags::BLIT_DST);
+    let can_copy = src_format == dst_format
+        && !scaled
+        && dst_features.contains(vk::FormatFeatureFlags::TRANSFER_DST)
+        // watermark is blitted onto export images even if the frame is copied
+        && (can_blit || !watermark);
+    if can_copy {
+        Some(false)
+    } else if can_blit {
+        Some(true)
+    } else {
+        None
+    }
+}
+
+/// returns whether export images allocated for the `fixated` format, modifier and conversion
+/// can't take frames after fixating `format` with `modifier` and `conversion`, `false` for the
+/// first fixation
+pub fn fixation_changed(
+    fixated: Option<(vk::Format, Option<u64>, Option<ConvertParams>)>,
+    format: vk::Format,
+    modifier: Option<u64>,
+    conversion: Option<ConvertParams>,
+) -> bool {
+    fixated.map_or(false, |v| v != (format, modifier, conversion))
+}
+
+/// Returns whether the fixation of a swapchain can be kept by the one recreating it with the
+/// same format. Export images fit frames of the same `stream_extent`, copies are recorded again
+/// for every frame, so the `command_buffers` have to cover the new `images`, none are allocated
+/// for metadata-only streams, and `scaled` frames have to be blitted.
+pub fn fixation_reusable(
+    old_extent: vk::Extent2D,
+    new_extent: vk::Extent2D,
+    command_buffers: usize,
+    images: usize,
+    need_blit: bool,
+    scaled: bool,
+) -> bool {
+    old_extent == new_extent
+        && (command_buffers == 0 || command_buffers >= images)
+        && (need_blit || !scaled)
+}
+
+/// Queue of `device` capture copies are submitted to, with its family index. Queues are listed
+/// as `(queue, device, family index, family flags)`, those of other devices are skipped, as they
+/// can't run command buffers of `device`. Blits `need` a graphics queue and conversions a compute
+/// one, plain copies take any transfer capable one, preferring one without graphics. A queue of
+/// `source_family`, last writing presented images, is taken first if it meets `need` and has no
+/// graphics, so images of compute renderers are copied on their compute queue without ownership
+/// transfers.
+pub fn select_capture_queue(
+    device: vk::Device,
+    queues: &[(vk::Queue, vk::Device, u32, vk::QueueFlags)],
+    need: vk::QueueFlags,
+    source_family: u32,
+) -> Option<(vk::Queue, u32)> {
+    // graphics and compute queues support transfer even when not reported
+    let usable = |queue_device: vk::Device, flags: vk::QueueFlags| {
+        queue_device == device
+            && flags.contains(need)
+            && (need.contains(vk::QueueFlags::GRAPHICS)
+                || flags.intersects(vk::QueueFlags::TRANSFER | vk::QueueFlags::COMPUTE))
+    };
+    let source = queues
+        .iter()
+        .find(|&&(_, queue_device, family_index, flags)| {
+            family_index == source_family
+                && !flags.contains(vk::QueueFlags::GRAPHICS)
+                && usable(queue_device, flags)
+        });
+    if let Some(&(queue, _, family_index, _)) = source {
+        return Some((queue, family_index));
+    }
+    let mut selected = None;
+    for &(queue, queue_device, family_index, flags) in queues {
+        if !usable(queue_device, flags) {
+            continue;
+        }
+        if need.contains(vk::QueueFlags::GRAPHICS) {
+            return Some((queue, family_index));
+        }
+        selected = Some((queue, family_index));
+        if !flags.contains(vk::QueueFlags::GRAPHICS) {
+            break;
+        }
+    }
+    selected
+}
+
+/// Returns whether a present at `now` creates the stream of a swapchain without one. Swapchains
+/// created capturable get a `deadline`, cleared once the swapchain recreating them retires them,
+/// so presents of images acquired before don't bring up a node about to go away.
+pub fn stream_due(deadline: Option<Instant>, now: Instant) -> bool {
+    deadline.map_or(false, |deadline| now >= deadline)
+}
+
+/// indices of the first occurrence of each swapchain listed in a present
+pub fn unique_swapchain_indices(swapchains: &[vk::SwapchainKHR]) -> Vec<usize> {
+    (0..swapchains.len())
+        .filter(|&i| !swapchains[..i].contains(&swapchains[i]))
+        .collect()
+}
+
+/// Waits of a present whose capture copies signal `captured`. The copies wait on the app's
+/// semaphores in place of the present, binary semaphores can only be waited on once, except
+/// `foreign` ones of other devices, which stay with the present. Empty if nothing was captured,
+/// the present keeps its own waits then.
+pub fn present_wait_semaphores(
+    captured: &[vk::Semaphore],
+    foreign: &[vk::Semaphore],
+) -> Vec<vk::Semaphore> {
+    if captured.is_empty() {
+        return vec![];
+    }
+    captured.iter().chain(foreign).copied().collect()
+}
+
+/// Binary semaphores of a present capturing several swapchains in turn. The first copy waits on
+/// the app's semaphores, each later one on the semaphores signaled by the copy before it, and the
+/// present on those of the last copy, so each is waited on exactly once.
+pub struct PresentWaitChain {
+    waits: Vec<vk::Semaphore>,
+    captured: bool,
+}
+
+impl PresentWaitChain {
+    pub fn new(app: Vec<vk::Semaphore>) -> Self {
+        Self {
+            waits: app,
+            captured: false,
+        }
+    }
+
+    /// semaphores the next copy waits on
+    pub fn waits(&self) -> &[vk::Semaphore] {
+        &self.waits
+    }
+
+    /// continues with the semaphores `signaled` by a copy submitted with `waits`
+    pub fn captured(&mut self, signaled: Vec<vk::Semaphore>) {
+        self.waits = signaled;
+        self.captured = true;
+    }
+
+    /// waits of the present, see `present_wait_semaphores`
+    pub fn present_waits(&self, foreign: &[vk::Semaphore]) -> Vec<vk::Semaphore> {
+        if !self.captured {
+            return vec![];
+        }
+        present_wait_semaphores(&self.waits, foreign)
+    }
+}
+
+/// Result of the present of swapchain `index`, `res` of the whole present if it only had one, as
+/// drivers may leave `results` untouched then.
+pub fn swapchain_present_result(
+    res: vk::Result,
+    results: &[vk::Result],
+    index: usize,
+) -> vk::Result {
+    match results {
+        [_] => res,
+        _ => results.get(index).copied().unwrap_or(res),
+    }
+}
+
+/// `present_info` waiting on `semaphores` instead of its own, as is if there are none
+pub fn present_info_with_waits(
+    present_info: &vk::PresentInfoKHR,
+    semaphores: &[vk::Semaphore],
+) -> vk::PresentInfoKHR {
+    let mut present_info = *present_info;
+    if !semaphores.is_empty() {
+        present_info.wait_semaphore_count = semaphores.len() as _;
+        present_info.p_wait_semaphores = semaphores.as_ptr();
+    }
+    present_info
+}
+
+/// Semaphores signaled by `submit` with the values of its `VkTimelineSemaphoreSubmitInfo`, 0
+/// without one, as for binary semaphores.
+pub unsafe fn submit_signals(submit: &vk::SubmitInfo) -> Vec<(vk::Semaphore, u64)> {
+    let semaphores = raw_slice(submit.p_signal_semaphores, submit.signal_semaphore_count);
+    let mut values: &[u64] = &[];
+    let mut p_next = submit.p_next as *const vk::BaseInStructure;
+    while let Some(next) = p_next.as_ref() {
+        if next.s_type == vk::StructureType::TIMELINE_SEMAPHORE_SUBMIT_INFO {
+            let info = &*(p_next as *const vk::TimelineSemaphoreSubmitInfo);
+            values = raw_slice(
+                info.p_signal_semaphore_values,
+                info.signal_semaphore_value_count,
+            );
+            break;
+        }
+        p_next = next.p_next;
+    }
+    semaphores
+        .iter()
+        .enumerate()
+        .map(|(i, &semaphore)| (semaphore, values.get(i).copied().unwrap_or(0)))
+        .collect()
+}
+
+/// `submit_signals` of `vkQueueSubmit2`, the values are part of the semaphore infos
+pub unsafe fn submit2_signals(submit: &vk::SubmitInfo2) -> Vec<(vk::Semaphore, u64)> {
+    raw_slice(
+        submit.p_signal_semaphore_infos,
+        submit.signal_semaphore_info_count,
+    )
+    .iter()
+    .map(|info| (info.semaphore, info.value))
+    .collect()
+}
+
+/// semaphores `submit` waits on
+pub unsafe fn submit_waits(submit: &vk::SubmitInfo) -> Vec<vk::Semaphore> {
+    raw_slice(submit.p_wait_semaphores, submit.wait_semaphore_count).to_vec()
+}
+
+/// `submit_waits` of `vkQueueSubmit2`
+pub unsafe fn submit2_waits(submit: &vk::SubmitInfo2) -> Vec<vk::Semaphore> {
+    raw_slice(
+        submit.p_wait_semaphore_infos,
+        submit.wait_semaphore_info_count,
+    )
+    .iter()
+    .map(|info| info.semaphore)
+    .collect()
+}
+
+/// Family owning an `EXCLUSIVE` presented image while it's copied. Apps should transfer images to
+/// the `present_family` before the present, but some present what another family rendered as is.
+/// That's the family of the queues that signaled the present's waits if they agree, otherwise
+/// the `render_family` of the submit that waited on the image's acquire, otherwise the present
+/// family. `QUEUE_FAMILY_IGNORED` stands for unknown ones.
+pub fn select_source_family(present_family: u32, wait_families: &[u32], render_family: u32) -> u32 {
+    let mut known = wait_families
+        .iter()
+        .copied()
+        .filter(|&family| family != vk::QUEUE_FAMILY_IGNORED);
+    match known.next() {
+        Some(first) if known.all(|family| family == first) => first,
+        _ if render_family != vk::QUEUE_FAMILY_IGNORED => render_family,
+        _ => present_family,
+    }
+}
+
+/// `count` elements at `ptr`, none if it's null, apps may pass that with a count of 0
+unsafe fn raw_slice<'a, T>(ptr: *const T, count: u32) -> &'a [T] {
+    if ptr.is_null() {
+        return &[];
+    }
+    std::slice::from_raw_parts(ptr, count as _)
+}
+
+/// whether swapchain images hold values beyond SDR, e.g. PQ encoded or scRGB
+pub fn is_hdr_color_space(color_space: vk::ColorSpaceKHR) -> bool {
+    matches!(
+        color_space,
+        vk::ColorSpaceKHR::HDR10_ST2084_EXT
+            | vk::ColorSpaceKHR::HDR10_HLG_EXT
+            | vk::ColorSpaceKHR::DOLBYVISION_EXT
+            | vk::ColorSpaceKHR::EXTENDED_SRGB_LINEAR_EXT
+            | vk::ColorSpaceKHR::EXTENDED_SRGB_NONLINEAR_EXT
+            | vk::ColorSpaceKHR::BT2020_LINEAR_EXT
+    )
+}
+
+/// `[start, start + size)` clipped to `[crop_start, crop_start + crop_size)`, relative to
+/// `crop_start` and scaled from `crop_size` to `scaled`
+fn span_in_stream(
+    start: i32,
+    size: u32,
+    crop_start: i32,
+    crop_size: u32,
+    scaled: u32,
+) -> Option<(i32, u32)> {
+    let (start, end) = (start as i64, start as i64 + size as i64);
+    let (crop_start, crop_end) = (crop_start as i64, crop_start as i64 + crop_size as i64);
+    let (start, end) = (
+        start.max(crop_start) - crop_start,
+        end.min(crop_end) - crop_start,
+    );
+    if end <= start {
+        return None;
+    }
+    let scale = |v: i64| v * scaled as i64 / crop_size as i64;
+    Some((scale(start) as i32, (scale(end) - scale(start)) as u32))
+}
+
+/// `region` of a swapchain image in coordinates of stream frames, which are `crop` scaled to
+/// `stream`, `None` if it's outside of `crop`
+pub fn region_in_stream(
+    region: vk::Rect2D,
+    crop: vk::Rect2D,
+    stream: vk::Extent2D,
+) -> Option<vk::Rect2D> {
+    let (x, width) = span_in_stream(
+        region.offset.x,
+        region.extent.width,
+        crop.offset.x,
+        crop.extent.width,
+        stream.width,
+    )?;
+    let (y, height) = span_in_stream(
+        region.offset.y,
+        region.extent.height,
+        crop.offset.y,
+        crop.extent.height,
+        stream.height,
+    )?;
+    Some(vk::Rect2D {
+        offset: vk::Offset2D { x, y },
+        extent: vk::Extent2D { width, height },
+    })
+}
+
+/// Frame of swapchains composed side by side, left to right in the order of their stream
+/// `extents`, each at the top and padded below to the highest. Returns the extent of the frame
+/// and where each is copied to.
+pub fn compose_layout(extents: &[vk::Extent2D]) -> (vk::Extent2D, Vec<vk::Offset2D>) {
+    let mut frame = vk::Extent2D::default();
+    let offsets = extents
+        .iter()
+        .map(|extent| {
+            let offset = vk::Offset2D {
+                x: frame.width as i32,
+                y: 0,
+            };
+            frame.width += extent.width;
+            frame.height = frame.height.max(extent.height);
+            offset
+        })
+        .collect();
+    (frame, offsets)
 }
 
+/// more rectangles between captures damage the whole image
+const MAX_DAMAGE_RECTS: usize = 64;
+
+/// Adds rectangles changed by a present to `damage` since the last capture, `None` meaning the
+/// whole image. Presents without `VK_KHR_incremental_present` rectangles change the whole image.
+pub fn accumulate_damage(damage: &mut Option<Vec<vk::Rect2D>>, rects: Option<&[vk::RectLayerKHR]>) {
+    let (Some(acc), Some(rects)) = (damage.as_mut(), rects) else {
+        *damage = None;
+        return;
+    };
+    if acc.len() + rects.len() > MAX_DAMAGE_RECTS {
+        *damage = None;
+        return;
+    }
+    acc.extend(rects.iter().map(|v| vk::Rect2D {
+        offset: v.offset,
+        extent: v.extent,
+    }));
+}
+
+/// Timestamp queries around the copies of each command buffer, with `PW_CAPTURE_GPU_TIMING`.
+pub struct CopyTimer {
+    query_pool: vk::QueryPool,
+    /// nanoseconds per tick
+    period: f32,
+    valid_bits: u32,
+    /// command buffers submitted with queries, results of others are undefined
+    written: Mutex<Vec<bool>>,
+}
+
+impl CopyTimer {
+    pub unsafe fn new(
+        device: &ash::Device,
+        count: usize,
+        period: f32,
+        valid_bits: u32,
+    ) -> VkResult<Self> {
+        let pool_info = vk::QueryPoolCreateInfo::builder()
+            .query_type(vk::QueryType::TIMESTAMP)
+            .query_count(count as u32 * 2);
+        let query_pool = device.create_query_pool(&pool_info, None)?;
+        Ok(Self {
+            query_pool,
+            period,
+            valid_bits,
+            written: Mutex::new(vec![false; count]),
+        })
+    }
+
+    unsafe fn cmd_begin(
+        &self,
+        device: &ash::Device,
+        command_buffer: vk::CommandBuffer,
+        index: usize,
+    ) {
+        let first = index as u32 * 2;
+        device.cmd_reset_query_pool(command_buffer, self.query_pool, first, 2);
+        device.cmd_write_timestamp(
+            command_buffer,
+            vk::PipelineStageFlags::TOP_OF_PIPE,
+            self.query_pool,
+            first,
+        );
+    }
+
+    unsafe fn cmd_end(
+        &self,
+        device: &ash::Device,
+        command_buffer: vk::CommandBuffer,
+        index: usize,
+    ) {
+        device.cmd_write_timestamp(
+            command_buffer,
+            vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+            self.query_pool,
+            index as u32 * 2 + 1,
+        );
+    }
+
+    /// marks queries of command buffer `index` as written once it's submitted
+    pub fn submitted(&self, index: usize) {
+        if let Ok(mut written) = self.written.lock() {
+            written[index] = true;
+        }
+    }
+
+    /// GPU time of the last copy of command buffer `index`, `None` if it never completed
+    pub unsafe fn last_duration(&self, device: &ash::Device, index: usize) -> Option<Duration> {
+        if !self.written.lock().map_or(false, |written| written[index]) {
+            return None;
+        }
+        let mut ticks = [0u64; 2];
+        // no waiting, results of a copy that was never submitted aren't ever available
+        device
+            .get_query_pool_results(
+                self.query_pool,
+                index as u32 * 2,
+                2,
+                &mut ticks,
+                vk::QueryResultFlags::TYPE_64,
+            )
+            .ok()?;
+        Some(ticks_to_duration(ticks, self.valid_bits, self.period))
+    }
+
+    pub unsafe fn destroy(&self, device: &ash::Device) {
+        device.destroy_query_pool(self.query_pool, None);
+    }
+}
+
+/// duration between `[start, end]` timestamps, which wrap around at `valid_bits`
+pub fn ticks_to_duration(ticks: [u64; 2], valid_bits: u32, period: f32) -> Duration {
+    let mask = u64::MAX >> (64 - valid_bits.clamp(1, 64));
+    let elapsed = ticks[1].wrapping_sub(ticks[0]) & mask;
+    Duration::from_nanos((elapsed as f64 * period as f64) as u64)
+}
+
+/// Release and acquire of an `EXCLUSIVE` swapchain image by the family owning it around the copy
+/// on a queue of another family, the copy command buffer holds the other half of each pair.
+/// That's the present queue family unless the app presents images of another one, see
+/// `select_source_family`.
+#[derive(Clone, Copy)]
+pub struct OwnershipTransfer {
+    pub owner_family: u32,
+    pub copy_family: u32,
+    command_pool: vk::CommandPool,
+    /// submitted on a queue of `owner_family` before the copy
+    pub release: vk::CommandBuffer,
+    /// submitted on the same queue after the copy
+    pub acquire: vk::CommandBuffer,
+    /// signaled by `release`, waited by the copy
+    pub released: vk::Semaphore,
+    /// signaled by `acquire`, waited by the present
+    pub acquired: vk::Semaphore,
+}
+
+impl OwnershipTransfer {
+    pub unsafe fn new(
+        device: &ash::Device,
+        image: vk::Image,
+        owner_family: u32,
+        copy_family: u32,
+    ) -> VkResult<Self> {
+        let mut transfer = Self {
+            owner_family,
+            copy_family,
+            command_pool: vk::CommandPool::null(),
+            release: vk::CommandBuffer::null(),
+            acquire: vk::CommandBuffer::null(),
+            released: vk::Semaphore::null(),
+            acquired: vk::Semaphore::null(),
+        };
+        // null handles of a partial init are ignored by `destroy`
+        if let Err(e) = transfer.init(device, image) {
+            transfer.destroy(device);
+            return Err(e);
+        }
+        Ok(transfer)
+    }
+
+    unsafe fn init(&mut self, device: &ash::Device, image: vk::Image) -> VkResult<()> {
+        let semaphore_info = vk::SemaphoreCreateInfo::builder();
+        self.released = device.create_semaphore(&semaphore_info, None)?;
+        self.acquired = device.create_semaphore(&semaphore_info, None)?;
+        let pool_info = vk::CommandPoolCreateInfo::builder().queue_family_index(self.owner_family);
+        self.command_pool = device.create_command_pool(&pool_info, None)?;
+        let buffers_info = vk::CommandBufferAllocateInfo::builder()
+            .command_pool(self.command_pool)
+            .level(vk::CommandBufferLevel::PRIMARY)
+            .command_buffer_count(2);
+        let command_buffers = device.allocate_command_buffers(&buffers_info)?;
+        self.release = command_buffers[0];
+        self.acquire = command_buffers[1];
+
+        let subresource = vk::ImageSubresourceRange::builder()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .base_mip_level(0)
+            .level_count(1)
+            .base_array_layer(0)
+            .layer_count(1)
+            .build();
+        // layouts must match the acquire and release in `record_copy_image`
+        let release_barrier = vk::ImageMemoryBarrier::builder()
+            .old_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+            .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+            .src_queue_family_index(self.owner_family)
+            .dst_queue_family_index(self.copy_family)
+            .image(image)
+            .subresource_range(subresource)
+            .build();
+        let acquire_barrier = vk::ImageMemoryBarrier::builder()
+            .old_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+            .new_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+            .src_queue_family_index(self.copy_family)
+            .dst_queue_family_index(self.owner_family)
+            .image(image)
+            .subresource_range(subresource)
+            .build();
+
+        // recorded once, resubmitted while a previous submit may still be pending
+        let begin_info = vk::CommandBufferBeginInfo::builder()
+            .flags(vk::CommandBufferUsageFlags::SIMULTANEOUS_USE);
+        for (command_buffer, barrier) in [
+            (self.release, release_barrier),
+            (self.acquire, acquire_barrier),
+        ] {
+            device.begin_command_buffer(command_buffer, &begin_info)?;
+            // semaphores order the halves, access masks of the other family are ignored
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::ALL_COMMANDS,
+                vk::PipelineStageFlags::ALL_COMMANDS,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[barrier],
+            );
+            device.end_command_buffer(command_buffer)?;
+        }
+        Ok(())
+    }
+
+    pub unsafe fn destroy(&self, device: &ash::Device) {
+        device.destroy_semaphore(self.released, None);
+        device.destroy_semaphore(self.acquired, None);
+        device.destroy_command_pool(self.command_pool, None);
+    }
+}
+
+/// Linear host image frames of a swapchain are copied into and read back from, with
+/// `PW_CAPTURE_POLL`. Copies are submitted on the present queue, so no ownership is transferred,
+/// and read back at the next copy instead of being waited for in the present. Frames may be
+/// converted by the kernel instead, see `converted`.
+pub struct PollReadback {
+    pub format: vk::Format,
+    pub extent: vk::Extent2D,
+    pub row_pitch: u64,
+    /// of a frame, rows included
+    pub size: usize,
+    need_blit: bool,
+    command_pool: vk::CommandPool,
+    command_buffer: vk::CommandBuffer,
+    fence: vk::Fence,
+    image: vk::Image,
+    memory: vk::DeviceMemory,
+    /// pointer, so `PollReadback` stays `Send`
+    ptr: usize,
+    coherent: bool,
+    /// per swapchain image, signaled by copies and waited by presents
+    semaphores: Vec<vk::Semaphore>,
+    /// `image` is the one to convert then, the buffer of the target is mapped instead
+    conversion: Option<(ConvertPipeline, ConvertTarget)>,
+    /// tag of the copy submitted with `fence`, not read back yet
+    pending: Mutex<Option<u64>>,
+}
+
+impl PollReadback {
+    /// Reads back frames of `src_format` images scaled to `extent`, converted to
+    /// `B8G8R8A8_UNORM` if the format can't be written to linear images.
+    #[allow(clippy::too_many_arguments)]
+    pub unsafe fn new(
+        ash_instance: &ash::Instance,
+        device: &ash::Device,
+        phy_device: vk::PhysicalDevice,
+        queue_family_index: u32,
+        src_format: vk::Format,
+        extent: vk::Extent2D,
+        scaled: bool,
+        image_count: usize,
+    ) -> Result<Self> {
+        let (format, need_blit) = [src_format, vk::Format::B8G8R8A8_UNORM]
+            .into_iter()
+            .find_map(|format| {
+                let features = ash_instance
+                    .get_physical_device_format_properties(phy_device, format)
+                    .linear_tiling_features;
+                let need_blit = copy_needs_blit(src_format, format, features, scaled, false)?;
+                Some((format, need_blit))
+            })
+            .ok_or(anyhow!("no linear format to read back {:?}", src_format))?;
+
+        let mut readback = Self {
+            format,
+            extent,
+            row_pitch: 0,
+            size: 0,
+            need_blit,
+            command_pool: vk::CommandPool::null(),
+            command_buffer: vk::CommandBuffer::null(),
+            fence: vk::Fence::null(),
+            image: vk::Image::null(),
+            memory: vk::DeviceMemory::null(),
+            ptr: 0,
+            coherent: false,
+            semaphores: vec![],
+            conversion: None,
+            pending: Mutex::new(None),
+        };
+        // null handles of a partial init are ignored by `destroy`
+        let res = readback
+            .init_commands(device, queue_family_index, image_count)
+            .and_then(|_| readback.init_image(ash_instance, device, phy_device));
+        if let Err(e) = res {
+            readback.destroy(device);
+            return Err(e);
+        }
+        Ok(readback)
+    }
+
+    /// Like `new`, but frames are converted by the kernel to the layout of `params`, e.g. NV12,
+    /// applying `transform` if the processing of `params` asks for it. The buffer the kernel
+    /// writes is read back, rows `row_pitch` apart. The queue family must support compute.
+    #[cfg(feature = "v4l2")]
+    #[allow(clippy::too_many_arguments)]
+    pub unsafe fn converted(
+        ash_instance: &ash::Instance,
+        device: &ash::Device,
+        phy_device: vk::PhysicalDevice,
+        queue_family_index: u32,
+        src_format: vk::Format,
+        params: ConvertParams,
+        scaled: bool,
+        image_count: usize,
+        transform: Option<&ColorTransform>,
+    ) -> Result<Self> {
+        let features = ash_instance
+            .get_physical_device_format_properties(phy_device, src_format)
+            .optimal_tiling_features;
+        let need_blit = format_convertible(src_format, features, scaled, false)
+            .ok_or(anyhow!("{:?} frames can't be converted", src_format))?;
+
+        let mut readback = Self {
+            format: src_format,
+            extent: vk::Extent2D {
+                width: params.width,
+                height: params.height,
+            },
+            row_pitch: params.stride as _,
+            size: params.size() as _,
+            need_blit,
+            command_pool: vk::CommandPool::null(),
+            command_buffer: vk::CommandBuffer::null(),
+            fence: vk::Fence::null(),
+            image: vk::Image::null(),
+            memory: vk::DeviceMemory::null(),
+            ptr: 0,
+            coherent: false,
+            semaphores: vec![],
+            conversion: None,
+            pending: Mutex::new(None),
+        };
+        let res = readback
+            .init_commands(device, queue_family_index, image_count)
+            .and_then(|_| {
+                readback.init_conversion(ash_instance, device, phy_device, params, transform)
+            });
+        if let Err(e) = res {
+            readback.destroy(device);
+            return Err(e);
+        }
+        Ok(readback)
+    }
+
+    unsafe fn init_commands(
+        &mut self,
+        device: &ash::Device,
+        queue_family_index: u32,
+        image_count: usize,
+    ) -> Result<()> {
+        let pool_info = vk::CommandPoolCreateInfo::builder().queue_family_index(queue_family_index);
+        self.command_pool = device.create_command_pool(&pool_info, None)?;
+        let buffers_info = vk::CommandBufferAllocateInfo::builder()
+            .command_pool(self.command_pool)
+            .level(vk::CommandBufferLevel::PRIMARY)
+            .command_buffer_count(1);
+        self.command_buffer = device.allocate_command_buffers(&buffers_info)?[0];
+        self.fence = device.create_fence(&vk::FenceCreateInfo::builder(), None)?;
+        let semaphore_info = vk::SemaphoreCreateInfo::builder();
+        for _ in 0..image_count {
+            let semaphore = device.create_semaphore(&semaphore_info, None)?;
+            self.semaphores.push(semaphore);
+        }
+        Ok(())
+    }
+
+    unsafe fn init_image(
+        &mut self,
+        ash_instance: &ash::Instance,
+        device: &ash::Device,
+        phy_device: vk::PhysicalDevice,
+    ) -> Result<()> {
+        let (image, memory, memory_props, layout, ptr) = create_host_image(
+            ash_instance,
+            device,
+            phy_device,
+            self.format,
+            self.extent.width,
+            self.extent.height,
+        )?;
+        self.image = image;
+        self.memory = memory;
+        self.row_pitch = layout.row_pitch;
+        self.size = layout.size as usize;
+        self.ptr = ptr as usize;
+        self.coherent = memory_props.contains(vk::MemoryPropertyFlags::HOST_COHERENT);
+        Ok(())
+    }
+
+    #[cfg(feature = "v4l2")]
+    unsafe fn init_conversion(
+        &mut self,
+        ash_instance: &ash::Instance,
+        device: &ash::Device,
+        phy_device: vk::PhysicalDevice,
+        params: ConvertParams,
+        transform: Option<&ColorTransform>,
+    ) -> Result<()> {
+        let (image, memory) = create_convert_image(
+            ash_instance,
+            device,
+            phy_device,
+            self.format,
+            self.extent.width,
+            self.extent.height,
+        )?;
+        self.image = image;
+        self.memory = memory;
+        let pipeline = ConvertPipeline::new(ash_instance, device, phy_device, transform)?;
+        let res = ConvertTarget::new(
+            ash_instance,
+            device,
+            phy_device,
+            &pipeline,
+            image,
+            self.format,
+            params,
+            false,
+        );
+        let (target, memory_props) = match res {
+            Ok(v) => v,
+            Err(e) => {
+                pipeline.destroy(device);
+                return Err(e);
+            }
+        };
+        let res = device.map_memory(
+            target.memory,
+            0,
+            vk::WHOLE_SIZE,
+            vk::MemoryMapFlags::empty(),
+        );
+        // unmapped once freed
+        self.conversion = Some((pipeline, target));
+        self.ptr = res? as usize;
+        self.coherent = memory_props.contains(vk::MemoryPropertyFlags::HOST_COHERENT);
+        Ok(())
+    }
+
+    /// Copies `crop` of `src_image` once `waits` are signaled, on `queue` of the family the
+    /// readback was created for, without waiting for it. Frames are read back one present
+    /// behind: the copy submitted before, normally long done, is waited for first and its
+    /// pixels, rows `row_pitch` apart, are handed to `read` with the `tag` it was submitted
+    /// with. Returns the semaphore the present must wait on instead of `waits`.
+    #[allow(clippy::too_many_arguments)]
+    pub unsafe fn copy(
+        &self,
+        device: &ash::Device,
+        queue: vk::Queue,
+        src_image: vk::Image,
+        image_index: usize,
+        crop: vk::Rect2D,
+        waits: &[vk::Semaphore],
+        tag: u64,
+        read: impl FnOnce(u64, &[u8]),
+    ) -> Result<vk::Semaphore> {
+        let semaphore = *self
+            .semaphores
+            .get(image_index)
+            .ok_or(anyhow!("image index {} out of range", image_index))?;
+        // also keeps copies of concurrent presents apart
+        let mut pending = self.pending.lock().map_err(|_| anyhow!("poisoned"))?;
+        if let Some(tag) = pending.take() {
+            device.wait_for_fences(&[self.fence], true, u64::MAX)?;
+            device.reset_fences(&[self.fence])?;
+            if !self.coherent {
+                let memory = match &self.conversion {
+                    Some((_, target)) => target.memory,
+                    None => self.memory,
+                };
+                let range = vk::MappedMemoryRange::builder()
+                    .memory(memory)
+                    .offset(0)
+                    .size(vk::WHOLE_SIZE)
+                    .build();
+                device.invalidate_mapped_memory_ranges(&[range])?;
+            }
+            read(
+                tag,
+                std::slice::from_raw_parts(self.ptr as *const u8, self.size),
+            );
+        }
+
+        // the previous copy was waited for, nothing is pending
+        device.reset_command_pool(self.command_pool, vk::CommandPoolResetFlags::empty())?;
+        record_copy_image(
+            device,
+            self.command_buffer,
+            src_image,
+            self.image,
+            vk::QUEUE_FAMILY_IGNORED,
+            vk::QUEUE_FAMILY_IGNORED,
+            crop,
+            vk::Rect2D {
+                offset: vk::Offset2D { x: 0, y: 0 },
+                extent: self.extent,
+            },
+            false,
+            self.need_blit,
+            true,
+            None,
+            self.conversion
+                .as_ref()
+                .map(|(pipeline, target)| (pipeline, target)),
+            None,
+        )?;
+
+        let command_buffers = &[self.command_buffer];
+        let signals = &[semaphore];
+        let wait_stages = vec![vk::PipelineStageFlags::TRANSFER; waits.len()];
+        let submit_info = vk::SubmitInfo::builder()
+            .command_buffers(command_buffers)
+            .wait_semaphores(waits)
+            .wait_dst_stage_mask(&wait_stages)
+            .signal_semaphores(signals);
+        device.queue_submit(queue, &[submit_info.build()], self.fence)?;
+        *pending = Some(tag);
+        Ok(semaphore)
+    }
+
+    pub unsafe fn destroy(&self, device: &ash::Device) {
+        if matches!(self.pending.lock().as_deref(), Ok(Some(_))) {
+            let _ = device.wait_for_fences(&[self.fence], true, u64::MAX);
+        }
+        for &semaphore in &self.semaphores {
+            device.destroy_semaphore(semaphore, None);
+        }
+        device.destroy_fence(self.fence, None);
+        device.destroy_command_pool(self.command_pool, None);
+        if let Some((pipeline, target)) = &self.conversion {
+            target.destroy(device);
+            pipeline.destroy(device);
+        }
+        device.destroy_image(self.image, None);
+        device.free_memory(self.memory, None);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub unsafe fn record_copy_image(
     ash_device: &ash::Device,
     command_buffer: vk::CommandBuffer,
@@ -226,18 +1493,38 @@ pub unsafe fn record_copy_image(
     export_image: vk::Image,
     mut src_queue_family: u32,
     mut dst_queue_family: u32,
-    width: u32,
-    height: u32,
+    src: vk::Rect2D,
+    // region of the export image written, all of it unless frames are composed into it
+    dst: vk::Rect2D,
+    // export image is cleared first, padding frames composed into it that don't fill it
+    clear: bool,
     need_blit: bool,
+    // export image is read back by the host after the copy
+    host_read: bool,
+    watermark: Option<&WatermarkOverlay>,
+    // export image holds the frame to convert, the kernel writes the buffer exported instead
+    convert: Option<(&ConvertPipeline, &ConvertTarget)>,
+    timer: Option<(&CopyTimer, usize)>,
 ) -> VkResult<()> {
     if src_queue_family == dst_queue_family {
         src_queue_family = vk::QUEUE_FAMILY_IGNORED;
         dst_queue_family = vk::QUEUE_FAMILY_IGNORED;
     }
+    let vk::Rect2D {
+        offset: src_offset,
+        extent: vk::Extent2D { width, height },
+    } = src;
+    let vk::Rect2D {
+        offset: dst_offset,
+        extent: dst_extent,
+    } = dst;
 
     let begin_info =
         vk::CommandBufferBeginInfo::builder().flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
     ash_device.begin_command_buffer(command_buffer, &begin_info)?;
+    if let Some((timer, index)) = timer {
+        timer.cmd_begin(ash_device, command_buffer, index);
+    }
 
     let subresource = vk::ImageSubresourceRange::builder()
         .aspect_mask(vk::ImageAspectFlags::COLOR)
@@ -269,15 +1556,50 @@ pub unsafe fn record_copy_image(
         .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
         .build();
 
+    // chained to the copy's wait on the app's semaphores at TRANSFER, which makes writes of the
+    // queue rendering the image visible, also of compute work presenting without graphics
     ash_device.cmd_pipeline_barrier(
         command_buffer,
-        vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+        vk::PipelineStageFlags::TRANSFER,
         vk::PipelineStageFlags::TRANSFER,
         vk::DependencyFlags::empty(),
         &[],
         &[],
         &[src_barrier, dst_barrier],
     );
+    frame_trace_barrier(
+        vk::PipelineStageFlags::TRANSFER,
+        vk::PipelineStageFlags::TRANSFER,
+        &[src_barrier, dst_barrier],
+    );
+
+    if clear {
+        let black = vk::ClearColorValue {
+            float32: [0.0, 0.0, 0.0, 1.0],
+        };
+        ash_device.cmd_clear_color_image(
+            command_buffer,
+            export_image,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            &black,
+            &[subresource],
+        );
+        frame_trace(|| format!("vkCmdClearColorImage {:?}", export_image));
+        // the copy writes over the cleared pixels
+        let clear_barrier = vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .build();
+        ash_device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::DependencyFlags::empty(),
+            &[clear_barrier],
+            &[],
+            &[],
+        );
+    }
 
     let subresource_layer = vk::ImageSubresourceLayers::builder()
         .aspect_mask(vk::ImageAspectFlags::COLOR)
@@ -303,25 +1625,39 @@ pub unsafe fn record_copy_image(
 
         let image_blit = vk::ImageBlit::builder()
             .src_offsets([
-                vk::Offset3D { x: 0, y: 0, z: 0 },
                 vk::Offset3D {
-                    x: width as _,
-                    y: height as _,
+                    x: src_offset.x,
+                    y: src_offset.y,
+                    z: 0,
+                },
+                vk::Offset3D {
+                    x: src_offset.x + width as i32,
+                    y: src_offset.y + height as i32,
                     z: 1,
                 },
             ])
             .src_subresource(src_subresource)
             .dst_offsets([
-                vk::Offset3D { x: 0, y: 0, z: 0 },
                 vk::Offset3D {
-                    x: width as _,
-                    y: height as _,
+                    x: dst_offset.x,
+                    y: dst_offset.y,
+                    z: 0,
+                },
+                vk::Offset3D {
+                    x: dst_offset.x + dst_extent.width as i32,
+                    y: dst_offset.y + dst_extent.height as i32,
                     z: 1,
                 },
             ])
             .dst_subresource(dst_subresource)
             .build();
 
+        // only downscaled frames are resized, format conversions map pixels 1:1
+        let filter = if dst_extent == src.extent {
+            vk::Filter::NEAREST
+        } else {
+            vk::Filter::LINEAR
+        };
         ash_device.cmd_blit_image(
             command_buffer,
             src_image,
@@ -329,8 +1665,14 @@ pub unsafe fn record_copy_image(
             export_image,
             vk::ImageLayout::TRANSFER_DST_OPTIMAL,
             &[image_blit],
-            vk::Filter::NEAREST,
-        )
+            filter,
+        );
+        frame_trace(|| {
+            format!(
+                "vkCmdBlitImage {:?} -> {:?}, {:?} -> {:?}, {:?}",
+                src_image, export_image, image_blit.src_offsets, image_blit.dst_offsets, filter
+            )
+        });
     } else {
         let image_copy = vk::ImageCopy::builder()
             .extent(vk::Extent3D {
@@ -338,7 +1680,17 @@ pub unsafe fn record_copy_image(
                 height,
                 depth: 1,
             })
+            .src_offset(vk::Offset3D {
+                x: src_offset.x,
+                y: src_offset.y,
+                z: 0,
+            })
             .src_subresource(subresource_layer)
+            .dst_offset(vk::Offset3D {
+                x: dst_offset.x,
+                y: dst_offset.y,
+                z: 0,
+            })
             .dst_subresource(subresource_layer)
             .build();
         ash_device.cmd_copy_image(
@@ -349,6 +1701,26 @@ pub unsafe fn record_copy_image(
             vk::ImageLayout::TRANSFER_DST_OPTIMAL,
             &[image_copy],
         );
+        frame_trace(|| {
+            format!(
+                "vkCmdCopyImage {:?} -> {:?}, from {:?} to {:?}, extent {:?}",
+                src_image,
+                export_image,
+                image_copy.src_offset,
+                image_copy.dst_offset,
+                image_copy.extent
+            )
+        });
+    }
+
+    if let Some(watermark) = watermark {
+        watermark.record(
+            ash_device,
+            command_buffer,
+            export_image,
+            dst_extent.width,
+            dst_extent.height,
+        );
     }
 
     let src_barrier = vk::ImageMemoryBarrier::builder()
@@ -370,20 +1742,576 @@ pub unsafe fn record_copy_image(
         .image(export_image)
         .subresource_range(subresource)
         .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
-        .dst_access_mask(vk::AccessFlags::empty())
+        .dst_access_mask(if convert.is_some() {
+            vk::AccessFlags::SHADER_READ
+        } else if host_read {
+            vk::AccessFlags::HOST_READ
+        } else {
+            vk::AccessFlags::empty()
+        })
         .build();
 
+    // waiting for the fence alone doesn't make writes visible to the host
+    let dst_stage = if convert.is_some() {
+        vk::PipelineStageFlags::BOTTOM_OF_PIPE | vk::PipelineStageFlags::COMPUTE_SHADER
+    } else if host_read {
+        vk::PipelineStageFlags::BOTTOM_OF_PIPE | vk::PipelineStageFlags::HOST
+    } else {
+        vk::PipelineStageFlags::BOTTOM_OF_PIPE
+    };
     ash_device.cmd_pipeline_barrier(
         command_buffer,
         vk::PipelineStageFlags::TRANSFER,
-        vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+        dst_stage,
         vk::DependencyFlags::empty(),
         &[],
         &[],
         &[src_barrier, dst_barrier],
     );
+    frame_trace_barrier(
+        vk::PipelineStageFlags::TRANSFER,
+        dst_stage,
+        &[src_barrier, dst_barrier],
+    );
+
+    if let Some((pipeline, target)) = convert {
+        pipeline.record(ash_device, command_buffer, target, host_read);
+    }
 
+    if let Some((timer, index)) = timer {
+        timer.cmd_end(ash_device, command_buffer, index);
+    }
     ash_device.end_command_buffer(command_buffer)?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pw_capture_client::Format;
+
+    #[test]
+    fn copy_or_blit() {
+        let src = vk::Format::B8G8R8A8_UNORM;
+        let copy = vk::FormatFeatureFlags::TRANSFER_DST;
+        let blit = vk::FormatFeatureFlags::BLIT_DST;
+        assert_eq!(
+            copy_needs_blit(src, src, copy | blit, false, false),
+            Some(false)
+        );
+        assert_eq!(copy_needs_blit(src, src, copy, false, false), Some(false));
+        // modifier of same format that can't be copied into
+        assert_eq!(copy_needs_blit(src, src, blit, false, false), Some(true));
+        assert_eq!(
+            copy_needs_blit(src, vk::Format::R8G8B8A8_UNORM, copy | blit, false, false),
+            Some(true)
+        );
+        assert_eq!(
+            copy_needs_blit(src, vk::Format::R8G8B8A8_UNORM, copy, false, false),
+            None
+        );
+        assert_eq!(copy_needs_blit(src, src, copy, false, true), None);
+        assert_eq!(
+            copy_needs_blit(src, src, copy | blit, false, true),
+            Some(false)
+        );
+        // downscaled frames are always blitted
+        assert_eq!(
+            copy_needs_blit(src, src, copy | blit, true, false),
+            Some(true)
+        );
+        assert_eq!(copy_needs_blit(src, src, copy, true, false), None);
+    }
+
+    #[test]
+    fn refixation() {
+        let bgra = vk::Format::B8G8R8A8_UNORM;
+        let rgba = vk::Format::R8G8B8A8_UNORM;
+        let linear = Some(0);
+        assert!(!fixation_changed(None, bgra, linear, None));
+        let fixated = Some((bgra, linear, None));
+        assert!(!fixation_changed(fixated, bgra, linear, None));
+        // second fixation to another format or modifier
+        assert!(fixation_changed(fixated, rgba, linear, None));
+        assert!(fixation_changed(
+            fixated,
+            bgra,
+            Some(0x0100_0000_0000_0001),
+            None
+        ));
+        assert!(fixation_changed(fixated, bgra, None, None));
+        // NV12 converted from a copy in the same format and modifier
+        let convert = |format| ConvertParams::new(format, 64, 64, bgra, Processing::default());
+        let nv12 = convert(Format::NV12);
+        assert!(fixation_changed(fixated, bgra, linear, nv12));
+        // or RGBA converted instead
+        let fixated = Some((bgra, linear, nv12));
+        assert!(!fixation_changed(fixated, bgra, linear, nv12));
+        assert!(fixation_changed(
+            fixated,
+            bgra,
+            linear,
+            convert(Format::BGRA)
+        ));
+    }
+
+    #[test]
+    fn multi_device_queues() {
+        use vk::Handle;
+
+        let (device_a, device_b) = (vk::Device::from_raw(1), vk::Device::from_raw(2));
+        let queue = |raw| vk::Queue::from_raw(raw);
+        let graphics = vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE;
+        let transfer = vk::QueueFlags::TRANSFER;
+        // both devices created by one app, e.g. on two GPUs, the other device's queues first
+        let queues = [
+            (queue(20), device_b, 0, graphics),
+            (queue(21), device_b, 1, transfer),
+            (queue(10), device_a, 0, graphics),
+            (queue(11), device_a, 2, transfer),
+        ];
+        let copy = vk::QueueFlags::empty();
+        let unknown = vk::QUEUE_FAMILY_IGNORED;
+        assert_eq!(
+            select_capture_queue(device_a, &queues, vk::QueueFlags::GRAPHICS, unknown),
+            Some((queue(10), 0))
+        );
+        assert_eq!(
+            select_capture_queue(device_a, &queues, copy, unknown),
+            Some((queue(11), 2))
+        );
+        assert_eq!(
+            select_capture_queue(device_b, &queues, vk::QueueFlags::GRAPHICS, unknown),
+            Some((queue(20), 0))
+        );
+        assert_eq!(
+            select_capture_queue(device_b, &queues, copy, unknown),
+            Some((queue(21), 1))
+        );
+        // conversions run on the graphics queue without an async compute one
+        assert_eq!(
+            select_capture_queue(device_a, &queues, vk::QueueFlags::COMPUTE, unknown),
+            Some((queue(10), 0))
+        );
+        // only a transfer queue on this device, blits can't borrow the other's graphics queue
+        let queues = [(queue(20), device_b, 0, graphics), queues[3]];
+        assert_eq!(
+            select_capture_queue(device_a, &queues, vk::QueueFlags::GRAPHICS, unknown),
+            None
+        );
+        assert_eq!(
+            select_capture_queue(device_a, &queues, vk::QueueFlags::COMPUTE, unknown),
+            None
+        );
+        // graphics queues copy too
+        assert_eq!(
+            select_capture_queue(device_b, &queues, copy, unknown),
+            Some((queue(20), 0))
+        );
+    }
+
+    #[test]
+    fn compute_source_queue() {
+        use vk::Handle;
+
+        let device = vk::Device::from_raw(1);
+        let queue = |raw| vk::Queue::from_raw(raw);
+        let graphics = vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE;
+        let compute = vk::QueueFlags::COMPUTE;
+        let transfer = vk::QueueFlags::TRANSFER;
+        let queues = [
+            (queue(10), device, 0, graphics),
+            (queue(11), device, 1, compute),
+            (queue(12), device, 2, transfer),
+        ];
+        let copy = vk::QueueFlags::empty();
+        // images written by the compute queue are copied there instead of the transfer queue
+        assert_eq!(
+            select_capture_queue(device, &queues, copy, 1),
+            Some((queue(11), 1))
+        );
+        assert_eq!(
+            select_capture_queue(device, &queues, vk::QueueFlags::COMPUTE, 1),
+            Some((queue(11), 1))
+        );
+        // blits still need the graphics queue
+        assert_eq!(
+            select_capture_queue(device, &queues, vk::QueueFlags::GRAPHICS, 1),
+            Some((queue(10), 0))
+        );
+        // apps rendering on the graphics queue keep it to themselves
+        assert_eq!(
+            select_capture_queue(device, &queues, copy, 0),
+            Some((queue(12), 2))
+        );
+        // unknown or of no queue of the device
+        assert_eq!(
+            select_capture_queue(device, &queues, copy, unknown),
+            Some((queue(12), 2))
+        );
+        assert_eq!(
+            select_capture_queue(device, &queues, copy, 5),
+            Some((queue(12), 2))
+        );
+        // a compute-only device, the present queue is the compute one
+        let queues = [queues[1], queues[2]];
+        assert_eq!(
+            select_capture_queue(device, &queues, vk::QueueFlags::GRAPHICS, 1),
+            None
+        );
+        assert_eq!(
+            select_capture_queue(device, &queues, copy, 1),
+            Some((queue(11), 1))
+        );
+    }
+
+    #[test]
+    fn retired_swapchain() {
+        let debounce = Duration::from_millis(200);
+        let created = Instant::now();
+        let mut old = Some(created + debounce);
+        assert!(!stream_due(old, created));
+        assert!(stream_due(old, created + debounce));
+
+        // recreated, as `create_swapchain_khr` does with `old_swapchain`
+        let recreated = created + debounce * 2;
+        let new = Some(recreated + debounce);
+        old = None;
+        // an image of the old swapchain acquired before is presented after the new one exists
+        assert!(!stream_due(old, recreated + debounce));
+        assert!(stream_due(new, recreated + debounce));
+    }
+
+    #[test]
+    fn recreate_same_size() {
+        let extent = |width, height| vk::Extent2D { width, height };
+        // e.g. recreated for a present mode change
+        assert!(fixation_reusable(
+            extent(1920, 1080),
+            extent(1920, 1080),
+            3,
+            3,
+            false,
+            false
+        ));
+        assert!(fixation_reusable(
+            extent(1920, 1080),
+            extent(1920, 1080),
+            0,
+            3,
+            false,
+            false
+        ));
+        assert!(!fixation_reusable(
+            extent(1920, 1080),
+            extent(1280, 720),
+            3,
+            3,
+            false,
+            false
+        ));
+        // more images than command buffers
+        assert!(!fixation_reusable(
+            extent(1920, 1080),
+            extent(1920, 1080),
+            2,
+            3,
+            false,
+            false
+        ));
+        // downscaled into the same stream size from another crop
+        assert!(!fixation_reusable(
+            extent(1280, 720),
+            extent(1280, 720),
+            3,
+            3,
+            false,
+            true
+        ));
+        assert!(fixation_reusable(
+            extent(1280, 720),
+            extent(1280, 720),
+            3,
+            3,
+            true,
+            true
+        ));
+    }
+
+    #[test]
+    fn duplicated_swapchain() {
+        use vk::Handle;
+
+        let a = vk::SwapchainKHR::from_raw(1);
+        let b = vk::SwapchainKHR::from_raw(2);
+        assert_eq!(unique_swapchain_indices(&[a, b]), vec![0, 1]);
+        assert_eq!(unique_swapchain_indices(&[a, b, a]), vec![0, 1]);
+        assert_eq!(unique_swapchain_indices(&[a, a, b]), vec![0, 2]);
+        assert!(unique_swapchain_indices(&[]).is_empty());
+    }
+
+    #[test]
+    fn present_result() {
+        let out_of_date = vk::Result::ERROR_OUT_OF_DATE_KHR;
+        // only one of two swapchains resized during the present
+        let results = [vk::Result::SUCCESS, out_of_date];
+        assert_eq!(
+            swapchain_present_result(out_of_date, &results, 0),
+            vk::Result::SUCCESS
+        );
+        assert_eq!(
+            swapchain_present_result(out_of_date, &results, 1),
+            out_of_date
+        );
+        // results not filled in for a single swapchain
+        assert_eq!(
+            swapchain_present_result(out_of_date, &[vk::Result::SUCCESS], 0),
+            out_of_date
+        );
+        assert_eq!(
+            swapchain_present_result(vk::Result::SUBOPTIMAL_KHR, &[vk::Result::SUCCESS], 0),
+            vk::Result::SUBOPTIMAL_KHR
+        );
+    }
+
+    #[test]
+    fn present_waits() {
+        use core::slice;
+        use vk::Handle;
+
+        let semaphores = |raws: &[u64]| {
+            raws.iter()
+                .map(|&raw| vk::Semaphore::from_raw(raw))
+                .collect::<Vec<_>>()
+        };
+        // every field, bytes would include padding
+        let fields = |info: &vk::PresentInfoKHR| {
+            (
+                info.s_type,
+                info.p_next,
+                info.wait_semaphore_count,
+                info.p_wait_semaphores,
+                info.swapchain_count,
+                info.p_swapchains,
+                info.p_image_indices,
+                info.p_results,
+            )
+        };
+        let app = semaphores(&[1, 2]);
+        let swapchains = [vk::SwapchainKHR::from_raw(10)];
+        let image_indices = [0];
+        let present_info = vk::PresentInfoKHR::builder()
+            .wait_semaphores(&app)
+            .swapchains(&swapchains)
+            .image_indices(&image_indices)
+            .build();
+
+        // nothing captured, passed through as is
+        let waits = present_wait_semaphores(&[], &semaphores(&[3]));
+        assert!(waits.is_empty());
+        let passed = present_info_with_waits(&present_info, &waits);
+        assert_eq!(fields(&passed), fields(&present_info));
+
+        // captured, app semaphores are waited on by the copies, the present waits on copies
+        // and on semaphores of other devices
+        let captured = semaphores(&[20, 21]);
+        let waits = present_wait_semaphores(&captured, &semaphores(&[3]));
+        assert_eq!(waits, semaphores(&[20, 21, 3]));
+        assert!(app.iter().all(|semaphore| !waits.contains(semaphore)));
+        let passed = present_info_with_waits(&present_info, &waits);
+        let passed_waits = unsafe {
+            slice::from_raw_parts(passed.p_wait_semaphores, passed.wait_semaphore_count as _)
+        };
+        assert_eq!(passed_waits, &waits[..]);
+        // everything else is the app's
+        let restored = vk::PresentInfoKHR {
+            wait_semaphore_count: present_info.wait_semaphore_count,
+            p_wait_semaphores: present_info.p_wait_semaphores,
+            ..passed
+        };
+        assert_eq!(fields(&restored), fields(&present_info));
+    }
+
+    #[test]
+    fn present_wait_chain() {
+        use vk::Handle;
+
+        let semaphores = |raws: &[u64]| {
+            raws.iter()
+                .map(|&raw| vk::Semaphore::from_raw(raw))
+                .collect::<Vec<_>>()
+        };
+        let app = semaphores(&[1, 2]);
+        let foreign = semaphores(&[3]);
+
+        // nothing captured, the present keeps its own waits
+        let chain = PresentWaitChain::new(app.clone());
+        assert_eq!(chain.waits(), &app[..]);
+        assert!(chain.present_waits(&foreign).is_empty());
+
+        // three swapchains presented at once, the second one skipped
+        let mut chain = PresentWaitChain::new(app.clone());
+        let mut waited = vec![];
+        waited.extend_from_slice(chain.waits());
+        chain.captured(semaphores(&[10]));
+        assert_eq!(chain.waits(), &semaphores(&[10])[..]);
+        waited.extend_from_slice(chain.waits());
+        chain.captured(semaphores(&[30]));
+        let present = chain.present_waits(&foreign);
+        assert_eq!(present, semaphores(&[30, 3]));
+        waited.extend(present);
+        // every semaphore is waited on once
+        let mut unique = waited.clone();
+        unique.sort_by_key(|v| v.as_raw());
+        unique.dedup();
+        assert_eq!(unique.len(), waited.len());
+        assert!(app.iter().all(|v| waited.contains(v)));
+    }
+
+    #[test]
+    fn timeline_signals() {
+        use vk::Handle;
+
+        let semaphores = [vk::Semaphore::from_raw(1), vk::Semaphore::from_raw(2)];
+        let values = [5, 7];
+        let mut timeline_info =
+            vk::TimelineSemaphoreSubmitInfo::builder().signal_semaphore_values(&values);
+        let submit = vk::SubmitInfo::builder()
+            .signal_semaphores(&semaphores)
+            .push_next(&mut timeline_info)
+            .build();
+        let signals = unsafe { submit_signals(&submit) };
+        assert_eq!(signals, [(semaphores[0], 5), (semaphores[1], 7)]);
+
+        // binary semaphores only, no values
+        let submit = vk::SubmitInfo::builder()
+            .signal_semaphores(&semaphores[..1])
+            .build();
+        let signals = unsafe { submit_signals(&submit) };
+        assert_eq!(signals, [(semaphores[0], 0)]);
+        let signals = unsafe { submit_signals(&vk::SubmitInfo::default()) };
+        assert!(signals.is_empty());
+
+        let infos = [vk::SemaphoreSubmitInfo::builder()
+            .semaphore(semaphores[1])
+            .value(9)
+            .build()];
+        let submit = vk::SubmitInfo2::builder()
+            .signal_semaphore_infos(&infos)
+            .build();
+        let signals = unsafe { submit2_signals(&submit) };
+        assert_eq!(signals, [(semaphores[1], 9)]);
+    }
+
+    #[test]
+    fn source_family() {
+        const UNKNOWN: u32 = vk::QUEUE_FAMILY_IGNORED;
+
+        // rendered and transferred to the present family, or nothing known
+        assert_eq!(select_source_family(0, &[0], 0), 0);
+        assert_eq!(select_source_family(0, &[], UNKNOWN), 0);
+        assert_eq!(select_source_family(0, &[UNKNOWN], UNKNOWN), 0);
+        // presented as rendered on a compute family
+        assert_eq!(select_source_family(0, &[2], 0), 2);
+        assert_eq!(select_source_family(0, &[2, UNKNOWN, 2], UNKNOWN), 2);
+        // signaled by several families, the one that rendered after the acquire
+        assert_eq!(select_source_family(0, &[1, 2], 2), 2);
+        assert_eq!(select_source_family(0, &[1, 2], UNKNOWN), 0);
+        // present waits on nothing the app submitted
+        assert_eq!(select_source_family(0, &[], 1), 1);
+    }
+
+    #[test]
+    fn stream_region() {
+        let rect = |x, y, width, height| vk::Rect2D {
+            offset: vk::Offset2D { x, y },
+            extent: vk::Extent2D { width, height },
+        };
+        let whole = rect(0, 0, 1920, 1080);
+        let letterboxed = rect(0, 140, 1920, 800);
+        assert_eq!(
+            region_in_stream(letterboxed, whole, whole.extent),
+            Some(letterboxed)
+        );
+        // cropped, partially outside, and downscaled to half
+        assert_eq!(
+            region_in_stream(
+                letterboxed,
+                rect(960, 0, 960, 1080),
+                vk::Extent2D {
+                    width: 480,
+                    height: 540
+                }
+            ),
+            Some(rect(0, 70, 480, 400))
+        );
+        assert_eq!(
+            region_in_stream(rect(0, 0, 100, 100), rect(200, 0, 100, 100), whole.extent),
+            None
+        );
+    }
+
+    #[test]
+    fn composition() {
+        let extent = |width, height| vk::Extent2D { width, height };
+        let offset = |x| vk::Offset2D { x, y: 0 };
+        // both eyes of the same size
+        assert_eq!(
+            compose_layout(&[extent(1440, 1600), extent(1440, 1600)]),
+            (extent(2880, 1600), vec![offset(0), offset(1440)])
+        );
+        // the lower one is padded
+        assert_eq!(
+            compose_layout(&[extent(1280, 720), extent(1920, 1080), extent(640, 480)]),
+            (
+                extent(3840, 1080),
+                vec![offset(0), offset(1280), offset(3200)]
+            )
+        );
+        assert_eq!(compose_layout(&[]), (extent(0, 0), vec![]));
+    }
+
+    #[test]
+    fn damage() {
+        let rect = |x| vk::RectLayerKHR {
+            offset: vk::Offset2D { x, y: 0 },
+            extent: vk::Extent2D {
+                width: 10,
+                height: 10,
+            },
+            layer: 0,
+        };
+        let mut damage = None;
+        accumulate_damage(&mut damage, Some(&[rect(0)]));
+        assert_eq!(damage, None);
+
+        damage = Some(vec![]);
+        accumulate_damage(&mut damage, Some(&[rect(0)]));
+        accumulate_damage(&mut damage, Some(&[rect(10), rect(20)]));
+        let offsets: Vec<_> = damage.iter().flatten().map(|v| v.offset.x).collect();
+        assert_eq!(offsets, [0, 10, 20]);
+
+        accumulate_damage(&mut damage, None);
+        assert_eq!(damage, None);
+
+        damage = Some(vec![]);
+        accumulate_damage(&mut damage, Some(&vec![rect(0); MAX_DAMAGE_RECTS + 1]));
+        assert_eq!(damage, None);
+    }
+
+    #[test]
+    fn timestamp_duration() {
+        assert_eq!(
+            ticks_to_duration([1000, 1500], 64, 2.0),
+            Duration::from_nanos(1000)
+        );
+        // wrapped around 36 valid bits
+        let max = (1u64 << 36) - 1;
+        assert_eq!(
+            ticks_to_duration([max - 9, 90], 36, 1.0),
+            Duration::from_nanos(100)
+        );
+    }
+}
@@ -0,0 +1,428 @@
+use crate::utils::*;
+
+use core::mem;
+use core::ptr;
+
+use anyhow::Result;
+use ash::vk;
+
+const GLYPH_WIDTH: u32 = 5;
+const GLYPH_HEIGHT: u32 = 7;
+// 1px spacing between glyphs and around text
+const CELL_WIDTH: u32 = GLYPH_WIDTH + 1;
+const MAX_CHARS: u32 = 64;
+const SCALE: u32 = 2;
+const MARGIN: u32 = 8;
+
+const WATERMARK_WIDTH: u32 = MAX_CHARS * CELL_WIDTH + 1;
+const WATERMARK_HEIGHT: u32 = GLYPH_HEIGHT + 2;
+const WATERMARK_BPP: u32 = 4;
+
+/// 5x7 glyphs, a byte per row with the leftmost pixel in bit 4
+const FONT: &[(char, [u8; 7])] = &[
+    (' ', [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]),
+    ('0', [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E]),
+    ('1', [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E]),
+    ('2', [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F]),
+    ('3', [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E]),
+    ('4', [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02]),
+    ('5', [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E]),
+    ('6', [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E]),
+    ('7', [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08]),
+    ('8', [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E]),
+    ('9', [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C]),
+    ('A', [0x0E, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11]),
+    ('B', [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E]),
+    ('C', [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E]),
+    ('D', [0x1C, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1C]),
+    ('E', [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F]),
+    ('F', [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10]),
+    ('G', [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F]),
+    ('H', [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11]),
+    ('I', [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E]),
+    ('J', [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C]),
+    ('K', [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11]),
+    ('L', [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F]),
+    ('M', [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11]),
+    ('N', [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11]),
+    ('O', [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E]),
+    ('P', [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10]),
+    ('Q', [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D]),
+    ('R', [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11]),
+    ('S', [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E]),
+    ('T', [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04]),
+    ('U', [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E]),
+    ('V', [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04]),
+    ('W', [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A]),
+    ('X', [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11]),
+    ('Y', [0x11, 0x11, 0x11, 0x0A, 0x04, 0x04, 0x04]),
+    ('Z', [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F]),
+    (':', [0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x0C, 0x00]),
+    ('-', [0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00]),
+    ('.', [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C]),
+    ('/', [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00]),
+    ('_', [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1F]),
+    ('#', [0x0A, 0x0A, 0x1F, 0x0A, 0x1F, 0x0A, 0x0A]),
+    ('(', [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02]),
+    (')', [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08]),
+];
+const GLYPH_UNKNOWN: [u8; 7] = [0x0E, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WatermarkCorner {
+    #[default]
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl WatermarkCorner {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "top-left" => Some(Self::TopLeft),
+            "top-right" => Some(Self::TopRight),
+            "bottom-left" => Some(Self::BottomLeft),
+            "bottom-right" => Some(Self::BottomRight),
+            _ => None,
+        }
+    }
+}
+
+fn glyph(c: char) -> [u8; 7] {
+    let c = c.to_ascii_uppercase();
+    FONT.iter()
+        .find(|(k, _)| *k == c)
+        .map(|(_, g)| *g)
+        .unwrap_or(GLYPH_UNKNOWN)
+}
+
+/// renders text in white on black RGBA pixels, returns width and tightly packed pixels
+pub fn render_text(text: &str) -> (u32, Vec<u8>) {
+    let chars: Vec<char> = text.chars().take(MAX_CHARS as _).collect();
+    let width = chars.len() as u32 * CELL_WIDTH + 1;
+    let mut pixels = [0x00, 0x00, 0x00, 0xff].repeat((width * WATERMARK_HEIGHT) as _);
+
+    for (i, &c) in chars.iter().enumerate() {
+        for (row, bits) in glyph(c).into_iter().enumerate() {
+            for col in 0..GLYPH_WIDTH {
+                if bits & (1 << (GLYPH_WIDTH - 1 - col)) == 0 {
+                    continue;
+                }
+                let x = 1 + i as u32 * CELL_WIDTH + col;
+                let y = 1 + row as u32;
+                let offset = ((y * width + x) * WATERMARK_BPP) as usize;
+                pixels[offset..offset + 3].fill(0xff);
+            }
+        }
+    }
+    (width, pixels)
+}
+
+/// expands `%f` to frame number, `%t` to local time and `%%` to `%`
+pub fn expand_watermark(template: &str, frame: u64) -> String {
+    let mut text = String::new();
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            text.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('f') => text.push_str(&frame.to_string()),
+            Some('t') => text.push_str(&local_time()),
+            Some('%') | None => text.push('%'),
+            Some(c) => {
+                text.push('%');
+                text.push(c);
+            }
+        }
+    }
+    text
+}
+
+fn local_time() -> String {
+    unsafe {
+        let now = libc::time(ptr::null_mut());
+        let mut tm: libc::tm = mem::zeroed();
+        libc::localtime_r(&now, &mut tm);
+        format!("{:02}:{:02}:{:02}", tm.tm_hour, tm.tm_min, tm.tm_sec)
+    }
+}
+
+/// text rendered on host and blitted onto export images
+pub struct WatermarkOverlay {
+    corner: WatermarkCorner,
+    image: vk::Image,
+    image_memory: vk::DeviceMemory,
+    buffer: vk::Buffer,
+    buffer_memory: vk::DeviceMemory,
+    mapped: *mut u8,
+    width: u32,
+}
+
+// the mapping is only accessed with the owning image data locked
+unsafe impl Send for WatermarkOverlay {}
+unsafe impl Sync for WatermarkOverlay {}
+
+impl WatermarkOverlay {
+    pub unsafe fn new(
+        ash_instance: &ash::Instance,
+        ash_device: &ash::Device,
+        phy_device: vk::PhysicalDevice,
+        corner: WatermarkCorner,
+    ) -> Result<Self> {
+        let mut overlay = Self {
+            corner,
+            image: vk::Image::null(),
+            image_memory: vk::DeviceMemory::null(),
+            buffer: vk::Buffer::null(),
+            buffer_memory: vk::DeviceMemory::null(),
+            mapped: ptr::null_mut(),
+            width: 0,
+        };
+        if let Err(e) = overlay.init(ash_instance, ash_device, phy_device) {
+            overlay.destroy(ash_device);
+            return Err(e);
+        }
+        Ok(overlay)
+    }
+
+    unsafe fn init(
+        &mut self,
+        ash_instance: &ash::Instance,
+        ash_device: &ash::Device,
+        phy_device: vk::PhysicalDevice,
+    ) -> Result<()> {
+        let image_info = vk::ImageCreateInfo::builder()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(vk::Format::R8G8B8A8_UNORM)
+            .extent(vk::Extent3D {
+                width: WATERMARK_WIDTH,
+                height: WATERMARK_HEIGHT,
+                depth: 1,
+            })
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::TRANSFER_SRC)
+            .initial_layout(vk::ImageLayout::UNDEFINED)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+        self.image = ash_device.create_image(&image_info, None)?;
+        self.image_memory = allocate_memory(
+            ash_instance,
+            ash_device,
+            phy_device,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            ash_device.get_image_memory_requirements(self.image),
+        )?;
+        ash_device.bind_image_memory(self.image, self.image_memory, 0)?;
+
+        let size = (WATERMARK_WIDTH * WATERMARK_HEIGHT * WATERMARK_BPP) as vk::DeviceSize;
+        let buffer_info = vk::BufferCreateInfo::builder()
+            .size(size)
+            .usage(vk::BufferUsageFlags::TRANSFER_SRC)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+        self.buffer = ash_device.create_buffer(&buffer_info, None)?;
+        self.buffer_memory = allocate_memory(
+            ash_instance,
+            ash_device,
+            phy_device,
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+            ash_device.get_buffer_memory_requirements(self.buffer),
+        )?;
+        ash_device.bind_buffer_memory(self.buffer, self.buffer_memory, 0)?;
+        self.mapped = ash_device.map_memory(
+            self.buffer_memory,
+            0,
+            vk::WHOLE_SIZE,
+            vk::MemoryMapFlags::empty(),
+        )? as _;
+        Ok(())
+    }
+
+    pub unsafe fn destroy(&self, ash_device: &ash::Device) {
+        if !self.mapped.is_null() {
+            ash_device.unmap_memory(self.buffer_memory);
+        }
+        ash_device.destroy_buffer(self.buffer, None);
+        ash_device.free_memory(self.buffer_memory, None);
+        ash_device.destroy_image(self.image, None);
+        ash_device.free_memory(self.image_memory, None);
+    }
+
+    /// must not be called while a previous recording is still executing
+    pub unsafe fn upload(&mut self, text: &str) {
+        let (width, pixels) = render_text(text);
+        ptr::copy_nonoverlapping(pixels.as_ptr(), self.mapped, pixels.len());
+        self.width = width;
+    }
+
+    /// records blitting uploaded text onto `export_image` in `TRANSFER_DST_OPTIMAL` layout,
+    /// skipped if it doesn't fit
+    pub unsafe fn record(
+        &self,
+        ash_device: &ash::Device,
+        command_buffer: vk::CommandBuffer,
+        export_image: vk::Image,
+        width: u32,
+        height: u32,
+    ) {
+        let dst_width = self.width * SCALE;
+        let dst_height = WATERMARK_HEIGHT * SCALE;
+        if self.width == 0 || dst_width + MARGIN * 2 > width || dst_height + MARGIN * 2 > height {
+            return;
+        }
+        let x = match self.corner {
+            WatermarkCorner::TopLeft | WatermarkCorner::BottomLeft => MARGIN,
+            _ => width - MARGIN - dst_width,
+        };
+        let y = match self.corner {
+            WatermarkCorner::TopLeft | WatermarkCorner::TopRight => MARGIN,
+            _ => height - MARGIN - dst_height,
+        };
+
+        let subresource = vk::ImageSubresourceRange::builder()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .base_mip_level(0)
+            .level_count(1)
+            .base_array_layer(0)
+            .layer_count(1)
+            .build();
+        let subresource_layer = vk::ImageSubresourceLayers::builder()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .mip_level(0)
+            .base_array_layer(0)
+            .layer_count(1)
+            .build();
+
+        let overlay_barrier = vk::ImageMemoryBarrier::builder()
+            .old_layout(vk::ImageLayout::UNDEFINED)
+            .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(self.image)
+            .subresource_range(subresource)
+            .src_access_mask(vk::AccessFlags::empty())
+            .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .build();
+        ash_device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::TOP_OF_PIPE,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &[overlay_barrier],
+        );
+
+        let region = vk::BufferImageCopy::builder()
+            .image_subresource(subresource_layer)
+            .image_extent(vk::Extent3D {
+                width: self.width,
+                height: WATERMARK_HEIGHT,
+                depth: 1,
+            })
+            .build();
+        ash_device.cmd_copy_buffer_to_image(
+            command_buffer,
+            self.buffer,
+            self.image,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            &[region],
+        );
+
+        let overlay_barrier = vk::ImageMemoryBarrier::builder()
+            .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+            .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(self.image)
+            .subresource_range(subresource)
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
+            .build();
+        // orders the blit after the frame copy onto export image
+        let export_barrier = vk::ImageMemoryBarrier::builder()
+            .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+            .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(export_image)
+            .subresource_range(subresource)
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .build();
+        ash_device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &[overlay_barrier, export_barrier],
+        );
+
+        let image_blit = vk::ImageBlit::builder()
+            .src_offsets([
+                vk::Offset3D { x: 0, y: 0, z: 0 },
+                vk::Offset3D {
+                    x: self.width as _,
+                    y: WATERMARK_HEIGHT as _,
+                    z: 1,
+                },
+            ])
+            .src_subresource(subresource_layer)
+            .dst_offsets([
+                vk::Offset3D {
+                    x: x as _,
+                    y: y as _,
+                    z: 0,
+                },
+                vk::Offset3D {
+                    x: (x + dst_width) as _,
+                    y: (y + dst_height) as _,
+                    z: 1,
+                },
+            ])
+            .dst_subresource(subresource_layer)
+            .build();
+        ash_device.cmd_blit_image(
+            command_buffer,
+            self.image,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            export_image,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            &[image_blit],
+            vk::Filter::NEAREST,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expand() {
+        assert_eq!(expand_watermark("REC #%f", 42), "REC #42");
+        assert_eq!(expand_watermark("100%%", 0), "100%");
+        assert_eq!(expand_watermark("%x%", 0), "%x%");
+        assert_eq!(expand_watermark("%t", 0).len(), 8);
+    }
+
+    #[test]
+    fn render() {
+        let (width, pixels) = render_text("1");
+        assert_eq!(width, CELL_WIDTH + 1);
+        assert_eq!(
+            pixels.len(),
+            (width * WATERMARK_HEIGHT * WATERMARK_BPP) as usize
+        );
+        // top row of '1' is 0x04, the center column
+        let offset = ((width + 1 + 2) * WATERMARK_BPP) as usize;
+        assert_eq!(&pixels[offset..offset + 4], &[0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(&pixels[0..4], &[0x00, 0x00, 0x00, 0xff]);
+    }
+}